@@ -0,0 +1,110 @@
+use crate::color::{self, bold, red};
+use crate::error::{Error, Result};
+use std::ffi::OsString;
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+/// Run this program for each of these accounts at the same time, with the same arguments but
+/// --all-accounts, each in its own process and on its own connection. Their output is prefixed
+/// with the name of their account, then the failures are reported together.
+pub fn run_all(accounts: &[String]) -> Result<()> {
+    let program = std::env::current_exe()?;
+    let args = std::env::args_os()
+        .skip(1)
+        .filter(|x| x != "--all-accounts")
+        .collect::<Vec<_>>();
+    let results = std::thread::scope(|scope| {
+        let runs = accounts
+            .iter()
+            .map(|account| scope.spawn(|| run(&program, &args, account)))
+            .collect::<Vec<_>>();
+        runs.into_iter()
+            .map(|x| x.join().expect("the runs do not panic"))
+            .collect::<Vec<_>>()
+    });
+    println!("{}", bold("Accounts:"));
+    let mut failed = 0;
+    for (account, result) in accounts.iter().zip(results) {
+        match result {
+            Ok(()) => println!("{}: done.", account),
+            Err(err) => {
+                println!("{}: {} {}", account, red("failed:"), err);
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        return Err(Error::Accounts {
+            failed,
+            total: accounts.len(),
+        });
+    }
+    Ok(())
+}
+
+/// Run for one account, returns the error it failed with.
+fn run(program: &Path, args: &[OsString], account: &str) -> std::result::Result<(), String> {
+    let mut child = Command::new(program)
+        .args(args)
+        .arg("--account")
+        .arg(account)
+        .env_remove("IMAP_CLEANUP_ALL_ACCOUNTS")
+        // Their output is piped, colored as this one unless --color is given.
+        .env(
+            "IMAP_CLEANUP_COLOR",
+            if color::stdout() { "always" } else { "never" },
+        )
+        // Nothing is asked: its questions and prompts would not be seen.
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|err| err.to_string())?;
+    let stdout = child.stdout.take().expect("stdout is piped");
+    let stderr = child.stderr.take().expect("stderr is piped");
+    let error = std::thread::scope(|scope| {
+        scope.spawn(|| prefix(stdout, |line| println!("[{}] {}", account, line)));
+        prefix(stderr, |line| eprintln!("[{}] {}", account, line))
+    });
+    let status = child.wait().map_err(|err| err.to_string())?;
+    match (status.success(), error) {
+        (true, _) => Ok(()),
+        (false, Some(error)) => Err(error),
+        (false, None) => Err(status.to_string()),
+    }
+}
+
+/// Print the lines of the output of a run, returns the error it ended with if any.
+fn prefix(output: impl Read, mut print: impl FnMut(&str)) -> Option<String> {
+    let mut error = None;
+    for line in BufReader::new(output).lines().map_while(|x| x.ok()) {
+        print(&line);
+        if let Some(message) = color::strip(&line).strip_prefix("Error: ") {
+            error = Some(message.to_string());
+        }
+    }
+    error
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn output() {
+        let mut lines = Vec::new();
+        let error = prefix(
+            &b"INBOX: 3 deleted.\nError: 1 of 2 mailboxes failed\n"[..],
+            |line| lines.push(format!("[work] {}", line)),
+        );
+        assert_eq!(
+            lines,
+            [
+                "[work] INBOX: 3 deleted.",
+                "[work] Error: 1 of 2 mailboxes failed"
+            ]
+        );
+        assert_eq!(error.as_deref(), Some("1 of 2 mailboxes failed"));
+    }
+}
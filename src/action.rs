@@ -0,0 +1,850 @@
+use crate::bar::Bar;
+use crate::color::yellow;
+use crate::error::{Error, Result};
+use crate::gmail;
+use crate::interrupt;
+use crate::mailbox::{self, quote};
+use crate::mime;
+use crate::output::tell;
+use crate::retry::parse_duration;
+use crate::tap::{Tap, KEEPALIVE};
+use crate::{batches, try_batches};
+use imap::types::Flag;
+use imap::Session;
+use std::io::{Read, Write};
+use std::str::FromStr;
+use std::sync::{Mutex, Once};
+use std::time::{Duration, Instant};
+
+/// What is done with the messages found in a mailbox.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Action {
+    /// Flag them \Deleted and expunge them.
+    Delete,
+    /// Move them to this mailbox.
+    Move(String),
+    /// Remove this Gmail label from them.
+    RemoveLabel(String),
+    /// Remove them from the Gmail INBOX, they stay in All Mail.
+    GmailArchive,
+    /// Replace them by a copy without their attachments.
+    StripAttachments,
+}
+
+/// The extensions the actions can use, queried once after authentication.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Extensions {
+    /// MOVE (RFC 6851).
+    pub can_move: bool,
+    /// UIDPLUS (RFC 4315), to verify the copies with COPYUID.
+    pub uidplus: bool,
+    /// The Gmail extensions.
+    pub gmail: bool,
+}
+
+impl Extensions {
+    pub fn query<S: Read + Write>(session: &mut Session<S>) -> Result<Self> {
+        let capabilities = session.capabilities()?;
+        Ok(Extensions {
+            can_move: capabilities.has_str("MOVE"),
+            uidplus: capabilities.has_str("UIDPLUS"),
+            gmail: capabilities.has_str(gmail::CAPABILITY),
+        })
+    }
+}
+
+/// The pace of the changes, for the servers throttling the accounts changing too many messages.
+#[derive(clap::Args, Debug)]
+pub struct RateArgs {
+    /// Change at most this many messages per second, minute or hour, like 500/min, for the
+    /// providers (like Gmail) throttling or locking the accounts changing too many messages too
+    /// fast. The STORE and MOVE commands wait their turn, in batches no bigger than the limit.
+    #[clap(
+        long,
+        value_name = "COUNT/PERIOD",
+        value_parser(RateLimit::from_str),
+        env = "IMAP_CLEANUP_RATE_LIMIT"
+    )]
+    pub rate_limit: Option<RateLimit>,
+
+    /// With --rate-limit, expunge the messages after each batch instead of all at the end.
+    #[clap(long, requires = "rate-limit", env = "IMAP_CLEANUP_SPREAD_EXPUNGE")]
+    pub spread_expunge: bool,
+}
+
+/// A number of messages per period.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RateLimit {
+    pub count: u32,
+    pub period: Duration,
+}
+
+impl FromStr for RateLimit {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (count, period) = s
+            .split_once('/')
+            .ok_or_else(|| "expected a number of messages per period, like 500/min".to_string())?;
+        let count = match count.parse::<u32>() {
+            Ok(0) | Err(_) => return Err(format!("invalid number of messages: {}", count)),
+            Ok(count) => count,
+        };
+        let period = match period {
+            "s" | "sec" | "second" => Duration::from_secs(1),
+            "m" | "min" | "minute" => Duration::from_secs(60),
+            "h" | "hour" => Duration::from_secs(3600),
+            period => parse_duration(period)?,
+        };
+        if period.is_zero() {
+            return Err("the period cannot be 0".to_string());
+        }
+        Ok(RateLimit { count, period })
+    }
+}
+
+/// Paces the changes of `Action::apply` following --rate-limit, across the mailboxes.
+#[derive(Debug, Default)]
+pub struct Limiter {
+    rate_limit: Option<RateLimit>,
+    spread_expunge: bool,
+    /// When the next batch can be sent.
+    next: Mutex<Option<Instant>>,
+}
+
+impl Limiter {
+    pub fn new(args: &RateArgs) -> Self {
+        Limiter {
+            rate_limit: args.rate_limit,
+            spread_expunge: args.spread_expunge,
+            next: Mutex::new(None),
+        }
+    }
+
+    /// The batch size fitting the limit.
+    pub fn batch_size(&self, batch_size: usize) -> usize {
+        self.rate_limit
+            .map_or(batch_size, |x| batch_size.min(x.count as usize))
+    }
+
+    /// Wait for the turn of a batch changing `count` messages, keeping the session alive. Fails
+    /// with `Error::Interrupted` on Ctrl+C.
+    fn wait<S: Read + Write>(
+        &self,
+        session: &mut Session<S>,
+        tap: &Tap,
+        count: usize,
+    ) -> Result<()> {
+        let rate_limit = match self.rate_limit {
+            Some(rate_limit) => rate_limit,
+            None => return Ok(()),
+        };
+        // The turn is taken first, for the other connections of --jobs.
+        let now = Instant::now();
+        let start = {
+            let mut next = self.next.lock().unwrap();
+            let start = next.map_or(now, |x| x.max(now));
+            *next = Some(start + rate_limit.period * count as u32 / rate_limit.count);
+            start
+        };
+        if start > now {
+            tell!(
+                "Rate limit: waiting {:.0}s.",
+                (start - now).as_secs_f32().ceil()
+            );
+        }
+        while let Some(wait) = start.checked_duration_since(Instant::now()) {
+            if wait.is_zero() {
+                break;
+            }
+            // Short enough to stop soon after Ctrl+C.
+            std::thread::sleep(wait.min(KEEPALIVE).min(Duration::from_secs(1)));
+            interrupt::check()?;
+            tap.keepalive(session)?;
+        }
+        Ok(())
+    }
+}
+
+impl Action {
+    /// Whether the space of the messages is freed, for the estimates of the dry runs: the
+    /// messages moved or archived are kept, the stripped ones only lose their attachments.
+    pub fn frees(&self) -> bool {
+        *self == Action::Delete
+    }
+
+    /// What happened to the messages, for the summary.
+    pub fn done(&self) -> String {
+        match self {
+            Action::Delete => "deleted".to_string(),
+            Action::Move(mailbox) => format!("moved to {}", mailbox),
+            Action::RemoveLabel(label) => format!("removed from label {}", label),
+            Action::GmailArchive => "archived".to_string(),
+            Action::StripAttachments => "stripped of their attachments".to_string(),
+        }
+    }
+
+    /// Apply the action to the messages with these UIDs in `mailbox`, the selected mailbox, whose
+    /// UIDVALIDITY was `uid_validity` when they were found.
+    ///
+    /// Without MOVE, the messages are copied then deleted, but only once the copies are verified:
+    /// with the COPYUID of each COPY or, without UIDPLUS, by counting the messages of the
+    /// destination before and after. The mailbox is then selected again, and its UIDVALIDITY
+    /// checked.
+    ///
+    /// What is done is kept in `progress`: when the connection is lost, the action is resumed on
+    /// a new one with the same progress, the messages already copied or flagged are skipped.
+    /// The changes are paced by the `limiter`. After Ctrl+C, the action stops between two batches
+    /// with `Error::Interrupted`, nothing is expunged.
+    /// Returns the UIDs left out because the server refused to flag them, already reported.
+    #[allow(clippy::too_many_arguments)]
+    pub fn apply<S: Read + Write>(
+        &self,
+        session: &mut Session<S>,
+        tap: &Tap,
+        limiter: &Limiter,
+        extensions: Extensions,
+        mailbox: &str,
+        uid_validity: Option<u32>,
+        uids: &[u32],
+        progress: &mut Progress,
+    ) -> Result<Vec<u32>> {
+        if uids.is_empty() {
+            return Ok(Vec::new());
+        }
+        let to_copy = progress.left(uids, &progress.copied);
+        match self {
+            Action::RemoveLabel(label) => {
+                let store = format!("-X-GM-LABELS.SILENT ({})", quote(label));
+                progress.store(uids, |set, batch| {
+                    limiter.wait(session, tap, batch.len())?;
+                    session.uid_store(set, &store)?;
+                    Ok(())
+                })?;
+                return Ok(progress.failed.clone());
+            }
+            Action::GmailArchive => {
+                progress.store(uids, |set, batch| {
+                    limiter.wait(session, tap, batch.len())?;
+                    session.uid_store(set, r"-X-GM-LABELS.SILENT (\Inbox)")?;
+                    Ok(())
+                })?;
+                return Ok(progress.failed.clone());
+            }
+            Action::StripAttachments => {
+                for &uid in uids {
+                    tap.keepalive(session)?;
+                    if progress.stored.contains(&uid) {
+                        continue;
+                    }
+                    limiter.wait(session, tap, 1)?;
+                    // When the connection was lost after appending the copy, the original is
+                    // only left to flag.
+                    if !progress.copied.contains(&uid) && strip_attachments(session, mailbox, uid)?
+                    {
+                        progress.copied.push(uid);
+                    }
+                    if progress.copied.contains(&uid) {
+                        session.uid_store(uid.to_string(), r"+FLAGS.SILENT (\Deleted)")?;
+                        progress.stored.push(uid);
+                    }
+                    interrupt::check()?;
+                }
+                if !progress.stored.is_empty() {
+                    expunge(session, extensions, &progress.stored)?;
+                }
+                return Ok(Vec::new());
+            }
+            Action::Move(destination) if extensions.can_move => {
+                let mut bar = Bar::new("MOVE", to_copy.len());
+                for (set, batch) in batches(&to_copy) {
+                    limiter.wait(session, tap, batch.len())?;
+                    session.uid_mv(set, destination)?;
+                    progress.copied.extend(batch);
+                    bar.inc(batch.len());
+                    interrupt::check()?;
+                }
+                return Ok(Vec::new());
+            }
+            Action::Move(destination) if extensions.uidplus => {
+                let mut bar = Bar::new("COPY", to_copy.len());
+                for (set, batch) in batches(&to_copy) {
+                    // Unlike MOVE, imap does not quote the mailbox of COPY.
+                    session.uid_copy(&set, quote(destination))?;
+                    let copied = tap.completion().as_deref().and_then(copyuid);
+                    if copied != Some(batch.len()) {
+                        return Err(unverified(destination, batch.len(), copied));
+                    }
+                    progress.copied.extend(batch);
+                    bar.inc(batch.len());
+                    interrupt::check()?;
+                }
+            }
+            Action::Move(destination) => {
+                if !to_copy.is_empty() {
+                    let before = session.examine(destination)?.exists as usize;
+                    mailbox::reselect(session, mailbox, uid_validity)?;
+                    for (set, _) in batches(&to_copy) {
+                        session.uid_copy(set, quote(destination))?;
+                    }
+                    let after = session.examine(destination)?.exists as usize;
+                    mailbox::reselect(session, mailbox, uid_validity)?;
+                    if after < before + to_copy.len() {
+                        return Err(unverified(
+                            destination,
+                            to_copy.len(),
+                            Some(after.saturating_sub(before)),
+                        ));
+                    }
+                    // Only once counted: when the connection is lost before, they are copied
+                    // again rather than deleted unverified.
+                    progress.copied.extend(&to_copy);
+                }
+            }
+            Action::Delete => {}
+        }
+        let spread = limiter.spread_expunge && extensions.uidplus;
+        let mut expunged = std::mem::take(&mut progress.expunged);
+        let result = progress.store(uids, |set, batch| {
+            limiter.wait(session, tap, batch.len())?;
+            session.uid_store(set, r"+FLAGS.SILENT (\Deleted)")?;
+            if spread {
+                session.uid_expunge(set)?;
+                expunged.extend(batch);
+            }
+            Ok(())
+        });
+        progress.expunged = expunged;
+        result?;
+        // When spread, the batches flagged before losing the connection are left.
+        let left = progress.unexpunged();
+        if !left.is_empty() {
+            expunge(session, extensions, &left)?;
+        }
+        Ok(progress.failed.clone())
+    }
+}
+
+/// What `Action::apply` did so far.
+#[derive(Debug, Default)]
+pub struct Progress {
+    /// The UIDs copied or moved to the destination, or whose copy without attachments was
+    /// appended.
+    pub copied: Vec<u32>,
+    /// The UIDs flagged \Deleted, whose labels were removed or whose attachments were stripped.
+    pub stored: Vec<u32>,
+    /// The UIDs the server refused to flag.
+    pub failed: Vec<u32>,
+    /// The UIDs expunged along the way with --spread-expunge.
+    pub expunged: Vec<u32>,
+}
+
+impl Progress {
+    /// The UIDs flagged but not expunged yet.
+    pub fn unexpunged(&self) -> Vec<u32> {
+        self.left(&self.stored, &self.expunged)
+    }
+
+    /// The UIDs not done yet.
+    fn left(&self, uids: &[u32], done: &[u32]) -> Vec<u32> {
+        uids.iter()
+            .copied()
+            .filter(|x| !done.contains(x) && !self.failed.contains(x))
+            .collect()
+    }
+
+    /// Run a STORE command on the UIDs not flagged yet.
+    fn store(
+        &mut self,
+        uids: &[u32],
+        mut run: impl FnMut(&str, &[u32]) -> Result<()>,
+    ) -> Result<()> {
+        let left = self.left(uids, &self.stored);
+        let stored = &mut self.stored;
+        let failed = try_batches("STORE", &left, |set, batch| {
+            run(set, batch)?;
+            stored.extend(batch);
+            interrupt::check()?;
+            Ok(())
+        })?;
+        self.failed.extend(failed);
+        Ok(())
+    }
+}
+
+/// Expunge the messages with these UIDs, flagged \Deleted. Without UIDPLUS, EXPUNGE also removes
+/// the messages other clients flagged \Deleted, which is told once.
+fn expunge<S: Read + Write>(
+    session: &mut Session<S>,
+    extensions: Extensions,
+    uids: &[u32],
+) -> Result<()> {
+    if extensions.uidplus {
+        for (set, _) in batches(uids) {
+            session.uid_expunge(set)?;
+        }
+        return Ok(());
+    }
+    static WARNING: Once = Once::new();
+    WARNING.call_once(|| {
+        tell!(
+            "{} the server does not support UIDPLUS, EXPUNGE also removes the messages \
+             other clients flagged \\Deleted.",
+            yellow("Warning:")
+        )
+    });
+    session.expunge()?;
+    Ok(())
+}
+
+/// Append a copy of a message without its attachments, with the same flags and internal date.
+/// Returns whether there was something to strip.
+fn strip_attachments<S: Read + Write>(
+    session: &mut Session<S>,
+    mailbox: &str,
+    uid: u32,
+) -> Result<bool> {
+    let fetch = session.uid_fetch(uid.to_string(), "(UID FLAGS INTERNALDATE BODY.PEEK[])")?;
+    let message = match fetch.iter().find(|x| x.uid == Some(uid)) {
+        Some(message) => message,
+        None => return Ok(false),
+    };
+    let stripped = match message.body().and_then(mime::strip_attachments) {
+        Some(stripped) => stripped,
+        None => return Ok(false),
+    };
+    // Those set by the server only, refused by APPEND.
+    let flags = message
+        .flags()
+        .iter()
+        .map(|x| Flag::from(x.to_string()))
+        .filter(|x| !matches!(x, Flag::Recent | Flag::MayCreate))
+        .collect::<Vec<_>>();
+    session.append_with_flags_and_date(mailbox, &stripped, &flags, message.internal_date())?;
+    Ok(true)
+}
+
+fn unverified(destination: &str, expected: usize, copied: Option<usize>) -> Error {
+    Error::Protocol(format!(
+        "could not verify the copy to {}: {} messages copied, {} expected, nothing deleted",
+        destination,
+        copied.map_or("unknown".to_string(), |x| x.to_string()),
+        expected
+    ))
+}
+
+/// The number of messages copied according to the COPYUID response code of a completion like
+/// `a4 OK [COPYUID 38505 304,319:320 3956:3958] Done`.
+fn copyuid(completion: &str) -> Option<usize> {
+    let start = completion.find("[COPYUID ")? + "[COPYUID ".len();
+    let code = &completion[start..];
+    let code = &code[..code.find(']')?];
+    let destination = code.split_ascii_whitespace().nth(2)?;
+    destination
+        .split(',')
+        .map(|x| match x.split_once(':') {
+            Some((a, b)) => {
+                let (a, b) = (a.parse::<u32>().ok()?, b.parse::<u32>().ok()?);
+                Some((a.max(b) - a.min(b)) as usize + 1)
+            }
+            None => x.parse::<u32>().ok().map(|_| 1),
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::connection::test::session;
+
+    #[test]
+    fn move_or_copy() {
+        let action = Action::Move("Archive".to_string());
+
+        let (mut imap, tap, sent) = session(b"a2 OK done\r\n");
+        let extensions = Extensions {
+            can_move: true,
+            uidplus: true,
+            ..Extensions::default()
+        };
+        action
+            .apply(
+                &mut imap,
+                &tap,
+                &Limiter::default(),
+                extensions,
+                "INBOX",
+                None,
+                &[1, 2, 5],
+                &mut Progress::default(),
+            )
+            .unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&sent.borrow()),
+            "a2 UID MOVE 1:2,5:5 \"Archive\"\r\n"
+        );
+
+        let (mut imap, tap, sent) = session(
+            b"a2 OK [COPYUID 7 3:4 10:11] done\r\n\
+              a3 OK done\r\n\
+              * 1 EXPUNGE\r\n\
+              * 1 EXPUNGE\r\n\
+              a4 OK done\r\n",
+        );
+        let extensions = Extensions {
+            uidplus: true,
+            ..Extensions::default()
+        };
+        action
+            .apply(
+                &mut imap,
+                &tap,
+                &Limiter::default(),
+                extensions,
+                "INBOX",
+                None,
+                &[3, 4],
+                &mut Progress::default(),
+            )
+            .unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&sent.borrow()),
+            "a2 UID COPY 3:4 \"Archive\"\r\n\
+             a3 UID STORE 3:4 +FLAGS.SILENT (\\Deleted)\r\n\
+             a4 UID EXPUNGE 3:4\r\n"
+        );
+
+        // The server copied only one message: nothing is deleted.
+        let (mut imap, tap, sent) = session(b"a2 OK [COPYUID 7 3 10] done\r\n");
+        assert!(action
+            .apply(
+                &mut imap,
+                &tap,
+                &Limiter::default(),
+                extensions,
+                "INBOX",
+                None,
+                &[3, 4],
+                &mut Progress::default()
+            )
+            .is_err());
+        assert_eq!(
+            String::from_utf8_lossy(&sent.borrow()),
+            "a2 UID COPY 3:4 \"Archive\"\r\n"
+        );
+    }
+
+    #[test]
+    fn resume() {
+        // The connection was lost after copying 3 and flagging it \Deleted.
+        let mut progress = Progress {
+            copied: vec![3],
+            stored: vec![3],
+            ..Progress::default()
+        };
+        let (mut imap, tap, sent) = session(
+            b"a2 OK [COPYUID 7 4 11] done\r\n\
+              a3 OK done\r\n\
+              * 1 EXPUNGE\r\n\
+              * 1 EXPUNGE\r\n\
+              a4 OK done\r\n",
+        );
+        let extensions = Extensions {
+            uidplus: true,
+            ..Extensions::default()
+        };
+        Action::Move("Archive".to_string())
+            .apply(
+                &mut imap,
+                &tap,
+                &Limiter::default(),
+                extensions,
+                "INBOX",
+                None,
+                &[3, 4],
+                &mut progress,
+            )
+            .unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&sent.borrow()),
+            "a2 UID COPY 4:4 \"Archive\"\r\n\
+             a3 UID STORE 4:4 +FLAGS.SILENT (\\Deleted)\r\n\
+             a4 UID EXPUNGE 3:4\r\n"
+        );
+        assert_eq!((progress.copied, progress.stored), (vec![3, 4], vec![3, 4]));
+    }
+
+    #[test]
+    fn resume_counted() {
+        // The connection is lost after copying, before counting the copies.
+        let mut progress = Progress::default();
+        let (mut imap, tap, _) = session(
+            b"* 5 EXISTS\r\na2 OK [READ-ONLY] done\r\n\
+              * 4 EXISTS\r\na3 OK [READ-WRITE] done\r\n\
+              a4 OK done\r\n",
+        );
+        let action = Action::Move("Archive".to_string());
+        let apply = |imap: &mut _, tap: &_, progress: &mut _| {
+            action.apply(
+                imap,
+                tap,
+                &Limiter::default(),
+                Extensions::default(),
+                "INBOX",
+                None,
+                &[3, 4],
+                progress,
+            )
+        };
+        assert!(apply(&mut imap, &tap, &mut progress).is_err());
+        assert!(progress.copied.is_empty());
+        // Resumed: copied and counted again before deleting anything.
+        let (mut imap, tap, sent) = session(
+            b"* 7 EXISTS\r\na2 OK [READ-ONLY] done\r\n\
+              * 4 EXISTS\r\na3 OK [READ-WRITE] done\r\n\
+              a4 OK done\r\n\
+              * 9 EXISTS\r\na5 OK [READ-ONLY] done\r\n\
+              * 4 EXISTS\r\na6 OK [READ-WRITE] done\r\n\
+              a7 OK done\r\n\
+              a8 OK done\r\n",
+        );
+        apply(&mut imap, &tap, &mut progress).unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&sent.borrow()),
+            "a2 EXAMINE \"Archive\"\r\n\
+             a3 SELECT \"INBOX\"\r\n\
+             a4 UID COPY 3:4 \"Archive\"\r\n\
+             a5 EXAMINE \"Archive\"\r\n\
+             a6 SELECT \"INBOX\"\r\n\
+             a7 UID STORE 3:4 +FLAGS.SILENT (\\Deleted)\r\n\
+             a8 EXPUNGE\r\n"
+        );
+        assert_eq!(progress.copied, [3, 4]);
+    }
+
+    #[test]
+    fn rate_limit() {
+        assert_eq!(
+            "500/min".parse(),
+            Ok(RateLimit {
+                count: 500,
+                period: Duration::from_secs(60)
+            })
+        );
+        assert_eq!(
+            "100/10m".parse(),
+            Ok(RateLimit {
+                count: 100,
+                period: Duration::from_secs(600)
+            })
+        );
+        assert!("500".parse::<RateLimit>().is_err());
+        assert!("0/s".parse::<RateLimit>().is_err());
+        assert!("10/0s".parse::<RateLimit>().is_err());
+
+        let limiter = Limiter::new(&RateArgs {
+            rate_limit: Some("20/s".parse().unwrap()),
+            spread_expunge: true,
+        });
+        assert_eq!(limiter.batch_size(500), 20);
+        let (mut imap, tap, sent) = session(
+            b"a2 OK done\r\n\
+              * 1 EXPUNGE\r\n\
+              a3 OK done\r\n",
+        );
+        let start = Instant::now();
+        limiter.wait(&mut imap, &tap, 2).unwrap();
+        let extensions = Extensions {
+            uidplus: true,
+            ..Extensions::default()
+        };
+        Action::Delete
+            .apply(
+                &mut imap,
+                &tap,
+                &limiter,
+                extensions,
+                "INBOX",
+                None,
+                &[3, 4],
+                &mut Progress::default(),
+            )
+            .unwrap();
+        // The batch waited for the 2 messages before.
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert_eq!(
+            String::from_utf8_lossy(&sent.borrow()),
+            "a2 UID STORE 3:4 +FLAGS.SILENT (\\Deleted)\r\n\
+             a3 UID EXPUNGE 3:4\r\n"
+        );
+    }
+
+    #[test]
+    fn count_copies() {
+        let (mut imap, tap, sent) = session(
+            b"* 5 EXISTS\r\na2 OK [READ-ONLY] done\r\n\
+              * 4 EXISTS\r\na3 OK [READ-WRITE] done\r\n\
+              a4 OK done\r\n\
+              * 7 EXISTS\r\na5 OK [READ-ONLY] done\r\n\
+              * 4 EXISTS\r\na6 OK [READ-WRITE] done\r\n\
+              a7 OK done\r\n\
+              a8 OK done\r\n",
+        );
+        Action::Move("Archive".to_string())
+            .apply(
+                &mut imap,
+                &tap,
+                &Limiter::default(),
+                Extensions::default(),
+                "INBOX",
+                None,
+                &[3, 4],
+                &mut Progress::default(),
+            )
+            .unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&sent.borrow()),
+            "a2 EXAMINE \"Archive\"\r\n\
+             a3 SELECT \"INBOX\"\r\n\
+             a4 UID COPY 3:4 \"Archive\"\r\n\
+             a5 EXAMINE \"Archive\"\r\n\
+             a6 SELECT \"INBOX\"\r\n\
+             a7 UID STORE 3:4 +FLAGS.SILENT (\\Deleted)\r\n\
+             a8 EXPUNGE\r\n"
+        );
+    }
+
+    #[test]
+    fn remove_label() {
+        let (mut imap, tap, sent) = session(b"a2 OK done\r\n");
+        Action::RemoveLabel("Newsletters".to_string())
+            .apply(
+                &mut imap,
+                &tap,
+                &Limiter::default(),
+                Extensions::default(),
+                "INBOX",
+                None,
+                &[3, 4],
+                &mut Progress::default(),
+            )
+            .unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&sent.borrow()),
+            "a2 UID STORE 3:4 -X-GM-LABELS.SILENT (\"Newsletters\")\r\n"
+        );
+
+        let (mut imap, tap, sent) = session(b"a2 OK done\r\n");
+        Action::GmailArchive
+            .apply(
+                &mut imap,
+                &tap,
+                &Limiter::default(),
+                Extensions::default(),
+                "INBOX",
+                None,
+                &[3],
+                &mut Progress::default(),
+            )
+            .unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&sent.borrow()),
+            "a2 UID STORE 3:3 -X-GM-LABELS.SILENT (\\Inbox)\r\n"
+        );
+    }
+
+    #[test]
+    fn strip_attachments() {
+        let (mut imap, tap, sent) = session(
+            b"* 1 FETCH (UID 3 FLAGS (\\Seen \\Recent $Work) INTERNALDATE \"01-Mar-2023 12:00:00 +0100\" \
+              BODY[] {186}\r\n\
+              Content-Type: multipart/mixed; boundary=b\r\n\
+              \r\n\
+              --b\r\n\
+              Content-Type: text/plain\r\n\
+              \r\n\
+              Hi\r\n\
+              --b\r\n\
+              Content-Type: application/pdf\r\n\
+              Content-Disposition: attachment; filename=a.pdf\r\n\
+              \r\n\
+              JVBERi0K\r\n\
+              --b--\r\n\
+              )\r\n\
+              a2 OK done\r\n\
+              + go ahead\r\n\
+              a3 OK appended\r\n\
+              a4 OK done\r\n\
+              * 2 FETCH (UID 4 FLAGS () BODY[] {2}\r\n\r\n)\r\n\
+              a5 OK done\r\n\
+              a6 OK expunged\r\n",
+        );
+        Action::StripAttachments
+            .apply(
+                &mut imap,
+                &tap,
+                &Limiter::default(),
+                Extensions::default(),
+                "INBOX",
+                None,
+                &[3, 4],
+                &mut Progress::default(),
+            )
+            .unwrap();
+        let sent = String::from_utf8_lossy(&sent.borrow()).into_owned();
+        let commands = sent
+            .lines()
+            .filter(|x| x.starts_with('a'))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            commands,
+            [
+                "a2 UID FETCH 3 (UID FLAGS INTERNALDATE BODY.PEEK[])",
+                "a3 APPEND \"INBOX\" (\\Seen $Work) \"01-Mar-2023 12:00:00 +0100\" {248}",
+                "a4 UID STORE 3 +FLAGS.SILENT (\\Deleted)",
+                "a5 UID FETCH 4 (UID FLAGS INTERNALDATE BODY.PEEK[])",
+                "a6 EXPUNGE",
+            ]
+        );
+        assert!(sent.contains("The attachment a.pdf (application/pdf, 8 bytes) was removed"));
+    }
+
+    #[test]
+    fn resume_stripped() {
+        // The connection was lost after appending the copy of 3, before flagging it.
+        let mut progress = Progress {
+            copied: vec![3],
+            ..Progress::default()
+        };
+        let (mut imap, tap, sent) = session(b"a2 OK done\r\na3 OK expunged\r\n");
+        Action::StripAttachments
+            .apply(
+                &mut imap,
+                &tap,
+                &Limiter::default(),
+                Extensions::default(),
+                "INBOX",
+                None,
+                &[3],
+                &mut progress,
+            )
+            .unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&sent.borrow()),
+            "a2 UID STORE 3 +FLAGS.SILENT (\\Deleted)\r\na3 EXPUNGE\r\n"
+        );
+        assert_eq!(progress.stored, [3]);
+    }
+
+    #[test]
+    fn parse_copyuid() {
+        assert_eq!(
+            copyuid("a4 OK [COPYUID 38505 304,319 3956:3957] Done"),
+            Some(2)
+        );
+        assert_eq!(copyuid("a4 OK [COPYUID 1 1:3,7 12,10:9,20] Done"), Some(4));
+        assert_eq!(copyuid("a4 OK Done"), None);
+        assert_eq!(copyuid("a4 OK [COPYUID 1 1 x] Done"), None);
+    }
+}
@@ -0,0 +1,86 @@
+use chrono::prelude::*;
+use std::str::FromStr;
+
+/// A retention period like `90d`, `6w`, `18m` or `2y`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize)]
+#[serde(try_from = "String")]
+pub enum Age {
+    Days(u32),
+    Weeks(u32),
+    Months(u32),
+    Years(u32),
+}
+
+impl FromStr for Age {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let split = s
+            .find(|x: char| !x.is_ascii_digit())
+            .ok_or_else(|| format!("missing unit in {:?} (d, w, m or y)", s))?;
+        let (count, unit) = s.split_at(split);
+        let count = count
+            .parse()
+            .map_err(|_| format!("invalid number in {:?}", s))?;
+        match unit {
+            "d" => Ok(Age::Days(count)),
+            "w" => Ok(Age::Weeks(count)),
+            "m" => Ok(Age::Months(count)),
+            "y" => Ok(Age::Years(count)),
+            _ => Err(format!("invalid unit in {:?} (d, w, m or y)", s)),
+        }
+    }
+}
+
+impl TryFrom<String> for Age {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl Age {
+    /// The date that is this old on `today`.
+    pub fn before<Tz: TimeZone>(self, today: Date<Tz>) -> Date<Tz> {
+        match self {
+            Age::Days(days) => today - chrono::Duration::days(days.into()),
+            Age::Weeks(weeks) => today - chrono::Duration::weeks(weeks.into()),
+            Age::Months(months) => sub_months(today, months),
+            Age::Years(years) => sub_months(today, years * 12),
+        }
+    }
+}
+
+/// Go back `months` months, clamping the day to the end of the target month.
+fn sub_months<Tz: TimeZone>(date: Date<Tz>, months: u32) -> Date<Tz> {
+    let total = date.year() * 12 + date.month0() as i32 - months as i32;
+    let (year, month0) = (total.div_euclid(12), total.rem_euclid(12) as u32);
+    let mut day = date.day();
+    loop {
+        if let Some(date) = date.timezone().ymd_opt(year, month0 + 1, day).single() {
+            return date;
+        }
+        day -= 1;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_and_subtract() {
+        let today = Utc.ymd(2024, 3, 31);
+        assert_eq!("90d".parse(), Ok(Age::Days(90)));
+        assert_eq!(Age::Days(31).before(today), Utc.ymd(2024, 2, 29));
+        assert_eq!(Age::Weeks(1).before(today), Utc.ymd(2024, 3, 24));
+        assert_eq!(Age::Months(1).before(today), Utc.ymd(2024, 2, 29));
+        assert_eq!(Age::Months(15).before(today), Utc.ymd(2022, 12, 31));
+        assert_eq!(Age::Years(2).before(today), Utc.ymd(2022, 3, 31));
+        assert!("90".parse::<Age>().is_err());
+        assert!("d".parse::<Age>().is_err());
+        assert!("3h".parse::<Age>().is_err());
+    }
+}
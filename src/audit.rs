@@ -0,0 +1,142 @@
+use crate::bar::Bar;
+use crate::error::Result;
+use crate::mime::decode_header;
+use crate::senders::addresses;
+use chrono::Utc;
+use imap::types::Fetch;
+use imap::Session;
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+/// The JSONL file of --audit-log: a line appended per message cleaned, to tell later where a
+/// message went.
+pub struct AuditLog {
+    file: Mutex<File>,
+    account: String,
+}
+
+/// What is known of a message before cleaning it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    message_id: Option<String>,
+    from: Option<String>,
+    subject: Option<String>,
+    size: Option<u32>,
+}
+
+/// A line of the file.
+#[derive(serde::Serialize)]
+struct Record<'a> {
+    timestamp: String,
+    account: &'a str,
+    mailbox: &'a str,
+    uid: u32,
+    action: &'a str,
+    message_id: Option<&'a str>,
+    from: Option<&'a str>,
+    subject: Option<&'a str>,
+    size: Option<u32>,
+}
+
+impl AuditLog {
+    /// Open the file to append to it, the lines of each run follow those of the previous ones.
+    pub fn open(path: &Path, account: String) -> Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            file: Mutex::new(file),
+            account,
+        })
+    }
+
+    /// Fetch the entries of these messages of the selected mailbox, before cleaning them.
+    pub fn entries<S: Read + Write>(
+        session: &mut Session<S>,
+        uids: &[u32],
+    ) -> Result<BTreeMap<u32, Entry>> {
+        let mut entries = BTreeMap::new();
+        let mut bar = Bar::new("FETCH", uids.len());
+        for (set, batch) in crate::batches(uids) {
+            for message in session.uid_fetch(set, "(ENVELOPE RFC822.SIZE)")?.iter() {
+                if let Some(uid) = message.uid {
+                    entries.insert(uid, entry(message));
+                }
+            }
+            bar.inc(batch.len());
+        }
+        Ok(entries)
+    }
+
+    /// Append a line per message of `uids` the action was applied to.
+    pub fn write(
+        &self,
+        mailbox: &str,
+        action: &str,
+        uids: &[u32],
+        entries: &BTreeMap<u32, Entry>,
+    ) -> Result<()> {
+        let timestamp = Utc::now().to_rfc3339();
+        let mut file = self.file.lock().unwrap();
+        for uid in uids {
+            let entry = entries.get(uid);
+            let record = Record {
+                timestamp: timestamp.clone(),
+                account: &self.account,
+                mailbox,
+                uid: *uid,
+                action,
+                message_id: entry.and_then(|x| x.message_id.as_deref()),
+                from: entry.and_then(|x| x.from.as_deref()),
+                subject: entry.and_then(|x| x.subject.as_deref()),
+                size: entry.and_then(|x| x.size),
+            };
+            // A line per write: the runs of --all-accounts may append to the same file.
+            let mut line = serde_json::to_string(&record).expect("the record is serializable");
+            line.push('\n');
+            file.write_all(line.as_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+fn entry(message: &Fetch) -> Entry {
+    let envelope = message.envelope();
+    Entry {
+        message_id: envelope
+            .and_then(|x| x.message_id)
+            .map(|x| String::from_utf8_lossy(x).into_owned()),
+        from: addresses(envelope.and_then(|x| x.from.as_ref()))
+            .into_iter()
+            .next(),
+        subject: envelope.and_then(|x| x.subject).map(decode_header),
+        size: message.size,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::connection::test::session;
+
+    #[test]
+    fn entries() {
+        let (mut imap, _tap, _sent) = session(
+            b"* 1 FETCH (UID 4 RFC822.SIZE 120 ENVELOPE (\"Fri, 1 Mar 2019 12:00:00 +0000\" \
+              \"Hello\" ((\"Boss\" NIL \"boss\" \"work.example\")) NIL NIL NIL NIL NIL NIL \
+              \"<1@work.example>\"))\r\n\
+              a2 OK done\r\n",
+        );
+        let entries = AuditLog::entries(&mut imap, &[4]).unwrap();
+        assert_eq!(
+            entries[&4],
+            Entry {
+                message_id: Some("<1@work.example>".to_string()),
+                from: Some("boss@work.example".to_string()),
+                subject: Some("Hello".to_string()),
+                size: Some(120),
+            }
+        );
+    }
+}
@@ -0,0 +1,362 @@
+#[cfg(feature = "gssapi")]
+mod gssapi;
+
+use crate::connection::Capabilities;
+use crate::error::{Error, Result};
+use crate::output::tell;
+use crate::password::PasswordArgs;
+use crate::secrets::SecretRef;
+use imap::{Authenticator, Client, Session};
+use std::io::{Read, Write};
+
+/// Authentication mechanism used to open the session.
+#[derive(clap::ValueEnum, serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthMethod {
+    /// Pick a mechanism advertised by the server: OAUTHBEARER or XOAUTH2 when OAuth credentials
+    /// are given, LOGIN otherwise.
+    Auto,
+    /// Plain LOGIN with a password.
+    Login,
+    /// SASL XOAUTH2 with an OAuth 2.0 access token (Gmail, Office 365).
+    Xoauth2,
+    /// SASL OAUTHBEARER (RFC 7628) with an OAuth 2.0 access token (Fastmail).
+    Oauthbearer,
+    /// SASL GSSAPI with the current Kerberos ticket (requires the `gssapi` feature).
+    Gssapi,
+}
+
+impl AuthMethod {
+    /// Resolve `Auto` to a concrete mechanism using the server capabilities.
+    pub fn resolve(self, capabilities: &Capabilities, oauth: &OAuthArgs) -> AuthMethod {
+        match self {
+            AuthMethod::Auto if oauth.has_credentials() => {
+                if capabilities.has_auth("OAUTHBEARER") {
+                    AuthMethod::Oauthbearer
+                } else {
+                    AuthMethod::Xoauth2
+                }
+            }
+            AuthMethod::Auto => AuthMethod::Login,
+            AuthMethod::Gssapi if !capabilities.has_auth("GSSAPI") => {
+                tell!("The server does not advertise AUTH=GSSAPI, falling back to LOGIN.");
+                AuthMethod::Login
+            }
+            method => method,
+        }
+    }
+}
+
+/// Authenticate with the given mechanism, getting a password if needed.
+pub fn authenticate<S: Read + Write>(
+    client: Client<S>,
+    method: AuthMethod,
+    capabilities: &Capabilities,
+    target: &Target,
+    oauth: &OAuthArgs,
+    password: &PasswordArgs,
+) -> Result<Session<S>> {
+    let session = match method.resolve(capabilities, oauth) {
+        AuthMethod::Auto | AuthMethod::Login => {
+            if capabilities.has("LOGINDISABLED") {
+                return Err(Error::Protocol(
+                    "the server does not allow LOGIN, use --auth with a SASL mechanism".to_string(),
+                ));
+            }
+            Ok(client
+                .login(target.user, password.get(target.host, target.user)?)
+                .map_err(|e| e.0)?)
+        }
+        AuthMethod::Xoauth2 => self::oauth(
+            client,
+            Bearer::XOAuth2,
+            target,
+            oauth,
+            password.secret_ref.as_ref(),
+        ),
+        AuthMethod::Oauthbearer => self::oauth(
+            client,
+            Bearer::OAuthBearer,
+            target,
+            oauth,
+            password.secret_ref.as_ref(),
+        ),
+        #[cfg(feature = "gssapi")]
+        AuthMethod::Gssapi => gssapi::authenticate(client, target),
+        #[cfg(not(feature = "gssapi"))]
+        AuthMethod::Gssapi => Err(Error::Gssapi(
+            "this binary was built without the gssapi feature".to_string(),
+        )),
+    };
+    session.map_err(|err| match err {
+        Error::Imap(imap::Error::No(msg) | imap::Error::Bad(msg)) => Error::Auth(msg),
+        err => err,
+    })
+}
+
+#[derive(clap::Args, Debug)]
+pub struct OAuthArgs {
+    /// OAuth 2.0 access token.
+    #[clap(long, env = "IMAP_CLEANUP_OAUTH_TOKEN", hide_env_values = true)]
+    pub oauth_token: Option<String>,
+
+    /// OAuth 2.0 refresh token, used to get a new access token when needed.
+    #[clap(long, env = "IMAP_CLEANUP_OAUTH_REFRESH_TOKEN", hide_env_values = true)]
+    pub oauth_refresh_token: Option<String>,
+
+    /// OAuth 2.0 client ID (required with --oauth-refresh-token).
+    #[clap(long, env = "IMAP_CLEANUP_OAUTH_CLIENT_ID")]
+    pub oauth_client_id: Option<String>,
+
+    /// OAuth 2.0 client secret.
+    #[clap(long, env = "IMAP_CLEANUP_OAUTH_CLIENT_SECRET", hide_env_values = true)]
+    pub oauth_client_secret: Option<String>,
+
+    /// OAuth 2.0 token endpoint. Use
+    /// https://login.microsoftonline.com/common/oauth2/v2.0/token for Office 365.
+    #[clap(
+        long,
+        default_value = "https://oauth2.googleapis.com/token",
+        env = "IMAP_CLEANUP_OAUTH_TOKEN_URL"
+    )]
+    pub oauth_token_url: String,
+}
+
+impl OAuthArgs {
+    fn has_credentials(&self) -> bool {
+        self.oauth_token.is_some() || self.can_refresh()
+    }
+
+    fn can_refresh(&self) -> bool {
+        self.oauth_refresh_token.is_some() && self.oauth_client_id.is_some()
+    }
+
+    /// Exchange the refresh token for a new access token.
+    fn refresh(&self) -> Result<String> {
+        #[derive(serde::Deserialize)]
+        struct TokenResponse {
+            access_token: String,
+        }
+
+        let (refresh_token, client_id) = match (&self.oauth_refresh_token, &self.oauth_client_id) {
+            (Some(refresh_token), Some(client_id)) => (refresh_token, client_id),
+            _ => {
+                return Err(Error::OAuth(
+                    "--oauth-refresh-token and --oauth-client-id are required to refresh \
+                        the access token"
+                        .to_string(),
+                ))
+            }
+        };
+
+        let mut form = vec![
+            ("grant_type", "refresh_token"),
+            ("refresh_token", refresh_token.as_str()),
+            ("client_id", client_id.as_str()),
+        ];
+        if let Some(client_secret) = &self.oauth_client_secret {
+            form.push(("client_secret", client_secret.as_str()));
+        }
+
+        let response: TokenResponse = http_agent()?
+            .post(&self.oauth_token_url)
+            .send_form(&form)
+            .map_err(|err| Error::OAuth(format!("token refresh failed: {}", err)))?
+            .into_json()?;
+
+        Ok(response.access_token)
+    }
+}
+
+/// HTTP client for the token endpoint and the secrets managers, using the same TLS implementation
+/// as the IMAP connection.
+pub fn http_agent() -> Result<ureq::Agent> {
+    let builder = ureq::AgentBuilder::new();
+    #[cfg(feature = "tls-native")]
+    let builder = builder.tls_connector(std::sync::Arc::new(
+        native_tls::TlsConnector::new().map_err(|err| Error::Tls(err.to_string()))?,
+    ));
+    Ok(builder.build())
+}
+
+/// SASL mechanisms carrying an OAuth 2.0 bearer token.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Bearer {
+    /// https://developers.google.com/gmail/imap/xoauth2-protocol
+    XOAuth2,
+    /// https://tools.ietf.org/html/rfc7628
+    OAuthBearer,
+}
+
+impl Bearer {
+    fn name(self) -> &'static str {
+        match self {
+            Bearer::XOAuth2 => "XOAUTH2",
+            Bearer::OAuthBearer => "OAUTHBEARER",
+        }
+    }
+}
+
+/// Where the token is going to be sent, OAUTHBEARER includes it in the initial response.
+pub struct Target<'a> {
+    pub user: &'a str,
+    pub host: &'a str,
+    pub port: u16,
+}
+
+struct BearerAuthenticator<'a> {
+    mechanism: Bearer,
+    target: &'a Target<'a>,
+    access_token: &'a str,
+}
+
+impl Authenticator for BearerAuthenticator<'_> {
+    type Response = String;
+
+    fn process(&self, challenge: &[u8]) -> Self::Response {
+        // A non-empty challenge is the server's JSON error report. The client must answer with a
+        // dummy response to get the final NO.
+        match (self.mechanism, challenge.is_empty()) {
+            (mechanism, true) => initial_response(mechanism, self.target, self.access_token),
+            (Bearer::XOAuth2, false) => String::new(),
+            (Bearer::OAuthBearer, false) => "\x01".to_string(),
+        }
+    }
+}
+
+fn initial_response(mechanism: Bearer, target: &Target, access_token: &str) -> String {
+    match mechanism {
+        Bearer::XOAuth2 => format!(
+            "user={}\x01auth=Bearer {}\x01\x01",
+            target.user, access_token
+        ),
+        Bearer::OAuthBearer => format!(
+            "n,a={},\x01host={}\x01port={}\x01auth=Bearer {}\x01\x01",
+            target.user.replace('=', "=3D").replace(',', "=2C"),
+            target.host,
+            target.port,
+            access_token,
+        ),
+    }
+}
+
+/// Authenticate with an OAuth 2.0 bearer token, refreshing the access token if there is none or
+/// if the server rejects it. The access token comes from --oauth-token or else from the secrets
+/// manager.
+pub fn oauth<S: Read + Write>(
+    client: Client<S>,
+    mechanism: Bearer,
+    target: &Target,
+    args: &OAuthArgs,
+    secret: Option<&SecretRef>,
+) -> Result<Session<S>> {
+    let given = match (&args.oauth_token, secret) {
+        (Some(access_token), _) => Some(access_token.clone()),
+        (None, Some(secret)) => Some(secret.fetch()?),
+        (None, None) => None,
+    };
+    let access_token = match &given {
+        Some(access_token) => access_token.clone(),
+        None => args.refresh()?,
+    };
+
+    let client = match client.authenticate(
+        mechanism.name(),
+        &BearerAuthenticator {
+            mechanism,
+            target,
+            access_token: &access_token,
+        },
+    ) {
+        Ok(session) => return Ok(session),
+        Err((imap::Error::No(_), client)) if given.is_some() && args.can_refresh() => client,
+        Err((err, _)) => return Err(err.into()),
+    };
+
+    let access_token = args.refresh()?;
+    client
+        .authenticate(
+            mechanism.name(),
+            &BearerAuthenticator {
+                mechanism,
+                target,
+                access_token: &access_token,
+            },
+        )
+        .map_err(|e| e.0.into())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::connection::test::Mock;
+
+    #[test]
+    fn xoauth2_format() {
+        let target = Target {
+            user: "someuser@example.com",
+            host: "imap.gmail.com",
+            port: 993,
+        };
+        assert_eq!(
+            initial_response(
+                Bearer::XOAuth2,
+                &target,
+                "ya29.vF9dft4qmTc2Nvb3RlckBhdHRhdmlzdGEuY29tCg"
+            ),
+            "user=someuser@example.com\x01auth=Bearer ya29.vF9dft4qmTc2Nvb3RlckBhdHRhdmlzdGEuY29tCg\x01\x01",
+        );
+    }
+
+    #[test]
+    fn oauthbearer_format() {
+        let target = Target {
+            user: "user@example.com",
+            host: "server.example.com",
+            port: 143,
+        };
+        assert_eq!(
+            initial_response(Bearer::OAuthBearer, &target, "vF9dft4qmTc2Nvb3RlckBhdGF2aXN0YS5jb20="),
+            "n,a=user@example.com,\x01host=server.example.com\x01port=143\x01auth=Bearer vF9dft4qmTc2Nvb3RlckBhdGF2aXN0YS5jb20=\x01\x01",
+        );
+    }
+
+    #[test]
+    fn refused() {
+        let client = imap::Client::new(Mock::new(
+            b"+ \r\na1 NO [AUTHENTICATIONFAILED] invalid credentials\r\n",
+        ));
+        let oauth = OAuthArgs {
+            oauth_token: Some("expired".to_string()),
+            oauth_refresh_token: None,
+            oauth_client_id: None,
+            oauth_client_secret: None,
+            oauth_token_url: String::new(),
+        };
+        let password = PasswordArgs {
+            password_stdin: false,
+            password_file: None,
+            password_env: None,
+            password_cmd: None,
+            secret_ref: None,
+        };
+        let target = Target {
+            user: "user@example.com",
+            host: "server.example.com",
+            port: 993,
+        };
+        let capabilities = Capabilities::default();
+        let err = authenticate(
+            client,
+            AuthMethod::Xoauth2,
+            &capabilities,
+            &target,
+            &oauth,
+            &password,
+        )
+        .err()
+        .unwrap();
+        assert!(matches!(&err, Error::Auth(msg) if msg.contains("invalid credentials")));
+        assert_eq!(err.exit_code(), 2);
+    }
+}
@@ -0,0 +1,81 @@
+use super::Target;
+use crate::error::{Error, Result};
+use imap::{Authenticator, Client, Session};
+use libgssapi::context::{ClientCtx, CtxFlags, SecurityContext};
+use libgssapi::name::Name;
+use libgssapi::oid::{GSS_MECH_KRB5, GSS_NT_HOSTBASED_SERVICE};
+use std::cell::{Cell, RefCell};
+use std::io::{Read, Write};
+
+/// SASL GSSAPI as described in https://tools.ietf.org/html/rfc4752
+///
+/// The context lives in a `RefCell` because `Authenticator::process` only gets `&self`. Errors
+/// cannot be returned from `process` either so they are kept aside and reported once the server
+/// rejects the exchange.
+struct Gssapi<'a> {
+    user: &'a str,
+    ctx: RefCell<ClientCtx>,
+    started: Cell<bool>,
+    error: RefCell<Option<String>>,
+}
+
+impl Gssapi<'_> {
+    fn step(&self, challenge: &[u8]) -> std::result::Result<Vec<u8>, libgssapi::error::Error> {
+        let mut ctx = self.ctx.borrow_mut();
+
+        if !ctx.is_complete() {
+            let token = if self.started.replace(true) {
+                Some(challenge)
+            } else {
+                None
+            };
+            return Ok(ctx
+                .step(token, None)?
+                .map(|buf| buf.to_vec())
+                .unwrap_or_default());
+        }
+
+        // The last challenge is the wrapped list of security layers supported by the server. We
+        // don't use any (the connection is already protected by TLS) and authorize as the user.
+        let _layers = ctx.unwrap(challenge)?;
+        let mut response = vec![1, 0, 0, 0];
+        response.extend_from_slice(self.user.as_bytes());
+        Ok(ctx.wrap(false, &response)?.to_vec())
+    }
+}
+
+impl Authenticator for Gssapi<'_> {
+    type Response = Vec<u8>;
+
+    fn process(&self, challenge: &[u8]) -> Self::Response {
+        self.step(challenge).unwrap_or_else(|err| {
+            *self.error.borrow_mut() = Some(err.to_string());
+            Vec::new()
+        })
+    }
+}
+
+/// Authenticate with the Kerberos ticket of the current user.
+pub fn authenticate<S: Read + Write>(client: Client<S>, target: &Target) -> Result<Session<S>> {
+    let service = format!("imap@{}", target.host);
+    let name = Name::new(service.as_bytes(), Some(&GSS_NT_HOSTBASED_SERVICE))
+        .map_err(|err| Error::Gssapi(err.to_string()))?;
+    let authenticator = Gssapi {
+        user: target.user,
+        ctx: RefCell::new(ClientCtx::new(
+            None,
+            name,
+            CtxFlags::GSS_C_MUTUAL_FLAG | CtxFlags::GSS_C_SEQUENCE_FLAG,
+            Some(&GSS_MECH_KRB5),
+        )),
+        started: Cell::new(false),
+        error: RefCell::new(None),
+    };
+
+    client
+        .authenticate("GSSAPI", &authenticator)
+        .map_err(|(err, _)| match authenticator.error.take() {
+            Some(msg) => Error::Gssapi(msg),
+            None => err.into(),
+        })
+}
@@ -0,0 +1,956 @@
+use crate::bar::Bar;
+use crate::color::yellow;
+use crate::error::Result;
+use crate::export::from_and_subject;
+use crate::mailbox;
+use crate::output::tell;
+use crate::senders::addresses;
+use chrono::{DateTime, Local, Utc};
+use imap::types::{Fetch, Flag};
+use imap::Session;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::SystemTime;
+
+/// The names of the files of --backup-eml by default.
+const TEMPLATE: &str = "{date}-{from}-{subject}.eml";
+
+/// The fields of the templates, those of a message.
+const FIELDS: [(&str, Field); 6] = [
+    ("date", Field::Date),
+    ("time", Field::Time),
+    ("from", Field::From),
+    ("subject", Field::Subject),
+    ("mailbox", Field::Mailbox),
+    ("uid", Field::Uid),
+];
+
+/// At most this many characters of a field in a file name, not to go over the limit of the file
+/// systems with long subjects.
+const FIELD_LENGTH: usize = 60;
+
+/// Where the messages are written before being cleaned, with --backup-mbox, --backup-maildir or
+/// --backup-eml.
+pub enum Backup {
+    Mbox(Mbox),
+    Maildir(Maildir),
+    Eml(Eml),
+}
+
+/// The mbox file of --backup-mbox: the messages are appended to it, in the mboxrd format of mutt,
+/// Thunderbird and the like.
+pub struct Mbox {
+    /// `None` when compressed or encrypted: those files cannot be appended to, a file is written
+    /// per mailbox instead.
+    file: Option<Mutex<File>>,
+    path: PathBuf,
+    filter: Filter,
+}
+
+/// The Maildir++ directory of --backup-maildir: INBOX in the directory itself and the other
+/// mailboxes in its folders, like `.Lists.rust` for `Lists/rust`, as read by mutt, Dovecot and
+/// the like.
+pub struct Maildir {
+    root: PathBuf,
+    /// The hierarchy delimiter of the server, asked once.
+    delimiter: OnceLock<Option<String>>,
+    host: String,
+    /// The messages written by the run, for unique file names.
+    count: AtomicU64,
+}
+
+/// The directory of --backup-eml: a file per message, named by --name-template.
+pub struct Eml {
+    dir: PathBuf,
+    template: Template,
+    recipient: Option<String>,
+}
+
+/// The commands a file is piped to before hitting the disk: zstd when its name ends with `.zst`,
+/// then age or gpg with --backup-encrypt-to.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Filter(Vec<Vec<String>>);
+
+/// The commands of a filter running, writing to a file.
+struct Pipeline {
+    stdin: Option<ChildStdin>,
+    children: Vec<(String, Child)>,
+}
+
+/// The names of the files of --backup-eml, like `{date}-{from}-{subject}.eml`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Template(Vec<Part>);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Part {
+    Text(String),
+    Field(Field),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Field {
+    /// The INTERNALDATE, like 2019-03-01.
+    Date,
+    /// The time of the INTERNALDATE, like 120000.
+    Time,
+    /// The address of the first sender.
+    From,
+    Subject,
+    Mailbox,
+    Uid,
+}
+
+impl Backup {
+    /// Open the file to append to it, the messages of each run follow those of the previous ones.
+    /// Compressed or encrypted, the files of the mailboxes are written next to it instead.
+    pub fn mbox(path: &Path, recipient: Option<&str>) -> Result<Self> {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let (filter, name) = Filter::new(&name, recipient);
+        let path = path.with_file_name(name);
+        let file = match filter.0.is_empty() {
+            true => Some(Mutex::new(
+                OpenOptions::new().create(true).append(true).open(&path)?,
+            )),
+            false => None,
+        };
+        Ok(Backup::Mbox(Mbox { file, path, filter }))
+    }
+
+    /// Create the directory and its INBOX when missing, the messages of each run are added to
+    /// those of the previous ones.
+    pub fn maildir(path: &Path) -> Result<Self> {
+        create_maildir(path)?;
+        Ok(Backup::Maildir(Maildir {
+            root: path.to_path_buf(),
+            delimiter: OnceLock::new(),
+            host: host(),
+            count: AtomicU64::new(0),
+        }))
+    }
+
+    /// Create the directory when missing, the files of each run are added to those of the
+    /// previous ones.
+    pub fn eml(path: &Path, template: Option<Template>, recipient: Option<&str>) -> Result<Self> {
+        std::fs::create_dir_all(path)?;
+        Ok(Backup::Eml(Eml {
+            dir: path.to_path_buf(),
+            template: template.unwrap_or_else(|| parse_template(TEMPLATE).expect("it is valid")),
+            recipient: recipient.map(String::from),
+        }))
+    }
+
+    /// Write these messages of the selected mailbox, fetched without flagging them \Seen.
+    /// Returns those written to the disk, the only ones to clean: the others are kept.
+    pub fn backup<S: Read + Write>(
+        &self,
+        session: &mut Session<S>,
+        mailbox: &str,
+        uids: &[u32],
+    ) -> Result<Vec<u32>> {
+        match self {
+            Backup::Mbox(mbox) => match &mbox.file {
+                Some(file) => fetch(session, mailbox, uids, |messages| append(file, messages)),
+                None => mbox.write_mailbox(session, mailbox, uids),
+            },
+            Backup::Maildir(maildir) => {
+                let folder = maildir.folder(session, mailbox)?;
+                fetch(session, mailbox, uids, |messages| {
+                    maildir.write(&folder, messages)
+                })
+            }
+            Backup::Eml(eml) => fetch(session, mailbox, uids, |messages| {
+                eml.write(mailbox, messages)
+            }),
+        }
+    }
+}
+
+/// Fetch these messages of the selected mailbox in batches for `write`, which returns those it
+/// wrote and why the others were not. Returns the messages written.
+fn fetch<S: Read + Write>(
+    session: &mut Session<S>,
+    mailbox: &str,
+    uids: &[u32],
+    mut write: impl FnMut(&[Message]) -> (Vec<u32>, Option<std::io::Error>),
+) -> Result<Vec<u32>> {
+    let mut backed_up = Vec::new();
+    let mut bar = Bar::new("BACKUP", uids.len());
+    for (set, batch) in crate::batches(uids) {
+        let fetch = session.uid_fetch(set, "(UID FLAGS INTERNALDATE ENVELOPE BODY.PEEK[])")?;
+        let messages = fetch
+            .iter()
+            .filter_map(|x| Some((x.uid?, x.body()?, x)))
+            .collect::<Vec<_>>();
+        let (written, error) = write(&messages);
+        if let Some(err) = error {
+            tell!(
+                "{} {}: {} messages could not be backed up, kept: {}",
+                yellow("Warning:"),
+                mailbox,
+                messages.len() - written.len(),
+                err
+            );
+        }
+        backed_up.extend(written);
+        bar.inc(batch.len());
+    }
+    backed_up.sort_unstable();
+    Ok(backed_up)
+}
+
+/// A message fetched: its UID, its body and the rest.
+type Message<'a> = (u32, &'a [u8], &'a Fetch);
+
+/// Append the messages of a batch to the mbox, all or none: those of a batch that could not be
+/// written are cut out of the file.
+fn append(file: &Mutex<File>, messages: &[Message]) -> (Vec<u32>, Option<std::io::Error>) {
+    let entries = entries(messages);
+    // Locked for the batch, the mailboxes of --jobs append to the same file.
+    let mut file = file.lock().unwrap();
+    let result = file.metadata().and_then(|metadata| {
+        let len = metadata.len();
+        let result = file.write_all(&entries).and_then(|()| file.sync_data());
+        if result.is_err() {
+            // Not to leave half a message for the next one to be appended to.
+            let _ = file.set_len(len);
+        }
+        result
+    });
+    match result {
+        Ok(()) => (messages.iter().map(|x| x.0).collect(), None),
+        Err(err) => (Vec::new(), Some(err)),
+    }
+}
+
+fn entries(messages: &[Message]) -> Vec<u8> {
+    let mut entries = Vec::new();
+    for (_, body, message) in messages {
+        entries.extend(entry(&from(message), date(message), body));
+    }
+    entries
+}
+
+impl Mbox {
+    /// Write the messages of a mailbox to a new file through the filter, all or none: the file
+    /// is only complete once the commands are done, like `archive-INBOX-20240501T100000.mbox.zst`
+    /// for `archive.mbox.zst`.
+    fn write_mailbox<S: Read + Write>(
+        &self,
+        session: &mut Session<S>,
+        mailbox: &str,
+        uids: &[u32],
+    ) -> Result<Vec<u32>> {
+        if uids.is_empty() {
+            return Ok(Vec::new());
+        }
+        let name = self.path.file_name().unwrap_or_default().to_string_lossy();
+        let (stem, extension) = split_extension(&name);
+        let name = format!(
+            "{}-{}-{}{}",
+            stem,
+            sanitize(mailbox),
+            Local::now().format("%Y%m%dT%H%M%S"),
+            extension
+        );
+        let dir = self.path.parent().unwrap_or(Path::new(""));
+        let (file, path) = create_new(dir, &name)?;
+        let mut pipeline = match self.filter.spawn(&file) {
+            Ok(pipeline) => pipeline,
+            Err(err) => {
+                let _ = std::fs::remove_file(&path);
+                return Err(err.into());
+            }
+        };
+        let mut error = None;
+        let result = fetch(session, mailbox, uids, |messages| {
+            if error.is_none() {
+                error = pipeline.write(&entries(messages)).err();
+            }
+            (messages.iter().map(|x| x.0).collect(), None)
+        });
+        let finished = pipeline.finish().and_then(|()| file.sync_all());
+        match (result, error.map_or(finished, Err)) {
+            (Ok(written), Ok(())) => Ok(written),
+            (result, finished) => {
+                let _ = std::fs::remove_file(&path);
+                if let Err(err) = finished {
+                    tell!(
+                        "{} {}: {} messages could not be backed up, kept: {}",
+                        yellow("Warning:"),
+                        mailbox,
+                        uids.len(),
+                        err
+                    );
+                }
+                result.map(|_| Vec::new())
+            }
+        }
+    }
+}
+
+impl Maildir {
+    /// The directory of the mailbox, created when missing.
+    fn folder<S: Read + Write>(&self, session: &mut Session<S>, mailbox: &str) -> Result<PathBuf> {
+        let delimiter = match self.delimiter.get() {
+            Some(delimiter) => delimiter,
+            None => {
+                let delimiter = mailbox::delimiter(session)?;
+                self.delimiter.get_or_init(|| delimiter)
+            }
+        };
+        let path = match folder_name(mailbox, delimiter.as_deref()) {
+            Some(name) => {
+                let path = self.root.join(name);
+                create_maildir(&path)?;
+                File::create(path.join("maildirfolder"))?;
+                path
+            }
+            None => self.root.clone(),
+        };
+        Ok(path)
+    }
+
+    /// Write the messages of a batch in `cur`, each written to `tmp` first not to be read half
+    /// written. Those that could not be written are left out.
+    fn write(&self, folder: &Path, messages: &[Message]) -> (Vec<u32>, Option<std::io::Error>) {
+        let mut written = Vec::new();
+        let mut error = None;
+        for (uid, body, message) in messages {
+            match self.deliver(folder, *uid, body, message) {
+                Ok(()) => written.push(*uid),
+                Err(err) => error = Some(err),
+            }
+        }
+        (written, error)
+    }
+
+    fn deliver(
+        &self,
+        folder: &Path,
+        uid: u32,
+        body: &[u8],
+        message: &Fetch,
+    ) -> std::io::Result<()> {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        let name = format!(
+            "{}.M{}P{}Q{}U{}.{}",
+            now.as_secs(),
+            now.subsec_micros(),
+            std::process::id(),
+            self.count.fetch_add(1, Ordering::Relaxed),
+            uid,
+            self.host
+        );
+        let tmp = folder.join("tmp").join(&name);
+        let result = (|| {
+            let mut file = File::create(&tmp)?;
+            file.write_all(&lf(body))?;
+            // The date the mail tools sort by.
+            if let Some(date) = message.internal_date() {
+                file.set_modified(date.into())?;
+            }
+            file.sync_all()?;
+            let cur = format!("{}:2,{}", name, info(message.flags()));
+            std::fs::rename(&tmp, folder.join("cur").join(cur))
+        })();
+        if result.is_err() {
+            let _ = std::fs::remove_file(&tmp);
+        }
+        result
+    }
+}
+
+impl Eml {
+    /// Write the messages of a batch, each to a new file: a number is added to the names of the
+    /// files already there. Those that could not be written are left out.
+    fn write(&self, mailbox: &str, messages: &[Message]) -> (Vec<u32>, Option<std::io::Error>) {
+        let mut written = Vec::new();
+        let mut error = None;
+        for (uid, body, message) in messages {
+            let (filter, name) = Filter::new(
+                &self.template.name(mailbox, message),
+                self.recipient.as_deref(),
+            );
+            match write_new(&self.dir, &name, body, message, &filter) {
+                Ok(()) => written.push(*uid),
+                Err(err) => error = Some(err),
+            }
+        }
+        (written, error)
+    }
+}
+
+/// Write a message to a new file of the directory, as fetched and through the filter.
+fn write_new(
+    dir: &Path,
+    name: &str,
+    body: &[u8],
+    message: &Fetch,
+    filter: &Filter,
+) -> std::io::Result<()> {
+    let (mut file, path) = create_new(dir, name)?;
+    let result = (|| {
+        match filter.0.is_empty() {
+            true => file.write_all(body)?,
+            false => {
+                let mut pipeline = filter.spawn(&file)?;
+                let written = pipeline.write(body);
+                pipeline.finish().and(written)?;
+            }
+        }
+        if let Some(date) = message.internal_date() {
+            file.set_modified(date.into())?;
+        }
+        file.sync_all()
+    })();
+    if result.is_err() {
+        let _ = std::fs::remove_file(&path);
+    }
+    result
+}
+
+/// Create a new file of the directory with this name, or with a number added to it when taken,
+/// like `Hello-2.eml`.
+fn create_new(dir: &Path, name: &str) -> std::io::Result<(File, PathBuf)> {
+    let (stem, extension) = split_extension(name);
+    (1..)
+        .find_map(|n| {
+            let path = match n {
+                1 => dir.join(name),
+                n => dir.join(format!("{}-{}{}", stem, n, extension)),
+            };
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => None,
+                result => Some(result.map(|file| (file, path))),
+            }
+        })
+        .expect("a name is free")
+}
+
+/// A file name and its extensions, with those of the filters: `.mbox.zst.age` for
+/// `archive.mbox.zst.age`.
+fn split_extension(name: &str) -> (&str, &str) {
+    let mut stem = name;
+    while let Some(stripped) = [".age", ".gpg", ".zst"]
+        .iter()
+        .find_map(|x| stem.strip_suffix(x))
+    {
+        stem = stripped;
+    }
+    if let Some(i) = stem.rfind('.').filter(|i| *i > 0) {
+        stem = &stem[..i];
+    }
+    name.split_at(stem.len())
+}
+
+impl Filter {
+    /// The filter of a file by its name, and the name with the extension of the encryption added
+    /// when missing. age encrypts to the age and SSH public keys, gpg to the others.
+    fn new(name: &str, recipient: Option<&str>) -> (Self, String) {
+        let mut commands = Vec::new();
+        let mut name = name.to_string();
+        let compressed = [".age", ".gpg"]
+            .iter()
+            .find_map(|x| name.strip_suffix(x))
+            .unwrap_or(&name)
+            .ends_with(".zst");
+        if compressed {
+            commands.push(vec!["zstd", "-q", "-c"]);
+        }
+        if let Some(recipient) = recipient {
+            let (command, extension) =
+                match recipient.starts_with("age1") || recipient.starts_with("ssh-") {
+                    true => (vec!["age", "--recipient", recipient], ".age"),
+                    // Trusted as given, gpg refuses the keys not signed in batch mode otherwise.
+                    false => (
+                        vec![
+                            "gpg",
+                            "--batch",
+                            "--quiet",
+                            "--trust-model",
+                            "always",
+                            "--encrypt",
+                            "--recipient",
+                            recipient,
+                        ],
+                        ".gpg",
+                    ),
+                };
+            commands.push(command);
+            if !name.ends_with(extension) {
+                name.push_str(extension);
+            }
+        }
+        let commands = commands
+            .into_iter()
+            .map(|x| x.into_iter().map(String::from).collect())
+            .collect();
+        (Filter(commands), name)
+    }
+
+    /// Run the commands, each piped to the next one and the last one to the file.
+    fn spawn(&self, output: &File) -> std::io::Result<Pipeline> {
+        let mut pipeline = Pipeline {
+            stdin: None,
+            children: Vec::new(),
+        };
+        let mut input = None;
+        for (i, command) in self.0.iter().enumerate() {
+            let stdout = match i + 1 == self.0.len() {
+                true => Stdio::from(output.try_clone()?),
+                false => Stdio::piped(),
+            };
+            let spawned = Command::new(&command[0])
+                .args(&command[1..])
+                .stdin(input.take().map_or_else(Stdio::piped, Stdio::from))
+                .stdout(stdout)
+                .spawn();
+            let mut child = match spawned {
+                Ok(child) => child,
+                Err(err) => {
+                    // The commands spawned already stop at the end of their input.
+                    let _ = pipeline.finish();
+                    return Err(std::io::Error::new(
+                        err.kind(),
+                        format!("{}: {}", command[0], err),
+                    ));
+                }
+            };
+            if i == 0 {
+                pipeline.stdin = child.stdin.take();
+            }
+            input = child.stdout.take();
+            pipeline.children.push((command[0].clone(), child));
+        }
+        Ok(pipeline)
+    }
+}
+
+impl Pipeline {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<()> {
+        self.stdin
+            .as_mut()
+            .expect("the input is open until finished")
+            .write_all(data)
+    }
+
+    /// Close the input and wait for the commands, failing when one of them did.
+    fn finish(mut self) -> std::io::Result<()> {
+        drop(self.stdin.take());
+        let mut result = Ok(());
+        for (command, mut child) in self.children {
+            let status = child.wait()?;
+            if !status.success() && result.is_ok() {
+                result = Err(std::io::Error::other(format!(
+                    "{} failed: {}",
+                    command, status
+                )));
+            }
+        }
+        result
+    }
+}
+
+impl Template {
+    /// The name of the file of a message, its fields made safe for a file name.
+    fn name(&self, mailbox: &str, message: &Fetch) -> String {
+        let (from, subject) = from_and_subject(message);
+        let date = message.internal_date();
+        self.0
+            .iter()
+            .map(|part| match part {
+                Part::Text(text) => text.clone(),
+                Part::Field(field) => sanitize(&match field {
+                    Field::Date => date
+                        .map(|x| x.format("%Y-%m-%d").to_string())
+                        .unwrap_or_default(),
+                    Field::Time => date
+                        .map(|x| x.format("%H%M%S").to_string())
+                        .unwrap_or_default(),
+                    Field::From => from.clone(),
+                    Field::Subject => subject.clone(),
+                    Field::Mailbox => mailbox.to_string(),
+                    Field::Uid => message.uid.map(|x| x.to_string()).unwrap_or_default(),
+                }),
+            })
+            .collect()
+    }
+}
+
+/// Parse a template of --name-template: text and fields between braces, like `{subject}.eml`.
+pub fn parse_template(s: &str) -> std::result::Result<Template, String> {
+    let expected = || {
+        FIELDS
+            .iter()
+            .map(|(name, _)| format!("{{{}}}", name))
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    if s.contains(['/', '\\']) {
+        return Err("expected a file name, without a slash".to_string());
+    }
+    let mut parts = Vec::new();
+    let mut rest = s;
+    while let Some(start) = rest.find('{') {
+        if start > 0 {
+            parts.push(Part::Text(rest[..start].to_string()));
+        }
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| format!("a {{ is not closed, expected {}", expected()))?;
+        let name = &rest[start + 1..start + end];
+        let field = FIELDS
+            .iter()
+            .find(|(x, _)| *x == name)
+            .ok_or_else(|| format!("unknown field {{{}}}, expected {}", name, expected()))?
+            .1;
+        parts.push(Part::Field(field));
+        rest = &rest[start + end + 1..];
+    }
+    if !rest.is_empty() {
+        parts.push(Part::Text(rest.to_string()));
+    }
+    if !parts.iter().any(|x| matches!(x, Part::Field(_))) {
+        return Err(format!("expected a field at least, like {}", expected()));
+    }
+    Ok(Template(parts))
+}
+
+/// A field for a file name: the characters file systems refuse and the controls replaced, the
+/// spaces collapsed and the dots at the start removed, not to hide the file.
+fn sanitize(field: &str) -> String {
+    let field = field
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => ' ',
+            c => c,
+        })
+        .collect::<String>();
+    let field = field.split_whitespace().collect::<Vec<_>>().join(" ");
+    let field = field
+        .trim_start_matches('.')
+        .chars()
+        .take(FIELD_LENGTH)
+        .collect::<String>();
+    match field.trim_end() {
+        "" => "unknown".to_string(),
+        field => field.to_string(),
+    }
+}
+
+fn create_maildir(path: &Path) -> std::io::Result<()> {
+    for dir in ["cur", "new", "tmp"] {
+        std::fs::create_dir_all(path.join(dir))?;
+    }
+    Ok(())
+}
+
+/// The Maildir++ folder of a mailbox, `None` for INBOX: its names joined with dots, those of
+/// INBOX too, the dots and slashes in them replaced by underscores.
+fn folder_name(mailbox: &str, delimiter: Option<&str>) -> Option<String> {
+    let names = match delimiter {
+        Some(delimiter) => mailbox.split(delimiter).collect::<Vec<_>>(),
+        None => vec![mailbox],
+    };
+    let names = match names.split_first() {
+        Some((first, rest)) if first.eq_ignore_ascii_case("INBOX") => rest,
+        _ => &names[..],
+    };
+    if names.is_empty() {
+        return None;
+    }
+    let names = names
+        .iter()
+        .map(|x| x.replace(['.', '/'], "_"))
+        .collect::<Vec<_>>();
+    Some(format!(".{}", names.join(".")))
+}
+
+/// The flags of the file name, in the order of the specification.
+fn info(flags: &[Flag]) -> String {
+    [
+        ('D', Flag::Draft),
+        ('F', Flag::Flagged),
+        ('P', Flag::Custom("$Forwarded".into())),
+        ('R', Flag::Answered),
+        ('S', Flag::Seen),
+        ('T', Flag::Deleted),
+    ]
+    .into_iter()
+    .filter(|(_, flag)| flags.contains(flag))
+    .map(|(letter, _)| letter)
+    .collect()
+}
+
+/// The name of the host for the file names, without the characters they must not contain.
+fn host() -> String {
+    let host = std::fs::read_to_string("/etc/hostname").unwrap_or_default();
+    match host.trim() {
+        "" => "localhost".to_string(),
+        host => host.replace('/', "\\057").replace(':', "\\072"),
+    }
+}
+
+/// The message with LF line endings, those of the local mail tools.
+fn lf(body: &[u8]) -> Vec<u8> {
+    let mut lf = Vec::with_capacity(body.len());
+    for line in body.split_inclusive(|x| *x == b'\n') {
+        match line.strip_suffix(b"\r\n") {
+            Some(line) => {
+                lf.extend(line);
+                lf.push(b'\n');
+            }
+            None => lf.extend(line),
+        }
+    }
+    lf
+}
+
+/// The address of the From line, without spaces: they would end it.
+fn from(message: &Fetch) -> String {
+    let envelope = message.envelope();
+    let sender = envelope.and_then(|x| x.sender.as_ref().or(x.from.as_ref()));
+    addresses(sender)
+        .into_iter()
+        .next()
+        .map(|x| x.split_whitespace().collect::<String>())
+        .filter(|x| !x.is_empty())
+        .unwrap_or_else(|| "MAILER-DAEMON".to_string())
+}
+
+fn date(message: &Fetch) -> DateTime<Utc> {
+    message
+        .internal_date()
+        .map_or_else(Utc::now, |x| x.with_timezone(&Utc))
+}
+
+/// A message of the mbox: its From line, the message with LF line endings and its lines starting
+/// with `From ` after any number of `>` quoted once more, then an empty line.
+fn entry(from: &str, date: DateTime<Utc>, body: &[u8]) -> Vec<u8> {
+    let mut entry = format!("From {} {}\n", from, date.format("%a %b %e %H:%M:%S %Y")).into_bytes();
+    for line in body.split_inclusive(|x| *x == b'\n') {
+        let line = line
+            .strip_suffix(b"\r\n")
+            .or_else(|| line.strip_suffix(b"\n"))
+            .unwrap_or(line);
+        let quotes = line.iter().take_while(|x| **x == b'>').count();
+        if line[quotes..].starts_with(b"From ") {
+            entry.push(b'>');
+        }
+        entry.extend(line);
+        entry.push(b'\n');
+    }
+    entry.push(b'\n');
+    entry
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::connection::test::session;
+    use chrono::TimeZone;
+    use itertools::Itertools;
+
+    const SERVER: &[u8] = b"* 1 FETCH (UID 3 INTERNALDATE \"01-Mar-2019 12:00:00 +0100\" \
+        FLAGS (\\Seen $Forwarded \\Flagged) ENVELOPE (NIL \"Hi\" ((\"Boss\" NIL \"boss\" \
+        \"work.example\")) NIL NIL NIL NIL NIL NIL NIL) BODY[] {44}\r\nSubject: Hi\r\n\r\nFrom \
+        me\r\n>From you\r\nFromage\r\n)\r\n\
+        * 2 FETCH (UID 4 INTERNALDATE \"02-Mar-2019 08:30:00 +0000\" BODY[] {4}\r\nHi\r\n)\r\n\
+        a2 OK done\r\n";
+
+    #[test]
+    fn escaped() {
+        let date = Utc.ymd(2019, 3, 1).and_hms(11, 0, 0);
+        assert_eq!(
+            String::from_utf8(entry("boss@work.example", date, b"A\r\nFrom b\r\n>>From c"))
+                .unwrap(),
+            "From boss@work.example Fri Mar  1 11:00:00 2019\nA\n>From b\n>>>From c\n\n"
+        );
+    }
+
+    #[test]
+    fn backed_up() {
+        let dir = std::env::temp_dir().join(format!("imap-cleanup-mbox-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("backup.mbox");
+        let mbox = Backup::mbox(&path, None).unwrap();
+        let (mut imap, _, sent) = session(SERVER);
+        // UID 5 is gone, it is not cleaned.
+        assert_eq!(mbox.backup(&mut imap, "INBOX", &[3, 4, 5]).unwrap(), [3, 4]);
+        assert_eq!(
+            String::from_utf8_lossy(&sent.borrow()),
+            "a2 UID FETCH 3:5 (UID FLAGS INTERNALDATE ENVELOPE BODY.PEEK[])\r\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "From boss@work.example Fri Mar  1 11:00:00 2019\nSubject: Hi\n\n>From me\n>>From \
+             you\nFromage\n\nFrom MAILER-DAEMON Sat Mar  2 08:30:00 2019\nHi\n\n"
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+
+        // A full disk: nothing is cleaned.
+        if let Ok(mbox) = Backup::mbox(Path::new("/dev/full"), None) {
+            let (mut imap, _, _) = session(SERVER);
+            assert!(mbox.backup(&mut imap, "INBOX", &[3, 4]).unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn maildir() {
+        assert_eq!(folder_name("INBOX", Some("/")), None);
+        assert_eq!(folder_name("Lists/rust", Some("/")).unwrap(), ".Lists.rust");
+        assert_eq!(
+            folder_name("INBOX.Lists.rust", Some(".")).unwrap(),
+            ".Lists.rust"
+        );
+        assert_eq!(folder_name("v1.0/../x", Some("/")).unwrap(), ".v1_0.__.x");
+        assert_eq!(folder_name("a/b", None).unwrap(), ".a_b");
+
+        let dir = std::env::temp_dir().join(format!("imap-cleanup-maildir-{}", std::process::id()));
+        let maildir = Backup::maildir(&dir).unwrap();
+        let server = format!(
+            "* LIST (\\Noselect) \"/\" \"\"\r\na2 OK done\r\n{}",
+            String::from_utf8_lossy(SERVER).replace("a2 OK", "a3 OK")
+        );
+        let (mut imap, _, sent) = session(server.as_bytes());
+        assert_eq!(
+            maildir.backup(&mut imap, "Lists/rust", &[3, 4]).unwrap(),
+            [3, 4]
+        );
+        assert!(String::from_utf8_lossy(&sent.borrow()).starts_with("a2 LIST \"\" \"\"\r\n"));
+        let folder = dir.join(".Lists.rust");
+        assert!(folder.join("maildirfolder").exists());
+        assert!(folder.join("new").is_dir());
+        let mut files = std::fs::read_dir(folder.join("cur"))
+            .unwrap()
+            .map(|x| x.unwrap().path())
+            .collect::<Vec<_>>();
+        files.sort();
+        assert_eq!(files.len(), 2);
+        let name = files[0].file_name().unwrap().to_string_lossy().into_owned();
+        assert!(name.contains("U3."), "{}", name);
+        assert!(name.ends_with(":2,FPS"), "{}", name);
+        assert_eq!(
+            std::fs::read_to_string(&files[0]).unwrap(),
+            "Subject: Hi\n\nFrom me\n>From you\nFromage\n"
+        );
+        let modified = std::fs::metadata(&files[0]).unwrap().modified().unwrap();
+        assert_eq!(
+            DateTime::<Utc>::from(modified),
+            Utc.ymd(2019, 3, 1).and_hms(11, 0, 0)
+        );
+        assert!(std::fs::read_dir(folder.join("tmp"))
+            .unwrap()
+            .next()
+            .is_none());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn eml() {
+        assert!(parse_template("{date}-{nope}.eml").is_err());
+        assert!(parse_template("{date.eml").is_err());
+        assert!(parse_template("backup.eml").is_err());
+        assert!(parse_template("{mailbox}/{uid}.eml").is_err());
+        assert_eq!(sanitize(" ..Re: a/b\t<c>  "), "Re_ a_b _c_".to_string());
+        assert_eq!(sanitize("..."), "unknown");
+
+        let dir = std::env::temp_dir().join(format!("imap-cleanup-eml-{}", std::process::id()));
+        let template = parse_template("{date}-{from}-{subject}.eml").unwrap();
+        let eml = Backup::eml(&dir, Some(template), None).unwrap();
+        for _ in 0..2 {
+            let (mut imap, _, _) = session(SERVER);
+            assert_eq!(eml.backup(&mut imap, "INBOX", &[3, 4]).unwrap(), [3, 4]);
+        }
+        let mut names = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|x| x.unwrap().file_name().to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(
+            names,
+            [
+                "2019-03-01-boss@work.example-Hi-2.eml",
+                "2019-03-01-boss@work.example-Hi.eml",
+                "2019-03-02-unknown-unknown-2.eml",
+                "2019-03-02-unknown-unknown.eml",
+            ]
+        );
+        assert_eq!(
+            std::fs::read(dir.join(&names[1])).unwrap(),
+            b"Subject: Hi\r\n\r\nFrom me\r\n>From you\r\nFromage\r\n"
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn filtered() {
+        let commands = |filter: Filter| filter.0.iter().map(|x| x[0].clone()).join(" ");
+        let (filter, name) = Filter::new("archive.mbox", None);
+        assert_eq!((filter, name.as_str()), (Filter::default(), "archive.mbox"));
+        let (filter, name) = Filter::new("archive.mbox.zst.age", Some("age1xyz"));
+        assert_eq!(
+            (commands(filter), name.as_str()),
+            ("zstd age".to_string(), "archive.mbox.zst.age")
+        );
+        let (filter, name) = Filter::new("Hi.eml", Some("me@example.com"));
+        assert_eq!(
+            (commands(filter), name.as_str()),
+            ("gpg".to_string(), "Hi.eml.gpg")
+        );
+
+        assert_eq!(
+            split_extension("archive.mbox.zst.age"),
+            ("archive", ".mbox.zst.age")
+        );
+        assert_eq!(
+            split_extension("a@b.example-Hi.eml.gpg"),
+            ("a@b.example-Hi", ".eml.gpg")
+        );
+        assert_eq!(split_extension(".zst"), ("", ".zst"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn piped() {
+        let dir = std::env::temp_dir().join(format!("imap-cleanup-piped-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mbox = |command: &[&str]| Mbox {
+            file: None,
+            path: dir.join("archive.mbox.zst"),
+            filter: Filter(vec![command.iter().map(|x| x.to_string()).collect()]),
+        };
+        let (mut imap, _, _) = session(SERVER);
+        let written = mbox(&["tr", "a-z", "A-Z"]).write_mailbox(&mut imap, "Lists/rust", &[3, 4]);
+        assert_eq!(written.unwrap(), [3, 4]);
+        let files = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|x| x.unwrap().path())
+            .collect::<Vec<_>>();
+        assert_eq!(files.len(), 1);
+        let name = files[0].file_name().unwrap().to_string_lossy().into_owned();
+        assert!(name.starts_with("archive-Lists_rust-2"), "{}", name);
+        assert!(name.ends_with(".mbox.zst"), "{}", name);
+        assert!(std::fs::read_to_string(&files[0])
+            .unwrap()
+            .starts_with("FROM BOSS@WORK.EXAMPLE FRI MAR  1 11:00:00 2019\nSUBJECT: HI\n"));
+
+        // The command failed: nothing is cleaned, nor left half written.
+        let (mut imap, _, _) = session(SERVER);
+        let written = mbox(&["false"]).write_mailbox(&mut imap, "INBOX", &[3, 4]);
+        assert!(written.unwrap().is_empty());
+        let (mut imap, _, _) = session(SERVER);
+        assert!(mbox(&["nope-not-a-command"])
+            .write_mailbox(&mut imap, "INBOX", &[3, 4])
+            .is_err());
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,144 @@
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use std::cell::RefCell;
+use std::io::IsTerminal;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+/// The number of messages from which a phase shows its progress.
+const MIN: usize = 1000;
+
+/// How often the progress is logged when the standard error is not a terminal.
+const LOG_EVERY: Duration = Duration::from_secs(10);
+
+thread_local! {
+    /// The mailbox of the bars of this thread, each cleaning one mailbox at a time.
+    static MAILBOX: RefCell<String> = RefCell::default();
+}
+
+/// The bars of the mailboxes cleaned at the same time with --jobs.
+fn bars() -> &'static MultiProgress {
+    static BARS: OnceLock<MultiProgress> = OnceLock::new();
+    BARS.get_or_init(|| MultiProgress::with_draw_target(ProgressDrawTarget::stderr()))
+}
+
+/// Set the mailbox the next bars of this thread are for.
+pub fn mailbox(name: &str) {
+    MAILBOX.with(|x| *x.borrow_mut() = name.to_string());
+}
+
+/// The progress of a phase over many messages of a mailbox: a bar on a terminal, or else a line
+/// every 10 seconds on the standard error. Cleared once dropped.
+pub struct Bar {
+    bar: Option<ProgressBar>,
+    prefix: String,
+    phase: String,
+    total: usize,
+    done: usize,
+    logged: Instant,
+}
+
+impl Bar {
+    /// The progress of a command, like FETCH or STORE, over `total` messages: nothing is shown
+    /// for a few messages.
+    pub fn new(command: &str, total: usize) -> Self {
+        let mut bar = Self {
+            bar: None,
+            prefix: MAILBOX.with(|x| x.borrow().clone()),
+            phase: phase(command),
+            total,
+            done: 0,
+            logged: Instant::now(),
+        };
+        if total >= MIN && std::io::stderr().is_terminal() {
+            let style = bar
+                .style("{msg} [{bar:30}] {pos}/{len} ({eta} left)")
+                .progress_chars("=> ");
+            let progress = ProgressBar::new(total as u64)
+                .with_style(style)
+                .with_prefix(bar.prefix.clone())
+                .with_message(bar.phase.clone());
+            bar.bar = Some(bars().add(progress));
+        }
+        bar
+    }
+
+    /// A spinner for a single long command, like SEARCH, on a terminal only.
+    pub fn spinner(command: &str) -> Self {
+        let mut bar = Self::new(command, 0);
+        if std::io::stderr().is_terminal() {
+            let progress = ProgressBar::new_spinner()
+                .with_style(bar.style("{msg} {spinner} ({elapsed})"))
+                .with_prefix(bar.prefix.clone())
+                .with_message(bar.phase.clone());
+            progress.enable_steady_tick(Duration::from_millis(100));
+            bar.bar = Some(bars().add(progress));
+        }
+        bar
+    }
+
+    /// The style of the bar, prefixed with its mailbox if any.
+    fn style(&self, template: &str) -> ProgressStyle {
+        let template = match self.prefix.as_str() {
+            "" => template.to_string(),
+            _ => format!("{{prefix}}: {}", template),
+        };
+        ProgressStyle::with_template(&template).expect("the template is valid")
+    }
+
+    /// Count `count` more messages done.
+    pub fn inc(&mut self, count: usize) {
+        self.done += count;
+        match &self.bar {
+            Some(bar) => bar.inc(count as u64),
+            None if self.total >= MIN && self.logged.elapsed() >= LOG_EVERY => {
+                self.logged = Instant::now();
+                eprintln!("{}", line(&self.prefix, &self.phase, self.done, self.total));
+            }
+            None => {}
+        }
+    }
+}
+
+impl Drop for Bar {
+    fn drop(&mut self) {
+        if let Some(bar) = self.bar.take() {
+            bar.finish_and_clear();
+            bars().remove(&bar);
+        }
+    }
+}
+
+/// What a command is doing, like `fetching` for FETCH.
+fn phase(command: &str) -> String {
+    match command {
+        "FETCH" => "fetching".to_string(),
+        "STORE" => "storing flags".to_string(),
+        "SEARCH" => "searching".to_string(),
+        "MOVE" => "moving".to_string(),
+        "COPY" => "copying".to_string(),
+        _ => command.to_lowercase(),
+    }
+}
+
+/// The line logged when the standard error is not a terminal.
+fn line(prefix: &str, phase: &str, done: usize, total: usize) -> String {
+    let percent = done * 100 / total.max(1);
+    match prefix {
+        "" => format!("{} {} of {} ({}%)", phase, done, total, percent),
+        _ => format!("{}: {} {} of {} ({}%)", prefix, phase, done, total, percent),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn lines() {
+        assert_eq!(
+            line("INBOX", &phase("STORE"), 1500, 4000),
+            "INBOX: storing flags 1500 of 4000 (37%)"
+        );
+        assert_eq!(line("", &phase("FETCH"), 0, 0), "fetching 0 of 0 (0%)");
+    }
+}
@@ -0,0 +1,186 @@
+use crate::bar::Bar;
+use crate::error::{Error, Result};
+use crate::preview::{self, Row, Sort};
+use crate::search::parse_size;
+use imap::Session;
+use std::io::{Read, Write};
+use std::sync::Mutex;
+
+/// A hard ceiling on the messages cleaned by a run, against a date or a query selecting far
+/// more than meant.
+#[derive(clap::Args, Debug)]
+pub struct CapArgs {
+    /// Clean at most this many messages in total: the run stops before the mailbox that would
+    /// bring it over, the dry runs too, and before asking the questions of --interactive. The
+    /// mailboxes cleaned before stay cleaned.
+    #[clap(long, value_name = "COUNT", env = "IMAP_CLEANUP_MAX_DELETE")]
+    pub max_delete: Option<usize>,
+
+    /// Clean the messages of the lowest UIDs up to --max-delete instead of stopping, and keep the
+    /// others.
+    #[clap(long, requires = "max-delete", env = "IMAP_CLEANUP_TRUNCATE")]
+    pub truncate: bool,
+
+    /// Clean at most this size of messages in total, like 5G, for the cleanups done a bit at a
+    /// time: the messages are taken in the order of --sort, the oldest first by default, until
+    /// the next one would bring it over. The others are kept.
+    #[clap(
+        long,
+        value_name = "SIZE",
+        value_parser = parse_size,
+        env = "IMAP_CLEANUP_MAX_BYTES"
+    )]
+    pub max_bytes: Option<u64>,
+}
+
+impl CapArgs {
+    pub fn cap(&self) -> Option<Cap> {
+        self.max_delete.map(|max| Cap {
+            max,
+            truncate: self.truncate,
+            left: Mutex::new(max),
+        })
+    }
+
+    /// The cap of --max-bytes, taking the messages in this order.
+    pub fn size_cap(&self, sort: Option<Sort>, reverse: bool) -> Option<SizeCap> {
+        self.max_bytes.map(|max| SizeCap {
+            sort: sort.unwrap_or(Sort::Date),
+            reverse,
+            left: Mutex::new(max),
+        })
+    }
+}
+
+/// What is left to clean under --max-delete, shared by the mailboxes.
+#[derive(Debug)]
+pub struct Cap {
+    max: usize,
+    truncate: bool,
+    left: Mutex<usize>,
+}
+
+impl Cap {
+    /// The messages of a mailbox to clean, those of the lowest UIDs up to what is left with
+    /// --truncate. Fails when there are more without it.
+    pub fn apply(&self, mailbox: &str, mut uids: Vec<u32>) -> Result<Vec<u32>> {
+        let mut left = self.left.lock().unwrap();
+        if uids.len() > *left {
+            if !self.truncate {
+                return Err(Error::Guard(format!(
+                    "{} messages to clean in {}, more than the {} left of --max-delete {} (see \
+                     --truncate)",
+                    uids.len(),
+                    mailbox,
+                    *left,
+                    self.max
+                )));
+            }
+            uids.sort_unstable();
+            uids.truncate(*left);
+        }
+        *left -= uids.len();
+        Ok(uids)
+    }
+
+    /// Give back what was taken for messages kept after all.
+    pub fn release(&self, count: usize) {
+        *self.left.lock().unwrap() += count;
+    }
+}
+
+/// What is left to clean under --max-bytes, shared by the mailboxes.
+#[derive(Debug)]
+pub struct SizeCap {
+    sort: Sort,
+    reverse: bool,
+    left: Mutex<u64>,
+}
+
+impl SizeCap {
+    /// The first of these candidate messages, sorted, that fit in what is left: their size is
+    /// taken from it.
+    pub fn apply<S: Read + Write>(
+        &self,
+        session: &mut Session<S>,
+        uids: &[u32],
+    ) -> Result<Vec<u32>> {
+        let mut left = self.left.lock().unwrap();
+        if uids.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut rows = Vec::new();
+        let mut bar = Bar::new("FETCH", uids.len());
+        for (set, batch) in crate::batches(uids) {
+            let fetch = session.uid_fetch(set, "(INTERNALDATE ENVELOPE RFC822.SIZE)")?;
+            rows.extend(fetch.iter().map(Row::new));
+            bar.inc(batch.len());
+        }
+        Ok(self.take(&mut left, rows))
+    }
+
+    fn take(&self, left: &mut u64, mut rows: Vec<Row>) -> Vec<u32> {
+        preview::sort_rows(&mut rows, Some(self.sort), self.reverse);
+        let mut selected = Vec::new();
+        for row in rows {
+            let size = u64::from(row.size());
+            if size > *left {
+                break;
+            }
+            *left -= size;
+            selected.push(row.uid());
+        }
+        selected.sort_unstable();
+        selected
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::connection::test::session;
+
+    #[test]
+    fn capped() {
+        let args = |truncate| CapArgs {
+            max_delete: Some(3),
+            truncate,
+            max_bytes: None,
+        };
+        let cap = args(false).cap().unwrap();
+        assert_eq!(cap.apply("INBOX", vec![1, 2]).unwrap(), [1, 2]);
+        assert!(matches!(
+            cap.apply("Archive", vec![5, 6]),
+            Err(Error::Guard(_))
+        ));
+        assert_eq!(cap.apply("Sent", vec![7]).unwrap(), [7]);
+        cap.release(2);
+        assert_eq!(cap.apply("Trash", vec![8, 9]).unwrap(), [8, 9]);
+        let cap = args(true).cap().unwrap();
+        assert_eq!(cap.apply("INBOX", vec![1, 2]).unwrap(), [1, 2]);
+        assert_eq!(cap.apply("Archive", vec![5, 6]).unwrap(), [5]);
+        assert!(cap.apply("Sent", vec![7]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn sized() {
+        let server =
+            b"* 1 FETCH (UID 1 INTERNALDATE \"01-Jan-2018 12:00:00 +0000\" RFC822.SIZE 100)\r\n\
+            * 2 FETCH (UID 2 INTERNALDATE \"01-Jan-2017 12:00:00 +0000\" RFC822.SIZE 300)\r\n\
+            * 3 FETCH (UID 3 INTERNALDATE \"01-Jan-2019 12:00:00 +0000\" RFC822.SIZE 50)\r\n\
+            a2 OK done\r\n";
+        let args = CapArgs {
+            max_delete: None,
+            truncate: false,
+            max_bytes: Some(420),
+        };
+        let (mut imap, _, _) = session(server);
+        let cap = args.size_cap(None, false).unwrap();
+        assert_eq!(cap.apply(&mut imap, &[1, 2, 3]).unwrap(), [1, 2]);
+        assert_eq!(*cap.left.lock().unwrap(), 20);
+        // The smallest first.
+        let (mut imap, _, _) = session(server);
+        let cap = args.size_cap(Some(Sort::Size), true).unwrap();
+        assert_eq!(cap.apply(&mut imap, &[1, 2, 3]).unwrap(), [1, 3]);
+    }
+}
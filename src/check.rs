@@ -0,0 +1,84 @@
+use crate::error::Result;
+use crate::mailbox;
+use crate::tap::Tap;
+use imap::Session;
+use imap_proto::types::Capability;
+use std::io::{Read, Write};
+use std::time::{Duration, Instant};
+
+/// The number of NOOP timed for the round trip.
+const ROUND_TRIPS: usize = 3;
+
+/// Report on the session just opened, in `connected`: its capabilities, the mailboxes opened
+/// read-only and the round trip to the server. Changes nothing, to try the connection and the
+/// credentials before a cleanup.
+pub fn check<S: Read + Write>(
+    session: &mut Session<S>,
+    tap: &Tap,
+    mailboxes: &[String],
+    connected: Duration,
+) -> Result<()> {
+    println!("Connected and authenticated in {}.", millis(connected));
+    let capabilities = session.capabilities()?;
+    println!("Capabilities: {}", names(capabilities.iter()).join(" "));
+    for name in mailboxes {
+        let selected = mailbox::open(session, tap, name, true)?;
+        let uid_validity = selected
+            .uid_validity
+            .map_or("none".to_string(), |x| x.to_string());
+        println!(
+            "{}: {} messages, UIDVALIDITY {}.",
+            name, selected.exists, uid_validity
+        );
+    }
+    let mut round_trip = Duration::MAX;
+    for _ in 0..ROUND_TRIPS {
+        let started = Instant::now();
+        session.noop()?;
+        round_trip = round_trip.min(started.elapsed());
+    }
+    println!(
+        "Round trip: {} (the fastest of {} NOOP).",
+        millis(round_trip),
+        ROUND_TRIPS
+    );
+    println!("OK.");
+    Ok(())
+}
+
+/// The names of the capabilities as the server gives them, sorted.
+fn names<'a>(capabilities: impl Iterator<Item = &'a Capability<'a>>) -> Vec<String> {
+    let mut names = capabilities
+        .map(|x| match x {
+            Capability::Imap4rev1 => "IMAP4rev1".to_string(),
+            Capability::Auth(mechanism) => format!("AUTH={}", mechanism),
+            Capability::Atom(name) => name.to_string(),
+        })
+        .collect::<Vec<_>>();
+    names.sort();
+    names
+}
+
+fn millis(duration: Duration) -> String {
+    format!("{:.1}ms", duration.as_secs_f64() * 1000.0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn capabilities() {
+        assert_eq!(
+            names(
+                [
+                    Capability::Atom("UIDPLUS"),
+                    Capability::Auth("PLAIN"),
+                    Capability::Imap4rev1
+                ]
+                .iter()
+            ),
+            ["AUTH=PLAIN", "IMAP4rev1", "UIDPLUS"]
+        );
+    }
+}
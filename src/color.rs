@@ -0,0 +1,139 @@
+use std::cell::Cell;
+use std::fmt;
+use std::io::IsTerminal;
+use std::sync::{Mutex, OnceLock};
+
+/// When to color the output.
+#[derive(clap::ValueEnum, serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    /// When printing to a terminal and NO_COLOR is not set.
+    Auto,
+    Always,
+    Never,
+}
+
+/// Set again once the profile of the configuration file is read.
+static MODE: Mutex<Mode> = Mutex::new(Mode::Auto);
+
+thread_local! {
+    /// The line being formatted is for the standard error, not the standard output.
+    static STDERR: Cell<bool> = const { Cell::new(false) };
+}
+
+pub fn set(mode: Mode) {
+    *MODE.lock().unwrap() = mode;
+}
+
+/// Whether the lines printed on the standard output are colored.
+pub fn stdout() -> bool {
+    enabled(false)
+}
+
+/// Whether the lines printed on the standard error are colored.
+pub fn stderr() -> bool {
+    enabled(true)
+}
+
+fn enabled(stderr: bool) -> bool {
+    static TERMINALS: OnceLock<(bool, bool)> = OnceLock::new();
+    let mode = *MODE.lock().unwrap();
+    match mode {
+        Mode::Always => true,
+        Mode::Never => false,
+        Mode::Auto => {
+            // https://no-color.org: set and not empty.
+            let no_color = std::env::var_os("NO_COLOR").is_some_and(|x| !x.is_empty());
+            let (stdout, stderr_) = *TERMINALS.get_or_init(|| {
+                (
+                    std::io::stdout().is_terminal(),
+                    std::io::stderr().is_terminal(),
+                )
+            });
+            !no_color && if stderr { stderr_ } else { stdout }
+        }
+    }
+}
+
+/// Format a line for the standard error or the standard output, colored for that one.
+pub fn formatting<T>(stderr: bool, format: impl FnOnce() -> T) -> T {
+    let previous = STDERR.with(|x| x.replace(stderr));
+    let line = format();
+    STDERR.with(|x| x.set(previous));
+    line
+}
+
+/// A value displayed in a color when the line is colored: its width and alignment apply to the
+/// value, not to the escape codes.
+pub struct Painted<T> {
+    value: T,
+    code: &'static str,
+}
+
+impl<T: fmt::Display> fmt::Display for Painted<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.code.is_empty() || !enabled(STDERR.with(Cell::get)) {
+            return self.value.fmt(f);
+        }
+        write!(f, "\x1b[{}m", self.code)?;
+        self.value.fmt(f)?;
+        write!(f, "\x1b[0m")
+    }
+}
+
+/// The messages deleted and the failures.
+pub fn red<T>(value: T) -> Painted<T> {
+    Painted { value, code: "31" }
+}
+
+/// The messages cleaned, in red when deleted.
+pub fn cleaned<T>(value: T, deleted: bool) -> Painted<T> {
+    Painted {
+        value,
+        code: if deleted { "31" } else { "" },
+    }
+}
+
+/// The messages kept or skipped, and the warnings.
+pub fn yellow<T>(value: T) -> Painted<T> {
+    Painted { value, code: "33" }
+}
+
+/// The summaries and the headers.
+pub fn bold<T>(value: T) -> Painted<T> {
+    Painted { value, code: "1" }
+}
+
+/// The errors.
+pub fn error<T>(value: T) -> Painted<T> {
+    Painted {
+        value,
+        code: "1;31",
+    }
+}
+
+/// A line without its colors, for the logs.
+pub fn strip(line: &str) -> String {
+    let mut stripped = String::with_capacity(line.len());
+    let mut rest = line;
+    while let Some(start) = rest.find("\x1b[") {
+        stripped.push_str(&rest[..start]);
+        rest = &rest[start + 2..];
+        rest = &rest[rest.find('m').map_or(rest.len(), |x| x + 1)..];
+    }
+    stripped.push_str(rest);
+    stripped
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn painted() {
+        set(Mode::Always);
+        let line = format!("{}: {:>3}.", bold("Total"), red(7));
+        assert_eq!(line, "\x1b[1mTotal\x1b[0m: \x1b[31m  7\x1b[0m.");
+        assert_eq!(strip(&line), "Total:   7.");
+    }
+}
@@ -0,0 +1,49 @@
+use crate::config::Config;
+use crate::error::Result;
+use clap::builder::PossibleValuesParser;
+use clap::Command;
+use clap_complete::Shell;
+use std::path::Path;
+
+/// Print the completions of `command` for `shell`. The names of the accounts and profiles of the
+/// configuration file, if any, are completed too: the completions are to generate again when
+/// some are added.
+pub fn print(mut command: Command, shell: Shell, config: Option<&Path>) -> Result<()> {
+    let config = match config.filter(|x| x.exists()) {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    let accounts = names(config.account.keys());
+    let profiles = names(config.profile.keys());
+    for (arg, names) in [("account", accounts), ("profile", profiles)] {
+        if !names.is_empty() {
+            command = command.mut_arg(arg, |x| x.value_parser(PossibleValuesParser::new(names)));
+        }
+    }
+    let name = command.get_name().to_string();
+    clap_complete::generate(shell, &mut command, name, &mut std::io::stdout());
+    Ok(())
+}
+
+/// The names as clap wants them, leaked: the program exits after printing the completions.
+fn names<'a>(names: impl Iterator<Item = &'a String>) -> Vec<&'static str> {
+    names.map(|x| &*x.clone().leak()).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Args;
+    use clap::CommandFactory;
+
+    #[test]
+    fn names() {
+        let accounts = ["home".to_string(), "work".to_string()];
+        let mut command = Args::command().mut_arg("account", |x| {
+            x.value_parser(PossibleValuesParser::new(super::names(accounts.iter())))
+        });
+        let mut output = Vec::new();
+        clap_complete::generate(Shell::Bash, &mut command, "imap-cleanup", &mut output);
+        assert!(String::from_utf8_lossy(&output).contains("home work"));
+    }
+}
@@ -0,0 +1,155 @@
+use crate::error::Result;
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress};
+use imap::Session;
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// The capability of COMPRESS=DEFLATE (RFC 4978).
+pub const CAPABILITY: &str = "COMPRESS=DEFLATE";
+
+/// Turns on the compression of a `Deflate` stream, once the server accepted it.
+#[derive(Clone, Default)]
+pub struct Switch(Arc<AtomicBool>);
+
+impl Switch {
+    pub fn wrap<S>(&self, inner: S) -> Deflate<S> {
+        Deflate {
+            inner,
+            active: self.0.clone(),
+            compress: Compress::new(Compression::default(), false),
+            decompress: Decompress::new(false),
+            input: Vec::new(),
+            consumed: 0,
+        }
+    }
+
+    /// Negotiate the compression of the session when the server supports it, returns whether it
+    /// is on.
+    pub fn start<S: Read + Write>(&self, session: &mut Session<S>) -> Result<bool> {
+        if self.0.load(Ordering::Relaxed) || !session.capabilities()?.has_str(CAPABILITY) {
+            return Ok(self.0.load(Ordering::Relaxed));
+        }
+        session.run_command_and_check_ok("COMPRESS DEFLATE")?;
+        // The server compresses what follows its answer, imap has read nothing more.
+        self.0.store(true, Ordering::Relaxed);
+        Ok(true)
+    }
+}
+
+/// A stream compressed with raw DEFLATE once its `Switch` is on, plain before.
+pub struct Deflate<S> {
+    inner: S,
+    active: Arc<AtomicBool>,
+    compress: Compress,
+    decompress: Decompress,
+    /// What was read from the server and is not decompressed yet, from `consumed`.
+    input: Vec<u8>,
+    consumed: usize,
+}
+
+impl<S: Read> Read for Deflate<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.active.load(Ordering::Relaxed) {
+            return self.inner.read(buf);
+        }
+        loop {
+            // Even without input, the output left by the last call is still to be taken.
+            let (total_in, total_out) = (self.decompress.total_in(), self.decompress.total_out());
+            self.decompress
+                .decompress(&self.input[self.consumed..], buf, FlushDecompress::Sync)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            self.consumed += (self.decompress.total_in() - total_in) as usize;
+            let n = (self.decompress.total_out() - total_out) as usize;
+            if n > 0 || buf.is_empty() {
+                return Ok(n);
+            }
+            self.input.drain(..self.consumed);
+            self.consumed = 0;
+            let mut chunk = [0; 4096];
+            let n = self.inner.read(&mut chunk)?;
+            if n == 0 {
+                return Ok(0);
+            }
+            self.input.extend_from_slice(&chunk[..n]);
+        }
+    }
+}
+
+impl<S: Write> Write for Deflate<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !self.active.load(Ordering::Relaxed) {
+            return self.inner.write(buf);
+        }
+        // Each write is flushed: the server must get the whole command to answer it.
+        let mut output = Vec::with_capacity(buf.len() + 64);
+        let start = self.compress.total_in();
+        loop {
+            let consumed = (self.compress.total_in() - start) as usize;
+            self.compress
+                .compress_vec(&buf[consumed..], &mut output, FlushCompress::Sync)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            // A full output may leave some of the flush out.
+            if (self.compress.total_in() - start) as usize == buf.len()
+                && output.len() < output.capacity()
+            {
+                break;
+            }
+            output.reserve(output.capacity());
+        }
+        self.inner.write_all(&output)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::connection::test::Mock;
+
+    fn deflate(data: &[u8]) -> Vec<u8> {
+        let mut compress = Compress::new(Compression::default(), false);
+        let mut output = Vec::with_capacity(data.len() + 64);
+        compress
+            .compress_vec(data, &mut output, FlushCompress::Sync)
+            .unwrap();
+        output
+    }
+
+    fn inflate(data: &[u8]) -> Vec<u8> {
+        let mut decompress = Decompress::new(false);
+        let mut output = Vec::with_capacity(data.len() * 10 + 1024);
+        decompress
+            .decompress_vec(data, &mut output, FlushDecompress::Sync)
+            .unwrap();
+        output
+    }
+
+    #[test]
+    fn switch() {
+        let response = b"* 1 FETCH (UID 3 FLAGS (\\Seen))\r\na3 OK done\r\n";
+        let mut server = b"a2 OK DEFLATE active\r\n".to_vec();
+        server.extend(deflate(response));
+        let switch = Switch::default();
+        let mut stream = switch.wrap(Mock::new(&server));
+
+        stream.write_all(b"a2 COMPRESS DEFLATE\r\n").unwrap();
+        let mut line = [0; 22];
+        stream.read_exact(&mut line).unwrap();
+        assert_eq!(&line, b"a2 OK DEFLATE active\r\n");
+
+        switch.0.store(true, Ordering::Relaxed);
+        stream.write_all(b"a3 UID FETCH 3 FLAGS\r\n").unwrap();
+        let mut output = Vec::new();
+        stream.read_to_end(&mut output).unwrap();
+        assert_eq!(output, response);
+
+        let sent = &stream.inner.1;
+        assert_eq!(&sent[..21], b"a2 COMPRESS DEFLATE\r\n");
+        assert_eq!(inflate(&sent[21..]), b"a3 UID FETCH 3 FLAGS\r\n");
+    }
+}
@@ -0,0 +1,230 @@
+use crate::age::Age;
+use crate::auth::AuthMethod;
+use crate::color;
+use crate::error::{Error, Result};
+use crate::output;
+use crate::preview::Sort;
+use chrono::NaiveDate;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// The configuration file, defining named accounts (where to connect) and profiles (what to
+/// cleanup). Both can be combined to run the same profile against several accounts:
+///
+/// ```toml
+/// [account.work]
+/// host = "imap.example.com"
+/// username = "me@example.com"
+/// mailbox = "INBOX"
+/// retention = "90d"
+///
+/// [profile.newsletter-purge]
+/// mailbox = "Lists/newsletters"
+/// retention = "30d"
+/// dry-run = true
+/// sort = "size"
+/// ```
+#[derive(serde::Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub account: BTreeMap<String, Account>,
+    #[serde(default)]
+    pub profile: BTreeMap<String, Profile>,
+}
+
+#[derive(serde::Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Account {
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    pub username: Option<String>,
+    pub auth: Option<AuthMethod>,
+    /// Mailboxes cleaned when -b is not given, a name or a list of names.
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub mailbox: Vec<String>,
+    /// Messages older than this are cleaned when --before is not given.
+    pub retention: Option<Age>,
+    /// Same as --password-cmd.
+    pub password_cmd: Option<String>,
+    /// The trash of empty-trash, when the server does not tell it with SPECIAL-USE.
+    pub trash: Option<String>,
+}
+
+/// Settings of a profile, they take precedence over the account's.
+#[derive(serde::Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Profile {
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub mailbox: Vec<String>,
+    /// Same as --before.
+    #[serde(default, deserialize_with = "date")]
+    pub before: Option<NaiveDate>,
+    /// Messages older than this are cleaned when --before is not given.
+    pub retention: Option<Age>,
+    /// Same as --dry-run.
+    pub dry_run: Option<bool>,
+    /// Same as --format.
+    pub format: Option<output::Format>,
+    /// Same as --color.
+    pub color: Option<color::Mode>,
+    /// Same as --sort.
+    pub sort: Option<Sort>,
+    /// Same as --reverse.
+    pub reverse: Option<bool>,
+}
+
+/// A string or a list of strings.
+pub fn one_or_many<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Vec<String>, D::Error> {
+    #[derive(serde::Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    match <OneOrMany as serde::Deserialize>::deserialize(deserializer)? {
+        OneOrMany::One(one) => Ok(vec![one]),
+        OneOrMany::Many(many) => Ok(many),
+    }
+}
+
+/// A TOML local date like `before = 2024-01-01`.
+fn date<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Option<NaiveDate>, D::Error> {
+    use serde::de::Error;
+
+    let date = <toml::value::Datetime as serde::Deserialize>::deserialize(deserializer)?;
+    match (date.date, date.time) {
+        (Some(date), None) => {
+            NaiveDate::from_ymd_opt(date.year.into(), date.month.into(), date.day.into())
+                .map(Some)
+                .ok_or_else(|| D::Error::custom("invalid date"))
+        }
+        _ => Err(D::Error::custom("expected a date like 2024-01-01")),
+    }
+}
+
+/// `$XDG_CONFIG_HOME/imap-cleanup/config.toml`, or `~/.config/imap-cleanup/config.toml`.
+pub fn default_path() -> Option<PathBuf> {
+    let base = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(base.join("imap-cleanup").join("config.toml"))
+}
+
+/// `$XDG_STATE_HOME/imap-cleanup`, or `~/.local/state/imap-cleanup`, for what is kept between
+/// the runs.
+pub fn state_dir() -> Option<PathBuf> {
+    let base = match std::env::var_os("XDG_STATE_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?)
+            .join(".local")
+            .join("state"),
+    };
+    Some(base.join("imap-cleanup"))
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|err| Error::Config(format!("{}: {}", path.display(), err)))?;
+        Self::parse(&content).map_err(|err| Error::Config(format!("{}: {}", path.display(), err)))
+    }
+
+    fn parse(content: &str) -> std::result::Result<Self, toml::de::Error> {
+        toml::from_str(content)
+    }
+
+    pub fn account(&self, name: &str) -> Result<&Account> {
+        lookup(&self.account, "account", name)
+    }
+
+    pub fn profile(&self, name: &str) -> Result<&Profile> {
+        lookup(&self.profile, "profile", name)
+    }
+}
+
+fn lookup<'a, T>(entries: &'a BTreeMap<String, T>, kind: &str, name: &str) -> Result<&'a T> {
+    entries.get(name).ok_or_else(|| {
+        Error::Config(format!(
+            "no {} {:?} in the configuration (available: {})",
+            kind,
+            name,
+            entries.keys().cloned().collect::<Vec<_>>().join(", ")
+        ))
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let config = Config::parse(
+            r#"
+            [account.work]
+            host = "imap.example.com"
+            port = 143
+            username = "me@example.com"
+            auth = "xoauth2"
+            retention = "6m"
+
+            [account.home]
+            host = "mail.example.org"
+            password-cmd = "pass show mail"
+
+            [profile.old]
+            mailbox = ["INBOX", "Archive"]
+            before = 2020-01-01
+            dry-run = true
+            format = "json"
+            sort = "size"
+            reverse = true
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.account("work").unwrap(),
+            &Account {
+                host: Some("imap.example.com".to_string()),
+                port: Some(143),
+                username: Some("me@example.com".to_string()),
+                auth: Some(AuthMethod::Xoauth2),
+                mailbox: vec![],
+                retention: Some(Age::Months(6)),
+                password_cmd: None,
+                trash: None,
+            }
+        );
+        assert_eq!(
+            config.account("home").unwrap().password_cmd.as_deref(),
+            Some("pass show mail")
+        );
+        assert!(config.account("other").is_err());
+        assert_eq!(
+            config.profile("old").unwrap(),
+            &Profile {
+                mailbox: vec!["INBOX".to_string(), "Archive".to_string()],
+                before: NaiveDate::from_ymd_opt(2020, 1, 1),
+                dry_run: Some(true),
+                format: Some(output::Format::Json),
+                sort: Some(Sort::Size),
+                reverse: Some(true),
+                ..Profile::default()
+            }
+        );
+        assert!(config.profile("work").is_err());
+
+        assert!(Config::parse("[account.x]\nhots = \"typo\"").is_err());
+        assert!(Config::parse("[account.x]\nretention = \"soon\"").is_err());
+        assert!(Config::parse("[profile.x]\nbefore = \"2020-01-01\"").is_err());
+        assert!(Config::parse("[profile.x]\nbefore = 2020-01-01T00:00:00").is_err());
+        assert!(Config::parse("[profile.x]\nsort = \"name\"").is_err());
+    }
+}
@@ -0,0 +1,389 @@
+use crate::compress;
+use crate::error::{Error, Result};
+use crate::proxy::Proxy;
+use crate::retry::parse_duration;
+use crate::tap::Tap;
+use crate::tls::{self, TlsArgs};
+use crate::trace::{Trace, TraceArgs};
+use crate::tunnel::{Preauth, Tunnel};
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+/// Capabilities advertised by the server before authentication.
+#[derive(Debug, Default)]
+pub struct Capabilities(Vec<String>);
+
+impl Capabilities {
+    pub fn has(&self, name: &str) -> bool {
+        self.0.iter().any(|x| x.eq_ignore_ascii_case(name))
+    }
+
+    pub fn has_auth(&self, mechanism: &str) -> bool {
+        self.0.iter().any(|x| {
+            x.len() > 5
+                && x[..5].eq_ignore_ascii_case("AUTH=")
+                && x[5..].eq_ignore_ascii_case(mechanism)
+        })
+    }
+
+    /// Parse the capability list out of a `* CAPABILITY ...` response or a greeting with a
+    /// `[CAPABILITY ...]` response code.
+    fn parse(line: &str) -> Option<Self> {
+        let line = line.trim_end();
+        let list = if let Some(rest) = strip_prefix_ignore_case(line, "* CAPABILITY ") {
+            rest
+        } else {
+            let start = line.find('[')? + 1;
+            let rest = strip_prefix_ignore_case(&line[start..], "CAPABILITY ")?;
+            &rest[..rest.find(']')?]
+        };
+        Some(Capabilities(
+            list.split_ascii_whitespace().map(String::from).collect(),
+        ))
+    }
+}
+
+fn strip_prefix_ignore_case<'a>(s: &'a str, prefix: &str) -> Option<&'a str> {
+    if s.len() >= prefix.len() && s[..prefix.len()].eq_ignore_ascii_case(prefix) {
+        Some(&s[prefix.len()..])
+    } else {
+        None
+    }
+}
+
+/// Any stream the IMAP session can run over.
+pub trait Stream: Read + Write + Send {}
+
+impl<T: Read + Write + Send> Stream for T {}
+
+pub type Client = imap::Client<Box<dyn Stream>>;
+
+#[derive(clap::Args, Debug)]
+pub struct ConnectionArgs {
+    /// Connect in plaintext and upgrade the connection with STARTTLS.
+    #[clap(long, env = "IMAP_CLEANUP_STARTTLS")]
+    pub starttls: bool,
+
+    #[clap(flatten)]
+    pub tls: TlsArgs,
+
+    /// Connect through a proxy: socks5://host:port, socks5h://host:port (resolve the server on
+    /// the proxy) or http://host:port (HTTP CONNECT). Credentials can be given as user:password@.
+    #[clap(long, env = "IMAP_CLEANUP_PROXY", hide_env_values = true)]
+    pub proxy: Option<Proxy>,
+
+    /// Speak IMAP over the stdin/stdout of this command instead of connecting to the host, for
+    /// example "ssh mailhost /usr/lib/dovecot/imap". No login is done if the server greets with
+    /// PREAUTH.
+    #[clap(long, conflicts_with_all = &["proxy", "starttls"], env = "IMAP_CLEANUP_TUNNEL")]
+    pub tunnel: Option<String>,
+
+    /// Give up connecting, or waiting for the server to answer a command, after this long, like
+    /// 30s or 2m, for the runs from cron. Not applied to --tunnel.
+    #[clap(
+        long,
+        value_name = "DURATION",
+        value_parser(parse_duration),
+        conflicts_with = "tunnel",
+        env = "IMAP_CLEANUP_TIMEOUT"
+    )]
+    pub timeout: Option<Duration>,
+
+    /// Do not compress the session, even when the server supports COMPRESS=DEFLATE. The
+    /// compression saves time on the slow links when fetching the headers of many messages.
+    #[clap(long, env = "IMAP_CLEANUP_NO_COMPRESS")]
+    pub no_compress: bool,
+
+    #[clap(flatten)]
+    pub trace: TraceArgs,
+}
+
+impl ConnectionArgs {
+    pub fn default_port(&self) -> u16 {
+        if self.starttls {
+            143
+        } else {
+            993
+        }
+    }
+}
+
+/// An unauthenticated connection and what the server told about itself.
+pub struct Connection {
+    pub client: Client,
+    pub capabilities: Capabilities,
+    /// The server greeted with PREAUTH: the session is already authenticated.
+    pub preauth: bool,
+    /// Compresses the session once authenticated.
+    pub compression: compress::Switch,
+}
+
+impl Connection {
+    fn new<S: Stream + 'static>(stream: S, capabilities: Capabilities, tap: &Tap) -> Self {
+        let compression = compress::Switch::default();
+        Connection {
+            client: imap::Client::new(Box::new(tap.wrap(compression.wrap(stream)))),
+            capabilities,
+            preauth: false,
+            compression,
+        }
+    }
+}
+
+/// Open a connection to the server: either through a tunnel command or with TLS (implicit or
+/// with STARTTLS). Then read the greeting and the capabilities. The responses of the session are
+/// tapped into `tap`, which can be given to the next connections after losing this one. The
+/// trace is taken below the compression and above the TLS, where the protocol is readable. The
+/// tunnel command does not get the variable of --password-env.
+pub fn connect(
+    host: &str,
+    port: u16,
+    args: &ConnectionArgs,
+    password_env: Option<&str>,
+    tap: &Tap,
+) -> Result<Connection> {
+    if let Some(command) = &args.tunnel {
+        tracing::info!(command, "connecting through the tunnel");
+        let mut stream = Trace::start().wrap(Tunnel::spawn(command, password_env)?);
+        let greeting = read_greeting(&mut stream)?;
+        let capabilities = match Capabilities::parse(&greeting) {
+            Some(capabilities) => capabilities,
+            None => query_capabilities(&mut stream)?,
+        };
+        if greeting.starts_with("* PREAUTH") {
+            let mut connection = Connection::new(Preauth::new(stream), capabilities, tap);
+            connection.preauth = true;
+            return Ok(connection);
+        }
+        return Ok(Connection::new(stream, capabilities, tap));
+    }
+
+    tracing::info!(host, port, starttls = args.starttls, "connecting");
+    let stream = match &args.proxy {
+        Some(proxy) => proxy.connect(host, port, args.timeout)?,
+        None => tcp_connect(host, port, args.timeout)?,
+    };
+
+    if args.starttls {
+        let mut stream = Trace::start().wrap(stream);
+        read_greeting(&mut stream)?;
+        starttls(&mut stream)?;
+        let (stream, trace) = stream.into_parts();
+        let mut stream = trace.wrap(tls::wrap(&args.tls, host, stream)?);
+        let capabilities = query_capabilities(&mut stream)?;
+        Ok(Connection::new(stream, capabilities, tap))
+    } else {
+        let mut stream = Trace::start().wrap(tls::wrap(&args.tls, host, stream)?);
+        let greeting = read_greeting(&mut stream)?;
+        let capabilities = match Capabilities::parse(&greeting) {
+            Some(capabilities) => capabilities,
+            None => query_capabilities(&mut stream)?,
+        };
+        Ok(Connection::new(stream, capabilities, tap))
+    }
+}
+
+/// Open a TCP connection whose connection, reads and writes fail after `timeout`. The timeouts
+/// are set before the TLS handshake, they apply to it too.
+pub fn tcp_connect(host: &str, port: u16, timeout: Option<Duration>) -> Result<TcpStream> {
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return Ok(TcpStream::connect((host, port))?),
+    };
+    let mut last = None;
+    for addr in (host, port).to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => {
+                stream.set_read_timeout(Some(timeout))?;
+                stream.set_write_timeout(Some(timeout))?;
+                return Ok(stream);
+            }
+            Err(err) => last = Some(err),
+        }
+    }
+    Err(last
+        .unwrap_or_else(|| std::io::Error::other(format!("could not resolve {}", host)))
+        .into())
+}
+
+/// Turn a connection greeted with PREAUTH into a session.
+pub fn preauthenticated(client: Client) -> Result<imap::Session<Box<dyn Stream>>> {
+    // The LOGIN is answered by the `Preauth` wrapper, it never reaches the server.
+    Ok(client.login("preauth", "").map_err(|e| e.0)?)
+}
+
+/// Read and check the server greeting.
+///
+/// This and the following commands are done on the raw stream because `imap::Client` does not
+/// expose any command before authentication.
+fn read_greeting<S: Read>(stream: &mut S) -> Result<String> {
+    let greeting = read_line(stream)?;
+    if let Some(reason) = strip_prefix_ignore_case(&greeting, "* BYE") {
+        return Err(Error::Bye(reason.trim().to_string()));
+    }
+    if !greeting.starts_with("* OK") && !greeting.starts_with("* PREAUTH") {
+        return Err(Error::Protocol(format!(
+            "unexpected greeting: {}",
+            greeting.trim_end()
+        )));
+    }
+    Ok(greeting)
+}
+
+/// Issue a CAPABILITY command.
+fn query_capabilities<S: Read + Write>(stream: &mut S) -> Result<Capabilities> {
+    let mut capabilities = Capabilities::default();
+    for line in run_command(stream, "CAPABILITY")? {
+        if let Some(parsed) = Capabilities::parse(&line) {
+            capabilities = parsed;
+        }
+    }
+    Ok(capabilities)
+}
+
+/// Issue STARTTLS. Any failure is fatal: credentials must never be sent in plaintext.
+fn starttls<S: Read + Write>(stream: &mut S) -> Result<()> {
+    run_command(stream, "STARTTLS").map(|_| ()).map_err(|err| {
+        Error::Protocol(format!("STARTTLS failed, not sending credentials: {}", err))
+    })
+}
+
+/// Run a command and return its untagged responses if it succeeded.
+fn run_command<S: Read + Write>(stream: &mut S, command: &str) -> Result<Vec<String>> {
+    stream.write_all(format!("c0 {}\r\n", command).as_bytes())?;
+    stream.flush()?;
+    let mut untagged = Vec::new();
+    loop {
+        let line = read_line(stream)?;
+        if let Some(tagged) = line.strip_prefix("c0 ") {
+            if !tagged.starts_with("OK") {
+                return Err(Error::Protocol(format!(
+                    "{} failed: {}",
+                    command,
+                    tagged.trim_end()
+                )));
+            }
+            return Ok(untagged);
+        }
+        untagged.push(line);
+    }
+}
+
+/// Read a single line without buffering past its end, so the stream can be handed over to
+/// `imap::Client` afterwards.
+fn read_line<S: Read>(stream: &mut S) -> Result<String> {
+    let mut line = Vec::new();
+    let mut byte = [0; 1];
+    while !line.ends_with(b"\r\n") {
+        if stream.read(&mut byte)? == 0 {
+            return Err(imap::Error::ConnectionLost.into());
+        }
+        line.push(byte[0]);
+    }
+    Ok(String::from_utf8_lossy(&line).into_owned())
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use crate::tap::Tapped;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// Replays the server side and records what the client sent.
+    pub struct Mock(pub std::io::Cursor<Vec<u8>>, pub Vec<u8>);
+
+    impl Mock {
+        pub fn new(server: &[u8]) -> Self {
+            Mock(std::io::Cursor::new(server.to_vec()), Vec::new())
+        }
+    }
+
+    impl Read for Mock {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+
+    impl Write for Mock {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.1.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Like `Mock` but the commands sent can still be read once the stream is owned by a session.
+    pub struct Shared(std::io::Cursor<Vec<u8>>, Rc<RefCell<Vec<u8>>>);
+
+    impl Read for Shared {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.1.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    pub type Session = imap::Session<Tapped<Shared>>;
+
+    /// A logged in session replaying `server` (starting with the tag `a2`), its tap and the
+    /// commands sent after the login.
+    pub fn session(server: &[u8]) -> (Session, Tap, Rc<RefCell<Vec<u8>>>) {
+        let mut replay = b"a1 OK logged in\r\n".to_vec();
+        replay.extend_from_slice(server);
+        let sent = Rc::new(RefCell::new(Vec::new()));
+        let tap = Tap::default();
+        let client =
+            imap::Client::new(tap.wrap(Shared(std::io::Cursor::new(replay), sent.clone())));
+        let session = client.login("user", "password").map_err(|e| e.0).unwrap();
+        sent.borrow_mut().clear();
+        (session, tap, sent)
+    }
+
+    #[test]
+    fn parse_capabilities() {
+        let caps = Capabilities::parse(
+            "* OK [CAPABILITY IMAP4rev1 SASL-IR AUTH=PLAIN AUTH=OAUTHBEARER] Dovecot ready.\r\n",
+        )
+        .unwrap();
+        assert!(caps.has("imap4rev1"));
+        assert!(caps.has_auth("oauthbearer"));
+        assert!(!caps.has_auth("XOAUTH2"));
+
+        let caps = Capabilities::parse("* CAPABILITY IMAP4rev1 AUTH=XOAUTH2\r\n").unwrap();
+        assert!(caps.has_auth("XOAUTH2"));
+
+        assert!(Capabilities::parse("* OK Gimap ready\r\n").is_none());
+    }
+
+    #[test]
+    fn capabilities_command() {
+        let mut mock =
+            Mock::new(b"* OK ready\r\n* CAPABILITY IMAP4rev1 AUTH=XOAUTH2\r\nc0 OK done\r\n");
+        assert_eq!(read_greeting(&mut mock).unwrap(), "* OK ready\r\n");
+        let caps = query_capabilities(&mut mock).unwrap();
+        assert!(caps.has_auth("XOAUTH2"));
+        assert_eq!(mock.1, b"c0 CAPABILITY\r\n");
+    }
+
+    #[test]
+    fn starttls_refused() {
+        let mut mock = Mock::new(b"c0 BAD STARTTLS not available\r\n");
+        assert!(starttls(&mut mock).is_err());
+
+        let mut mock = Mock::new(b"c0 OK Begin TLS negotiation now\r\n");
+        assert!(starttls(&mut mock).is_ok());
+        assert_eq!(mock.1, b"c0 STARTTLS\r\n");
+    }
+}
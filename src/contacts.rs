@@ -0,0 +1,183 @@
+use crate::error::{Error, Result};
+use crate::senders::addresses;
+use crate::try_batches;
+use imap::Session;
+use std::collections::BTreeSet;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+/// The address book whose correspondents are never cleaned.
+#[derive(clap::Args, Debug)]
+pub struct ContactsArgs {
+    /// Never cleanup the messages from or to the addresses of these contacts: a directory of
+    /// vCard files (*.vcf, searched recursively) like ~/.contacts/, or a single .vcf file.
+    #[clap(long, value_name = "PATH", env = "IMAP_CLEANUP_PROTECT_CONTACTS")]
+    pub protect_contacts: Option<PathBuf>,
+}
+
+impl ContactsArgs {
+    pub fn load(&self) -> Result<Option<Contacts>> {
+        self.protect_contacts
+            .as_deref()
+            .map(Contacts::load)
+            .transpose()
+    }
+}
+
+/// The email addresses of the contacts, in lowercase.
+#[derive(Debug, Default)]
+pub struct Contacts {
+    pub addresses: BTreeSet<String>,
+}
+
+impl Contacts {
+    pub fn load(path: &Path) -> Result<Self> {
+        let mut contacts = Contacts::default();
+        contacts
+            .load_path(path)
+            .map_err(|err| Error::Config(format!("{}: {}", path.display(), err)))?;
+        Ok(contacts)
+    }
+
+    fn load_path(&mut self, path: &Path) -> std::io::Result<()> {
+        if path.is_dir() {
+            for entry in std::fs::read_dir(path)? {
+                let path = entry?.path();
+                let is_vcf = path
+                    .extension()
+                    .is_some_and(|x| x.eq_ignore_ascii_case("vcf"));
+                if is_vcf || path.is_dir() {
+                    self.load_path(&path)?;
+                }
+            }
+        } else {
+            self.addresses
+                .extend(parse_vcard(&std::fs::read_to_string(path)?));
+        }
+        Ok(())
+    }
+
+    pub fn contains(&self, address: &str) -> bool {
+        self.addresses.contains(&address.to_lowercase())
+    }
+
+    /// Keep out the UIDs of the selected mailbox from or to a contact, returns the others and the
+    /// number kept out.
+    pub fn apply<S: Read + Write>(
+        &self,
+        session: &mut Session<S>,
+        uids: &[u32],
+    ) -> Result<(Vec<u32>, usize)> {
+        let mut kept = Vec::new();
+        let mut protected = 0;
+        try_batches("FETCH", uids, |set, _| {
+            let fetch = session.uid_fetch(set, "(UID ENVELOPE)")?;
+            for message in fetch.iter() {
+                let uid = match message.uid {
+                    Some(uid) => uid,
+                    None => continue,
+                };
+                let envelope = message.envelope();
+                let from = addresses(envelope.and_then(|x| x.from.as_ref()));
+                let to = addresses(envelope.and_then(|x| x.to.as_ref()));
+                if from.iter().chain(&to).any(|x| self.contains(x)) {
+                    protected += 1;
+                } else {
+                    kept.push(uid);
+                }
+            }
+            Ok(())
+        })?;
+        kept.sort_unstable();
+        Ok((kept, protected))
+    }
+}
+
+/// The EMAIL properties of the vCards of a file (RFC 6350, also read for versions 2.1 and 3.0).
+fn parse_vcard(content: &str) -> Vec<String> {
+    // Unfold the lines first: a line starting with a space or a tab continues the previous one.
+    let mut lines: Vec<String> = Vec::new();
+    for line in content.lines() {
+        match (line.strip_prefix([' ', '\t']), lines.last_mut()) {
+            (Some(rest), Some(last)) => last.push_str(rest),
+            _ => lines.push(line.to_string()),
+        }
+    }
+    lines
+        .iter()
+        .filter_map(|line| {
+            // The parameters may quote a colon: EMAIL;TYPE="a:b":x@example.com.
+            let mut quoted = false;
+            let colon = line.find(|c| {
+                if c == '"' {
+                    quoted = !quoted;
+                }
+                c == ':' && !quoted
+            })?;
+            let (property, value) = (&line[..colon], &line[colon + 1..]);
+            // Drop the group (item1.EMAIL) and the parameters (EMAIL;TYPE=work).
+            let name = property.split(';').next()?;
+            let name = name.rsplit('.').next()?;
+            if !name.eq_ignore_ascii_case("EMAIL") {
+                return None;
+            }
+            let value = value.trim();
+            let value = value
+                .get(..7)
+                .filter(|x| x.eq_ignore_ascii_case("mailto:"))
+                .map_or(value, |_| &value[7..]);
+            if value.contains('@') {
+                Some(value.to_lowercase())
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::connection::test::session;
+
+    #[test]
+    fn vcard() {
+        assert_eq!(
+            parse_vcard(
+                "BEGIN:VCARD\r\n\
+                 VERSION:3.0\r\n\
+                 FN:Mom\r\n\
+                 EMAIL;TYPE=INTERNET,HOME:Mom@Example.com\r\n\
+                 item1.EMAIL;TYPE=\"work:main\":mom@work.exa\r\n mple\r\n\
+                 NOTE:EMAIL:not@an.address\r\n\
+                 END:VCARD\r\n\
+                 BEGIN:VCARD\r\n\
+                 VERSION:4.0\r\n\
+                 EMAIL;VALUE=uri:mailto:dad@example.com\r\n\
+                 EMAIL:\r\n\
+                 END:VCARD\r\n"
+            ),
+            ["mom@example.com", "mom@work.example", "dad@example.com"]
+        );
+    }
+
+    #[test]
+    fn protected() {
+        let (mut imap, _, _) = session(
+            b"* 1 FETCH (UID 3 ENVELOPE (NIL NIL ((NIL NIL \"news\" \"shop.example\")) \
+              NIL NIL ((NIL NIL \"me\" \"example.com\")) NIL NIL NIL NIL))\r\n\
+              * 2 FETCH (UID 4 ENVELOPE (NIL NIL ((NIL NIL \"Mom\" \"Example.com\")) \
+              NIL NIL ((NIL NIL \"me\" \"example.com\")) NIL NIL NIL NIL))\r\n\
+              * 3 FETCH (UID 5 ENVELOPE (NIL NIL ((NIL NIL \"me\" \"example.com\")) \
+              NIL NIL ((NIL NIL \"dad\" \"example.com\")) NIL NIL NIL NIL))\r\n\
+              a2 OK done\r\n",
+        );
+        let contacts = Contacts {
+            addresses: ["mom@example.com", "dad@example.com"]
+                .iter()
+                .map(|x| x.to_string())
+                .collect(),
+        };
+        assert_eq!(contacts.apply(&mut imap, &[3, 4, 5]).unwrap(), (vec![3], 2));
+    }
+}
@@ -0,0 +1,502 @@
+use crate::action::{Action, Extensions, Limiter, Progress};
+use crate::color::{bold, cleaned, red};
+use crate::error::{Error, Result};
+use crate::mailbox;
+use crate::mime::parse_headers;
+use crate::output::{say, tell};
+use crate::tap::Tap;
+use chrono::{DateTime, FixedOffset};
+use imap::Session;
+use itertools::Itertools;
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+
+/// The flags that cannot be stored on the copy kept.
+const TRANSIENT_FLAGS: &[&str] = &["\\Recent", "\\Deleted"];
+
+/// A copy of a message.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Entry {
+    pub mailbox: String,
+    pub uid: u32,
+    pub date: Option<DateTime<FixedOffset>>,
+    pub flags: Vec<String>,
+}
+
+/// The copies of a message: the one received first is kept, or the first in the canonical
+/// mailbox.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Duplicates {
+    pub message_id: String,
+    pub keep: Entry,
+    pub remove: Vec<Entry>,
+}
+
+impl Duplicates {
+    /// The flags of the copies removed that the copy kept does not have, to not lose a \Flagged
+    /// or a \Answered.
+    pub fn missing_flags(&self) -> Vec<String> {
+        self.remove
+            .iter()
+            .flat_map(|x| &x.flags)
+            .filter(|x| !self.keep.flags.contains(x))
+            .filter(|x| !TRANSIENT_FLAGS.iter().any(|y| x.eq_ignore_ascii_case(y)))
+            .sorted()
+            .dedup()
+            .cloned()
+            .collect()
+    }
+}
+
+/// The messages of one or more mailboxes by Message-ID and size. The copies of a message have the
+/// same size: a different size is a different message reusing the ID, which some mailers do.
+/// The messages without a Message-ID are left alone.
+#[derive(Debug, Default)]
+pub struct Index {
+    messages: BTreeMap<(String, u32), Vec<Entry>>,
+    /// The UIDVALIDITY of each mailbox indexed, checked before changing it.
+    uid_validity: BTreeMap<String, Option<u32>>,
+}
+
+impl Index {
+    /// Add the messages of `mailbox`, the selected mailbox.
+    pub fn fetch<S: Read + Write>(
+        &mut self,
+        session: &mut Session<S>,
+        mailbox: &str,
+        selected: &imap::types::Mailbox,
+    ) -> Result<()> {
+        self.uid_validity
+            .insert(mailbox.to_string(), selected.uid_validity);
+        if selected.exists == 0 {
+            return Ok(());
+        }
+        let fetch = session.uid_fetch(
+            "1:*",
+            "(UID RFC822.SIZE INTERNALDATE FLAGS BODY.PEEK[HEADER.FIELDS (MESSAGE-ID)])",
+        )?;
+        for message in fetch.iter() {
+            let (uid, size, header) = match (message.uid, message.size, message.header()) {
+                (Some(uid), Some(size), Some(header)) => (uid, size, header),
+                _ => continue,
+            };
+            let message_id = match message_id(header) {
+                Some(message_id) => message_id,
+                None => continue,
+            };
+            self.messages
+                .entry((message_id, size))
+                .or_default()
+                .push(Entry {
+                    mailbox: mailbox.to_string(),
+                    uid,
+                    date: message.internal_date(),
+                    flags: message.flags().iter().map(|x| x.to_string()).collect(),
+                });
+        }
+        Ok(())
+    }
+
+    /// The messages with several copies, ordered by Message-ID.
+    pub fn duplicates(&self, canonical: Option<&str>) -> Vec<Duplicates> {
+        self.messages
+            .iter()
+            .filter(|(_, entries)| entries.len() > 1)
+            .map(|((message_id, _), entries)| {
+                // The first received, the messages without a date last.
+                let mut entries = entries.clone();
+                entries.sort_by_key(|x| {
+                    let canonical = canonical == Some(x.mailbox.as_str());
+                    (!canonical, x.date.is_none(), x.date, x.uid)
+                });
+                let keep = entries.remove(0);
+                Duplicates {
+                    message_id: message_id.clone(),
+                    keep,
+                    remove: entries,
+                }
+            })
+            .collect()
+    }
+}
+
+/// The Message-ID of a message, like `<1234@example.com>`.
+fn message_id(header: &[u8]) -> Option<String> {
+    let headers = parse_headers(header);
+    let (_, value) = headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("Message-ID"))?;
+    let value = value.trim();
+    if value.is_empty() {
+        return None;
+    }
+    Some(value.to_string())
+}
+
+/// How to remove the duplicates.
+#[derive(Debug)]
+pub struct Dedup<'a> {
+    pub action: Action,
+    pub limiter: &'a Limiter,
+    pub extensions: Extensions,
+    /// Also remove the copies in different mailboxes.
+    pub across_mailboxes: bool,
+    /// The mailbox whose copies are kept across mailboxes.
+    pub canonical: Option<String>,
+    pub dry_run: bool,
+}
+
+impl Dedup<'_> {
+    /// Remove the duplicates in each mailbox, or across them.
+    pub fn run<S: Read + Write>(
+        &self,
+        session: &mut Session<S>,
+        tap: &Tap,
+        mailboxes: &[String],
+    ) -> Result<()> {
+        let mut counts = BTreeMap::new();
+        let mut failed = Vec::new();
+        if self.across_mailboxes {
+            // The index is complete before removing anything, or a copy could be removed from a
+            // mailbox before finding the canonical one.
+            let mut index = Index::default();
+            for mailbox in mailboxes {
+                let selected = mailbox::open(session, tap, mailbox, self.dry_run)?;
+                index.fetch(session, mailbox, &selected)?;
+            }
+            let duplicates = index.duplicates(self.canonical.as_deref());
+            self.print(&duplicates);
+            let selected = mailboxes.last().map(String::as_str);
+            counts = self.remove(session, tap, &index, &duplicates, selected, &mut failed)?;
+        } else {
+            for mailbox in mailboxes {
+                let mut index = Index::default();
+                let result = mailbox::open(session, tap, mailbox, self.dry_run)
+                    .and_then(|selected| index.fetch(session, mailbox, &selected));
+                match result {
+                    Ok(()) => {}
+                    // The server refused something for this mailbox, the others may still work.
+                    Err(Error::Imap(err @ (imap::Error::No(_) | imap::Error::Bad(_)))) => {
+                        tell!("{}: {} {}", mailbox, red("failed:"), err);
+                        failed.push(mailbox.clone());
+                        continue;
+                    }
+                    Err(err) => return Err(err),
+                };
+                let duplicates = index.duplicates(None);
+                self.print(&duplicates);
+                counts.extend(self.remove(
+                    session,
+                    tap,
+                    &index,
+                    &duplicates,
+                    Some(mailbox),
+                    &mut failed,
+                )?);
+            }
+        }
+        let counts = mailboxes
+            .iter()
+            .filter(|x| !failed.contains(x))
+            .map(|x| (x.as_str(), counts.get(x).copied().unwrap_or_default()))
+            .collect::<Vec<_>>();
+        self.summary(&counts, mailboxes);
+        if !failed.is_empty() {
+            return Err(Error::Partial {
+                failed: failed.len(),
+                total: mailboxes.len(),
+            });
+        }
+        Ok(())
+    }
+
+    fn print(&self, duplicates: &[Duplicates]) {
+        if !self.dry_run {
+            return;
+        }
+        for duplicate in duplicates {
+            say!(
+                "{}: {} copies, keeping UID {} in {}",
+                duplicate.message_id,
+                duplicate.remove.len() + 1,
+                duplicate.keep.uid,
+                duplicate.keep.mailbox
+            );
+        }
+    }
+
+    fn summary(&self, counts: &[(&str, usize)], mailboxes: &[String]) {
+        let done = self.action.done();
+        for (mailbox, count) in counts {
+            if self.dry_run {
+                let count = format!("{} duplicates not {}", count, done);
+                say!(
+                    "{}: {} (dry run).",
+                    mailbox,
+                    cleaned(count, self.action.frees())
+                );
+            } else {
+                let count = format!("{} duplicates {}", count, done);
+                say!("{}: {}.", mailbox, cleaned(count, self.action.frees()));
+            }
+        }
+        if mailboxes.len() > 1 {
+            let total = counts.iter().map(|(_, count)| count).sum::<usize>();
+            if self.dry_run {
+                say!(
+                    "{}",
+                    bold(format!(
+                        "Total: {} duplicates not {} in {} mailboxes (dry run).",
+                        total,
+                        done,
+                        mailboxes.len()
+                    ))
+                );
+            } else {
+                say!(
+                    "{}",
+                    bold(format!(
+                        "Total: {} duplicates {} in {} mailboxes.",
+                        total,
+                        done,
+                        mailboxes.len()
+                    ))
+                );
+            }
+        }
+    }
+
+    /// Give the copies kept the flags of the others, then remove the others. Returns the number
+    /// of copies removed in each mailbox (or that would be), `selected` is the mailbox selected
+    /// if any. The mailboxes refusing the removal are added to `failed`.
+    fn remove<S: Read + Write>(
+        &self,
+        session: &mut Session<S>,
+        tap: &Tap,
+        index: &Index,
+        duplicates: &[Duplicates],
+        selected: Option<&str>,
+        failed: &mut Vec<String>,
+    ) -> Result<BTreeMap<String, usize>> {
+        let mut uids = BTreeMap::<&str, Vec<u32>>::new();
+        let mut flags = BTreeMap::<&str, Vec<(u32, Vec<String>)>>::new();
+        for duplicate in duplicates {
+            for entry in &duplicate.remove {
+                uids.entry(&entry.mailbox).or_default().push(entry.uid);
+            }
+            let missing = duplicate.missing_flags();
+            if !missing.is_empty() {
+                let keep = &duplicate.keep;
+                flags
+                    .entry(&keep.mailbox)
+                    .or_default()
+                    .push((keep.uid, missing));
+            }
+        }
+        if self.dry_run {
+            return Ok(uids
+                .into_iter()
+                .map(|(mailbox, uids)| (mailbox.to_string(), uids.len()))
+                .collect());
+        }
+
+        let uid_validity = |mailbox: &str| index.uid_validity.get(mailbox).copied().flatten();
+        let mut selected = selected.map(String::from);
+        let mut select = |session: &mut Session<S>, mailbox: &str| -> Result<()> {
+            if selected.as_deref() != Some(mailbox) {
+                mailbox::reselect(session, mailbox, uid_validity(mailbox))?;
+                selected = Some(mailbox.to_string());
+            }
+            Ok(())
+        };
+        for (mailbox, flags) in &flags {
+            select(session, mailbox)?;
+            for (uid, flags) in flags {
+                session.uid_store(
+                    uid.to_string(),
+                    format!("+FLAGS.SILENT ({})", flags.join(" ")),
+                )?;
+            }
+        }
+        let mut counts = BTreeMap::new();
+        for (mailbox, mut uids) in uids {
+            uids.sort_unstable();
+            let result = select(session, mailbox).and_then(|()| {
+                self.action.apply(
+                    session,
+                    tap,
+                    self.limiter,
+                    self.extensions,
+                    mailbox,
+                    uid_validity(mailbox),
+                    &uids,
+                    &mut Progress::default(),
+                )
+            });
+            match result {
+                Ok(left_out) => {
+                    counts.insert(mailbox.to_string(), uids.len() - left_out.len());
+                }
+                Err(Error::Imap(err @ (imap::Error::No(_) | imap::Error::Bad(_)))) => {
+                    tell!("{}: {} {}", mailbox, red("failed:"), err);
+                    failed.push(mailbox.to_string());
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(counts)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::connection::test::session;
+
+    #[test]
+    fn duplicates() {
+        let (mut imap, _, sent) = session(
+            b"* 1 FETCH (UID 3 RFC822.SIZE 120 INTERNALDATE \"02-Mar-2023 12:00:00 +0000\" \
+              FLAGS (\\Seen \\Flagged) BODY[HEADER.FIELDS (MESSAGE-ID)] {21}\r\n\
+              Message-ID: <a@x>\r\n\r\n)\r\n\
+              * 2 FETCH (UID 4 RFC822.SIZE 120 INTERNALDATE \"01-Mar-2023 12:00:00 +0000\" \
+              FLAGS (\\Recent) BODY[HEADER.FIELDS (MESSAGE-ID)] {21}\r\n\
+              Message-ID: <a@x>\r\n\r\n)\r\n\
+              * 3 FETCH (UID 5 RFC822.SIZE 300 INTERNALDATE \"01-Mar-2023 12:00:00 +0000\" \
+              FLAGS () BODY[HEADER.FIELDS (MESSAGE-ID)] {21}\r\n\
+              Message-ID: <a@x>\r\n\r\n)\r\n\
+              * 4 FETCH (UID 6 RFC822.SIZE 120 INTERNALDATE \"01-Mar-2023 12:00:00 +0000\" \
+              FLAGS () BODY[HEADER.FIELDS (MESSAGE-ID)] {2}\r\n\r\n)\r\n\
+              * 5 FETCH (UID 7 RFC822.SIZE 120 INTERNALDATE \"01-Mar-2023 12:00:00 +0000\" \
+              FLAGS () BODY[HEADER.FIELDS (MESSAGE-ID)] {2}\r\n\r\n)\r\n\
+              * 6 FETCH (UID 8 RFC822.SIZE 80 INTERNALDATE \"05-Mar-2023 12:00:00 +0000\" \
+              FLAGS ($Important) BODY[HEADER.FIELDS (MESSAGE-ID)] {21}\r\n\
+              Message-ID: <b@x>\r\n\r\n)\r\n\
+              * 7 FETCH (UID 9 RFC822.SIZE 80 INTERNALDATE \"05-Mar-2023 12:00:00 +0000\" \
+              FLAGS () BODY[HEADER.FIELDS (MESSAGE-ID)] {21}\r\n\
+              Message-ID: <b@x>\r\n\r\n)\r\n\
+              a2 OK done\r\n",
+        );
+        let mut index = Index::default();
+        let selected = imap::types::Mailbox {
+            exists: 7,
+            ..imap::types::Mailbox::default()
+        };
+        index.fetch(&mut imap, "INBOX", &selected).unwrap();
+        let duplicates = index.duplicates(None);
+        assert_eq!(
+            String::from_utf8_lossy(&sent.borrow()),
+            "a2 UID FETCH 1:* \
+             (UID RFC822.SIZE INTERNALDATE FLAGS BODY.PEEK[HEADER.FIELDS (MESSAGE-ID)])\r\n"
+        );
+        assert_eq!(
+            duplicates
+                .iter()
+                .map(|x| (
+                    x.message_id.as_str(),
+                    x.keep.uid,
+                    x.remove.iter().map(|x| x.uid).collect::<Vec<_>>(),
+                    x.missing_flags(),
+                ))
+                .collect::<Vec<_>>(),
+            [
+                (
+                    "<a@x>",
+                    4,
+                    vec![3],
+                    vec!["\\Flagged".to_string(), "\\Seen".to_string()]
+                ),
+                ("<b@x>", 8, vec![9], vec![]),
+            ]
+        );
+    }
+
+    #[test]
+    fn remove() {
+        let (mut imap, tap, sent) = session(
+            b"* 2 EXISTS\r\na2 OK [READ-WRITE] done\r\n\
+              * 1 FETCH (UID 3 RFC822.SIZE 120 INTERNALDATE \"02-Mar-2023 12:00:00 +0000\" \
+              FLAGS (\\Answered) BODY[HEADER.FIELDS (MESSAGE-ID)] {21}\r\n\
+              Message-ID: <a@x>\r\n\r\n)\r\n\
+              * 2 FETCH (UID 4 RFC822.SIZE 120 INTERNALDATE \"01-Mar-2023 12:00:00 +0000\" \
+              FLAGS () BODY[HEADER.FIELDS (MESSAGE-ID)] {21}\r\n\
+              Message-ID: <a@x>\r\n\r\n)\r\n\
+              a3 OK done\r\n\
+              a4 OK done\r\n\
+              a5 OK done\r\n\
+              * 1 EXPUNGE\r\n\
+              a6 OK done\r\n",
+        );
+        let dedup = Dedup {
+            action: Action::Delete,
+            limiter: &Limiter::default(),
+            extensions: Extensions::default(),
+            across_mailboxes: false,
+            canonical: None,
+            dry_run: false,
+        };
+        dedup.run(&mut imap, &tap, &["INBOX".to_string()]).unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&sent.borrow()),
+            "a2 SELECT \"INBOX\"\r\n\
+             a3 UID FETCH 1:* \
+             (UID RFC822.SIZE INTERNALDATE FLAGS BODY.PEEK[HEADER.FIELDS (MESSAGE-ID)])\r\n\
+             a4 UID STORE 4 +FLAGS.SILENT (\\Answered)\r\n\
+             a5 UID STORE 3:3 +FLAGS.SILENT (\\Deleted)\r\n\
+             a6 EXPUNGE\r\n"
+        );
+    }
+
+    #[test]
+    fn across_mailboxes() {
+        let (mut imap, tap, sent) = session(
+            b"* 2 EXISTS\r\n* OK [UIDVALIDITY 7] ok\r\na2 OK [READ-WRITE] done\r\n\
+              * 1 FETCH (UID 3 RFC822.SIZE 120 INTERNALDATE \"01-Mar-2023 12:00:00 +0000\" \
+              FLAGS (\\Flagged) BODY[HEADER.FIELDS (MESSAGE-ID)] {21}\r\n\
+              Message-ID: <a@x>\r\n\r\n)\r\n\
+              * 2 FETCH (UID 4 RFC822.SIZE 80 INTERNALDATE \"01-Mar-2023 12:00:00 +0000\" \
+              FLAGS () BODY[HEADER.FIELDS (MESSAGE-ID)] {21}\r\n\
+              Message-ID: <b@x>\r\n\r\n)\r\n\
+              a3 OK done\r\n\
+              * 1 EXISTS\r\na4 OK [READ-WRITE] done\r\n\
+              * 1 FETCH (UID 7 RFC822.SIZE 120 INTERNALDATE \"05-Mar-2023 12:00:00 +0000\" \
+              FLAGS () BODY[HEADER.FIELDS (MESSAGE-ID)] {21}\r\n\
+              Message-ID: <a@x>\r\n\r\n)\r\n\
+              a5 OK done\r\n\
+              a6 OK done\r\n\
+              * 2 EXISTS\r\n* OK [UIDVALIDITY 7] ok\r\na7 OK [READ-WRITE] done\r\n\
+              a8 OK done\r\n\
+              * 1 EXPUNGE\r\n\
+              a9 OK done\r\n",
+        );
+        // The copy in Archive is kept, even if received later.
+        let dedup = Dedup {
+            action: Action::Delete,
+            limiter: &Limiter::default(),
+            extensions: Extensions::default(),
+            across_mailboxes: true,
+            canonical: Some("Archive".to_string()),
+            dry_run: false,
+        };
+        dedup
+            .run(
+                &mut imap,
+                &tap,
+                &["INBOX".to_string(), "Archive".to_string()],
+            )
+            .unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&sent.borrow()),
+            "a2 SELECT \"INBOX\"\r\n\
+             a3 UID FETCH 1:* \
+             (UID RFC822.SIZE INTERNALDATE FLAGS BODY.PEEK[HEADER.FIELDS (MESSAGE-ID)])\r\n\
+             a4 SELECT \"Archive\"\r\n\
+             a5 UID FETCH 1:* \
+             (UID RFC822.SIZE INTERNALDATE FLAGS BODY.PEEK[HEADER.FIELDS (MESSAGE-ID)])\r\n\
+             a6 UID STORE 7 +FLAGS.SILENT (\\Flagged)\r\n\
+             a7 SELECT \"INBOX\"\r\n\
+             a8 UID STORE 3:3 +FLAGS.SILENT (\\Deleted)\r\n\
+             a9 EXPUNGE\r\n"
+        );
+    }
+}
@@ -0,0 +1,121 @@
+use std::fmt;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[derive(Debug)]
+pub enum Error {
+    Imap(imap::Error),
+    Tls(String),
+    Io(std::io::Error),
+    OAuth(String),
+    Gssapi(String),
+    Password(String),
+    Protocol(String),
+    Proxy(String),
+    Secret(String),
+    Config(String),
+    /// The server refused the credentials, with this reason.
+    Auth(String),
+    /// A mailbox is locked by another run, for this reason.
+    Locked(String),
+    /// The server closed the connection, with this reason.
+    Bye(String),
+    /// A quota reached --warn-at.
+    Quota(String),
+    /// The user did not confirm.
+    Aborted,
+    /// A safety guard stopped the run, for this reason.
+    Guard(String),
+    /// Interrupted with Ctrl+C after applying the action to `done` messages, already reported.
+    Interrupted {
+        done: usize,
+    },
+    /// Some accounts of --all-accounts failed, the reasons were already reported.
+    Accounts {
+        failed: usize,
+        total: usize,
+    },
+    /// Some mailboxes could not be cleaned, the reasons were already reported.
+    Partial {
+        failed: usize,
+        total: usize,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            // What the read and write timeouts of --timeout fail with.
+            Error::Io(err) | Error::Imap(imap::Error::Io(err))
+                if matches!(
+                    err.kind(),
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                ) =>
+            {
+                write!(f, "timed out waiting for the server")
+            }
+            Error::Imap(err) => write!(f, "{}", err),
+            Error::Tls(err) => write!(f, "TLS error: {}", err),
+            Error::Io(err) => write!(f, "I/O error: {}", err),
+            Error::OAuth(msg) => write!(f, "OAuth error: {}", msg),
+            Error::Gssapi(msg) => write!(f, "GSSAPI error: {}", msg),
+            Error::Password(msg) => write!(f, "password error: {}", msg),
+            Error::Protocol(msg) => write!(f, "protocol error: {}", msg),
+            Error::Proxy(msg) => write!(f, "proxy error: {}", msg),
+            Error::Secret(msg) => write!(f, "secrets manager error: {}", msg),
+            Error::Config(msg) => write!(f, "configuration error: {}", msg),
+            Error::Auth(msg) => write!(f, "authentication failed: {}", msg),
+            Error::Locked(msg) => write!(f, "locked: {}", msg),
+            Error::Bye(msg) => write!(f, "the server closed the connection: {}", msg),
+            Error::Quota(msg) => write!(f, "quota warning: {}", msg),
+            Error::Aborted => write!(f, "aborted"),
+            Error::Guard(msg) => write!(f, "aborted: {}", msg),
+            Error::Interrupted { .. } => write!(f, "interrupted"),
+            Error::Accounts { failed, total } => {
+                write!(f, "{} of {} accounts failed", failed, total)
+            }
+            Error::Partial { failed, total } => {
+                write!(f, "{} of {} mailboxes failed", failed, total)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl Error {
+    /// The exit status of a run failing with this error, for cron and the scripts: see
+    /// `EXIT_STATUS` in main.rs.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Auth(_) | Error::OAuth(_) | Error::Gssapi(_) | Error::Password(_) => 2,
+            Error::Tls(_)
+            | Error::Io(_)
+            | Error::Proxy(_)
+            | Error::Bye(_)
+            | Error::Imap(imap::Error::Io(_) | imap::Error::ConnectionLost) => 3,
+            Error::Partial { .. } | Error::Accounts { .. } => 4,
+            Error::Aborted | Error::Guard(_) => 5,
+            Error::Interrupted { .. } => 130,
+            _ => 1,
+        }
+    }
+
+    /// Whether the error stops the run rather than failing the mailbox it happened in: the
+    /// session is lost, or the user aborted or interrupted it.
+    pub fn stops(&self) -> bool {
+        matches!(self.exit_code(), 2 | 3 | 5 | 130)
+    }
+}
+
+impl From<imap::Error> for Error {
+    fn from(err: imap::Error) -> Self {
+        Error::Imap(err)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Error::Io(err)
+    }
+}
@@ -0,0 +1,98 @@
+use crate::error::Result;
+use crate::mime::decode_header;
+use crate::senders::addresses;
+use imap::types::Fetch;
+use itertools::Itertools;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+/// The columns of the file.
+const HEADER: [&str; 7] = ["mailbox", "uid", "date", "from", "subject", "size", "flags"];
+
+/// The CSV file of --export-csv: a row per message found by the dry run, to review them before
+/// cleaning them.
+pub struct Export {
+    file: Mutex<BufWriter<File>>,
+}
+
+impl Export {
+    /// Create the file, with the header.
+    pub fn create(path: &Path) -> Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+        row(&mut file, HEADER.map(String::from))?;
+        file.flush()?;
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Write a row per message of this mailbox, fetched with their INTERNALDATE, FLAGS,
+    /// ENVELOPE and RFC822.SIZE.
+    pub fn write(&self, mailbox: &str, messages: &[Fetch]) -> Result<()> {
+        let mut file = self.file.lock().unwrap();
+        for message in messages {
+            row(&mut *file, fields(mailbox, message))?;
+        }
+        file.flush()?;
+        Ok(())
+    }
+}
+
+/// The first sender and the decoded subject of a message fetched with its ENVELOPE.
+pub fn from_and_subject(message: &Fetch) -> (String, String) {
+    let envelope = message.envelope();
+    let from = addresses(envelope.and_then(|x| x.from.as_ref()))
+        .into_iter()
+        .next()
+        .unwrap_or_default();
+    let subject = envelope
+        .and_then(|x| x.subject)
+        .map(decode_header)
+        .unwrap_or_default();
+    (from, subject)
+}
+
+fn fields(mailbox: &str, message: &Fetch) -> [String; 7] {
+    let (from, subject) = from_and_subject(message);
+    [
+        mailbox.to_string(),
+        message.uid.map(|x| x.to_string()).unwrap_or_default(),
+        message
+            .internal_date()
+            .map(|x| x.to_rfc3339())
+            .unwrap_or_default(),
+        from,
+        subject,
+        message.size.map(|x| x.to_string()).unwrap_or_default(),
+        message.flags().iter().map(|x| x.to_string()).join(" "),
+    ]
+}
+
+fn row(file: &mut impl Write, fields: [String; 7]) -> Result<()> {
+    let fields = fields.iter().map(|x| escape(x)).collect::<Vec<_>>();
+    write!(file, "{}\r\n", fields.join(","))?;
+    Ok(())
+}
+
+/// The field quoted if needed (RFC 4180).
+fn escape(field: &str) -> String {
+    match field.contains([',', '"', '\r', '\n']) {
+        true => format!("\"{}\"", field.replace('"', "\"\"")),
+        false => field.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn escaped() {
+        assert_eq!(escape("Hello"), "Hello");
+        assert_eq!(escape("Re: a, b"), "\"Re: a, b\"");
+        assert_eq!(escape("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(escape("a\nb"), "\"a\nb\"");
+    }
+}
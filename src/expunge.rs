@@ -0,0 +1,154 @@
+use crate::action::Extensions;
+use crate::color::{bold, red, yellow};
+use crate::error::{Error, Result};
+use crate::interrupt;
+use crate::lock::Locker;
+use crate::mailbox;
+use crate::output::{say, tell};
+use crate::tap::Tap;
+use imap::Session;
+use std::io::{Read, Write};
+
+/// Expunge the messages already flagged \Deleted in these mailboxes, by other clients that never
+/// expunge, without searching or flagging anything. With `uids`, only those are expunged with
+/// UID EXPUNGE. The dry run counts them.
+pub fn run<S: Read + Write>(
+    session: &mut Session<S>,
+    tap: &Tap,
+    locker: &Locker,
+    extensions: Extensions,
+    mailboxes: &[String],
+    uids: Option<&str>,
+    dry_run: bool,
+) -> Result<()> {
+    if uids.is_some() && !extensions.uidplus {
+        return Err(Error::Protocol(
+            "--uids requires a server supporting UIDPLUS".to_string(),
+        ));
+    }
+    let mut total = 0;
+    let mut failed = 0;
+    for name in mailboxes {
+        let result = interrupt::check().and_then(|()| {
+            let _lock = locker.lock(name)?;
+            expunge(session, tap, name, uids, dry_run)
+        });
+        match result {
+            Ok(count) if dry_run => {
+                say!(
+                    "{}: {} (dry run).",
+                    name,
+                    red(format!("{} not expunged", count))
+                );
+                total += count;
+            }
+            Ok(count) => {
+                say!("{}: {}.", name, red(format!("{} expunged", count)));
+                total += count;
+            }
+            Err(Error::Locked(reason)) => {
+                tell!("{}: {} {}", name, yellow("skipped:"), reason);
+                failed += 1;
+            }
+            Err(err) if err.stops() => return Err(err),
+            // The server refused something for this mailbox, the others may still work.
+            Err(err) => {
+                tell!("{}: {} {}", name, red("failed:"), err);
+                failed += 1;
+            }
+        }
+    }
+    if mailboxes.len() > 1 {
+        match dry_run {
+            true => say!(
+                "{}",
+                bold(format!(
+                    "Total: {} not expunged in {} mailboxes (dry run).",
+                    total,
+                    mailboxes.len()
+                ))
+            ),
+            false => say!(
+                "{}",
+                bold(format!(
+                    "Total: {} expunged in {} mailboxes.",
+                    total,
+                    mailboxes.len()
+                ))
+            ),
+        }
+    }
+    if failed > 0 {
+        return Err(Error::Partial {
+            failed,
+            total: mailboxes.len(),
+        });
+    }
+    Ok(())
+}
+
+/// Expunge a mailbox, returns the number of messages expunged (or that would be).
+fn expunge<S: Read + Write>(
+    session: &mut Session<S>,
+    tap: &Tap,
+    name: &str,
+    uids: Option<&str>,
+    dry_run: bool,
+) -> Result<usize> {
+    mailbox::open(session, tap, name, dry_run)?;
+    if dry_run {
+        let query = match uids {
+            Some(uids) => format!("UID {} DELETED", uids),
+            None => "DELETED".to_string(),
+        };
+        return Ok(session.uid_search(query)?.len());
+    }
+    Ok(match uids {
+        Some(uids) => session.uid_expunge(uids)?.len(),
+        None => session.expunge()?.len(),
+    })
+}
+
+/// Parse a UID set like `1:100,205,300:*`.
+pub fn parse_uid_set(s: &str) -> Result<String, String> {
+    let number = |x: &str| x == "*" || x.parse::<u32>().is_ok_and(|x| x > 0);
+    let valid = s.split(',').all(|range| match range.split_once(':') {
+        Some((start, end)) => number(start) && number(end),
+        None => number(range),
+    });
+    match valid {
+        true => Ok(s.to_string()),
+        false => Err("expected a UID set like 1:100,205,300:*".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::connection::test::session;
+
+    #[test]
+    fn uid_set() {
+        assert!(parse_uid_set("1:100,205,300:*").is_ok());
+        assert!(parse_uid_set("0").is_err());
+        assert!(parse_uid_set("1,,2").is_err());
+        assert!(parse_uid_set("1-5").is_err());
+    }
+
+    #[test]
+    fn expunged() {
+        let (mut imap, tap, sent) = session(
+            b"* 4 EXISTS\r\n\
+              a2 OK [READ-WRITE] done\r\n\
+              * 2 EXPUNGE\r\n\
+              * 2 EXPUNGE\r\n\
+              a3 OK done\r\n",
+        );
+        assert_eq!(expunge(&mut imap, &tap, "INBOX", None, false).unwrap(), 2);
+        assert_eq!(
+            String::from_utf8_lossy(&sent.borrow()),
+            "a2 SELECT \"INBOX\"\r\n\
+             a3 EXPUNGE\r\n"
+        );
+    }
+}
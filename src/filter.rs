@@ -0,0 +1,426 @@
+use crate::error::Result;
+use crate::mime::{decode_header, has_attachments, parse_headers};
+use crate::search;
+use crate::tap::Tap;
+use crate::try_batches;
+use imap::types::Fetch;
+use imap::Session;
+use regex::Regex;
+use std::collections::HashSet;
+use std::io::{Read, Write};
+use std::sync::OnceLock;
+
+/// Filters applied here to the messages found by the search, for what IMAP cannot search.
+#[derive(clap::Args, Clone, Debug)]
+pub struct FilterArgs {
+    /// Only cleanup the messages whose subject matches this regular expression. The subject is
+    /// decoded and matched here, IMAP only searches substrings. For example '^\[JIRA\] '.
+    #[clap(
+        long,
+        value_name = "REGEX",
+        value_parser(Regex::new),
+        env = "IMAP_CLEANUP_SUBJECT_REGEX"
+    )]
+    pub subject_regex: Option<Regex>,
+
+    /// Only cleanup the messages with a header matching a regular expression, as NAME=REGEX, for
+    /// example 'X-Mailer=^(Mailchimp|Sendinblue)'. Can be given multiple times, all must match.
+    #[clap(
+        long,
+        value_name = "NAME=REGEX",
+        value_parser(parse_header_regex),
+        env = "IMAP_CLEANUP_HEADER_REGEX"
+    )]
+    pub header_regex: Vec<HeaderRegex>,
+
+    /// Only cleanup the messages with at least this spam score, like 5.0, even the flagged ones
+    /// unless --protect-flag is given. The score is read from --spam-header, the messages without
+    /// it are not cleaned.
+    #[clap(
+        long,
+        value_name = "SCORE",
+        value_parser(parse_score),
+        env = "IMAP_CLEANUP_MIN_SPAM_SCORE"
+    )]
+    pub min_spam_score: Option<f64>,
+
+    /// The header where the spam filter puts the score, like X-Spam-Score (5.3) or X-Spam-Status
+    /// (Yes, score=5.3 required=5.0).
+    #[clap(
+        long,
+        value_name = "NAME",
+        default_value = "X-Spam-Score",
+        env = "IMAP_CLEANUP_SPAM_HEADER"
+    )]
+    pub spam_header: String,
+
+    /// Only cleanup the messages with attachments, not counting the inline images and the
+    /// signatures. Useful with --larger-than to free the quota.
+    #[clap(long, env = "IMAP_CLEANUP_WITH_ATTACHMENTS")]
+    pub with_attachments: bool,
+
+    /// Only cleanup the messages without attachments.
+    #[clap(
+        long,
+        conflicts_with = "with-attachments",
+        env = "IMAP_CLEANUP_WITHOUT_ATTACHMENTS"
+    )]
+    pub without_attachments: bool,
+
+    /// Always keep this number of the newest messages of each mailbox (by internal date),
+    /// whatever the other options, for example --keep-last 500.
+    #[clap(long, value_name = "COUNT", env = "IMAP_CLEANUP_KEEP_LAST")]
+    pub keep_last: Option<usize>,
+}
+
+#[derive(Clone, Debug)]
+pub struct HeaderRegex {
+    pub name: String,
+    pub regex: Regex,
+}
+
+fn parse_header_regex(s: &str) -> std::result::Result<HeaderRegex, String> {
+    let (name, regex) = search::parse_header(s)?;
+    Ok(HeaderRegex {
+        name,
+        regex: Regex::new(&regex).map_err(|err| err.to_string())?,
+    })
+}
+
+fn parse_score(s: &str) -> std::result::Result<f64, String> {
+    spam_score(s).ok_or_else(|| "expected a number like 5.0".to_string())
+}
+
+/// Read a spam score like `5.3`, `+5,3 (+++++)` or `Yes, score=5.3 required=5.0 tests=...`.
+fn spam_score(value: &str) -> Option<f64> {
+    static NUMBER: OnceLock<Regex> = OnceLock::new();
+    let number = NUMBER.get_or_init(|| Regex::new(r"[-+]?\d+(?:[.,]\d+)?").unwrap());
+    let value = match value.to_ascii_lowercase().find("score=") {
+        Some(i) => &value[i + "score=".len()..],
+        None => value,
+    };
+    number
+        .find(value)?
+        .as_str()
+        .trim_start_matches('+')
+        .replace(',', ".")
+        .parse()
+        .ok()
+}
+
+/// What the filters know about a message.
+#[derive(Debug, Default)]
+pub struct Message {
+    /// The decoded subject.
+    pub subject: String,
+    /// The header fields fetched for the filters, with their decoded values.
+    pub headers: Vec<(String, String)>,
+    /// Whether the message has attachments, if its BODYSTRUCTURE was fetched.
+    pub attachments: Option<bool>,
+}
+
+impl Message {
+    fn from_fetch(fetch: &Fetch) -> Self {
+        let envelope = fetch.envelope();
+        Message {
+            subject: envelope
+                .and_then(|x| x.subject)
+                .map(decode_header)
+                .unwrap_or_default(),
+            headers: fetch.header().map(parse_headers).unwrap_or_default(),
+            attachments: fetch.bodystructure().map(has_attachments),
+        }
+    }
+
+    /// The values of the header fields with this name.
+    pub fn header<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> {
+        self.headers
+            .iter()
+            .filter(move |(x, _)| x.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+impl FilterArgs {
+    pub fn is_empty(&self) -> bool {
+        self.subject_regex.is_none()
+            && self.header_regex.is_empty()
+            && self.min_spam_score.is_none()
+            && !self.with_attachments
+            && !self.without_attachments
+    }
+
+    /// The FETCH items needed by the filters.
+    fn items(&self) -> String {
+        let mut items = vec!["UID".to_string(), "ENVELOPE".to_string()];
+        if self.with_attachments || self.without_attachments {
+            items.push("BODYSTRUCTURE".to_string());
+        }
+        let mut fields = self
+            .header_regex
+            .iter()
+            .map(|x| x.name.as_str())
+            .chain(self.min_spam_score.map(|_| self.spam_header.as_str()))
+            .map(str::to_ascii_lowercase)
+            .collect::<Vec<_>>();
+        fields.sort();
+        fields.dedup();
+        if !fields.is_empty() {
+            items.push(format!("BODY.PEEK[HEADER.FIELDS ({})]", fields.join(" ")));
+        }
+        format!("({})", items.join(" "))
+    }
+
+    /// Keep the UIDs of the selected mailbox passing the filters.
+    pub fn apply<S: Read + Write>(
+        &self,
+        session: &mut Session<S>,
+        tap: &Tap,
+        uids: &[u32],
+    ) -> Result<Vec<u32>> {
+        if self.is_empty() {
+            return Ok(uids.to_vec());
+        }
+        let mut kept = Vec::new();
+        try_batches("FETCH", uids, |set, _| {
+            let fetch = session.uid_fetch(set, self.items())?;
+            for message in fetch.iter() {
+                tap.keepalive(session)?;
+                let uid = match message.uid {
+                    Some(uid) => uid,
+                    None => continue,
+                };
+                if self.keep(&Message::from_fetch(message)) {
+                    kept.push(uid);
+                }
+            }
+            Ok(())
+        })?;
+        kept.sort_unstable();
+        Ok(kept)
+    }
+
+    /// Keep out the --keep-last newest messages of the selected mailbox, which has `exists`
+    /// messages.
+    pub fn keep_newest<S: Read + Write>(
+        &self,
+        session: &mut Session<S>,
+        exists: u32,
+        uids: &[u32],
+    ) -> Result<Vec<u32>> {
+        let keep_last = match self.keep_last {
+            Some(keep_last) if !uids.is_empty() => keep_last,
+            _ => return Ok(uids.to_vec()),
+        };
+        if exists as usize <= keep_last {
+            return Ok(Vec::new());
+        }
+        let fetch = session.uid_fetch("1:*", "(UID INTERNALDATE)")?;
+        let mut messages = fetch
+            .iter()
+            .filter_map(|x| Some((x.internal_date()?, x.uid?)))
+            .collect::<Vec<_>>();
+        messages.sort_unstable();
+        let newest = messages
+            .iter()
+            .rev()
+            .take(keep_last)
+            .map(|(_, uid)| *uid)
+            .collect::<HashSet<_>>();
+        Ok(uids
+            .iter()
+            .copied()
+            .filter(|uid| !newest.contains(uid))
+            .collect())
+    }
+
+    fn keep(&self, message: &Message) -> bool {
+        if let Some(regex) = &self.subject_regex {
+            if !regex.is_match(&message.subject) {
+                return false;
+            }
+        }
+        if self.with_attachments && message.attachments != Some(true)
+            || self.without_attachments && message.attachments != Some(false)
+        {
+            return false;
+        }
+        if let Some(min) = self.min_spam_score {
+            if !message
+                .header(&self.spam_header)
+                .filter_map(spam_score)
+                .any(|score| score >= min)
+            {
+                return false;
+            }
+        }
+        self.header_regex
+            .iter()
+            .all(|x| message.header(&x.name).any(|value| x.regex.is_match(value)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::connection::test::session;
+
+    /// The filters without options, to set those tested.
+    fn args() -> FilterArgs {
+        use clap::Parser;
+
+        #[derive(clap::Parser)]
+        struct Args {
+            #[clap(flatten)]
+            filter: FilterArgs,
+        }
+
+        Args::parse_from(["test"]).filter
+    }
+
+    #[test]
+    fn subject_regex() {
+        let (mut imap, tap, sent) = session(
+            b"* 1 FETCH (UID 3 ENVELOPE (NIL \"=?utf-8?q?=5BJIRA=5D_Caf=C3=A9?=\" \
+              NIL NIL NIL NIL NIL NIL NIL NIL))\r\n\
+              * 2 FETCH (UID 4 ENVELOPE (NIL \"Re: [JIRA] x\" NIL NIL NIL NIL NIL NIL NIL NIL))\r\n\
+              * 3 FETCH (UID 7 ENVELOPE (NIL NIL NIL NIL NIL NIL NIL NIL NIL NIL))\r\n\
+              a2 OK done\r\n",
+        );
+        let filter = FilterArgs {
+            subject_regex: Some(Regex::new(r"^\[JIRA\] Caf\u{e9}").unwrap()),
+            ..args()
+        };
+        assert_eq!(filter.apply(&mut imap, &tap, &[3, 4, 7]).unwrap(), [3]);
+        assert_eq!(
+            String::from_utf8_lossy(&sent.borrow()),
+            "a2 UID FETCH 3:4,7:7 (UID ENVELOPE)\r\n"
+        );
+    }
+
+    #[test]
+    fn header_regex() {
+        let (mut imap, tap, sent) = session(
+            b"* 1 FETCH (UID 3 ENVELOPE (NIL NIL NIL NIL NIL NIL NIL NIL NIL NIL) \
+              BODY[HEADER.FIELDS (x-mailer)] {25}\r\nX-Mailer: Mailchimp 1\r\n\r\n)\r\n\
+              * 2 FETCH (UID 4 ENVELOPE (NIL NIL NIL NIL NIL NIL NIL NIL NIL NIL) \
+              BODY[HEADER.FIELDS (x-mailer)] {2}\r\n\r\n)\r\n\
+              a2 OK done\r\n",
+        );
+        let filter = FilterArgs {
+            header_regex: vec![parse_header_regex("X-Mailer=^mailchimp|^Mailchimp").unwrap()],
+            ..args()
+        };
+        assert_eq!(filter.apply(&mut imap, &tap, &[3, 4]).unwrap(), [3]);
+        assert_eq!(
+            String::from_utf8_lossy(&sent.borrow()),
+            "a2 UID FETCH 3:4 (UID ENVELOPE BODY.PEEK[HEADER.FIELDS (x-mailer)])\r\n"
+        );
+    }
+
+    #[test]
+    fn spam_scores() {
+        assert_eq!(spam_score("5.3"), Some(5.3));
+        assert_eq!(spam_score(" +5,3 (+++++)"), Some(5.3));
+        assert_eq!(spam_score("-0.1"), Some(-0.1));
+        assert_eq!(
+            spam_score("Yes, score=7.2 required=5.0 tests=BAYES_99"),
+            Some(7.2)
+        );
+        assert_eq!(spam_score("No, Score=-1 required=5.0"), Some(-1.0));
+        assert_eq!(spam_score("none"), None);
+    }
+
+    #[test]
+    fn min_spam_score() {
+        let (mut imap, tap, sent) = session(
+            b"* 1 FETCH (UID 3 ENVELOPE (NIL NIL NIL NIL NIL NIL NIL NIL NIL NIL) \
+              BODY[HEADER.FIELDS (x-spam-status)] {54}\r\n\
+              X-Spam-Status: Yes, score=7.2 required=5.0 tests=x\r\n\r\n)\r\n\
+              * 2 FETCH (UID 4 ENVELOPE (NIL NIL NIL NIL NIL NIL NIL NIL NIL NIL) \
+              BODY[HEADER.FIELDS (x-spam-status)] {53}\r\n\
+              X-Spam-Status: No, score=1.5 required=5.0 tests=x\r\n\r\n)\r\n\
+              * 3 FETCH (UID 5 ENVELOPE (NIL NIL NIL NIL NIL NIL NIL NIL NIL NIL) \
+              BODY[HEADER.FIELDS (x-spam-status)] {2}\r\n\r\n)\r\n\
+              a2 OK done\r\n",
+        );
+        let filter = FilterArgs {
+            min_spam_score: Some(5.0),
+            spam_header: "X-Spam-Status".to_string(),
+            ..args()
+        };
+        assert_eq!(filter.apply(&mut imap, &tap, &[3, 4, 5]).unwrap(), [3]);
+        assert_eq!(
+            String::from_utf8_lossy(&sent.borrow()),
+            "a2 UID FETCH 3:5 (UID ENVELOPE BODY.PEEK[HEADER.FIELDS (x-spam-status)])\r\n"
+        );
+    }
+
+    #[test]
+    fn attachments() {
+        // No space between the parts of a multipart (RFC 3501).
+        let response = b"* 1 FETCH (UID 3 ENVELOPE (NIL NIL NIL NIL NIL NIL NIL NIL NIL NIL) \
+              BODYSTRUCTURE ((\"text\" \"plain\" (\"charset\" \"utf-8\") NIL NIL \"7bit\" 10 1)\
+              (\"application\" \"pdf\" (\"name\" \"a.pdf\") NIL NIL \"base64\" 1000 NIL \
+              (\"attachment\" (\"filename\" \"a.pdf\")) NIL) \"mixed\"))\r\n\
+              * 2 FETCH (UID 4 ENVELOPE (NIL NIL NIL NIL NIL NIL NIL NIL NIL NIL) \
+              BODYSTRUCTURE (((\"text\" \"html\" NIL NIL NIL \"7bit\" 10 1)\
+              (\"image\" \"png\" (\"name\" \"logo.png\") \"<logo>\" NIL \"base64\" 100) \
+              \"related\")\
+              (\"application\" \"pgp-signature\" (\"name\" \"signature.asc\") NIL NIL \
+              \"7bit\" 100 NIL (\"attachment\" NIL) NIL) \"signed\"))\r\n\
+              * 3 FETCH (UID 5 ENVELOPE (NIL NIL NIL NIL NIL NIL NIL NIL NIL NIL) \
+              BODYSTRUCTURE ((\"text\" \"plain\" NIL NIL NIL \"7bit\" 10 1)\
+              (\"image\" \"jpeg\" (\"name\" \"cat.jpg\") NIL NIL \"base64\" 1000) \
+              \"mixed\"))\r\n\
+              a2 OK done\r\n";
+        let filter = |with_attachments| FilterArgs {
+            with_attachments,
+            without_attachments: !with_attachments,
+            ..args()
+        };
+        let (mut imap, tap, sent) = session(response);
+        assert_eq!(
+            filter(true).apply(&mut imap, &tap, &[3, 4, 5]).unwrap(),
+            [3, 5]
+        );
+        assert_eq!(
+            String::from_utf8_lossy(&sent.borrow()),
+            "a2 UID FETCH 3:5 (UID ENVELOPE BODYSTRUCTURE)\r\n"
+        );
+        let (mut imap, tap, _) = session(response);
+        assert_eq!(
+            filter(false).apply(&mut imap, &tap, &[3, 4, 5]).unwrap(),
+            [4]
+        );
+    }
+
+    #[test]
+    fn keep_last() {
+        let (mut imap, _, sent) = session(
+            b"* 1 FETCH (UID 3 INTERNALDATE \"01-Mar-2023 12:00:00 +0000\")\r\n\
+              * 2 FETCH (UID 4 INTERNALDATE \"01-Jan-2023 12:00:00 +0000\")\r\n\
+              * 3 FETCH (UID 7 INTERNALDATE \"01-Feb-2023 12:00:00 +0000\")\r\n\
+              * 4 FETCH (UID 8 INTERNALDATE \"01-Apr-2023 12:00:00 +0000\")\r\n\
+              a2 OK done\r\n",
+        );
+        let filter = FilterArgs {
+            keep_last: Some(2),
+            ..args()
+        };
+        assert_eq!(
+            filter.keep_newest(&mut imap, 4, &[3, 4, 7]).unwrap(),
+            [4, 7]
+        );
+        assert_eq!(
+            String::from_utf8_lossy(&sent.borrow()),
+            "a2 UID FETCH 1:* (UID INTERNALDATE)\r\n"
+        );
+        let (mut imap, _, sent) = session(b"");
+        assert!(filter
+            .keep_newest(&mut imap, 2, &[3, 4])
+            .unwrap()
+            .is_empty());
+        assert!(sent.borrow().is_empty());
+    }
+}
@@ -0,0 +1,184 @@
+use crate::error::{Error, Result};
+use crate::output::say;
+use crate::quota;
+use crate::search::{format_size, parse_size};
+use crate::tap::Tap;
+use imap::Session;
+use std::io::{Read, Write};
+use std::sync::Mutex;
+
+/// Cleanup only enough messages to free some space, instead of every message before the date.
+#[derive(clap::Args, Debug)]
+pub struct FreeArgs {
+    /// Only cleanup enough messages to bring the STORAGE quota down to this percentage of its
+    /// limit, like `80%`. --before defaults to tomorrow then.
+    #[clap(
+        long,
+        value_name = "PERCENT",
+        value_parser = parse_percent,
+        conflicts_with_all = &["free-bytes", "move-to", "strip-attachments", "gmail-remove-label", "gmail-archive"],
+        env = "IMAP_CLEANUP_FREE_UNTIL"
+    )]
+    pub free_until: Option<u8>,
+
+    /// Only cleanup enough messages to free this size, like `2G`. --before defaults to tomorrow
+    /// then.
+    #[clap(
+        long,
+        value_name = "SIZE",
+        value_parser = parse_size,
+        conflicts_with_all = &["move-to", "strip-attachments", "gmail-remove-label", "gmail-archive"],
+        env = "IMAP_CLEANUP_FREE_BYTES"
+    )]
+    pub free_bytes: Option<u64>,
+
+    /// The messages cleaned first with --free-until or --free-bytes, in each mailbox. The
+    /// mailboxes are cleaned in their order until enough is freed.
+    #[clap(
+        long,
+        value_enum,
+        default_value = "oldest",
+        env = "IMAP_CLEANUP_FREE_FIRST"
+    )]
+    pub free_first: First,
+}
+
+/// The messages cleaned first.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum First {
+    /// By INTERNALDATE.
+    Oldest,
+    Largest,
+}
+
+/// What is left to free, shared by the mailboxes.
+#[derive(Debug)]
+pub struct Budget {
+    first: First,
+    left: Mutex<u64>,
+}
+
+impl FreeArgs {
+    pub fn is_used(&self) -> bool {
+        self.free_until.is_some() || self.free_bytes.is_some()
+    }
+
+    /// What to free, from the quota with --free-until. None without these options.
+    pub fn budget<S: Read + Write>(
+        &self,
+        session: &mut Session<S>,
+        tap: &Tap,
+    ) -> Result<Option<Budget>> {
+        let left = match (self.free_until, self.free_bytes) {
+            (Some(percent), _) => {
+                let (usage, limit) = quota::storage(session, tap)?.ok_or_else(|| {
+                    Error::Protocol("no STORAGE quota for --free-until".to_string())
+                })?;
+                let target = limit * u64::from(percent) / 100;
+                say!(
+                    "Quota: {} used of {}, {} to free to reach {}%.",
+                    format_size(usage),
+                    format_size(limit),
+                    format_size(usage.saturating_sub(target)),
+                    percent
+                );
+                usage.saturating_sub(target)
+            }
+            (None, Some(bytes)) => bytes,
+            (None, None) => return Ok(None),
+        };
+        Ok(Some(Budget {
+            first: self.free_first,
+            left: Mutex::new(left),
+        }))
+    }
+}
+
+impl Budget {
+    /// What is left to free.
+    pub fn left(&self) -> u64 {
+        *self.left.lock().unwrap()
+    }
+
+    /// The first of these candidate messages, sorted, freeing what is left: their size is taken
+    /// from it.
+    pub fn apply<S: Read + Write>(
+        &self,
+        session: &mut Session<S>,
+        uids: &[u32],
+    ) -> Result<Vec<u32>> {
+        let mut left = self.left.lock().unwrap();
+        if *left == 0 || uids.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut messages = Vec::new();
+        for (set, _) in crate::batches(uids) {
+            for message in session.uid_fetch(set, "(INTERNALDATE RFC822.SIZE)")?.iter() {
+                if let Some(uid) = message.uid {
+                    let size = u64::from(message.size.unwrap_or_default());
+                    messages.push((uid, message.internal_date(), size));
+                }
+            }
+        }
+        match self.first {
+            First::Oldest => messages.sort_by_key(|x| x.1),
+            First::Largest => messages.sort_by(|a, b| b.2.cmp(&a.2).then(a.1.cmp(&b.1))),
+        }
+        let mut selected = Vec::new();
+        for (uid, _, size) in messages {
+            if *left == 0 {
+                break;
+            }
+            selected.push(uid);
+            *left = left.saturating_sub(size);
+        }
+        selected.sort_unstable();
+        Ok(selected)
+    }
+}
+
+/// Parse a percentage like `80%`.
+fn parse_percent(s: &str) -> Result<u8, String> {
+    s.strip_suffix('%')
+        .unwrap_or(s)
+        .parse::<u8>()
+        .ok()
+        .filter(|x| (1..100).contains(x))
+        .ok_or_else(|| "expected a percentage like 80%, from 1% to 99%".to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::connection::test::session;
+
+    #[test]
+    fn percent() {
+        assert_eq!(parse_percent("80%"), Ok(80));
+        assert_eq!(parse_percent("5"), Ok(5));
+        assert!(parse_percent("100%").is_err());
+        assert!(parse_percent("0").is_err());
+    }
+
+    #[test]
+    fn budget() {
+        let (mut imap, _, sent) = session(
+            b"* 1 FETCH (UID 3 INTERNALDATE \"03-Jan-2020 10:00:00 +0000\" RFC822.SIZE 1000)\r\n\
+              * 2 FETCH (UID 5 INTERNALDATE \"02-Dec-2019 10:00:00 +0000\" RFC822.SIZE 500)\r\n\
+              * 3 FETCH (UID 8 INTERNALDATE \"20-Dec-2019 10:00:00 +0000\" RFC822.SIZE 200)\r\n\
+              a2 OK done\r\n",
+        );
+        let budget = Budget {
+            first: First::Oldest,
+            left: Mutex::new(600),
+        };
+        assert_eq!(budget.apply(&mut imap, &[3, 5, 8]).unwrap(), [5, 8]);
+        assert_eq!(budget.left(), 0);
+        // Nothing left, nothing fetched.
+        assert_eq!(budget.apply(&mut imap, &[3]).unwrap(), [] as [u32; 0]);
+        assert_eq!(
+            String::from_utf8_lossy(&sent.borrow()),
+            "a2 UID FETCH 3:3,5:5,8:8 (INTERNALDATE RFC822.SIZE)\r\n"
+        );
+    }
+}
@@ -0,0 +1,80 @@
+use crate::mailbox::quote;
+
+/// The capability of the Gmail extensions.
+pub const CAPABILITY: &str = "X-GM-EXT-1";
+
+/// Gmail specific options, using its IMAP extensions.
+///
+/// On Gmail a mailbox is a label: deleting a message from a label only removes the label, the
+/// message stays in All Mail (unless the account settings say otherwise).
+#[derive(clap::Args, Debug)]
+pub struct GmailArgs {
+    /// Only cleanup the messages with this Gmail label, for example --gmail-label Newsletters.
+    #[clap(long, value_name = "LABEL", env = "IMAP_CLEANUP_GMAIL_LABEL")]
+    pub gmail_label: Option<String>,
+
+    /// Only cleanup the messages matching this Gmail search (X-GM-RAW), on top of --before. For
+    /// example --gmail-raw 'category:promotions older_than:6m'.
+    #[clap(long, value_name = "QUERY", env = "IMAP_CLEANUP_GMAIL_RAW")]
+    pub gmail_raw: Option<String>,
+
+    /// Remove the label given by --gmail-label from the messages instead of deleting them.
+    #[clap(
+        long,
+        requires = "gmail-label",
+        conflicts_with = "move-to",
+        env = "IMAP_CLEANUP_GMAIL_REMOVE_LABEL"
+    )]
+    pub gmail_remove_label: bool,
+
+    /// Archive the messages instead of deleting them: remove them from INBOX, they stay in All
+    /// Mail. Only INBOX is cleaned.
+    #[clap(
+        long,
+        conflicts_with_all = &["move-to", "gmail-remove-label"],
+        env = "IMAP_CLEANUP_GMAIL_ARCHIVE"
+    )]
+    pub gmail_archive: bool,
+}
+
+impl GmailArgs {
+    /// Whether any of the options requires a Gmail server.
+    pub fn is_used(&self) -> bool {
+        self.gmail_label.is_some() || self.gmail_raw.is_some() || self.gmail_archive
+    }
+
+    /// The search criteria to add to the query.
+    pub fn criteria(&self) -> Vec<String> {
+        let labels = self
+            .gmail_label
+            .iter()
+            .map(|label| format!("X-GM-LABELS {}", quote(label)));
+        let raw = self
+            .gmail_raw
+            .iter()
+            .map(|query| format!("X-GM-RAW {}", quote(query)));
+        labels.chain(raw).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn criteria() {
+        let args = GmailArgs {
+            gmail_label: Some("Lists/Rust \"announce\"".to_string()),
+            gmail_raw: Some("category:promotions older_than:6m".to_string()),
+            gmail_remove_label: false,
+            gmail_archive: false,
+        };
+        assert_eq!(
+            args.criteria(),
+            [
+                "X-GM-LABELS \"Lists/Rust \\\"announce\\\"\"",
+                "X-GM-RAW \"category:promotions older_than:6m\"",
+            ]
+        );
+    }
+}
@@ -0,0 +1,173 @@
+use crate::bar::Bar;
+use crate::color::{bold, formatting, yellow};
+use crate::error::{Error, Result};
+use crate::interrupt;
+use crate::preview::{self, Row};
+use imap::Session;
+use std::collections::HashSet;
+use std::io::{Read, Write};
+use std::sync::Mutex;
+
+const HELP: &str = "y: clean it
+n: keep it
+s: keep it and the other messages of its sender, for the rest of the run
+a: clean it and the rest of the mailbox
+q: quit, without cleaning the mailbox";
+
+/// The choices of --interactive, shared by the mailboxes.
+#[derive(Debug, Default)]
+pub struct Interactive {
+    /// The senders whose messages are kept, in lowercase. Locked while asking, not to mix the
+    /// questions of --jobs.
+    kept_senders: Mutex<HashSet<String>>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Answer {
+    Clean,
+    Keep,
+    KeepSender,
+    All,
+    Quit,
+}
+
+impl Answer {
+    /// The answer to a question, keeping the message by default.
+    fn parse(answer: &str) -> Option<Self> {
+        match answer.trim() {
+            "y" | "Y" | "yes" => Some(Answer::Clean),
+            "" | "n" | "N" | "no" => Some(Answer::Keep),
+            "s" | "S" => Some(Answer::KeepSender),
+            "a" | "A" => Some(Answer::All),
+            "q" | "Q" => Some(Answer::Quit),
+            _ => None,
+        }
+    }
+}
+
+impl Interactive {
+    /// The messages to clean among these messages of a mailbox, asked one by one with their
+    /// envelope but for the senders kept already. Quitting aborts the run.
+    pub fn apply<S: Read + Write>(
+        &self,
+        session: &mut Session<S>,
+        mailbox: &str,
+        uids: &[u32],
+    ) -> Result<Vec<u32>> {
+        if uids.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut rows = Vec::new();
+        let mut bar = Bar::new("FETCH", uids.len());
+        for (set, batch) in crate::batches(uids) {
+            let fetch = session.uid_fetch(set, "(INTERNALDATE FLAGS ENVELOPE RFC822.SIZE)")?;
+            rows.extend(fetch.iter().map(Row::new));
+            bar.inc(batch.len());
+        }
+        drop(bar);
+        rows.sort_by_key(Row::uid);
+        self.choose(mailbox, &rows, ask)
+    }
+
+    fn choose(
+        &self,
+        mailbox: &str,
+        rows: &[Row],
+        mut ask: impl FnMut(&str) -> Result<String>,
+    ) -> Result<Vec<u32>> {
+        let mut kept_senders = self.kept_senders.lock().unwrap();
+        let kept =
+            |senders: &HashSet<String>, row: &Row| senders.contains(&row.from().to_lowercase());
+        let mut chosen = Vec::new();
+        let mut header = formatting(true, || {
+            format!(
+                "{}: {} messages to clean.\n{}\n",
+                bold(mailbox),
+                rows.len(),
+                bold(preview::header())
+            )
+        });
+        let mut rows = rows.iter();
+        while let Some(row) = rows.next() {
+            if kept(&kept_senders, row) {
+                continue;
+            }
+            let question = formatting(true, || {
+                format!(
+                    "{}{}\nClean it? [y,n,s,a,q,?] ",
+                    std::mem::take(&mut header),
+                    row
+                )
+            });
+            let mut answer = ask(&question)?;
+            let answer = loop {
+                match Answer::parse(&answer) {
+                    Some(answer) => break answer,
+                    None => {
+                        let help = formatting(true, || format!("{}\n", yellow(HELP)));
+                        answer = ask(&format!("{}Clean it? [y,n,s,a,q,?] ", help))?;
+                    }
+                }
+            };
+            match answer {
+                Answer::Clean => chosen.push(row.uid()),
+                Answer::Keep => {}
+                Answer::KeepSender => {
+                    kept_senders.insert(row.from().to_lowercase());
+                }
+                Answer::All => {
+                    chosen.push(row.uid());
+                    chosen.extend(
+                        rows.by_ref()
+                            .filter(|x| !kept(&kept_senders, x))
+                            .map(Row::uid),
+                    );
+                }
+                Answer::Quit => return Err(Error::Aborted),
+            }
+        }
+        Ok(chosen)
+    }
+}
+
+/// Ask a question on the terminal. Ctrl+D quits.
+fn ask(question: &str) -> Result<String> {
+    eprint!("{}", question);
+    let mut answer = String::new();
+    let read = std::io::stdin().read_line(&mut answer)?;
+    interrupt::check()?;
+    if read == 0 {
+        return Err(Error::Aborted);
+    }
+    Ok(answer)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::preview::test::row;
+
+    #[test]
+    fn chosen() {
+        let interactive = Interactive::default();
+        let scripted = |answers: &'static [&'static str]| {
+            let mut answers = answers.iter();
+            move |_: &str| Ok(answers.next().unwrap().to_string())
+        };
+        let rows = [
+            row(1, 10, "a@x"),
+            row(2, 10, "Spam@x"),
+            row(3, 10, "spam@x"),
+            row(4, 10, "b@x"),
+            row(5, 10, "c@x"),
+        ];
+        let chosen =
+            interactive.choose("INBOX", &rows, scripted(&["y\n", "s\n", "?\n", "\n", "y"]));
+        assert_eq!(chosen.unwrap(), [1, 5]);
+        // The sender stays kept and the rest of the mailbox is cleaned.
+        let chosen = interactive.choose("Archive", &rows, scripted(&["n", "a"]));
+        assert_eq!(chosen.unwrap(), [4, 5]);
+        let chosen = interactive.choose("Sent", &rows, scripted(&["y", "q"]));
+        assert!(matches!(chosen, Err(Error::Aborted)));
+    }
+}
@@ -0,0 +1,29 @@
+use crate::color::yellow;
+use crate::error::{Error, Result};
+use crate::output::tell;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Catch Ctrl+C: the first one lets the command in flight finish and the cleanup stop at the
+/// next `check`, the second one quits right away.
+pub fn install() -> Result<()> {
+    ctrlc::set_handler(|| {
+        if INTERRUPTED.swap(true, Ordering::Relaxed) {
+            std::process::exit(130);
+        }
+        tell!(
+            "{} stopping after the current command, Ctrl+C again to quit now.",
+            yellow("Interrupted:")
+        );
+    })
+    .map_err(|err| Error::Io(std::io::Error::other(err)))
+}
+
+/// Fail with `Error::Interrupted` once Ctrl+C was pressed, between two batches.
+pub fn check() -> Result<()> {
+    if INTERRUPTED.load(Ordering::Relaxed) {
+        return Err(Error::Interrupted { done: 0 });
+    }
+    Ok(())
+}
@@ -0,0 +1,213 @@
+use crate::age::Age;
+use crate::error::Result;
+use crate::mime::parse_headers;
+use crate::search::DateSource;
+use crate::try_batches;
+use chrono::{Date, DateTime, Local, NaiveDate};
+use imap::types::Fetch;
+use imap::Session;
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::str::FromStr;
+
+/// A shorter retention for the mailing lists, told apart by their List-Id header.
+#[derive(clap::Args, Debug)]
+pub struct ListArgs {
+    /// Cleanup the messages of mailing lists (with a List-Id header) older than this, like 30d,
+    /// 6w, 3m or 1y. The other messages are still cleaned by --before.
+    #[clap(
+        long,
+        value_name = "AGE",
+        value_parser(Age::from_str),
+        env = "IMAP_CLEANUP_LIST_MAX_AGE"
+    )]
+    pub list_max_age: Option<Age>,
+}
+
+impl ListArgs {
+    /// The retention on `today`, None without --list-max-age.
+    pub fn retention(
+        &self,
+        today: Date<Local>,
+        before: Date<Local>,
+        date_source: DateSource,
+    ) -> Option<Retention> {
+        self.list_max_age.map(|age| Retention {
+            before,
+            list_before: age.before(today),
+            date_source,
+        })
+    }
+}
+
+/// When the messages are old enough to cleanup, depending on their list.
+#[derive(Clone, Debug)]
+pub struct Retention {
+    /// For the messages of no list.
+    pub before: Date<Local>,
+    /// For the messages of a list.
+    pub list_before: Date<Local>,
+    pub date_source: DateSource,
+}
+
+/// The messages to cleanup of one mailing list, or of no list.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Group {
+    /// The identifier of the list, like `rust.lists.example`.
+    pub list_id: Option<String>,
+    pub uids: Vec<u32>,
+}
+
+impl Retention {
+    /// The search must find the messages older than the latest of the two dates, the others are
+    /// sorted out by `apply`.
+    pub fn search_before(&self) -> Date<Local> {
+        self.before.max(self.list_before)
+    }
+
+    /// The day before which the messages of this list (or of no list) are cleaned.
+    pub fn cutoff(&self, list_id: Option<&str>) -> Date<Local> {
+        match list_id {
+            Some(_) => self.list_before,
+            None => self.before,
+        }
+    }
+
+    /// Group the UIDs of the selected mailbox by list and keep the messages old enough for their
+    /// group. The messages without a date are kept out.
+    pub fn apply<S: Read + Write>(
+        &self,
+        session: &mut Session<S>,
+        uids: &[u32],
+    ) -> Result<Vec<Group>> {
+        let mut groups = BTreeMap::<Option<String>, Vec<u32>>::new();
+        try_batches("FETCH", uids, |set, _| {
+            let fetch = session.uid_fetch(
+                set,
+                "(UID INTERNALDATE ENVELOPE BODY.PEEK[HEADER.FIELDS (LIST-ID)])",
+            )?;
+            for message in fetch.iter() {
+                let (uid, date) = match (message.uid, self.date(message)) {
+                    (Some(uid), Some(date)) => (uid, date),
+                    _ => continue,
+                };
+                let list_id = list_id(message);
+                if date < self.cutoff(list_id.as_deref()).naive_utc() {
+                    groups.entry(list_id).or_default().push(uid);
+                }
+            }
+            Ok(())
+        })?;
+        Ok(groups
+            .into_iter()
+            .map(|(list_id, mut uids)| {
+                uids.sort_unstable();
+                Group { list_id, uids }
+            })
+            .collect())
+    }
+
+    /// The day of the message compared to the cutoffs, like the server does for BEFORE.
+    fn date(&self, fetch: &Fetch) -> Option<NaiveDate> {
+        match self.date_source {
+            DateSource::Internal => fetch.internal_date().map(|x| x.naive_local().date()),
+            DateSource::Sent => {
+                let date = String::from_utf8_lossy(fetch.envelope()?.date?);
+                // chrono does not read a trailing comment like (UTC).
+                let date = date.split('(').next().unwrap_or_default().trim();
+                DateTime::parse_from_rfc2822(date)
+                    .ok()
+                    .map(|x| x.naive_local().date())
+            }
+        }
+    }
+}
+
+/// The identifier of the list of a message, from `List-Id: Rust users <rust.lists.example>`.
+fn list_id(fetch: &Fetch) -> Option<String> {
+    let headers = parse_headers(fetch.header()?);
+    let (_, value) = headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("List-Id"))?;
+    let id = match (value.rfind('<'), value.rfind('>')) {
+        (Some(start), Some(end)) if start < end => &value[start + 1..end],
+        _ => value.as_str(),
+    };
+    let id = id.trim();
+    if id.is_empty() {
+        return None;
+    }
+    Some(id.to_ascii_lowercase())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::connection::test::session;
+    use chrono::TimeZone;
+
+    #[test]
+    fn groups() {
+        let (mut imap, _, sent) = session(
+            b"* 1 FETCH (UID 3 INTERNALDATE \"01-Mar-2023 12:00:00 +0000\" \
+              ENVELOPE (NIL NIL NIL NIL NIL NIL NIL NIL NIL NIL) \
+              BODY[HEADER.FIELDS (LIST-ID)] {38}\r\nList-Id: Rust <Rust.Lists.Example>\r\n\r\n)\r\n\
+              * 2 FETCH (UID 4 INTERNALDATE \"01-Mar-2023 12:00:00 +0000\" \
+              ENVELOPE (NIL NIL NIL NIL NIL NIL NIL NIL NIL NIL) \
+              BODY[HEADER.FIELDS (LIST-ID)] {2}\r\n\r\n)\r\n\
+              * 3 FETCH (UID 5 INTERNALDATE \"01-Jun-2019 12:00:00 +0000\" \
+              ENVELOPE (NIL NIL NIL NIL NIL NIL NIL NIL NIL NIL) \
+              BODY[HEADER.FIELDS (LIST-ID)] {2}\r\n\r\n)\r\n\
+              * 4 FETCH (UID 6 INTERNALDATE \"01-Jun-2024 12:00:00 +0000\" \
+              ENVELOPE (NIL NIL NIL NIL NIL NIL NIL NIL NIL NIL) \
+              BODY[HEADER.FIELDS (LIST-ID)] {23}\r\nList-Id: other.list\r\n\r\n)\r\n\
+              a2 OK done\r\n",
+        );
+        let retention = Retention {
+            before: Local.ymd(2020, 1, 1),
+            list_before: Local.ymd(2024, 1, 1),
+            date_source: DateSource::Internal,
+        };
+        assert_eq!(retention.search_before(), Local.ymd(2024, 1, 1));
+        assert_eq!(
+            retention.apply(&mut imap, &[3, 4, 5, 6]).unwrap(),
+            [
+                Group {
+                    list_id: None,
+                    uids: vec![5],
+                },
+                Group {
+                    list_id: Some("rust.lists.example".to_string()),
+                    uids: vec![3],
+                },
+            ]
+        );
+        assert_eq!(
+            String::from_utf8_lossy(&sent.borrow()),
+            "a2 UID FETCH 3:6 \
+             (UID INTERNALDATE ENVELOPE BODY.PEEK[HEADER.FIELDS (LIST-ID)])\r\n"
+        );
+    }
+
+    #[test]
+    fn sent_date() {
+        let (mut imap, _, _) = session(
+            b"* 1 FETCH (UID 3 INTERNALDATE \"01-Mar-2023 12:00:00 +0000\" \
+              ENVELOPE (\"Tue, 1 Nov 2022 09:00:00 +0100 (CET)\" NIL NIL NIL NIL NIL NIL NIL \
+              NIL NIL) BODY[HEADER.FIELDS (LIST-ID)] {2}\r\n\r\n)\r\n\
+              a2 OK done\r\n",
+        );
+        let retention = Retention {
+            before: Local.ymd(2023, 1, 1),
+            list_before: Local.ymd(2022, 1, 1),
+            date_source: DateSource::Sent,
+        };
+        assert_eq!(
+            retention.apply(&mut imap, &[3]).unwrap(),
+            [Group {
+                list_id: None,
+                uids: vec![3],
+            }]
+        );
+    }
+}
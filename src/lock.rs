@@ -0,0 +1,121 @@
+use crate::config;
+use crate::error::{Error, Result};
+use crate::output::tell;
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::Write;
+use std::path::PathBuf;
+
+/// The locks keeping two runs from cleaning the same mailbox at the same time.
+#[derive(clap::Args, Debug)]
+pub struct LockArgs {
+    /// Wait for the other runs cleaning a mailbox instead of skipping it. Each mailbox is locked
+    /// while cleaned, with a file in ~/.local/state/imap-cleanup/locks.
+    #[clap(long, conflicts_with = "no-lock", env = "IMAP_CLEANUP_WAIT_LOCK")]
+    pub wait_lock: bool,
+
+    /// Do not lock the mailboxes.
+    #[clap(long, env = "IMAP_CLEANUP_NO_LOCK")]
+    pub no_lock: bool,
+}
+
+/// Locks the mailboxes of an account.
+#[derive(Debug, Default)]
+pub struct Locker {
+    /// The directory of the lock files of the account, None when not locking.
+    dir: Option<PathBuf>,
+    wait: bool,
+}
+
+/// Held while cleaning a mailbox, unlocked when dropped.
+#[derive(Debug)]
+pub struct Lock {
+    _file: Option<File>,
+}
+
+impl Locker {
+    /// The locker of the mailboxes of `username` on `host`, doing nothing with --no-lock.
+    pub fn new(args: &LockArgs, username: &str, host: &str) -> Result<Self> {
+        if args.no_lock {
+            return Ok(Locker::default());
+        }
+        let dir = config::state_dir()
+            .ok_or_else(|| Error::Config("no home directory for the locks".to_string()))?
+            .join("locks")
+            .join(file_name(&format!("{}@{}", username, host)));
+        std::fs::create_dir_all(&dir)
+            .map_err(|err| Error::Config(format!("{}: {}", dir.display(), err)))?;
+        Ok(Locker {
+            dir: Some(dir),
+            wait: args.wait_lock,
+        })
+    }
+
+    /// Lock the mailbox, or fail with `Error::Locked` if another run has it, unless waiting.
+    pub fn lock(&self, mailbox: &str) -> Result<Lock> {
+        let dir = match &self.dir {
+            Some(dir) => dir,
+            None => return Ok(Lock { _file: None }),
+        };
+        let path = dir.join(format!("{}.lock", file_name(mailbox)));
+        let mut file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .map_err(|err| Error::Config(format!("{}: {}", path.display(), err)))?;
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) if self.wait => {
+                tell!("{}: waiting for the other run cleaning it.", mailbox);
+                file.lock()?;
+            }
+            Err(TryLockError::WouldBlock) => {
+                return Err(Error::Locked(format!(
+                    "another run is cleaning it ({})",
+                    path.display()
+                )))
+            }
+            Err(TryLockError::Error(err)) => return Err(err.into()),
+        }
+        // Who has it, for the curious.
+        file.set_len(0)?;
+        writeln!(file, "{}", std::process::id())?;
+        Ok(Lock { _file: Some(file) })
+    }
+}
+
+/// A name usable as a file name, the other characters written %XX.
+fn file_name(name: &str) -> String {
+    name.bytes()
+        .map(|x| match x {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' | b'@' => (x as char).to_string(),
+            b'.' if name != "." && name != ".." => ".".to_string(),
+            _ => format!("%{:02X}", x),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn lock() {
+        assert_eq!(file_name("Lists/rust-users"), "Lists%2Frust-users");
+        assert_eq!(file_name("me@example.com"), "me@example.com");
+        assert_eq!(file_name(".."), "%2E%2E");
+
+        let dir = std::env::temp_dir().join(format!("imap-cleanup-lock-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let locker = Locker {
+            dir: Some(dir.clone()),
+            wait: false,
+        };
+        let lock = locker.lock("INBOX").unwrap();
+        assert!(matches!(locker.lock("INBOX"), Err(Error::Locked(_))));
+        assert!(locker.lock("Archive").is_ok());
+        drop(lock);
+        assert!(locker.lock("INBOX").is_ok());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,319 @@
+use crate::error::{Error, Result};
+use crate::search::parse_size;
+use chrono::prelude::*;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tracing::{Level, Metadata};
+use tracing_subscriber::filter::{filter_fn, LevelFilter};
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::{Layer, Registry};
+
+/// The target of the lines for humans, logged to the file only: they are printed already.
+pub const OUTPUT: &str = "imap_cleanup::output";
+
+/// The logs of the run, on the standard error: what is done in each mailbox and batch, to debug
+/// the long unattended runs after the fact.
+#[derive(clap::Args, Debug)]
+pub struct LoggingArgs {
+    /// Log what is done: -v for the connection and each mailbox, -vv for each batch of
+    /// messages too, -vvv for everything.
+    #[clap(
+        short,
+        long,
+        global = true,
+        action = clap::ArgAction::Count,
+        env = "IMAP_CLEANUP_VERBOSE"
+    )]
+    pub verbose: u8,
+
+    /// The format of the logs: lines for humans or a JSON object per line.
+    #[clap(
+        long,
+        value_enum,
+        value_name = "FORMAT",
+        default_value = "text",
+        global = true,
+        env = "IMAP_CLEANUP_LOG_FORMAT"
+    )]
+    pub log_format: LogFormat,
+
+    /// Also append the logs to this file, with the lines printed, the warnings and the errors:
+    /// a history of the runs from cron. At least what -v logs is written.
+    #[clap(
+        long,
+        value_name = "PATH",
+        global = true,
+        env = "IMAP_CLEANUP_LOG_FILE"
+    )]
+    pub log_file: Option<PathBuf>,
+
+    /// Rotate the log file once it would get larger than a size, like 10M, or daily: it is
+    /// renamed with a `.1` suffix, the previous ones shifted to `.2` and so on.
+    #[clap(
+        long,
+        value_name = "SIZE|daily",
+        value_parser(parse_rotation),
+        requires = "log-file",
+        global = true,
+        env = "IMAP_CLEANUP_LOG_ROTATE"
+    )]
+    pub log_rotate: Option<Rotation>,
+
+    /// The number of rotated log files kept.
+    #[clap(
+        long,
+        value_name = "COUNT",
+        default_value_t = 5,
+        global = true,
+        env = "IMAP_CLEANUP_LOG_KEEP"
+    )]
+    pub log_keep: u32,
+
+    /// Also send the logs to the system logger, with the priority of their level: the lines
+    /// printed, the warnings and the errors, like with --log-file.
+    #[clap(
+        long,
+        value_enum,
+        value_name = "TARGET",
+        global = true,
+        env = "IMAP_CLEANUP_LOG_TARGET"
+    )]
+    pub log_target: Option<LogTarget>,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogTarget {
+    /// The syslog socket, /dev/log.
+    Syslog,
+    /// The journal of systemd, with the fields of the events.
+    Journald,
+}
+
+/// When to rotate the log file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rotation {
+    /// Before it gets larger than this size in bytes.
+    Size(u64),
+    /// On the first write of each day.
+    Daily,
+}
+
+/// Parse a rotation like `10M` or `daily`.
+fn parse_rotation(s: &str) -> std::result::Result<Rotation, String> {
+    match s {
+        "daily" => Ok(Rotation::Daily),
+        _ => parse_size(s)
+            .ok()
+            .filter(|x| *x > 0)
+            .map(Rotation::Size)
+            .ok_or_else(|| "expected a size like 10M, or daily".to_string()),
+    }
+}
+
+type BoxedLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+impl LoggingArgs {
+    /// Install the loggers: on the standard error with -v, and in the log file if any.
+    pub fn init(&self) -> Result<()> {
+        let level = match self.verbose {
+            0 => None,
+            1 => Some(Level::INFO),
+            2 => Some(Level::DEBUG),
+            _ => Some(Level::TRACE),
+        };
+        let mut layers = Vec::new();
+        if let Some(level) = level {
+            let ansi = crate::color::stderr();
+            let printed = |x: &Metadata| x.target() != OUTPUT;
+            layers.push(
+                self.layer(std::io::stderr, ansi, level)
+                    .with_filter(filter_fn(printed))
+                    .boxed(),
+            );
+        }
+        if let Some(path) = &self.log_file {
+            let file = LogFile::open(path, self.log_rotate, self.log_keep)?;
+            let level = level.unwrap_or(Level::INFO);
+            layers.push(self.layer(Mutex::new(file), false, level));
+        }
+        if let Some(target) = self.log_target {
+            let level = level.unwrap_or(Level::INFO);
+            layers.push(
+                system_layer(target)?
+                    .with_filter(LevelFilter::from_level(level))
+                    .boxed(),
+            );
+        }
+        if !layers.is_empty() {
+            tracing_subscriber::registry().with(layers).init();
+        }
+        Ok(())
+    }
+
+    fn layer<W>(&self, writer: W, ansi: bool, level: Level) -> BoxedLayer
+    where
+        W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+    {
+        // The time spent in each mailbox and batch, when closed.
+        let spans = match level {
+            Level::INFO => FmtSpan::NONE,
+            _ => FmtSpan::CLOSE,
+        };
+        let layer = tracing_subscriber::fmt::layer()
+            .with_span_events(spans)
+            .with_ansi(ansi)
+            .with_writer(writer);
+        let filter = LevelFilter::from_level(level);
+        match self.log_format {
+            LogFormat::Text => layer.with_filter(filter).boxed(),
+            LogFormat::Json => layer.json().with_filter(filter).boxed(),
+        }
+    }
+}
+
+/// The layer of the system logger, which adds the time and the program.
+#[cfg(unix)]
+fn system_layer(target: LogTarget) -> Result<BoxedLayer> {
+    Ok(match target {
+        LogTarget::Syslog => tracing_subscriber::fmt::layer()
+            .without_time()
+            .with_level(false)
+            .with_ansi(false)
+            .with_writer(crate::syslog::Syslog::connect()?)
+            .boxed(),
+        LogTarget::Journald => tracing_journald::layer()
+            .map_err(|err| Error::Config(format!("--log-target journald: {}", err)))?
+            .with_syslog_identifier("imap-cleanup".to_string())
+            .boxed(),
+    })
+}
+
+#[cfg(not(unix))]
+fn system_layer(_: LogTarget) -> Result<BoxedLayer> {
+    Err(Error::Config(
+        "--log-target is only supported on Unix".to_string(),
+    ))
+}
+
+/// The log file, appended to and rotated.
+struct LogFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    /// The day of the last write, for the daily rotation.
+    date: NaiveDate,
+    rotation: Option<Rotation>,
+    keep: u32,
+}
+
+impl LogFile {
+    fn open(path: &Path, rotation: Option<Rotation>, keep: u32) -> Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let metadata = file.metadata()?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+            size: metadata.len(),
+            date: DateTime::<Local>::from(metadata.modified()?)
+                .naive_local()
+                .date(),
+            rotation,
+            keep,
+        })
+    }
+
+    /// Whether the file is to rotate before writing `len` more bytes on `today`.
+    fn due(&self, len: usize, today: NaiveDate) -> bool {
+        match self.rotation {
+            Some(Rotation::Size(max)) => self.size > 0 && self.size + len as u64 > max,
+            Some(Rotation::Daily) => self.size > 0 && self.date != today,
+            None => false,
+        }
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        let rotated = |i: u32| {
+            let mut path = self.path.clone().into_os_string();
+            path.push(format!(".{}", i));
+            PathBuf::from(path)
+        };
+        if self.keep == 0 {
+            std::fs::remove_file(&self.path)?;
+        } else {
+            for i in (1..self.keep).rev() {
+                match std::fs::rename(rotated(i), rotated(i + 1)) {
+                    Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err),
+                    _ => {}
+                }
+            }
+            std::fs::rename(&self.path, rotated(1))?;
+        }
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for LogFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let today = Local::today().naive_local();
+        if self.due(buf.len(), today) {
+            self.rotate()?;
+        }
+        self.date = today;
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rotation() {
+        assert_eq!(parse_rotation("daily"), Ok(Rotation::Daily));
+        assert_eq!(parse_rotation("10M"), Ok(Rotation::Size(10 << 20)));
+        assert!(parse_rotation("0").is_err());
+        assert!(parse_rotation("weekly").is_err());
+    }
+
+    #[test]
+    fn rotated() {
+        let dir = std::env::temp_dir().join(format!("imap-cleanup-log-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("cleanup.log");
+        let mut file = LogFile::open(&path, Some(Rotation::Size(10)), 2).unwrap();
+        for line in ["first\n", "second\n", "third\n", "fourth\n"] {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+        let read = |suffix: &str| {
+            let mut path = path.clone().into_os_string();
+            path.push(suffix);
+            std::fs::read_to_string(path).unwrap_or_default()
+        };
+        assert_eq!(read(""), "fourth\n");
+        assert_eq!(read(".1"), "third\n");
+        assert_eq!(read(".2"), "second\n");
+        assert_eq!(read(".3"), "");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,630 @@
+use crate::color::yellow;
+use crate::error::{Error, Result};
+use crate::output::{say, tell};
+use crate::tap::Tap;
+use imap::types::{Name, NameAttribute};
+use imap::Session;
+use std::io::{Read, Write};
+
+/// Special-use mailboxes (RFC 6154) skipped when expanding wildcards.
+const PROTECTED_SPECIAL_USE: &[&str] = &["\\Sent", "\\Drafts", "\\Flagged", "\\Trash"];
+
+/// Which mailboxes to cleanup.
+#[derive(clap::Args, Debug)]
+pub struct MailboxArgs {
+    /// Mailbox to cleanup, can be given multiple times [default: INBOX]. Wildcards are expanded
+    /// using LIST: `*` matches anything, `%` does not match the hierarchy delimiter (written `/`
+    /// whatever the server uses) and `?` matches one character, for example 'Lists/*'.
+    #[clap(long, short = 'b', global = true, env = "IMAP_CLEANUP_MAILBOX")]
+    pub mailbox: Vec<String>,
+
+    /// Cleanup every selectable mailbox.
+    #[clap(long, conflicts_with = "mailbox", env = "IMAP_CLEANUP_ALL_MAILBOXES")]
+    pub all_mailboxes: bool,
+
+    /// Skip the mailboxes matching this pattern, can be given multiple times. Same wildcards as
+    /// --mailbox, for example --exclude 'Sent*' --exclude Drafts.
+    #[clap(long, value_name = "PATTERN", env = "IMAP_CLEANUP_EXCLUDE")]
+    pub exclude: Vec<String>,
+
+    /// Also match the special-use mailboxes (sent, drafts, flagged and trash) with wildcards and
+    /// --all-mailboxes. They are skipped by default, naming them explicitly always works.
+    #[clap(long, env = "IMAP_CLEANUP_INCLUDE_SPECIAL_USE")]
+    pub include_special_use: bool,
+
+    /// Only match the subscribed mailboxes (LSUB) with wildcards and --all-mailboxes, leaving
+    /// alone the folders one unsubscribed from. Naming a mailbox explicitly always works.
+    #[clap(long, env = "IMAP_CLEANUP_SUBSCRIBED_ONLY")]
+    pub subscribed_only: bool,
+
+    /// Resolve the mailbox names and patterns in this namespace, using the prefix and delimiter
+    /// advertised by the server with NAMESPACE. For example --namespace shared -b 'alice/*'.
+    #[clap(long, value_enum, env = "IMAP_CLEANUP_NAMESPACE")]
+    pub namespace: Option<NamespaceKind>,
+}
+
+impl MailboxArgs {
+    /// The names of the mailboxes to cleanup.
+    pub fn resolve<S: Read + Write>(
+        &self,
+        session: &mut Session<S>,
+        tap: &Tap,
+    ) -> Result<Vec<String>> {
+        let specs = if self.all_mailboxes {
+            vec!["*".to_string()]
+        } else if self.mailbox.is_empty() {
+            vec!["INBOX".to_string()]
+        } else {
+            self.mailbox.clone()
+        };
+        self.resolve_specs(session, tap, &specs)
+    }
+
+    /// The names of the mailboxes matching these names and patterns, with the other options.
+    pub fn resolve_specs<S: Read + Write>(
+        &self,
+        session: &mut Session<S>,
+        tap: &Tap,
+        specs: &[String],
+    ) -> Result<Vec<String>> {
+        let namespace = match self.namespace {
+            Some(kind) => Some(namespace(session, tap, kind)?),
+            None => None,
+        };
+        expand(
+            session,
+            specs,
+            &self.exclude,
+            self.include_special_use,
+            self.subscribed_only,
+            namespace.as_ref(),
+        )
+    }
+}
+
+/// A mailbox as returned by LIST.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Mailbox {
+    pub name: String,
+    pub delimiter: Option<String>,
+    /// The attributes like `\Noselect` or `\HasChildren`.
+    pub attributes: Vec<String>,
+}
+
+impl Mailbox {
+    pub fn has_attribute(&self, attribute: &str) -> bool {
+        self.attributes
+            .iter()
+            .any(|x| x.eq_ignore_ascii_case(attribute))
+    }
+
+    pub fn is_selectable(&self) -> bool {
+        !self.has_attribute("\\Noselect") && !self.has_attribute("\\NonExistent")
+    }
+
+    /// Whether this is a special-use mailbox one rarely wants to cleanup.
+    pub fn is_protected(&self) -> bool {
+        PROTECTED_SPECIAL_USE.iter().any(|x| self.has_attribute(x))
+    }
+}
+
+/// The trash: the mailbox with the `\Trash` attribute of SPECIAL-USE, or else `name`.
+pub fn trash<S: Read + Write>(session: &mut Session<S>, name: Option<&str>) -> Result<String> {
+    let found = list(session, "*")?
+        .into_iter()
+        .find(|x| x.has_attribute("\\Trash"))
+        .map(|x| x.name);
+    found.or_else(|| name.map(String::from)).ok_or_else(|| {
+        Error::Protocol("the server does not tell the trash, give it with --trash".to_string())
+    })
+}
+
+/// Whether `name` contains wildcards. Like in IMAP, `*` matches anything and `%` matches anything
+/// but the hierarchy delimiter. `?` matches a single character.
+pub fn is_pattern(name: &str) -> bool {
+    name.contains(['*', '%', '?'])
+}
+
+/// List the mailboxes on the server matching the IMAP `pattern`.
+pub fn list<S: Read + Write>(session: &mut Session<S>, pattern: &str) -> Result<Vec<Mailbox>> {
+    Ok(session
+        .list(Some(""), Some(&quote(pattern)))?
+        .iter()
+        .map(Mailbox::from)
+        .collect())
+}
+
+/// List the subscribed mailboxes matching the IMAP `pattern`.
+///
+/// The attributes returned by LSUB only tell about the subscription, not about the mailbox
+/// itself (a `\Noselect` parent can just be unsubscribed).
+pub fn lsub<S: Read + Write>(session: &mut Session<S>, pattern: &str) -> Result<Vec<Mailbox>> {
+    Ok(session
+        .lsub(Some(""), Some(&quote(pattern)))?
+        .iter()
+        .map(Mailbox::from)
+        .collect())
+}
+
+impl From<&Name> for Mailbox {
+    fn from(name: &Name) -> Self {
+        Mailbox {
+            name: name.name().to_string(),
+            delimiter: name.delimiter().map(String::from),
+            attributes: name
+                .attributes()
+                .iter()
+                .map(|x| match x {
+                    NameAttribute::NoInferiors => "\\Noinferiors".to_string(),
+                    NameAttribute::NoSelect => "\\Noselect".to_string(),
+                    NameAttribute::Marked => "\\Marked".to_string(),
+                    NameAttribute::Unmarked => "\\Unmarked".to_string(),
+                    NameAttribute::Custom(x) => x.to_string(),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// The hierarchy delimiter of the server, `None` for a flat namespace.
+pub fn delimiter<S: Read + Write>(session: &mut Session<S>) -> Result<Option<String>> {
+    Ok(session
+        .list(Some(""), Some("\"\""))?
+        .iter()
+        .find_map(|x| x.delimiter().map(String::from)))
+}
+
+/// Open a mailbox with SELECT or, for a dry run, `read_only` with EXAMINE: the server then
+/// changes nothing, not even the \Seen flags of the messages fetched. The read-only status is
+/// told either way.
+pub fn open<S: Read + Write>(
+    session: &mut Session<S>,
+    tap: &Tap,
+    mailbox: &str,
+    read_only: bool,
+) -> Result<imap::types::Mailbox> {
+    if !read_only {
+        let selected = session.select(mailbox)?;
+        tracing::debug!(mailbox, exists = selected.exists, "selected");
+        return Ok(selected);
+    }
+    let selected = session.examine(mailbox)?;
+    tracing::debug!(mailbox, exists = selected.exists, "examined");
+    let confirmed = tap
+        .completion()
+        .is_some_and(|x| x.to_ascii_uppercase().contains("[READ-ONLY]"));
+    if confirmed {
+        say!("{}: opened read-only.", mailbox);
+    } else {
+        tell!(
+            "{} {} was opened with EXAMINE but the server did not confirm it is read-only.",
+            yellow("Warning:"),
+            mailbox
+        );
+    }
+    Ok(selected)
+}
+
+/// Select a mailbox again before changing its messages, checking that the UIDs found are still
+/// theirs: a new UIDVALIDITY means the server assigned new UIDs (RFC 3501 2.3.1.1), for example
+/// because the mailbox was deleted and created again.
+pub fn reselect<S: Read + Write>(
+    session: &mut Session<S>,
+    mailbox: &str,
+    uid_validity: Option<u32>,
+) -> Result<()> {
+    let current = session.select(mailbox)?.uid_validity;
+    if current != uid_validity {
+        return Err(Error::Protocol(format!(
+            "the UIDVALIDITY of {} changed from {} to {}, its messages were not changed",
+            mailbox,
+            uid_validity.map_or("none".to_string(), |x| x.to_string()),
+            current.map_or("none".to_string(), |x| x.to_string())
+        )));
+    }
+    Ok(())
+}
+
+/// Quote a string for a command, for the few commands imap does not quote itself.
+pub fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// The kinds of namespaces of RFC 2342.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NamespaceKind {
+    /// The user's own mailboxes.
+    Personal,
+    /// The mailboxes of other users shared with the user ("Other Users' Namespace").
+    Shared,
+    /// The public mailboxes ("Shared Namespace" in RFC 2342).
+    Public,
+}
+
+/// The prefix and hierarchy delimiter of a namespace.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Namespace {
+    pub prefix: String,
+    pub delimiter: Option<String>,
+}
+
+/// Query the first namespace of this kind.
+pub fn namespace<S: Read + Write>(
+    session: &mut Session<S>,
+    tap: &Tap,
+    kind: NamespaceKind,
+) -> Result<Namespace> {
+    if !session.capabilities()?.has_str("NAMESPACE") {
+        return Err(Error::Protocol(
+            "the server does not support NAMESPACE".to_string(),
+        ));
+    }
+    // imap-proto does not know NAMESPACE, the tap sets the response aside.
+    let response = tap.run(session, "NAMESPACE")?.concat();
+    let response = String::from_utf8_lossy(&response);
+    parse_namespaces(&response)
+        .and_then(|x| x.into_iter().nth(kind as usize))
+        .ok_or_else(|| Error::Protocol(format!("invalid NAMESPACE response: {:?}", response)))?
+        .into_iter()
+        .next()
+        .ok_or_else(|| Error::Protocol(format!("the server has no {:?} namespace", kind)))
+}
+
+/// Parse `* NAMESPACE (("" "/")) NIL (("#public." "."))` into the three kinds of namespaces.
+fn parse_namespaces(response: &str) -> Option<Vec<Vec<Namespace>>> {
+    #[derive(Debug)]
+    enum Token {
+        List(Vec<Token>),
+        String(String),
+        Nil,
+    }
+
+    fn parse(chars: &mut std::iter::Peekable<std::str::Chars>) -> Option<Token> {
+        while chars.peek() == Some(&' ') {
+            chars.next();
+        }
+        match chars.next()? {
+            '(' => {
+                let mut list = Vec::new();
+                loop {
+                    while chars.peek() == Some(&' ') {
+                        chars.next();
+                    }
+                    if chars.peek() == Some(&')') {
+                        chars.next();
+                        return Some(Token::List(list));
+                    }
+                    list.push(parse(chars)?);
+                }
+            }
+            '"' => {
+                let mut string = String::new();
+                loop {
+                    match chars.next()? {
+                        '"' => return Some(Token::String(string)),
+                        '\\' => string.push(chars.next()?),
+                        c => string.push(c),
+                    }
+                }
+            }
+            c => {
+                let mut atom = c.to_string();
+                while let Some(&c) = chars.peek() {
+                    if c == ' ' || c == '(' || c == ')' || c == '\r' {
+                        break;
+                    }
+                    atom.push(c);
+                    chars.next();
+                }
+                atom.eq_ignore_ascii_case("NIL").then_some(Token::Nil)
+            }
+        }
+    }
+
+    let line = response
+        .lines()
+        .find_map(|x| x.strip_prefix("* NAMESPACE "))?;
+    let mut chars = line.chars().peekable();
+    (0..3)
+        .map(|_| match parse(&mut chars)? {
+            Token::Nil => Some(Vec::new()),
+            Token::List(namespaces) => namespaces
+                .into_iter()
+                .map(|namespace| match namespace {
+                    Token::List(fields) => match fields.as_slice() {
+                        [Token::String(prefix), Token::String(delimiter), ..] => Some(Namespace {
+                            prefix: prefix.clone(),
+                            delimiter: Some(delimiter.clone()),
+                        }),
+                        [Token::String(prefix), Token::Nil, ..] => Some(Namespace {
+                            prefix: prefix.clone(),
+                            delimiter: None,
+                        }),
+                        _ => None,
+                    },
+                    _ => None,
+                })
+                .collect(),
+            Token::String(_) => None,
+        })
+        .collect()
+}
+
+/// Replace the patterns by the names of the selectable mailboxes matching them and remove the
+/// names matching one of the `excludes`. `/` in a pattern stands for the server's hierarchy
+/// delimiter. Names without wildcards are kept as they are. Protected special-use mailboxes only
+/// match a pattern with `include_special_use`, unsubscribed ones never do with `subscribed_only`.
+///
+/// With a `namespace`, the names, patterns and excludes are relative to it: they get its prefix
+/// and `/` stands for its delimiter. INBOX is never prefixed.
+pub fn expand<S: Read + Write>(
+    session: &mut Session<S>,
+    specs: &[String],
+    excludes: &[String],
+    include_special_use: bool,
+    subscribed_only: bool,
+    namespace: Option<&Namespace>,
+) -> Result<Vec<String>> {
+    let has_patterns = specs.iter().any(|x| is_pattern(x));
+    if excludes.is_empty() && !has_patterns && namespace.is_none() {
+        return Ok(specs.to_vec());
+    }
+    let (prefix, delimiter) = match namespace {
+        Some(namespace) => (namespace.prefix.clone(), namespace.delimiter.clone()),
+        None => (String::new(), delimiter(session)?),
+    };
+    let mut mailboxes = if has_patterns {
+        list(session, &format!("{}*", prefix))?
+    } else {
+        Vec::new()
+    };
+    if has_patterns && subscribed_only {
+        // Keep the attributes of LIST, LSUB does not return the special-use ones.
+        let subscribed = lsub(session, &format!("{}*", prefix))?;
+        mailboxes.retain(|x| subscribed.iter().any(|y| y.name == x.name));
+    }
+    let native = |pattern: &str| {
+        if pattern.eq_ignore_ascii_case("INBOX") {
+            return pattern.to_string();
+        }
+        match &delimiter {
+            Some(delimiter) => format!("{}{}", prefix, pattern.replace('/', delimiter)),
+            None => format!("{}{}", prefix, pattern),
+        }
+    };
+    let excludes = excludes.iter().map(|x| native(x)).collect::<Vec<_>>();
+    let excluded = |name: &str| {
+        excludes
+            .iter()
+            .any(|x| matches(x, name, delimiter.as_deref()))
+    };
+
+    let mut names: Vec<String> = Vec::new();
+    for spec in specs {
+        if !is_pattern(spec) {
+            let name = match namespace {
+                Some(_) => native(spec),
+                None => spec.clone(),
+            };
+            if !names.contains(&name) && !excluded(&name) {
+                names.push(name);
+            }
+            continue;
+        }
+        let pattern = native(spec);
+        let mut found = false;
+        for mailbox in mailboxes.iter().filter(|x| x.is_selectable()) {
+            if !matches(&pattern, &mailbox.name, delimiter.as_deref()) {
+                continue;
+            }
+            found = true;
+            if !include_special_use && mailbox.is_protected() {
+                tell!(
+                    "{} special-use mailbox {} (see --include-special-use).",
+                    yellow("Skipping"),
+                    mailbox.name
+                );
+            } else if !names.contains(&mailbox.name) && !excluded(&mailbox.name) {
+                names.push(mailbox.name.clone());
+            }
+        }
+        if !found {
+            tell!("No mailbox matches {:?}.", spec);
+        }
+    }
+    Ok(names)
+}
+
+fn matches(pattern: &str, name: &str, delimiter: Option<&str>) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let name = name.chars().collect::<Vec<_>>();
+    let delimiter = delimiter.and_then(|x| x.chars().next());
+    matches_chars(&pattern, &name, delimiter)
+}
+
+fn matches_chars(pattern: &[char], name: &[char], delimiter: Option<char>) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some(('*', rest)) => (0..=name.len()).any(|i| matches_chars(rest, &name[i..], delimiter)),
+        Some(('%', rest)) => (0..=name.len())
+            .take_while(|&i| i == 0 || Some(name[i - 1]) != delimiter)
+            .any(|i| matches_chars(rest, &name[i..], delimiter)),
+        Some(('?', rest)) => !name.is_empty() && matches_chars(rest, &name[1..], delimiter),
+        Some((c, rest)) => name.first() == Some(c) && matches_chars(rest, &name[1..], delimiter),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::connection::test::session;
+
+    #[test]
+    fn wildcards() {
+        assert!(matches("Lists/*", "Lists/rust", Some("/")));
+        assert!(matches("Lists/*", "Lists/rust/announce", Some("/")));
+        assert!(!matches("Lists/*", "Lists", Some("/")));
+        assert!(matches("Lists/%", "Lists/rust", Some("/")));
+        assert!(!matches("Lists/%", "Lists/rust/announce", Some("/")));
+        assert!(matches("%", "INBOX", Some("/")));
+        assert!(matches("Arch??e", "Archive", None));
+        assert!(!matches("Arch?", "Archive", None));
+    }
+
+    #[test]
+    fn read_only() {
+        let (mut imap, tap, sent) = session(
+            b"* 3 EXISTS\r\na2 OK [READ-ONLY] done\r\n\
+              * 3 EXISTS\r\na3 OK [READ-WRITE] done\r\n",
+        );
+        assert_eq!(open(&mut imap, &tap, "INBOX", true).unwrap().exists, 3);
+        assert_eq!(open(&mut imap, &tap, "INBOX", false).unwrap().exists, 3);
+        assert_eq!(
+            String::from_utf8_lossy(&sent.borrow()),
+            "a2 EXAMINE \"INBOX\"\r\na3 SELECT \"INBOX\"\r\n"
+        );
+    }
+
+    #[test]
+    fn uid_validity() {
+        let (mut imap, _, _) = session(
+            b"* 3 EXISTS\r\n* OK [UIDVALIDITY 7] ok\r\na2 OK [READ-WRITE] done\r\n\
+              * 3 EXISTS\r\n* OK [UIDVALIDITY 8] ok\r\na3 OK [READ-WRITE] done\r\n",
+        );
+        reselect(&mut imap, "INBOX", Some(7)).unwrap();
+        assert!(reselect(&mut imap, "INBOX", Some(7)).is_err());
+    }
+
+    #[test]
+    fn expand_patterns() {
+        let (mut session, _, sent) = session(
+            b"* LIST (\\Noselect) \".\" \"\"\r\n\
+              a2 OK done\r\n\
+              * LIST () \".\" INBOX\r\n\
+              * LIST (\\Noselect \\HasChildren) \".\" Lists\r\n\
+              * LIST (\\HasNoChildren) \".\" Lists.rust\r\n\
+              * LIST (\\HasNoChildren) \".\" Lists.tokio\r\n\
+              * LIST (\\HasNoChildren) \".\" Lists.tokio.dev\r\n\
+              * LIST (\\HasNoChildren \\Sent) \".\" Lists.sent\r\n\
+              a3 OK done\r\n",
+        );
+        let specs = ["INBOX".to_string(), "Lists/*".to_string()];
+        let excludes = ["Lists/tokio/*".to_string()];
+        assert_eq!(
+            expand(&mut session, &specs, &excludes, false, false, None).unwrap(),
+            ["INBOX", "Lists.rust", "Lists.tokio"]
+        );
+        assert_eq!(
+            String::from_utf8_lossy(&sent.borrow()),
+            "a2 LIST \"\" \"\"\r\na3 LIST \"\" \"*\"\r\n"
+        );
+    }
+
+    #[test]
+    fn namespaces() {
+        let namespaces = parse_namespaces(
+            "* NAMESPACE ((\"\" \"/\")) ((\"#shared/\" \"/\" \"X-PARAM\" (\"FLAG1\"))) NIL\r\n",
+        )
+        .unwrap();
+        assert_eq!(
+            namespaces,
+            [
+                vec![Namespace {
+                    prefix: "".to_string(),
+                    delimiter: Some("/".to_string()),
+                }],
+                vec![Namespace {
+                    prefix: "#shared/".to_string(),
+                    delimiter: Some("/".to_string()),
+                }],
+                vec![],
+            ]
+        );
+        assert!(parse_namespaces("* NAMESPACE NIL\r\n").is_none());
+
+        let (mut session, _, sent) = session(
+            b"* LIST (\\HasNoChildren) \".\" INBOX.Lists.rust\r\n\
+              a2 OK done\r\n",
+        );
+        let namespace = Namespace {
+            prefix: "INBOX.".to_string(),
+            delimiter: Some(".".to_string()),
+        };
+        let specs = [
+            "INBOX".to_string(),
+            "Lists/*".to_string(),
+            "Archive/2020".to_string(),
+        ];
+        assert_eq!(
+            expand(&mut session, &specs, &[], false, false, Some(&namespace)).unwrap(),
+            ["INBOX", "INBOX.Lists.rust", "INBOX.Archive.2020"]
+        );
+        assert_eq!(
+            String::from_utf8_lossy(&sent.borrow()),
+            "a2 LIST \"\" \"INBOX.*\"\r\n"
+        );
+
+        let (mut session, tap, _) = crate::connection::test::session(
+            b"* CAPABILITY IMAP4rev1 NAMESPACE\r\n\
+              a2 OK done\r\n\
+              * NAMESPACE ((\"\" \".\")) ((\"Other.\" \".\")) NIL\r\n\
+              a3 OK done\r\n",
+        );
+        assert_eq!(
+            super::namespace(&mut session, &tap, NamespaceKind::Shared).unwrap(),
+            Namespace {
+                prefix: "Other.".to_string(),
+                delimiter: Some(".".to_string()),
+            }
+        );
+    }
+
+    #[test]
+    fn subscribed_only() {
+        let (mut session, _, sent) = session(
+            b"* LIST (\\Noselect) \"/\" \"\"\r\n\
+              a2 OK done\r\n\
+              * LIST (\\HasNoChildren) \"/\" Lists/rust\r\n\
+              * LIST (\\HasNoChildren) \"/\" Lists/old\r\n\
+              a3 OK done\r\n\
+              * LSUB (\\Noselect) \"/\" Lists\r\n\
+              * LSUB () \"/\" Lists/rust\r\n\
+              a4 OK done\r\n",
+        );
+        let specs = ["Lists/*".to_string(), "Lists/old".to_string()];
+        assert_eq!(
+            expand(&mut session, &specs, &[], false, true, None).unwrap(),
+            ["Lists/rust", "Lists/old"]
+        );
+        assert!(String::from_utf8_lossy(&sent.borrow()).ends_with("a4 LSUB \"\" \"*\"\r\n"));
+    }
+
+    #[test]
+    fn special_use() {
+        let mailbox = |attributes: &[&str]| Mailbox {
+            name: "Sent Items".to_string(),
+            delimiter: Some("/".to_string()),
+            attributes: attributes.iter().map(|x| x.to_string()).collect(),
+        };
+        assert!(mailbox(&["\\HasNoChildren", "\\sent"]).is_protected());
+        assert!(mailbox(&["\\Trash"]).is_protected());
+        assert!(!mailbox(&["\\Junk"]).is_protected());
+        assert!(!mailbox(&["\\Noselect"]).is_selectable());
+    }
+
+    #[test]
+    fn find_trash() {
+        let (mut imap, _, _) = session(
+            b"* LIST () \"/\" INBOX\r\n\
+              * LIST (\\HasNoChildren \\Trash) \"/\" \"Deleted Items\"\r\n\
+              a2 OK done\r\n\
+              * LIST () \"/\" INBOX\r\n\
+              a3 OK done\r\n\
+              * LIST () \"/\" INBOX\r\n\
+              a4 OK done\r\n",
+        );
+        assert_eq!(trash(&mut imap, Some("Trash")).unwrap(), "Deleted Items");
+        assert_eq!(trash(&mut imap, Some("Trash")).unwrap(), "Trash");
+        assert!(trash(&mut imap, None).is_err());
+    }
+}
@@ -1,94 +1,2142 @@
+mod accounts;
+mod action;
+mod age;
+mod audit;
+mod auth;
+mod backup;
+mod bar;
+mod cap;
+mod check;
+mod color;
+mod completions;
+mod compress;
+mod config;
+mod connection;
+mod contacts;
+mod dedup;
+mod error;
+mod export;
+mod expunge;
+mod filter;
+mod free;
+mod gmail;
+mod interactive;
+mod interrupt;
+mod lists;
+mod lock;
+mod logging;
+mod mailbox;
+mod man;
+mod mime;
+mod output;
+mod password;
+mod plan;
+mod policy;
+mod preview;
+mod proxy;
+mod quota;
+mod retry;
+mod review;
+mod search;
+mod secrets;
+mod senders;
+mod stats;
+#[cfg(unix)]
+mod syslog;
+mod tap;
+mod threads;
+mod tls;
+mod trace;
+mod tunnel;
+mod uidlist;
+mod window;
+
+use action::{Action, Extensions, Limiter, Progress};
 use chrono::prelude::*;
-use clap::Parser;
-use imap::error::Result;
+use clap::{CommandFactory, Parser};
+use color::{bold, cleaned, red, yellow};
+use error::{Error, Result};
 use imap::Session;
 use itertools::Itertools;
-use std::io::{Read, Write};
+use output::{say, tell};
+use std::collections::BTreeMap;
+use std::io::{IsTerminal, Read, Write};
 use std::ops::RangeInclusive;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{mpsc, Mutex};
+use std::time::Instant;
+use tap::Tap;
+
+/// The exit status of the runs, to tell apart in cron the failures to log in from a run that
+/// cleaned nothing.
+const EXIT_STATUS: &str = "EXIT STATUS:
+    0    Success, even when nothing was cleaned.
+    1    Any other error, like a usage or configuration error.
+    2    The authentication failed.
+    3    The connection failed: network, TLS, proxy or timeout.
+    4    Some mailboxes (or accounts) failed, the others were cleaned.
+    5    Aborted by a safety guard, like a confirmation not given.
+    130  Interrupted with Ctrl+C.";
 
-/// Simple program to greet a person
+/// Cleanup your old emails: the messages found by the search in the mailboxes of an IMAP account
+/// are deleted, or moved, archived or stripped of their attachments.
 #[derive(clap::Parser, Debug)]
-#[clap(author, version, about, long_about = None)]
+#[clap(author, version, about, long_about = None, after_help = EXIT_STATUS)]
 struct Args {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
+    /// Configuration file [default: ~/.config/imap-cleanup/config.toml].
+    #[clap(long, env = "IMAP_CLEANUP_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Use the settings of this account from the configuration file. Options given on the
+    /// command line take precedence.
+    #[clap(long, env = "IMAP_CLEANUP_ACCOUNT")]
+    account: Option<String>,
+
+    /// Run for every account of the configuration file at the same time, each on its own
+    /// connection with the same options, then report the accounts that failed. The output of
+    /// each is prefixed with the name of its account. Nothing is asked: --yes is required from a
+    /// terminal, and the passwords cannot be prompted.
+    #[clap(long, conflicts_with = "account", env = "IMAP_CLEANUP_ALL_ACCOUNTS")]
+    all_accounts: bool,
+
+    /// Use the settings of this profile from the configuration file. They take precedence over
+    /// the account's, options given on the command line take precedence over both.
+    #[clap(long, env = "IMAP_CLEANUP_PROFILE")]
+    profile: Option<String>,
+
     /// Host name to connect to.
-    #[clap(short, long)]
-    host: String,
+    #[clap(short, long, env = "IMAP_CLEANUP_HOST")]
+    host: Option<String>,
 
-    /// Host port to connect to.
-    #[clap(short, long, default_value = "993")]
-    port: u16,
+    /// Host port to connect to [default: 993, or 143 with --starttls].
+    #[clap(short, long, env = "IMAP_CLEANUP_PORT")]
+    port: Option<u16>,
 
     /// Username.
-    #[clap(short, long)]
-    username: String,
+    #[clap(short, long, env = "IMAP_CLEANUP_USERNAME")]
+    username: Option<String>,
 
-    /// Before date.
-    #[clap(long, value_parser(parse_date))]
-    before: Date<Local>,
+    /// Before date (required to cleanup).
+    #[clap(long, value_parser(search::parse_date), env = "IMAP_CLEANUP_BEFORE")]
+    before: Option<Date<Local>>,
+
+    #[clap(flatten)]
+    search: search::SearchArgs,
+
+    #[clap(flatten)]
+    filter: filter::FilterArgs,
+
+    #[clap(flatten)]
+    lists: lists::ListArgs,
+
+    #[clap(flatten)]
+    senders: senders::SendersArgs,
+
+    #[clap(flatten)]
+    contacts: contacts::ContactsArgs,
+
+    #[clap(flatten)]
+    threads: threads::ThreadArgs,
+
+    #[clap(flatten)]
+    mailboxes: mailbox::MailboxArgs,
+
+    #[clap(flatten)]
+    window: window::WindowArgs,
+
+    #[clap(flatten)]
+    free: free::FreeArgs,
+
+    #[clap(flatten)]
+    cap: cap::CapArgs,
+
+    /// Only cleanup the messages of this list, like the output of `top --uid-list`, or `-` to
+    /// read it from the standard input: a `MAILBOX<TAB>UIDVALIDITY<TAB>UID` line per message. The
+    /// mailboxes are those of the list unless given by --mailbox, and --before defaults to
+    /// tomorrow. A mailbox whose UIDVALIDITY changed is not cleaned.
+    #[clap(long, value_name = "PATH", env = "IMAP_CLEANUP_UIDS_FROM")]
+    uids_from: Option<PathBuf>,
+
+    /// The messages chosen by `review`, the only ones cleaned.
+    #[clap(skip)]
+    selection: Option<uidlist::UidList>,
+
+    /// Move the messages to this mailbox instead of deleting them.
+    #[clap(long, value_name = "MAILBOX", env = "IMAP_CLEANUP_MOVE_TO")]
+    move_to: Option<String>,
 
-    #[clap(long, short = 'b', default_value = "INBOX")]
-    mailbox: String,
+    /// Remove the attachments of the messages instead of deleting them: each message is replaced
+    /// by a copy without them, with the same flags and date, to keep the text but free the
+    /// space. Only the messages with attachments are cleaned.
+    #[clap(
+        long,
+        conflicts_with_all = &["move-to", "gmail-remove-label", "gmail-archive", "without-attachments"],
+        env = "IMAP_CLEANUP_STRIP_ATTACHMENTS"
+    )]
+    strip_attachments: bool,
 
-    /// Host port to connect to.
-    #[clap(short = 'n', long)]
+    /// Only list the messages that would be cleaned and what would be freed: the mailboxes are
+    /// opened read-only and nothing is changed.
+    #[clap(short = 'n', long, env = "IMAP_CLEANUP_DRY_RUN")]
     dry_run: bool,
+
+    /// Do not ask for any confirmation. From a terminal, the name of each mailbox is to type
+    /// before cleaning its messages otherwise.
+    #[clap(short, long, env = "IMAP_CLEANUP_YES")]
+    yes: bool,
+
+    /// Ask for each message found whether to clean it, showing its envelope: yes, no, no for
+    /// all the messages of its sender for the rest of the run, yes for the rest of the mailbox,
+    /// or quit. Requires a terminal.
+    #[clap(
+        short,
+        long,
+        conflicts_with_all = &["dry-run", "yes"],
+        env = "IMAP_CLEANUP_INTERACTIVE"
+    )]
+    interactive: bool,
+
+    /// Print a JSON document at the end instead, for the scripts: what was done (or would be) in
+    /// each mailbox with the UIDs, the messages found by a dry run, the totals and the error. The
+    /// lines for humans go to the standard error. Only for the cleanup, apply and empty-trash.
+    #[clap(
+        long,
+        value_enum,
+        default_value = "text",
+        conflicts_with = "all-accounts",
+        env = "IMAP_CLEANUP_FORMAT"
+    )]
+    format: output::Format,
+
+    /// Color the lines for humans: the messages deleted in red, those kept or skipped in yellow,
+    /// the summaries in bold. By default only on a terminal, and never when NO_COLOR is set.
+    #[clap(
+        long,
+        value_enum,
+        value_name = "WHEN",
+        default_value = "auto",
+        global = true,
+        env = "IMAP_CLEANUP_COLOR"
+    )]
+    color: color::Mode,
+
+    /// Write the messages found by the dry run to this CSV file, to review them before cleaning
+    /// them: their mailbox, UID, date, sender, subject, size and flags.
+    #[clap(
+        long,
+        value_name = "PATH",
+        requires = "dry-run",
+        env = "IMAP_CLEANUP_EXPORT_CSV"
+    )]
+    export_csv: Option<PathBuf>,
+
+    /// Sort the messages listed by the dry run: by date for the oldest first, by size for the
+    /// largest first, or by sender. They are listed in the order of their UIDs otherwise. The
+    /// messages kept by --max-bytes are the last ones in this order.
+    #[clap(long, value_enum, value_name = "KEY", env = "IMAP_CLEANUP_SORT")]
+    sort: Option<preview::Sort>,
+
+    /// Reverse the order of --sort.
+    #[clap(long, requires = "sort", env = "IMAP_CLEANUP_REVERSE")]
+    reverse: bool,
+
+    /// Append a JSON line per message cleaned to this file, to tell later where a message went:
+    /// the time, the account, the mailbox, the UID, the action, and the Message-ID, sender,
+    /// subject and size fetched before cleaning it. Nothing is written by the dry runs.
+    #[clap(long, value_name = "PATH", env = "IMAP_CLEANUP_AUDIT_LOG")]
+    audit_log: Option<PathBuf>,
+
+    /// Append the messages to this mbox file before cleaning them, fetched without flagging them
+    /// \Seen. The messages that could not be written to it are kept. Nothing is written by the
+    /// dry runs.
+    ///
+    /// Compressed or encrypted (see --backup-encrypt-to), the file cannot be appended to: a file
+    /// is written next to it per mailbox and run instead, named after it with the mailbox and
+    /// the time, like `archive-INBOX-20240501T100000.mbox.zst` for `archive.mbox.zst`.
+    #[clap(long, value_name = "PATH", env = "IMAP_CLEANUP_BACKUP_MBOX")]
+    backup_mbox: Option<PathBuf>,
+
+    /// Write the messages to this Maildir++ directory before cleaning them, INBOX in the
+    /// directory itself and the other mailboxes in its folders, like `.Lists.rust` for
+    /// `Lists/rust`. Their flags and dates are kept, for the local mail tools to read them.
+    /// The messages that could not be written to it are kept. Nothing is written by the dry runs.
+    #[clap(
+        long,
+        value_name = "DIR",
+        conflicts_with = "backup-mbox",
+        env = "IMAP_CLEANUP_BACKUP_MAILDIR"
+    )]
+    backup_maildir: Option<PathBuf>,
+
+    /// Write each message to a file of this directory before cleaning it, as fetched, named by
+    /// --name-template. The messages that could not be written are kept. Nothing is written by
+    /// the dry runs.
+    #[clap(
+        long,
+        value_name = "DIR",
+        conflicts_with_all = &["backup-mbox", "backup-maildir"],
+        env = "IMAP_CLEANUP_BACKUP_EML"
+    )]
+    backup_eml: Option<PathBuf>,
+
+    /// The names of the files of --backup-eml, `{date}-{from}-{subject}.eml` by default: text
+    /// and the fields {date}, {time}, {from}, {subject}, {mailbox} and {uid}. The characters
+    /// file systems refuse are replaced in the fields, and a number is added to the names of the
+    /// files already there.
+    #[clap(
+        long,
+        value_name = "TEMPLATE",
+        value_parser = backup::parse_template,
+        requires = "backup-eml",
+        env = "IMAP_CLEANUP_NAME_TEMPLATE"
+    )]
+    name_template: Option<backup::Template>,
+
+    /// Encrypt the backups of --backup-mbox and --backup-eml to this recipient before they hit
+    /// the disk: with age for an age or SSH public key, with gpg for the others. The extension
+    /// .age or .gpg is added to the file names when missing. Those ending with .zst, like
+    /// `archive.mbox.zst.age`, are compressed with zstd first. The commands must be installed.
+    #[clap(
+        long,
+        value_name = "RECIPIENT",
+        conflicts_with = "backup-maildir",
+        env = "IMAP_CLEANUP_BACKUP_ENCRYPT_TO"
+    )]
+    backup_encrypt_to: Option<String>,
+
+    /// Clean this many mailboxes at the same time, each on its own connection. The dry runs
+    /// clean them one by one, to keep their output readable.
+    #[clap(
+        long,
+        value_name = "COUNT",
+        default_value_t = 1,
+        value_parser = clap::value_parser!(u32).range(1..),
+        env = "IMAP_CLEANUP_JOBS"
+    )]
+    jobs: u32,
+
+    /// On Ctrl+C, remove the \Deleted flag from the messages of the current mailbox already
+    /// flagged, instead of leaving them for the next expunge.
+    #[clap(long, env = "IMAP_CLEANUP_REVERT_ON_INTERRUPT")]
+    revert_on_interrupt: bool,
+
+    /// How many UIDs to put in each FETCH, STORE, COPY or MOVE command [default: 500, 100 for
+    /// Outlook.com, Office 365 and iCloud]. Lower it for the servers rejecting long commands or
+    /// timing out on big ranges.
+    #[clap(
+        long,
+        value_name = "COUNT",
+        value_parser = clap::value_parser!(u32).range(1..),
+        env = "IMAP_CLEANUP_BATCH_SIZE"
+    )]
+    batch_size: Option<u32>,
+
+    /// Authentication mechanism [default: auto].
+    #[clap(long, value_enum, env = "IMAP_CLEANUP_AUTH")]
+    auth: Option<auth::AuthMethod>,
+
+    #[clap(flatten)]
+    gmail: gmail::GmailArgs,
+
+    #[clap(flatten)]
+    password: password::PasswordArgs,
+
+    #[clap(flatten)]
+    oauth: auth::OAuthArgs,
+
+    #[clap(flatten)]
+    connection: connection::ConnectionArgs,
+
+    #[clap(flatten)]
+    retry: retry::RetryArgs,
+
+    #[clap(flatten)]
+    logging: logging::LoggingArgs,
+
+    #[clap(flatten)]
+    rate: action::RateArgs,
+
+    #[clap(flatten)]
+    lock: lock::LockArgs,
+}
+
+impl Args {
+    /// Fill the options not given on the command line from the configuration file.
+    fn load_config(&mut self) -> Result<()> {
+        if self.account.is_none() && self.profile.is_none() {
+            return Ok(());
+        }
+        let path = match self.config.clone().or_else(config::default_path) {
+            Some(path) => path,
+            None => usage_error(
+                clap::ErrorKind::MissingRequiredArgument,
+                "--config is required with --account or --profile",
+            ),
+        };
+        let config = config::Config::load(&path)?;
+        if let Some(name) = &self.profile {
+            let profile = config.profile(name)?.clone();
+            self.apply_profile(profile);
+        }
+        if let Some(name) = &self.account {
+            let account = config.account(name)?.clone();
+            self.apply_account(account);
+        }
+        Ok(())
+    }
+
+    fn apply_profile(&mut self, profile: config::Profile) {
+        // The messages of a plan are cleaned in its mailboxes, whatever their date.
+        if self.mailboxes.mailbox.is_empty() && self.selection.is_none() {
+            self.mailboxes.mailbox = profile.mailbox;
+        }
+        if self.selection.is_none() {
+            self.before = self
+                .before
+                .or_else(|| {
+                    profile
+                        .before
+                        .and_then(|x| Local.from_local_date(&x).single())
+                })
+                .or_else(|| profile.retention.map(|age| age.before(Local::today())));
+        }
+        self.dry_run |= profile.dry_run.unwrap_or_default();
+        // Those left to their default on the command line.
+        if self.format == output::Format::Text {
+            self.format = profile.format.unwrap_or(self.format);
+        }
+        if self.color == color::Mode::Auto {
+            self.color = profile.color.unwrap_or(self.color);
+        }
+        self.sort = self.sort.or(profile.sort);
+        self.reverse |= profile.reverse.unwrap_or_default();
+    }
+
+    fn apply_account(&mut self, account: config::Account) {
+        self.host = self.host.take().or(account.host);
+        self.port = self.port.or(account.port);
+        self.username = self.username.take().or(account.username);
+        self.auth = self.auth.or(account.auth);
+        if self.mailboxes.mailbox.is_empty() && self.selection.is_none() {
+            self.mailboxes.mailbox = account.mailbox;
+        }
+        if self.selection.is_none() {
+            self.before = self
+                .before
+                .or_else(|| account.retention.map(|age| age.before(Local::today())));
+        }
+        self.password.password_cmd = self.password.password_cmd.take().or(account.password_cmd);
+        if let Some(Command::EmptyTrash { trash, .. }) = &mut self.command {
+            *trash = trash.take().or(account.trash);
+        }
+    }
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// Manage the password saved in the OS keyring (requires the `keyring` feature).
+    #[clap(subcommand)]
+    Auth(AuthCommand),
+    /// Apply the rules of a policy file to their mailboxes, in one connection, or a plan made by
+    /// `plan`. The other options apply to every rule, except those selecting the mailboxes, the
+    /// date and the action.
+    ///
+    /// The rules run in their order, but a rule moving messages to a mailbox runs before the
+    /// rules cleaning it: "INBOX to Archive after 90d" then "delete Archive after 3y" also
+    /// deletes the old messages just moved. The dry run previews them too.
+    ///
+    /// A plan is applied to its messages only, the others found since are kept, and not at all
+    /// when made for another account or when a mailbox got new UIDs since (a new UIDVALIDITY).
+    Apply {
+        /// The policy file: a TOML file with a [[rule]] table per set of mailboxes, with the keys
+        /// mailbox, max-age, larger-than, smaller-than, protect-flag, action and move-to.
+        #[clap(
+            long,
+            value_name = "PATH",
+            required_unless_present = "plan",
+            env = "IMAP_CLEANUP_POLICY"
+        )]
+        policy: Option<PathBuf>,
+
+        /// The plan file written by `plan`.
+        #[clap(value_name = "PLAN", conflicts_with = "policy")]
+        plan: Option<PathBuf>,
+    },
+    /// Remove the duplicates in the mailboxes: the copies of a message with the same Message-ID
+    /// and size. The copy received first is kept and gets the flags of the others. The messages
+    /// are deleted, or moved with --move-to. The search and filter options do not apply.
+    Dedup {
+        /// Also remove the copies of a message in different mailboxes, like INBOX and Archive.
+        #[clap(long, env = "IMAP_CLEANUP_ACROSS_MAILBOXES")]
+        across_mailboxes: bool,
+
+        /// Keep the copy in this mailbox when there is one, the copies elsewhere are removed. It
+        /// is indexed too if not given by --mailbox.
+        #[clap(
+            long,
+            value_name = "MAILBOX",
+            requires = "across-mailboxes",
+            env = "IMAP_CLEANUP_CANONICAL"
+        )]
+        canonical: Option<String>,
+    },
+    /// Connect, authenticate, print the capabilities, open the mailboxes read-only and time the
+    /// round trip to the server, changing nothing: to try the connection and the credentials,
+    /// before a cleanup from cron for example.
+    Check,
+    /// Print the completions for a shell, with the names of the accounts and profiles of the
+    /// configuration file: for bash, `imap-cleanup completions bash >
+    /// ~/.local/share/bash-completion/completions/imap-cleanup`.
+    Completions {
+        /// The shell.
+        #[clap(value_enum)]
+        shell: clap_complete::Shell,
+    },
+    /// Delete the messages of the trash, found with SPECIAL-USE, or only the old ones. The search
+    /// and filter options apply.
+    EmptyTrash {
+        /// The trash when the server does not tell it with SPECIAL-USE.
+        #[clap(long, value_name = "MAILBOX", env = "IMAP_CLEANUP_TRASH")]
+        trash: Option<String>,
+
+        /// Only delete the messages older than this, like 30d, 6w, 3m or 1y.
+        #[clap(
+            long,
+            value_name = "AGE",
+            value_parser(age::Age::from_str),
+            env = "IMAP_CLEANUP_OLDER_THAN"
+        )]
+        older_than: Option<age::Age>,
+    },
+    /// Expunge the messages already flagged \Deleted in the mailboxes, left by other clients that
+    /// never expunge, without searching or flagging anything.
+    Expunge {
+        /// Only expunge these messages with UID EXPUNGE, like `1:100,205` (requires UIDPLUS).
+        #[clap(
+            long,
+            value_name = "UIDS",
+            value_parser = expunge::parse_uid_set,
+            env = "IMAP_CLEANUP_UIDS"
+        )]
+        uids: Option<String>,
+    },
+    /// Print the man page, with the subcommands and the format of the files: `imap-cleanup man >
+    /// imap-cleanup.1`.
+    Man,
+    /// List the mailboxes with their number of messages, unseen messages and size, to see where
+    /// to aim the cleanup: every mailbox, or those given by --mailbox. Without STATUS=SIZE, the
+    /// size of the big mailboxes is estimated from a sample of their messages.
+    List,
+    /// Write what the cleanup would do to a plan file, with the UIDVALIDITY and the UID of each
+    /// message, to review it then `apply` it as is: a dry run.
+    Plan {
+        /// The plan file.
+        #[clap(short, long, value_name = "PATH", env = "IMAP_CLEANUP_OUTPUT")]
+        output: PathBuf,
+    },
+    /// Review the messages to cleanup on a full screen before cleaning them: grouped by mailbox,
+    /// sender or year, they are all cleaned unless kept one by one or by group, and each can be
+    /// read. Nothing changes until the choice is confirmed, the other options apply as for the
+    /// cleanup. Ctrl-L draws the screen again after a resize of the terminal.
+    Review,
+    /// Count the messages of the mailboxes by month or year of their arrival, with their size and
+    /// the totals since the oldest, to see what --before would cleanup.
+    Stats {
+        /// The period of each line.
+        #[clap(long, value_enum, default_value = "month", env = "IMAP_CLEANUP_BY")]
+        by: stats::Period,
+    },
+    /// Print the usage and limit of the quotas of the account. With --warn-at, it fails when one
+    /// reaches the percentage, to monitor it.
+    Quota {
+        /// Fail when a resource of a quota is used at this percentage of its limit or more.
+        #[clap(
+            long,
+            value_name = "PERCENT",
+            value_parser = clap::value_parser!(u8).range(1..=100),
+            env = "IMAP_CLEANUP_WARN_AT"
+        )]
+        warn_at: Option<u8>,
+    },
+    /// List the biggest (or oldest) messages of the mailboxes, with their mailbox, UID, date,
+    /// sender and subject, to find what fills them. By sender or domain, list the senders whose
+    /// messages take the most space instead, to see which newsletters to write rules for.
+    Top {
+        /// How the messages are ranked.
+        #[clap(long, value_enum, default_value = "size", env = "IMAP_CLEANUP_BY")]
+        by: stats::Rank,
+
+        /// The number of messages, or senders, listed.
+        #[clap(
+            short = 'n',
+            long,
+            value_name = "COUNT",
+            default_value_t = 20,
+            env = "IMAP_CLEANUP_COUNT"
+        )]
+        count: usize,
+
+        /// Print a `MAILBOX<TAB>UIDVALIDITY<TAB>UID` line per message instead, to cleanup them
+        /// with --uids-from.
+        #[clap(long, env = "IMAP_CLEANUP_UID_LIST")]
+        uid_list: bool,
+    },
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum AuthCommand {
+    /// Save the password for the host and username in the OS keyring.
+    Store,
+    /// Remove the password for the host and username from the OS keyring.
+    Forget,
+}
+
+fn main() {
+    let args = Args::try_parse().unwrap_or_else(|err| usage_exit(err));
+    color::set(args.color);
+    let result = match args
+        .logging
+        .init()
+        .and_then(|()| args.connection.trace.init())
+    {
+        Err(err) => Err(err),
+        Ok(()) if args.all_accounts => all_accounts(&args),
+        Ok(()) => run(args),
+    };
+    if output::is_json() {
+        output::print(result.as_ref().err());
+    } else if let Err(Error::Partial { .. }) = result {
+        output::failures();
+    }
+    if let Err(err) = result {
+        // An error for the system logger, to alert on the failed cleanups.
+        tracing::error!(target: logging::OUTPUT, "Error: {}", err);
+        let line = color::formatting(true, || format!("{} {}", color::error("Error:"), err));
+        eprintln!("{}", line);
+        std::process::exit(err.exit_code());
+    }
+}
+
+/// Exit on a usage error, or after printing the help. clap exits with 2 on the usage errors, the
+/// status of the authentication failures: they exit with 1 like the other errors instead.
+fn usage_exit(err: clap::Error) -> ! {
+    let _ = err.print();
+    std::process::exit(if err.use_stderr() { 1 } else { 0 });
+}
+
+fn usage_error(kind: clap::ErrorKind, message: impl std::fmt::Display) -> ! {
+    usage_exit(Args::command().error(kind, message))
+}
+
+/// Run for every account of the configuration file, see `accounts::run_all`.
+fn all_accounts(args: &Args) -> Result<()> {
+    let path = match args.config.clone().or_else(config::default_path) {
+        Some(path) => path,
+        None => usage_error(
+            clap::ErrorKind::MissingRequiredArgument,
+            "--config is required with --all-accounts",
+        ),
+    };
+    // The runs cannot ask: their output is piped, and they would all read the terminal at once.
+    if !args.yes && !args.dry_run && std::io::stdin().is_terminal() {
+        usage_error(
+            clap::ErrorKind::MissingRequiredArgument,
+            "--all-accounts cannot ask for confirmations, --yes is required from a terminal",
+        );
+    }
+    let config = config::Config::load(&path)?;
+    if config.account.is_empty() {
+        return Err(Error::Config(format!("{}: no account", path.display())));
+    }
+    // The runs stop gracefully on Ctrl+C, this one waits for them.
+    interrupt::install()?;
+    accounts::run_all(&config.account.keys().cloned().collect::<Vec<_>>())
 }
 
-fn main() -> Result<()> {
-    let args = Args::parse();
-    let password = rpassword::prompt_password("Password: ").unwrap();
-    let tls = native_tls::TlsConnector::builder().build()?;
-    let client = imap::connect((args.host.as_str(), args.port), &args.host, &tls)?;
-    let mut session = client.login(&args.username, password).map_err(|e| e.0)?;
-    cleanup_emails(&mut session, &args.mailbox, args.before, args.dry_run)
+fn run(mut args: Args) -> Result<()> {
+    if let Some(Command::Completions { shell }) = &args.command {
+        let path = args.config.clone().or_else(config::default_path);
+        return completions::print(Args::command(), *shell, path.as_deref());
+    }
+    if let Some(Command::Man) = &args.command {
+        return man::print(Args::command());
+    }
+    let (subcommand, conflicts) = match &args.command {
+        // The rules set them.
+        Some(Command::Apply { .. }) => (
+            "apply",
+            vec![
+                (args.before.is_some(), "--before"),
+                (!args.mailboxes.mailbox.is_empty(), "--mailbox"),
+                (args.mailboxes.all_mailboxes, "--all-mailboxes"),
+                (args.move_to.is_some(), "--move-to"),
+                (args.uids_from.is_some(), "--uids-from"),
+                (args.strip_attachments, "--strip-attachments"),
+                (args.gmail.gmail_remove_label, "--gmail-remove-label"),
+                (args.gmail.gmail_archive, "--gmail-archive"),
+            ],
+        ),
+        Some(Command::Dedup { .. }) => (
+            "dedup",
+            vec![
+                (args.before.is_some(), "--before"),
+                (args.uids_from.is_some(), "--uids-from"),
+                (args.free.free_until.is_some(), "--free-until"),
+                (args.free.free_bytes.is_some(), "--free-bytes"),
+                (args.strip_attachments, "--strip-attachments"),
+                (args.gmail.gmail_remove_label, "--gmail-remove-label"),
+                (args.gmail.gmail_archive, "--gmail-archive"),
+                (args.window.search_window.is_some(), "--search-window"),
+            ],
+        ),
+        // It changes nothing but the expunged messages.
+        Some(Command::Expunge { .. }) => (
+            "expunge",
+            vec![
+                (args.before.is_some(), "--before"),
+                (args.move_to.is_some(), "--move-to"),
+                (args.uids_from.is_some(), "--uids-from"),
+                (args.strip_attachments, "--strip-attachments"),
+                (args.gmail.gmail_remove_label, "--gmail-remove-label"),
+                (args.gmail.gmail_archive, "--gmail-archive"),
+            ],
+        ),
+        // It cleans the trash.
+        Some(Command::EmptyTrash { .. }) => (
+            "empty-trash",
+            vec![
+                (args.before.is_some(), "--before"),
+                (!args.mailboxes.mailbox.is_empty(), "--mailbox"),
+                (args.mailboxes.all_mailboxes, "--all-mailboxes"),
+                (args.move_to.is_some(), "--move-to"),
+                (args.uids_from.is_some(), "--uids-from"),
+                (args.strip_attachments, "--strip-attachments"),
+                (args.gmail.gmail_remove_label, "--gmail-remove-label"),
+                (args.gmail.gmail_archive, "--gmail-archive"),
+            ],
+        ),
+        Some(Command::Review) => (
+            "review",
+            vec![
+                (args.dry_run, "--dry-run"),
+                (args.interactive, "--interactive"),
+            ],
+        ),
+        Some(Command::Plan { .. }) => ("plan", vec![(args.interactive, "--interactive")]),
+        _ => ("", vec![]),
+    };
+    if let Some((_, name)) = conflicts.iter().find(|(given, _)| *given) {
+        usage_error(
+            clap::ErrorKind::ArgumentConflict,
+            format!("{} cannot be used with {}", name, subcommand),
+        );
+    }
+    if let Some(Command::Top {
+        by: stats::Rank::Sender | stats::Rank::Domain,
+        uid_list: true,
+        ..
+    }) = &args.command
+    {
+        usage_error(
+            clap::ErrorKind::ArgumentConflict,
+            "--uid-list cannot be used with --by sender or --by domain",
+        );
+    }
+    if args.interactive && !std::io::stdin().is_terminal() {
+        usage_error(
+            clap::ErrorKind::ArgumentConflict,
+            "--interactive requires a terminal",
+        );
+    }
+    if args.backup_encrypt_to.is_some() && args.backup_mbox.is_none() && args.backup_eml.is_none() {
+        usage_error(
+            clap::ErrorKind::MissingRequiredArgument,
+            "--backup-encrypt-to requires --backup-mbox or --backup-eml",
+        );
+    }
+    let reviewing = matches!(args.command, Some(Command::Review));
+    if reviewing && !(std::io::stdin().is_terminal() && std::io::stderr().is_terminal()) {
+        usage_error(
+            clap::ErrorKind::ArgumentConflict,
+            "review requires a terminal",
+        );
+    }
+    let cleans = matches!(
+        args.command,
+        None | Some(Command::Apply { .. } | Command::EmptyTrash { .. })
+    );
+    let planning = matches!(args.command, Some(Command::Plan { .. }));
+    // The messages planned, cleaned like with --uids-from.
+    let planned = match &args.command {
+        Some(Command::Apply {
+            plan: Some(path), ..
+        }) => Some(plan::Plan::load(path)?),
+        _ => None,
+    };
+    if let Some(planned) = &planned {
+        args.command = None;
+        args.selection = Some(planned.uid_list());
+        set_action(&mut args, planned.action.clone());
+    }
+    args.load_config()?;
+    color::set(args.color);
+    // Also when given by the profile.
+    if args.sort.is_some() && !args.dry_run && args.cap.max_bytes.is_none() {
+        usage_error(
+            clap::ErrorKind::MissingRequiredArgument,
+            "--sort requires --dry-run or --max-bytes",
+        );
+    }
+    if args.format == output::Format::Json && !cleans {
+        usage_error(
+            clap::ErrorKind::ArgumentConflict,
+            "--format json only applies to the cleanup, apply and empty-trash",
+        );
+    }
+    // The candidates are found by a dry run first.
+    if reviewing || planning {
+        args.dry_run = true;
+    }
+    output::set(args.format, args.dry_run);
+    if args.connection.tunnel.is_none() {
+        for (value, name) in [(&args.host, "--host"), (&args.username, "--username")] {
+            if value.is_none() {
+                usage_error(
+                    clap::ErrorKind::MissingRequiredArgument,
+                    format!("{} is required unless given by --account or --tunnel", name),
+                );
+            }
+        }
+    }
+    let host = args.host.as_deref().unwrap_or_default();
+    let username = args.username.as_deref().unwrap_or_default();
+
+    match &args.command {
+        Some(Command::Auth(AuthCommand::Store)) => {
+            return password::store(host, username, &args.password.read()?);
+        }
+        Some(Command::Auth(AuthCommand::Forget)) => return password::forget(host, username),
+        Some(
+            Command::Apply { .. }
+            | Command::Check
+            | Command::Completions { .. }
+            | Command::Dedup { .. }
+            | Command::EmptyTrash { .. }
+            | Command::Expunge { .. }
+            | Command::List
+            | Command::Man
+            | Command::Plan { .. }
+            | Command::Review
+            | Command::Stats { .. }
+            | Command::Quota { .. }
+            | Command::Top { .. },
+        )
+        | None => {}
+    }
+
+    let today = Local::today();
+    // Spam is cleaned even when flagged, only the flags asked explicitly are protected.
+    if args.filter.min_spam_score.is_some() && args.search.protect_flag.is_empty() {
+        args.search.no_protect_flag = true;
+    }
+    let rules = match &args.command {
+        Some(Command::Apply {
+            policy: Some(policy),
+            ..
+        }) => {
+            let rules = policy::Policy::load(policy)?.rule;
+            for (i, rule) in rules.iter().enumerate() {
+                rule.search(&args.search)
+                    .validate(&rule.max_age.before(today))
+                    .map_err(|err| {
+                        Error::Config(format!("{}: rule {}: {}", policy.display(), i + 1, err))
+                    })?;
+            }
+            Some(rules)
+        }
+        _ => None,
+    };
+    let before = match (args.before, &args.command) {
+        // Not the retention of the account.
+        (_, Some(Command::EmptyTrash { older_than, .. })) => {
+            older_than.map_or(today.succ(), |age| age.before(today))
+        }
+        (Some(before), _) => before,
+        // Each rule has its own date, the other subcommands have none.
+        (
+            None,
+            Some(
+                Command::Apply { .. }
+                | Command::Check
+                | Command::Completions { .. }
+                | Command::Dedup { .. }
+                | Command::Expunge { .. }
+                | Command::List
+                | Command::Man
+                | Command::Stats { .. }
+                | Command::Quota { .. }
+                | Command::Top { .. },
+            ),
+        ) => today,
+        // The messages of the list, or those freeing the space, are cleaned whatever their date.
+        (None, None | Some(Command::Plan { .. } | Command::Review))
+            if args.uids_from.is_some() || args.selection.is_some() || args.free.is_used() =>
+        {
+            today.succ()
+        }
+        (None, _) => usage_error(
+            clap::ErrorKind::MissingRequiredArgument,
+            "--before is required to cleanup",
+        ),
+    };
+    if let Err(err) = args.search.validate(&before) {
+        usage_error(clap::ErrorKind::ValueValidation, err);
+    }
+    if args.gmail.gmail_archive
+        && (args.mailboxes.all_mailboxes
+            || args
+                .mailboxes
+                .mailbox
+                .iter()
+                .any(|x| !x.eq_ignore_ascii_case("INBOX")))
+    {
+        usage_error(
+            clap::ErrorKind::ArgumentConflict,
+            "--gmail-archive only cleans INBOX",
+        );
+    }
+    if args.search.include_flagged
+        && !args.dry_run
+        && !args.yes
+        && !confirm("Flagged messages will be cleaned too, continue?")?
+    {
+        return Err(Error::Aborted);
+    }
+    let keep_senders = args.senders.load()?;
+    // Those of a plan are cleaned in its mailboxes, the only ones.
+    let selected = args.selection.is_some();
+    let uid_list = match args.selection.take() {
+        Some(selection) => Some(selection),
+        None => args
+            .uids_from
+            .as_deref()
+            .map(uidlist::UidList::load)
+            .transpose()?,
+    };
+    let contacts = args.contacts.load()?;
+    let export = args
+        .export_csv
+        .as_deref()
+        .map(export::Export::create)
+        .transpose()?;
+    // The messages chosen by the review are cleaned by this run.
+    let changes = !args.dry_run || reviewing;
+    let audit = match (&args.audit_log, changes) {
+        (Some(path), true) => Some(audit::AuditLog::open(path, account(&args, host, username))?),
+        _ => None,
+    };
+    let recipient = args.backup_encrypt_to.as_deref();
+    let backup = match (&args.backup_mbox, &args.backup_maildir, &args.backup_eml) {
+        _ if !changes => None,
+        (Some(path), _, _) => Some(backup::Backup::mbox(path, recipient)?),
+        (_, Some(path), _) => Some(backup::Backup::maildir(path)?),
+        (_, _, Some(path)) => Some(backup::Backup::eml(
+            path,
+            args.name_template.clone(),
+            recipient,
+        )?),
+        _ => None,
+    };
+    let port = args.port.unwrap_or_else(|| args.connection.default_port());
+    // The dry runs change nothing.
+    let locker = match changes {
+        false => lock::Locker::default(),
+        true => lock::Locker::new(&args.lock, username, host)?,
+    };
+    let tap = Tap::default();
+    // Also used to resume the cleanup of a mailbox after losing the connection, and for the
+    // connections of --jobs.
+    let connect = |tap: &Tap| {
+        args.retry.run("connecting", || {
+            let password_env = args.password.password_env.as_deref();
+            let connection = connection::connect(host, port, &args.connection, password_env, tap)?;
+            let compression = connection.compression.clone();
+            let mut session = if connection.preauth {
+                connection::preauthenticated(connection.client)?
+            } else {
+                let target = auth::Target {
+                    user: username,
+                    host,
+                    port,
+                };
+                auth::authenticate(
+                    connection.client,
+                    args.auth.unwrap_or(auth::AuthMethod::Auto),
+                    &connection.capabilities,
+                    &target,
+                    &args.oauth,
+                    &args.password,
+                )?
+            };
+            tracing::info!(user = username, "logged in");
+            if !args.connection.no_compress {
+                compression.start(&mut session)?;
+            }
+            Ok(session)
+        })
+    };
+    let started = Instant::now();
+    let mut session = connect(&tap)?;
+    if let Some(planned) = &planned {
+        planned.validate(&mut session, &account(&args, host, username))?;
+    }
+    if let Some(Command::Check) = &args.command {
+        let mailboxes = args.mailboxes.resolve(&mut session, &tap)?;
+        return check::check(&mut session, &tap, &mailboxes, started.elapsed());
+    }
+    let extensions = Extensions::query(&mut session)?;
+    let batch_size = args
+        .batch_size
+        .map_or_else(|| default_batch_size(host), |x| x as usize);
+    let limiter = Limiter::new(&args.rate);
+    BATCH_SIZE.store(limiter.batch_size(batch_size), Ordering::Relaxed);
+    if args.gmail.is_used() && !extensions.gmail {
+        return Err(Error::Protocol(format!(
+            "the --gmail-* options require a Gmail server ({})",
+            gmail::CAPABILITY
+        )));
+    }
+    if let Some(Command::List) = &args.command {
+        let mailboxes = if args.mailboxes.mailbox.is_empty() && !args.mailboxes.all_mailboxes {
+            mailbox::list(&mut session, "*")?
+                .into_iter()
+                .filter(mailbox::Mailbox::is_selectable)
+                .map(|x| x.name)
+                .collect()
+        } else {
+            args.mailboxes.resolve(&mut session, &tap)?
+        };
+        return stats::list(&mut session, &tap, &mailboxes);
+    }
+    if let Some(Command::Quota { warn_at }) = &args.command {
+        return quota::quota(&mut session, &tap, *warn_at);
+    }
+    if let Some(Command::Stats { by }) = &args.command {
+        let mailboxes = args.mailboxes.resolve(&mut session, &tap)?;
+        return stats::histogram(&mut session, &mailboxes, *by);
+    }
+    if let Some(Command::Top {
+        by,
+        count,
+        uid_list,
+    }) = &args.command
+    {
+        let mailboxes = args.mailboxes.resolve(&mut session, &tap)?;
+        return stats::top(&mut session, &mailboxes, *by, *count, *uid_list);
+    }
+    if !args.dry_run {
+        interrupt::install()?;
+    }
+    if let Some(Command::Expunge { uids }) = &args.command {
+        let mailboxes = args.mailboxes.resolve(&mut session, &tap)?;
+        return expunge::run(
+            &mut session,
+            &tap,
+            &locker,
+            extensions,
+            &mailboxes,
+            uids.as_deref(),
+            args.dry_run,
+        );
+    }
+    if let Some(Command::Dedup {
+        across_mailboxes,
+        canonical,
+    }) = &args.command
+    {
+        let mut mailboxes = args.mailboxes.resolve(&mut session, &tap)?;
+        if let Some(canonical) = canonical {
+            if !mailboxes.contains(canonical) {
+                mailboxes.insert(0, canonical.clone());
+            }
+        }
+        let action = match &args.move_to {
+            Some(move_to) => Action::Move(move_to.clone()),
+            None => Action::Delete,
+        };
+        skip_destination(&mut mailboxes, &action);
+        let dedup = dedup::Dedup {
+            action,
+            limiter: &limiter,
+            extensions,
+            across_mailboxes: *across_mailboxes,
+            canonical: canonical.clone(),
+            dry_run: args.dry_run,
+        };
+        let _locks = mailboxes
+            .iter()
+            .map(|x| locker.lock(x))
+            .collect::<Result<Vec<_>>>()?;
+        let result = dedup.run(&mut session, &tap, &mailboxes);
+        if let Err(Error::Interrupted { .. }) = result {
+            logout(&mut session);
+        }
+        return result;
+    }
+    let budget = args.free.budget(&mut session, &tap)?;
+    let cap = args.cap.cap();
+    let size_cap = args.cap.size_cap(args.sort, args.reverse);
+    let interactive = args.interactive.then(interactive::Interactive::default);
+    let review = reviewing.then(review::Review::default);
+    let planner = planning.then(plan::Planner::default);
+    if budget.as_ref().is_some_and(|x| x.left() == 0) {
+        say!("Nothing to free.");
+        return Ok(());
+    }
+    let cleanup = |search: &search::SearchArgs, before: Date<Local>, action: Action| {
+        let mut filter = args.filter.clone();
+        if action == Action::StripAttachments {
+            filter.with_attachments = true;
+        }
+        let retention = args.lists.retention(today, before, search.date_source);
+        let search_before = retention
+            .as_ref()
+            .map_or(before, lists::Retention::search_before);
+        let mut query = search.query(&search_before);
+        for criterion in args.gmail.criteria() {
+            query.criterion(criterion);
+        }
+        Cleanup {
+            query: query.build(),
+            filter,
+            retention,
+            keep_senders: keep_senders.as_ref(),
+            contacts: contacts.as_ref(),
+            active_threads: args.threads.active_threads(today),
+            windows: args
+                .window
+                .windows(search.date_source, search.after, search_before),
+            action,
+            uid_list: uid_list.as_ref(),
+            budget: budget.as_ref(),
+            interactive: interactive.as_ref(),
+            review: review.as_ref(),
+            plan: planner.as_ref(),
+            cap: cap.as_ref(),
+            size_cap: size_cap.as_ref(),
+            export: export.as_ref(),
+            audit: audit.as_ref(),
+            backup: backup.as_ref(),
+            limiter: &limiter,
+            locker: &locker,
+            extensions,
+            dry_run: args.dry_run,
+            confirm: !args.dry_run
+                && !args.yes
+                && !args.interactive
+                && std::io::stdin().is_terminal(),
+            before,
+            revert_on_interrupt: args.revert_on_interrupt,
+            found: AtomicU64::default(),
+            sort: args.sort,
+            reverse: args.reverse,
+        }
+    };
+    let mut jobs = Vec::new();
+    let mut order = vec![0];
+    match &rules {
+        Some(rules) => {
+            for rule in rules {
+                let mut mailboxes =
+                    args.mailboxes
+                        .resolve_specs(&mut session, &tap, &rule.mailbox)?;
+                skip_destination(&mut mailboxes, &rule.action);
+                let search = rule.search(&args.search);
+                let before = rule.max_age.before(today);
+                jobs.push((mailboxes, cleanup(&search, before, rule.action.clone())));
+            }
+            order = policy::order(
+                &jobs
+                    .iter()
+                    .map(|(mailboxes, cleanup)| (mailboxes.as_slice(), &cleanup.action))
+                    .collect::<Vec<_>>(),
+            )
+            .ok_or_else(|| {
+                Error::Config("the moves of the rules make a cycle between mailboxes".to_string())
+            })?;
+        }
+        None => {
+            let mut mailboxes = match (&args.command, &uid_list) {
+                (Some(Command::EmptyTrash { trash, .. }), _) => {
+                    vec![mailbox::trash(&mut session, trash.as_deref())?]
+                }
+                (_, Some(uid_list))
+                    if selected
+                        || args.mailboxes.mailbox.is_empty() && !args.mailboxes.all_mailboxes =>
+                {
+                    uid_list.mailboxes()
+                }
+                (_, _) => args.mailboxes.resolve(&mut session, &tap)?,
+            };
+            let action = match &args.move_to {
+                _ if args.gmail.gmail_archive => Action::GmailArchive,
+                _ if args.strip_attachments => Action::StripAttachments,
+                _ if args.gmail.gmail_remove_label => {
+                    Action::RemoveLabel(args.gmail.gmail_label.clone().unwrap_or_default())
+                }
+                Some(move_to) => Action::Move(move_to.clone()),
+                None => Action::Delete,
+            };
+            skip_destination(&mut mailboxes, &action);
+            jobs.push((mailboxes, cleanup(&args.search, before, action)));
+        }
+    }
+    // Deleting from the trash of Gmail is for good.
+    if extensions.gmail
+        && !matches!(args.command, Some(Command::EmptyTrash { .. }))
+        && jobs
+            .iter()
+            .any(|(_, cleanup)| matches!(cleanup.action, Action::Delete | Action::StripAttachments))
+    {
+        tell!(
+            "{} on Gmail, deleting from a label only removes the label: unless the account is \
+             set otherwise, the messages stay in All Mail.",
+            yellow("Note:")
+        );
+    }
+
+    let pool = Pool {
+        connect: &connect,
+        jobs: args.jobs as usize,
+        idle: Mutex::default(),
+    };
+    let mut total = 0;
+    let mut failed = 0;
+    let mut moved = Moved::new();
+    let mut summary = Vec::new();
+    let mut interrupted = None;
+    for i in order {
+        let (mailboxes, cleanup) = &jobs[i];
+        let rule = rules
+            .as_ref()
+            .map(|rules| format!("Rule {} ({})", i + 1, rules[i].mailbox.join(", ")));
+        if let Some(rule) = &rule {
+            say!("{}:", bold(rule));
+        }
+        output::rule(rules.as_ref().map(|_| i));
+        let result = match cleanup_emails(&mut session, &tap, &pool, mailboxes, cleanup, &mut moved)
+        {
+            Ok(count) if cleanup.dry_run => {
+                let found = cleanup.found.load(Ordering::Relaxed);
+                format!(
+                    "{} not {}{} (dry run)",
+                    count,
+                    cleanup.action.done(),
+                    would_free(cleanup, found)
+                )
+            }
+            Ok(count) => format!("{} {}", count, cleanup.action.done()),
+            // The other rules may still work.
+            Err(Error::Partial {
+                failed: rule_failed,
+                total: rule_total,
+            }) => {
+                failed += rule_failed;
+                format!("{} of {} mailboxes failed", rule_failed, rule_total)
+            }
+            Err(Error::Interrupted { done }) => {
+                interrupted = Some(done);
+                format!("{} {}, interrupted", done, cleanup.action.done())
+            }
+            Err(err) => return Err(err),
+        };
+        total += mailboxes.len();
+        summary.extend(rule.map(|rule| format!("{}: {}.", rule, result)));
+        if interrupted.is_some() {
+            break;
+        }
+    }
+    if summary.len() > 1 {
+        say!("{}", bold("Summary:"));
+        for line in summary {
+            say!("{}", line);
+        }
+        let found = jobs
+            .iter()
+            .filter(|(_, cleanup)| cleanup.action.frees())
+            .map(|(_, cleanup)| cleanup.found.load(Ordering::Relaxed))
+            .sum::<u64>();
+        if args.dry_run && found > 0 {
+            say!(
+                "{}",
+                bold(format!(
+                    "Would free ~{} in total (dry run).",
+                    search::format_size(found)
+                ))
+            );
+        }
+    }
+    if let Some(left) = budget.as_ref().map(free::Budget::left) {
+        if left > 0 && interrupted.is_none() {
+            tell!(
+                "{} {} still to free, not enough messages were found.",
+                yellow("Warning:"),
+                search::format_size(left)
+            );
+        }
+    }
+    if let Some(done) = interrupted {
+        logout(&mut session);
+        return Err(Error::Interrupted { done });
+    }
+    if failed > 0 {
+        return Err(Error::Partial { failed, total });
+    }
+    // What the cleanup reviewed does, the only one.
+    let action = jobs.pop().map(|(_, cleanup)| cleanup.action);
+    drop(jobs);
+    if let (Some(review), Some(action)) = (&review, action.clone()) {
+        let selection = review.run(&mut session, &action)?;
+        if selection.is_empty() {
+            say!("Nothing to clean.");
+            return Ok(());
+        }
+        // The messages chosen, cleaned on the same connection in their mailboxes only.
+        interrupt::install()?;
+        output::set(args.format, false);
+        let mut mailboxes = selection.mailboxes();
+        skip_destination(&mut mailboxes, &action);
+        let cleanup = reviewed(cleanup(&args.search, before, action), &selection);
+        let result = cleanup_emails(&mut session, &tap, &pool, &mailboxes, &cleanup, &mut moved);
+        if let Err(Error::Interrupted { .. }) = result {
+            logout(&mut session);
+        }
+        result?;
+    }
+    if let (Some(planner), Some(Command::Plan { output }), Some(action)) =
+        (planner, &args.command, &action)
+    {
+        let plan = planner.plan(account(&args, host, username), action.clone());
+        plan.write(output)?;
+        say!(
+            "Planned {} messages in {} mailboxes: {}.",
+            plan.count(),
+            plan.mailboxes.len(),
+            output.display()
+        );
+    }
+    Ok(())
+}
+
+/// The name of the account for the audit log and the plans: the host is not known with --tunnel.
+fn account(args: &Args, host: &str, username: &str) -> String {
+    match (&args.account, host) {
+        (Some(name), _) => name.clone(),
+        (None, "") => username.to_string(),
+        (None, host) => format!("{}@{}", username, host),
+    }
+}
+
+/// Set the options of an action, that of a plan.
+fn set_action(args: &mut Args, action: Action) {
+    match action {
+        Action::Delete => {}
+        Action::Move(mailbox) => args.move_to = Some(mailbox),
+        Action::RemoveLabel(label) => {
+            args.gmail.gmail_remove_label = true;
+            args.gmail.gmail_label = Some(label);
+        }
+        Action::GmailArchive => args.gmail.gmail_archive = true,
+        Action::StripAttachments => args.strip_attachments = true,
+    }
+}
+
+/// Log out, to leave the session in a known state after Ctrl+C. The server may have dropped it
+/// already.
+fn logout<S: Read + Write>(session: &mut Session<S>) {
+    if let Err(err) = session.logout() {
+        tell!("{} could not log out: {}", yellow("Warning:"), err);
+    }
 }
 
-fn parse_date(s: &str) -> chrono::ParseResult<Date<Local>> {
-    Ok(Local
-        .from_local_date(&NaiveDate::parse_from_str(s, "%Y-%m-%d")?)
-        .unwrap())
+/// The cleanup of the messages chosen by `review`: confirmed already, and within the limits of
+/// the dry run that found them, used up by it.
+fn reviewed<'a>(mut cleanup: Cleanup<'a>, selection: &'a uidlist::UidList) -> Cleanup<'a> {
+    cleanup.uid_list = Some(selection);
+    cleanup.review = None;
+    cleanup.cap = None;
+    cleanup.size_cap = None;
+    cleanup.budget = None;
+    cleanup.dry_run = false;
+    cleanup.confirm = false;
+    cleanup
 }
 
-fn cleanup_emails<S: Read + Write, Tz: TimeZone>(
+/// Skip the destination of a move, the messages would be moved again.
+fn skip_destination(mailboxes: &mut Vec<String>, action: &Action) {
+    if let Action::Move(move_to) = action {
+        if mailboxes.contains(move_to) {
+            tell!(
+                "{} {}, the destination of the moved messages.",
+                yellow("Skipping"),
+                move_to
+            );
+            mailboxes.retain(|x| x != move_to);
+        }
+    }
+}
+
+/// What to do in each mailbox.
+struct Cleanup<'a> {
+    /// The SEARCH query selecting the messages.
+    query: String,
+    /// Applied to the messages found by the query.
+    filter: filter::FilterArgs,
+    /// The retention of the mailing lists, applied after the filters.
+    retention: Option<lists::Retention>,
+    /// The senders whose messages are kept out.
+    keep_senders: Option<&'a senders::KeepSenders>,
+    /// The contacts whose correspondence is kept out.
+    contacts: Option<&'a contacts::Contacts>,
+    /// The conversations whose messages are kept out.
+    active_threads: Option<threads::ActiveThreads>,
+    /// The windows of the search with --search-window.
+    windows: Option<window::Windows>,
+    action: Action,
+    /// The messages of --uids-from, the only ones cleaned.
+    uid_list: Option<&'a uidlist::UidList>,
+    /// What is left to free with --free-until or --free-bytes, the messages beyond are kept.
+    budget: Option<&'a free::Budget>,
+    /// The choices of --interactive, the messages not chosen are kept.
+    interactive: Option<&'a interactive::Interactive>,
+    /// The candidates of `review`, found by the dry run instead of listed.
+    review: Option<&'a review::Review>,
+    /// The messages found by the dry run of `plan`.
+    plan: Option<&'a plan::Planner>,
+    /// What is left to clean with --max-delete.
+    cap: Option<&'a cap::Cap>,
+    /// What is left to clean with --max-bytes.
+    size_cap: Option<&'a cap::SizeCap>,
+    /// The CSV file of --export-csv, for the dry runs.
+    export: Option<&'a export::Export>,
+    /// The file of --audit-log, the messages are appended to once cleaned.
+    audit: Option<&'a audit::AuditLog>,
+    /// The file of --backup-mbox or the directory of --backup-maildir or --backup-eml, the
+    /// messages are written to before being cleaned.
+    backup: Option<&'a backup::Backup>,
+    limiter: &'a Limiter,
+    /// Locks each mailbox while cleaned.
+    locker: &'a lock::Locker,
+    extensions: Extensions,
+    dry_run: bool,
+    /// Ask to type the name of each mailbox before cleaning it.
+    confirm: bool,
+    /// The date the messages are cleaned before, for the confirmation.
+    before: Date<Local>,
+    revert_on_interrupt: bool,
+    /// The size of the messages found by the dry run so far, in bytes.
+    found: AtomicU64,
+    /// The order of the messages listed by the dry run, reversed or not.
+    sort: Option<preview::Sort>,
+    reverse: bool,
+}
+
+/// The estimate of the space a dry run would free, to append to its counts.
+fn would_free(cleanup: &Cleanup, found: u64) -> String {
+    match cleanup.dry_run && cleanup.action.frees() && found > 0 {
+        true => format!(", would free ~{}", search::format_size(found)),
+        false => String::new(),
+    }
+}
+
+/// Ask a yes/no question when run from a terminal, the answer is yes otherwise.
+fn confirm(question: &str) -> Result<bool> {
+    if !std::io::stdin().is_terminal() {
+        return Ok(true);
+    }
+    eprint!("{} [y/N] ", question);
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+/// Show what is about to be done in a mailbox and ask to type its name to go on, when run from a
+/// terminal without --yes. Anything else aborts the run.
+fn confirm_mailbox<S: Read + Write>(
     session: &mut Session<S>,
     mailbox: &str,
-    before: Date<Tz>,
-    dry_run: bool,
+    cleanup: &Cleanup,
+    uids: &[u32],
 ) -> Result<()> {
-    let _ = session.select(mailbox)?;
-    let mut uids = session
-        .search(
-            before
-                .naive_utc()
-                .format("BEFORE %-e-%b-%Y NOT FLAGGED")
-                .to_string(),
-        )?
-        .into_iter()
-        .collect::<Vec<_>>();
-    uids.sort();
+    // One question at a time with --jobs.
+    static QUESTION: Mutex<()> = Mutex::new(());
+    if !cleanup.confirm || uids.is_empty() {
+        return Ok(());
+    }
+    let mut size = 0;
+    for (set, _) in batches(uids) {
+        for message in session.uid_fetch(set, "RFC822.SIZE")?.iter() {
+            size += u64::from(message.size.unwrap_or_default());
+        }
+    }
+    let _question = QUESTION.lock().unwrap();
+    let question = color::formatting(true, || {
+        format!(
+            "{}: {} messages ({}) before {} will be {}.\nType the name of the mailbox to go \
+             on: ",
+            bold(mailbox),
+            uids.len(),
+            search::format_size(size),
+            cleanup.before.format("%Y-%m-%d"),
+            cleaned(cleanup.action.done(), cleanup.action.frees())
+        )
+    });
+    eprint!("{}", question);
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    interrupt::check()?;
+    if answer.trim_end_matches(['\r', '\n']) != mailbox {
+        return Err(Error::Aborted);
+    }
+    Ok(())
+}
+
+/// The messages a dry run would have moved, by destination: their mailbox and UIDs. They are not
+/// there yet for the next rules, which preview them where they are.
+type Moved = BTreeMap<String, Vec<(String, Vec<u32>)>>;
+
+/// The connections for --jobs, opened when needed and kept for the next rules.
+struct Pool<'a, S: Read + Write> {
+    connect: &'a (dyn Fn(&Tap) -> Result<Session<S>> + Sync),
+    jobs: usize,
+    idle: Mutex<Vec<(Session<S>, Tap)>>,
+}
+
+impl<S: Read + Write> Pool<'_, S> {
+    /// An idle connection still alive, or a new one.
+    fn take(&self) -> Result<(Session<S>, Tap)> {
+        loop {
+            // Not locked during the NOOP, which may wait for a dead connection.
+            let idle = self.idle.lock().unwrap().pop();
+            let Some((mut session, tap)) = idle else {
+                break;
+            };
+            if session.noop().is_ok() {
+                return Ok((session, tap));
+            }
+        }
+        let tap = Tap::default();
+        let session = (self.connect)(&tap)?;
+        Ok((session, tap))
+    }
+
+    fn put(&self, session: Session<S>, tap: Tap) {
+        self.idle.lock().unwrap().push((session, tap));
+    }
+}
+
+/// The results of the mailboxes cleaned by `cleanup_emails`, told as they come.
+#[derive(Default)]
+struct Results {
+    total: usize,
+    failed: usize,
+    /// The number of mailboxes done, successfully or not.
+    mailboxes: usize,
+    interrupted: bool,
+    /// The first error stopping the cleanup.
+    error: Option<Error>,
+    /// The size of the messages found by the dry run in the mailboxes reported.
+    found: u64,
+}
+
+impl Results {
+    /// Report the result of a mailbox, returns the UIDs cleaned when it succeeded.
+    fn add(
+        &mut self,
+        mailbox: &str,
+        cleanup: &Cleanup,
+        result: Result<Vec<u32>>,
+    ) -> Option<Vec<u32>> {
+        self.mailboxes += 1;
+        let done = cleanup.action.done();
+        let found = self.found(cleanup);
+        match result {
+            Ok(uids) => {
+                output::done(mailbox, None, &done, &uids, found);
+                if cleanup.dry_run {
+                    say!(
+                        "{}: {}{} (dry run).",
+                        mailbox,
+                        cleaned(
+                            format!("{} not {}", uids.len(), done),
+                            cleanup.action.frees()
+                        ),
+                        would_free(cleanup, found)
+                    );
+                } else {
+                    say!(
+                        "{}: {}.",
+                        mailbox,
+                        cleaned(format!("{} {}", uids.len(), done), cleanup.action.frees())
+                    );
+                }
+                self.total += uids.len();
+                return Some(uids);
+            }
+            Err(Error::Locked(reason)) => {
+                tell!("{}: {} {}", mailbox, yellow("skipped:"), reason);
+                output::failed(mailbox, &done, output::Status::Skipped, 0, Some(reason));
+                self.failed += 1;
+            }
+            Err(Error::Interrupted { done: count }) => {
+                output::failed(mailbox, &done, output::Status::Interrupted, count, None);
+                self.total += count;
+                self.interrupted = true;
+            }
+            Err(err) if err.stops() => {
+                let error = Some(err.to_string());
+                output::failed(mailbox, &done, output::Status::Failed, 0, error);
+                self.error.get_or_insert(err);
+            }
+            // The server refused something for this mailbox, the others may still work.
+            Err(err) => {
+                tell!("{}: {} {}", mailbox, red("failed:"), err);
+                let error = Some(err.to_string());
+                output::failed(mailbox, &done, output::Status::Failed, 0, error);
+                self.failed += 1;
+            }
+        }
+        None
+    }
+
+    /// The size of the messages found by the dry run since the last mailbox reported.
+    fn found(&mut self, cleanup: &Cleanup) -> u64 {
+        let found = cleanup.found.load(Ordering::Relaxed);
+        found - std::mem::replace(&mut self.found, found)
+    }
+
+    /// Whether to stop cleaning the mailboxes.
+    fn stopped(&self) -> bool {
+        self.interrupted || self.error.is_some()
+    }
+}
+
+/// Cleanup the mailboxes and return the number of messages the action was applied to (or would
+/// be). With --jobs, the mailboxes are cleaned at the same time on the connections of the pool.
+fn cleanup_emails<S: Read + Write + Send>(
+    session: &mut Session<S>,
+    tap: &Tap,
+    pool: &Pool<S>,
+    mailboxes: &[String],
+    cleanup: &Cleanup,
+    moved: &mut Moved,
+) -> Result<usize> {
+    let done = cleanup.action.done();
+    let dry_run = cleanup.dry_run;
     if dry_run {
-        for range in ranges(&uids) {
-            let fetch = session.fetch(
-                format!("{}:{}", range.start(), range.end()),
-                "(INTERNALDATE FLAGS)",
-            )?;
+        say!("Search: {}", cleanup.query);
+    }
+    let record = |moved: &mut Moved, mailbox: &str, uids: Vec<u32>| {
+        if let (true, Action::Move(move_to)) = (dry_run, &cleanup.action) {
+            if !uids.is_empty() {
+                moved
+                    .entry(move_to.clone())
+                    .or_default()
+                    .push((mailbox.to_string(), uids));
+            }
+        }
+    };
+    let mut results = Results::default();
+    if pool.jobs > 1 && mailboxes.len() > 1 && !dry_run {
+        cleanup_parallel(session, tap, pool, mailboxes, cleanup, |mailbox, result| {
+            results.add(mailbox, cleanup, result);
+            !results.stopped()
+        });
+    } else {
+        let reconnect = || (pool.connect)(tap);
+        for mailbox in mailboxes {
+            let only = cleanup.uid_list.map(|x| x.uids(mailbox));
+            let result = cleanup_mailbox(session, tap, &reconnect, mailbox, cleanup, only);
+            if let Some(uids) = results.add(mailbox, cleanup, result) {
+                record(moved, mailbox, uids);
+            }
+            if results.stopped() {
+                break;
+            }
+            let incoming = moved.get(mailbox).cloned().unwrap_or_default();
+            for (source, uids) in incoming {
+                let uids =
+                    cleanup_mailbox(session, tap, &reconnect, &source, cleanup, Some(&uids))?;
+                let found = results.found(cleanup);
+                output::done(mailbox, Some(&source), &done, &uids, found);
+                say!(
+                    "{}: {}{} (dry run).",
+                    mailbox,
+                    cleaned(
+                        format!("{} moved from {} not {}", uids.len(), source, done),
+                        cleanup.action.frees()
+                    ),
+                    would_free(cleanup, found)
+                );
+                results.total += uids.len();
+                record(moved, &source, uids);
+            }
+        }
+    }
+    if let Some(err) = results.error {
+        return Err(err);
+    }
+    if results.interrupted {
+        if mailboxes.len() > 1 {
+            say!(
+                "{}",
+                bold(format!(
+                    "Total: {} {} in {} of {} mailboxes, interrupted.",
+                    results.total,
+                    done,
+                    results.mailboxes,
+                    mailboxes.len()
+                ))
+            );
+        }
+        return Err(Error::Interrupted {
+            done: results.total,
+        });
+    }
+    if mailboxes.len() > 1 {
+        if dry_run {
+            say!(
+                "{}",
+                bold(format!(
+                    "Total: {} not {} in {} mailboxes{} (dry run).",
+                    results.total,
+                    done,
+                    mailboxes.len(),
+                    would_free(cleanup, results.found)
+                ))
+            );
+        } else {
+            say!(
+                "{}",
+                bold(format!(
+                    "Total: {} {} in {} mailboxes.",
+                    results.total,
+                    done,
+                    mailboxes.len()
+                ))
+            );
+        }
+    }
+    if results.failed > 0 {
+        return Err(Error::Partial {
+            failed: results.failed,
+            total: mailboxes.len(),
+        });
+    }
+    Ok(results.total)
+}
+
+/// Cleanup the mailboxes on up to --jobs connections at the same time: this one and the others
+/// of the pool, each taking the next mailbox when done with one. The result of each mailbox is
+/// given to `done` from this thread, the mailboxes left are not started once it returns false.
+fn cleanup_parallel<S: Read + Write + Send>(
+    session: &mut Session<S>,
+    tap: &Tap,
+    pool: &Pool<S>,
+    mailboxes: &[String],
+    cleanup: &Cleanup,
+    mut done: impl FnMut(&str, Result<Vec<u32>>) -> bool,
+) {
+    let next = AtomicUsize::new(0);
+    let stop = AtomicBool::new(false);
+    let work = |session: &mut Session<S>, tap: &Tap, sender: &mpsc::Sender<_>| {
+        let reconnect = || (pool.connect)(tap);
+        while !stop.load(Ordering::Relaxed) {
+            let i = next.fetch_add(1, Ordering::Relaxed);
+            let mailbox = match mailboxes.get(i) {
+                Some(mailbox) => mailbox,
+                None => break,
+            };
+            let only = cleanup.uid_list.map(|x| x.uids(mailbox));
+            let result = cleanup_mailbox(session, tap, &reconnect, mailbox, cleanup, only);
+            if sender.send((i, result)).is_err() {
+                break;
+            }
+        }
+    };
+    std::thread::scope(|scope| {
+        let (sender, receiver) = mpsc::channel();
+        for job in 1..pool.jobs.min(mailboxes.len()) {
+            let sender = sender.clone();
+            scope.spawn(move || match pool.take() {
+                Ok((mut session, tap)) => {
+                    work(&mut session, &tap, &sender);
+                    pool.put(session, tap);
+                }
+                // Like too many connections, the others go on.
+                Err(err) => tell!(
+                    "{} could not open connection {}: {}",
+                    yellow("Warning:"),
+                    job + 1,
+                    err
+                ),
+            });
+        }
+        scope.spawn(move || work(session, tap, &sender));
+        for (i, result) in receiver {
+            if !done(&mailboxes[i], result) {
+                stop.store(true, Ordering::Relaxed);
+            }
+        }
+    });
+}
+
+/// Cleanup one mailbox, or `only` these messages of it, and return the UIDs of the messages the
+/// action was applied to (or would be). When the connection is lost while changing the messages,
+/// a new one is opened with `reconnect` to resume from where it stopped, as long as progress is
+/// made.
+fn cleanup_mailbox<S: Read + Write>(
+    session: &mut Session<S>,
+    tap: &Tap,
+    reconnect: &dyn Fn() -> Result<Session<S>>,
+    mailbox: &str,
+    cleanup: &Cleanup,
+    only: Option<&[u32]>,
+) -> Result<Vec<u32>> {
+    let query = match only {
+        Some([]) => return Ok(Vec::new()),
+        Some(uids) => format!(
+            "UID {} {}",
+            ranges(uids)
+                .iter()
+                .map(|range| format!("{}:{}", range.start(), range.end()))
+                .join(","),
+            cleanup.query
+        ),
+        None => cleanup.query.clone(),
+    };
+    interrupt::check()?;
+    let _span = tracing::info_span!("mailbox", mailbox).entered();
+    let _lock = cleanup.locker.lock(mailbox)?;
+    bar::mailbox(mailbox);
+    let selected = mailbox::open(session, tap, mailbox, cleanup.dry_run)?;
+    let (exists, uid_validity) = (selected.exists, selected.uid_validity);
+    if let Some(uid_list) = cleanup.uid_list {
+        uid_list.check(mailbox, uid_validity)?;
+    }
+    let searching = bar::Bar::spinner("SEARCH");
+    let uids = match (&cleanup.windows, only) {
+        (Some(windows), None) => windows.search(session, mailbox, exists, &query)?,
+        _ => {
+            let mut uids = session.uid_search(&query)?.into_iter().collect::<Vec<_>>();
+            uids.sort();
+            uids
+        }
+    };
+    drop(searching);
+    tracing::info!(query = %query, found = uids.len(), "searched");
+    let uids = cleanup.filter.apply(session, tap, &uids)?;
+    let uids = match &cleanup.retention {
+        Some(retention) => {
+            let groups = retention.apply(session, &uids)?;
+            if cleanup.dry_run {
+                for group in &groups {
+                    let cutoff = retention.cutoff(group.list_id.as_deref());
+                    say!(
+                        "{}: {} before {}",
+                        group.list_id.as_deref().unwrap_or("(no list)"),
+                        group.uids.len(),
+                        cutoff.format("%Y-%m-%d")
+                    );
+                }
+            }
+            groups.into_iter().flat_map(|x| x.uids).sorted().collect()
+        }
+        None => uids,
+    };
+    let uids = match &cleanup.keep_senders {
+        Some(keep_senders) => {
+            let kept = keep_senders.apply(session, &uids)?;
+            if cleanup.dry_run {
+                for (entry, count) in keep_senders.entries.iter().zip(&kept.protected) {
+                    if *count > 0 {
+                        say!("{}", yellow(format!("Kept by {}: {}", entry, count)));
+                    }
+                }
+            }
+            kept.uids
+        }
+        None => uids,
+    };
+    let uids = match cleanup.filter.keep_last {
+        Some(keep_last) => {
+            let kept = cleanup.filter.keep_newest(session, exists, &uids)?;
+            if cleanup.dry_run && kept.len() < uids.len() {
+                say!(
+                    "{}",
+                    yellow(format!(
+                        "Kept among the {} newest: {}",
+                        keep_last,
+                        uids.len() - kept.len()
+                    ))
+                );
+            }
+            kept
+        }
+        None => uids,
+    };
+    let uids = match &cleanup.contacts {
+        Some(contacts) => {
+            let (kept, protected) = contacts.apply(session, &uids)?;
+            if cleanup.dry_run && protected > 0 {
+                say!("{}", yellow(format!("Kept by the contacts: {}", protected)));
+            }
+            kept
+        }
+        None => uids,
+    };
+    let uids = match &cleanup.active_threads {
+        Some(active_threads) => {
+            let (kept, protected) = active_threads.apply(session, tap, &uids)?;
+            if cleanup.dry_run && protected > 0 {
+                say!(
+                    "{}",
+                    yellow(format!("Kept in active conversations: {}", protected))
+                );
+            }
+            kept
+        }
+        None => uids,
+    };
+    let uids = match &cleanup.budget {
+        Some(budget) => {
+            let kept = budget.apply(session, &uids)?;
+            if cleanup.dry_run && kept.len() < uids.len() {
+                say!(
+                    "{}",
+                    yellow(format!(
+                        "Kept, enough is freed: {}",
+                        uids.len() - kept.len()
+                    ))
+                );
+            }
+            kept
+        }
+        None => uids,
+    };
+    // Before the questions, not to ask them for a run stopping anyway.
+    let uids = match cleanup.cap {
+        Some(cap) => {
+            let found = uids.len();
+            let uids = cap.apply(mailbox, uids)?;
+            if uids.len() < found && cleanup.dry_run {
+                say!(
+                    "{}",
+                    yellow(format!(
+                        "Kept, --max-delete reached: {}",
+                        found - uids.len()
+                    ))
+                );
+            } else if uids.len() < found {
+                tell!(
+                    "{} {}: --max-delete reached, {} messages kept.",
+                    yellow("Warning:"),
+                    mailbox,
+                    found - uids.len()
+                );
+            }
+            uids
+        }
+        None => uids,
+    };
+    let capped = uids.len();
+    let uids = match cleanup.interactive {
+        Some(interactive) => interactive.apply(session, mailbox, &uids)?,
+        None => uids,
+    };
+    let uids = match cleanup.size_cap {
+        Some(size_cap) => {
+            let kept = size_cap.apply(session, &uids)?;
+            if kept.len() < uids.len() && cleanup.dry_run {
+                say!(
+                    "{}",
+                    yellow(format!(
+                        "Kept, --max-bytes reached: {}",
+                        uids.len() - kept.len()
+                    ))
+                );
+            } else if kept.len() < uids.len() {
+                tell!(
+                    "{} {}: --max-bytes reached, {} messages kept.",
+                    yellow("Warning:"),
+                    mailbox,
+                    uids.len() - kept.len()
+                );
+            }
+            kept
+        }
+        None => uids,
+    };
+    if let Some(cap) = cleanup.cap {
+        // Those kept by the questions and --max-bytes are left to the other mailboxes.
+        cap.release(capped - uids.len());
+    }
+    if cleanup.dry_run {
+        let mut rows = Vec::new();
+        try_batches("FETCH", &uids, |set, _| {
+            let fetch = session.uid_fetch(set, "(INTERNALDATE FLAGS ENVELOPE RFC822.SIZE)")?;
+            if let Some(export) = cleanup.export {
+                export.write(mailbox, &fetch)?;
+            }
             for message in &fetch {
-                let internal_date = message.internal_date().unwrap();
-                println!("{} {:?}", internal_date, message.flags());
+                let size = message.size.unwrap_or_default();
+                cleanup.found.fetch_add(size.into(), Ordering::Relaxed);
+                rows.push(preview::Row::new(message));
+                output::message(message);
+                if let Some(planner) = cleanup.plan {
+                    planner.add(mailbox, uid_validity, message);
+                }
             }
+            Ok(())
+        })?;
+        match cleanup.review {
+            Some(review) => review.add(mailbox, uid_validity, rows),
+            None => preview::print(&mut rows, cleanup.sort, cleanup.reverse),
         }
-        println!("{} not deleted (dry run).", uids.len());
+        Ok(uids)
     } else {
-        for range in ranges(&uids) {
-            session.store(
-                format!("{}:{}", range.start(), range.end()),
-                r"+FLAGS.SILENT (\Deleted)",
-            )?;
+        confirm_mailbox(session, mailbox, cleanup, &uids)?;
+        let uids = match cleanup.backup {
+            Some(backup) => backup.backup(session, mailbox, &uids)?,
+            None => uids,
+        };
+        let entries = match cleanup.audit {
+            Some(_) => audit::AuditLog::entries(session, &uids)?,
+            None => BTreeMap::new(),
+        };
+        let log = |uids: &[u32]| match cleanup.audit {
+            Some(audit) => audit.write(mailbox, &cleanup.action.done(), uids, &entries),
+            None => Ok(()),
+        };
+        tracing::info!(action = %cleanup.action.done(), uids = uids.len(), "applying");
+        let mut progress = Progress::default();
+        // How much was done when resuming last, not to retry for ever without progress.
+        let mut resumed = None;
+        loop {
+            let result = cleanup.action.apply(
+                session,
+                tap,
+                cleanup.limiter,
+                cleanup.extensions,
+                mailbox,
+                uid_validity,
+                &uids,
+                &mut progress,
+            );
+            match result {
+                Ok(failed) => {
+                    let uids = uids
+                        .into_iter()
+                        .filter(|x| !failed.contains(x))
+                        .collect::<Vec<_>>();
+                    log(&uids)?;
+                    return Ok(uids);
+                }
+                Err(Error::Interrupted { .. }) => {
+                    log(applied(cleanup, &progress))?;
+                    let done = report_interrupted(session, mailbox, cleanup, &progress)?;
+                    return Err(Error::Interrupted { done });
+                }
+                Err(err)
+                    if retry::is_transient(&err)
+                        && resumed < Some(progress.copied.len() + progress.stored.len()) =>
+                {
+                    resumed = Some(progress.copied.len() + progress.stored.len());
+                    tell!(
+                        "{}: the connection was lost ({}), reconnecting to resume.",
+                        mailbox,
+                        err
+                    );
+                    *session = reconnect()?;
+                    mailbox::reselect(session, mailbox, uid_validity)?;
+                }
+                Err(err) => return Err(err),
+            }
         }
-        session.expunge()?;
-        println!("{} deleted.", uids.len());
     }
-    Ok(())
+}
+
+/// The UIDs the action was applied to before Ctrl+C.
+fn applied<'a>(cleanup: &Cleanup, progress: &'a Progress) -> &'a [u32] {
+    match &cleanup.action {
+        Action::Move(_) if cleanup.extensions.can_move => &progress.copied,
+        Action::RemoveLabel(_) | Action::GmailArchive => &progress.stored,
+        _ => &progress.expunged,
+    }
+}
+
+/// Report what was done in a mailbox before Ctrl+C, and remove the \Deleted flags with
+/// --revert-on-interrupt. Returns the number of messages the action was applied to.
+fn report_interrupted<S: Read + Write>(
+    session: &mut Session<S>,
+    mailbox: &str,
+    cleanup: &Cleanup,
+    progress: &Progress,
+) -> Result<usize> {
+    let done = applied(cleanup, progress).len();
+    say!(
+        "{}: {} {}, interrupted.",
+        mailbox,
+        done,
+        cleanup.action.done()
+    );
+    let flagged = progress.unexpunged();
+    match &cleanup.action {
+        Action::Move(_) if cleanup.extensions.can_move => {}
+        Action::Delete | Action::Move(_) => {
+            if let Action::Move(destination) = &cleanup.action {
+                let kept = progress.copied.len() - progress.expunged.len();
+                if kept > 0 {
+                    say!(
+                        "{}: {} copied to {} are still here.",
+                        mailbox,
+                        kept,
+                        destination
+                    );
+                }
+            }
+            if !flagged.is_empty() && cleanup.revert_on_interrupt {
+                try_batches("STORE", &flagged, |set, _| {
+                    session.uid_store(set, r"-FLAGS.SILENT (\Deleted)")?;
+                    Ok(())
+                })?;
+                say!(
+                    "{}: the \\Deleted flag was removed from {} messages.",
+                    mailbox,
+                    flagged.len()
+                );
+            } else if !flagged.is_empty() {
+                say!(
+                    "{}: {} flagged \\Deleted, not expunged.",
+                    mailbox,
+                    flagged.len()
+                );
+            }
+        }
+        Action::StripAttachments if !flagged.is_empty() => say!(
+            "{}: {} copied without their attachments, the originals flagged \\Deleted, not \
+             expunged.",
+            mailbox,
+            flagged.len()
+        ),
+        _ => {}
+    }
+    Ok(done)
 }
 
 fn ranges<'a>(uids: impl IntoIterator<Item = &'a u32> + 'a) -> Vec<RangeInclusive<u32>> {
@@ -110,10 +2158,104 @@ fn ranges<'a>(uids: impl IntoIterator<Item = &'a u32> + 'a) -> Vec<RangeInclusiv
         .collect()
 }
 
+/// The number of UIDs per command, set from --batch-size once connected.
+static BATCH_SIZE: AtomicUsize = AtomicUsize::new(DEFAULT_BATCH_SIZE);
+
+const DEFAULT_BATCH_SIZE: usize = 500;
+
+/// The batch sizes of the servers known to reject long commands, by the end of their host name.
+const BATCH_SIZES: &[(&str, usize)] = &[
+    ("outlook.office365.com", 100),
+    ("outlook.com", 100),
+    ("mail.me.com", 100),
+];
+
+fn default_batch_size(host: &str) -> usize {
+    BATCH_SIZES
+        .iter()
+        .find(|(suffix, _)| host.to_ascii_lowercase().ends_with(suffix))
+        .map_or(DEFAULT_BATCH_SIZE, |(_, size)| *size)
+}
+
+/// These sorted UIDs in batches of at most --batch-size UIDs for the commands, with their
+/// sequence set.
+fn batches(uids: &[u32]) -> Vec<(String, &[u32])> {
+    chunks(uids, BATCH_SIZE.load(Ordering::Relaxed))
+}
+
+/// Run a command on these sorted UIDs, in batches of at most --batch-size UIDs. When the server
+/// answers NO or BAD, the batch is split in halves retried, down to single UIDs: the UIDs still
+/// failing are reported and returned. The command is given the sequence set and its UIDs.
+fn try_batches(
+    command: &str,
+    uids: &[u32],
+    mut run: impl FnMut(&str, &[u32]) -> Result<()>,
+) -> Result<Vec<u32>> {
+    let mut failed = Vec::new();
+    let mut error = None;
+    let mut bar = bar::Bar::new(command, uids.len());
+    for batch in uids.chunks(BATCH_SIZE.load(Ordering::Relaxed)) {
+        let _span = tracing::debug_span!("batch", command, uids = batch.len()).entered();
+        bisect(batch, &mut run, &mut failed, &mut error)?;
+        bar.inc(batch.len());
+    }
+    if let Some(error) = error {
+        tell!(
+            "{} {} failed, {} messages left out (UIDs {}): {}",
+            yellow("Warning:"),
+            command,
+            failed.len(),
+            set(&failed),
+            error
+        );
+    }
+    Ok(failed)
+}
+
+fn bisect(
+    uids: &[u32],
+    run: &mut impl FnMut(&str, &[u32]) -> Result<()>,
+    failed: &mut Vec<u32>,
+    error: &mut Option<Error>,
+) -> Result<()> {
+    match run(&set(uids), uids) {
+        Err(err @ Error::Imap(imap::Error::No(_) | imap::Error::Bad(_))) if uids.len() > 1 => {
+            tracing::debug!(error = %err, uids = uids.len(), "refused, split in halves");
+            let (first, second) = uids.split_at(uids.len() / 2);
+            bisect(first, run, failed, error)?;
+            bisect(second, run, failed, error)
+        }
+        Err(err @ Error::Imap(imap::Error::No(_) | imap::Error::Bad(_))) => {
+            failed.extend(uids);
+            *error = Some(err);
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+/// These sorted UIDs in batches of at most `size` UIDs, with their sequence set.
+fn chunks(uids: &[u32], size: usize) -> Vec<(String, &[u32])> {
+    uids.chunks(size).map(|batch| (set(batch), batch)).collect()
+}
+
+/// The sequence set of these sorted UIDs, like `1:3,5:5`.
+fn set(uids: &[u32]) -> String {
+    ranges(uids)
+        .iter()
+        .map(|range| format!("{}:{}", range.start(), range.end()))
+        .join(",")
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
 
+    #[test]
+    fn cli() {
+        Args::command().debug_assert();
+    }
+
     #[test]
     fn range() {
         assert_eq!(ranges(&[1, 2, 3]), &[1..=3]);
@@ -123,4 +2265,225 @@ mod test {
         assert_eq!(ranges(&[]), &[]);
         assert_eq!(ranges(&[1, 3]), &[1..=1, 3..=3]);
     }
+
+    #[test]
+    fn batch() {
+        let batches = |uids: &[u32], size| {
+            chunks(uids, size)
+                .into_iter()
+                .map(|(set, batch)| format!("{} {}", set, batch.len()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(batches(&[1, 2, 3, 5, 7, 8, 9], 500), ["1:3,5:5,7:9 7"]);
+        assert_eq!(
+            batches(&[1, 2, 3, 5, 7, 8, 9], 2),
+            ["1:2 2", "3:3,5:5 2", "7:8 2", "9:9 1"]
+        );
+        assert_eq!(
+            batches(&(1..=7).collect::<Vec<_>>(), 3),
+            ["1:3 3", "4:6 3", "7:7 1"]
+        );
+        assert!(batches(&[], 3).is_empty());
+        assert_eq!(default_batch_size("Outlook.Office365.com"), 100);
+        assert_eq!(default_batch_size("imap.example.com"), 500);
+    }
+
+    #[test]
+    fn retry() {
+        let mut sent = Vec::new();
+        let failed = try_batches("STORE", &[1, 2, 3, 4, 6], |set, _| {
+            sent.push(set.to_string());
+            if set.contains('4') {
+                return Err(Error::Imap(imap::Error::No("nope".to_string())));
+            }
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(failed, [4]);
+        assert_eq!(
+            sent,
+            ["1:4,6:6", "1:2", "3:4,6:6", "3:3", "4:4,6:6", "4:4", "6:6"]
+        );
+
+        let result = try_batches("STORE", &[1, 2], |_, _| {
+            Err(Error::Protocol("connection lost".to_string()))
+        });
+        assert!(result.is_err());
+    }
+
+    /// Delete all the messages.
+    fn cleanup<'a>(limiter: &'a Limiter, locker: &'a lock::Locker) -> Cleanup<'a> {
+        let args = Args::parse_from(["imap-cleanup"]);
+        Cleanup {
+            query: "ALL".to_string(),
+            filter: args.filter,
+            retention: None,
+            keep_senders: None,
+            contacts: None,
+            active_threads: None,
+            windows: None,
+            action: Action::Delete,
+            uid_list: None,
+            budget: None,
+            interactive: None,
+            review: None,
+            plan: None,
+            cap: None,
+            size_cap: None,
+            export: None,
+            audit: None,
+            backup: None,
+            limiter,
+            locker,
+            extensions: Extensions::default(),
+            dry_run: false,
+            confirm: false,
+            before: Local::today(),
+            revert_on_interrupt: true,
+            found: AtomicU64::default(),
+            sort: None,
+            reverse: false,
+        }
+    }
+
+    #[test]
+    fn interrupted() {
+        let (limiter, locker) = (Limiter::default(), lock::Locker::default());
+        let cleanup = cleanup(&limiter, &locker);
+        // Interrupted after expunging a first batch with --spread-expunge.
+        let progress = Progress {
+            stored: vec![1, 2, 3, 4],
+            expunged: vec![1, 2],
+            ..Progress::default()
+        };
+        let (mut imap, _, sent) = connection::test::session(b"a2 OK done\r\n");
+        assert_eq!(
+            report_interrupted(&mut imap, "INBOX", &cleanup, &progress).unwrap(),
+            2
+        );
+        assert_eq!(
+            String::from_utf8_lossy(&sent.borrow()),
+            "a2 UID STORE 3:4 -FLAGS.SILENT (\\Deleted)\r\n"
+        );
+    }
+
+    #[test]
+    fn continued() {
+        let (limiter, locker) = (Limiter::default(), lock::Locker::default());
+        let cleanup = cleanup(&limiter, &locker);
+        let mut results = Results::default();
+        let refused = Error::Imap(imap::Error::No("permission denied".to_string()));
+        assert!(results.add("Shared", &cleanup, Err(refused)).is_none());
+        let protocol = Error::Protocol("unexpected response".to_string());
+        assert!(results.add("Lists", &cleanup, Err(protocol)).is_none());
+        assert_eq!(
+            results.add("INBOX", &cleanup, Ok(vec![1, 2])),
+            Some(vec![1, 2])
+        );
+        assert!(!results.stopped());
+        assert_eq!((results.failed, results.total), (2, 2));
+        let lost = Error::Imap(imap::Error::ConnectionLost);
+        results.add("Archive", &cleanup, Err(lost));
+        assert!(results.stopped());
+    }
+
+    #[test]
+    fn planned() {
+        let dir = std::env::temp_dir().join(format!("imap-cleanup-plan-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.toml");
+        std::fs::write(
+            &path,
+            "[account.home]\nusername = \"u\"\nmailbox = \"INBOX\"\nretention = \"1d\"\n",
+        )
+        .unwrap();
+        let config = path.to_str().unwrap();
+        let mut args = Args::parse_from(["imap-cleanup", "--config", config, "--account", "home"]);
+        args.load_config().unwrap();
+        assert_eq!(args.mailboxes.mailbox, ["INBOX"]);
+        assert!(args.before.is_some());
+
+        // The mailboxes and messages of the plan, not those of the account.
+        let mut args = Args::parse_from(["imap-cleanup", "--config", config, "--account", "home"]);
+        let mut selection = uidlist::UidList::default();
+        selection.insert("Archive", Some(7), vec![3]);
+        args.selection = Some(selection);
+        args.load_config().unwrap();
+        assert!(args.mailboxes.mailbox.is_empty());
+        assert!(args.before.is_none());
+        assert_eq!(args.username.as_deref(), Some("u"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reviewed_capped() {
+        let (limiter, locker) = (Limiter::default(), lock::Locker::default());
+        let args = cap::CapArgs {
+            max_delete: Some(2),
+            truncate: false,
+            max_bytes: None,
+        };
+        let cap = args.cap().unwrap();
+        // Used up by the dry run finding the candidates.
+        cap.apply("INBOX", vec![1, 2]).unwrap();
+        let mut selection = uidlist::UidList::default();
+        selection.insert("INBOX", Some(7), vec![1, 2]);
+        let mut cleanup = cleanup(&limiter, &locker);
+        cleanup.cap = Some(&cap);
+        let cleanup = reviewed(cleanup, &selection);
+        let (mut imap, tap, sent) = connection::test::session(
+            b"* 2 EXISTS\r\n* OK [UIDVALIDITY 7] ok\r\na2 OK [READ-WRITE] done\r\n\
+            * SEARCH 1 2\r\na3 OK done\r\n\
+            a4 OK done\r\n\
+            a5 OK done\r\n",
+        );
+        let reconnect = || Err(Error::Protocol("connection lost".to_string()));
+        let uids = cleanup_mailbox(
+            &mut imap,
+            &tap,
+            &reconnect,
+            "INBOX",
+            &cleanup,
+            Some(&[1, 2]),
+        );
+        assert_eq!(uids.unwrap(), [1, 2]);
+        assert!(String::from_utf8_lossy(&sent.borrow())
+            .contains("a4 UID STORE 1:2 +FLAGS.SILENT (\\Deleted)\r\n"));
+    }
+
+    #[test]
+    fn profiled() {
+        let dir = std::env::temp_dir().join(format!("imap-cleanup-profile-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.toml");
+        std::fs::write(
+            &path,
+            "[profile.big]\nformat = \"json\"\ncolor = \"never\"\nsort = \"size\"\n",
+        )
+        .unwrap();
+        let config = path.to_str().unwrap();
+        let mut args = Args::parse_from(["imap-cleanup", "--config", config, "--profile", "big"]);
+        args.load_config().unwrap();
+        assert_eq!(args.format, output::Format::Json);
+        assert_eq!(args.color, color::Mode::Never);
+        assert_eq!(args.sort, Some(preview::Sort::Size));
+        assert!(!args.reverse);
+
+        // Those of the command line win.
+        let mut args = Args::parse_from([
+            "imap-cleanup",
+            "--config",
+            config,
+            "--profile",
+            "big",
+            "--color",
+            "always",
+            "--sort",
+            "date",
+        ]);
+        args.load_config().unwrap();
+        assert_eq!(args.color, color::Mode::Always);
+        assert_eq!(args.sort, Some(preview::Sort::Date));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,129 @@
+use crate::error::Result;
+use clap::Command;
+use clap_mangen::Man;
+use std::io::Write;
+
+/// The files, written in roff: clap does not know them.
+const FILES: &str = r#".SH "POLICY FILE"
+The policy file of \fBapply \-\-policy\fR is a TOML file with a \fB[[rule]]\fR table per set of
+mailboxes. The rules are applied in their order, except that a rule moving messages to a mailbox
+goes before the rules cleaning it. The options given on the command line apply to all of them.
+.TP
+\fBmailbox\fR
+The names or patterns of the mailboxes, with the wildcards of \-\-mailbox: a name or a list of
+names. Required.
+.TP
+\fBmax\-age\fR
+The messages older than this are cleaned, like 30d, 6w, 3m or 1y. Required.
+.TP
+\fBlarger\-than\fR, \fBsmaller\-than\fR
+Same as \-\-larger\-than and \-\-smaller\-than.
+.TP
+\fBprotect\-flag\fR
+Same as \-\-protect\-flag, an empty list protects no flag.
+.TP
+\fBaction\fR
+delete (the default), move or strip\-attachments.
+.TP
+\fBmove\-to\fR
+The destination of the moved messages, for the move action.
+.PP
+For example:
+.PP
+.nf
+[[rule]]
+mailbox = "Lists/*"
+max\-age = "30d"
+
+[[rule]]
+mailbox = "Notifications"
+max\-age = "90d"
+move\-to = "Trash"
+.fi
+.SH "CONFIGURATION FILE"
+The configuration file defines named accounts, where to connect, used with \-\-account, and
+profiles, what to cleanup, used with \-\-profile. The options given on the command line take
+precedence over both.
+.TP
+\fB[account.NAME]\fR
+The keys host, port, username, auth, password\-cmd, mailbox (a name or a list of names),
+retention (like 90d, the messages older are cleaned when \-\-before is not given) and trash
+(for empty\-trash, when the server does not tell it).
+.TP
+\fB[profile.NAME]\fR
+The keys mailbox, before, retention and dry\-run, and those of the output: format, color, sort and
+reverse.
+.SH FILES
+.TP
+\fI$XDG_CONFIG_HOME/imap\-cleanup/config.toml\fR, or \fI~/.config/imap\-cleanup/config.toml\fR
+The configuration file, unless given by \-\-config.
+.TP
+\fI$XDG_STATE_HOME/imap\-cleanup/locks\fR, or \fI~/.local/state/imap\-cleanup/locks\fR
+The locks of the mailboxes being cleaned.
+"#;
+
+/// Print the man page of `command`: its options, each subcommand with its own, then the files.
+pub fn print(command: Command) -> Result<()> {
+    let mut page = Vec::new();
+    let man = Man::new(command.clone());
+    man.render_title(&mut page)?;
+    man.render_name_section(&mut page)?;
+    man.render_synopsis_section(&mut page)?;
+    man.render_description_section(&mut page)?;
+    man.render_options_section(&mut page)?;
+    man.render_subcommands_section(&mut page)?;
+    for subcommand in subcommands(&command) {
+        subcommand_section(&mut page, command.get_name(), subcommand)?;
+    }
+    page.extend_from_slice(FILES.as_bytes());
+    man.render_version_section(&mut page)?;
+    man.render_authors_section(&mut page)?;
+    std::io::stdout().write_all(&page)?;
+    Ok(())
+}
+
+/// The subcommands, but the help of clap.
+fn subcommands<'a, 'help>(command: &'a Command<'help>) -> impl Iterator<Item = &'a Command<'help>> {
+    command
+        .get_subcommands()
+        .filter(|x| x.get_name() != "help" && !x.is_hide_set())
+}
+
+/// The section of a subcommand, with a subsection per section of its own page, followed by the
+/// sections of its subcommands.
+fn subcommand_section(page: &mut Vec<u8>, parent: &str, subcommand: &Command) -> Result<()> {
+    let name = format!("{} {}", parent, subcommand.get_name());
+    writeln!(page, ".SH \"{}\"", name.to_uppercase().replace('-', "\\-"))?;
+    let man = Man::new(subcommand.clone().name(&name));
+    let mut section = Vec::new();
+    man.render_description_section(&mut section)?;
+    man.render_synopsis_section(&mut section)?;
+    if subcommand.get_arguments().any(|x| !x.is_hide_set()) {
+        man.render_options_section(&mut section)?;
+    }
+    let section = String::from_utf8_lossy(&section).replace(".SH ", ".SS ");
+    page.extend_from_slice(section.as_bytes());
+    for nested in subcommands(subcommand) {
+        subcommand_section(page, &name, nested)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Args;
+    use clap::CommandFactory;
+
+    #[test]
+    fn subcommands() {
+        let mut page = Vec::new();
+        let command = Args::command();
+        let apply = command.find_subcommand("apply").unwrap();
+        subcommand_section(&mut page, "imap-cleanup", apply).unwrap();
+        let page = String::from_utf8(page).unwrap();
+        assert!(page.starts_with(".SH \"IMAP\\-CLEANUP APPLY\"\n"));
+        assert!(page.contains(".SS OPTIONS"));
+        assert!(!page.contains("\n.SH "));
+    }
+}
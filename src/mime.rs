@@ -0,0 +1,570 @@
+use imap_proto::types::{BodyContentCommon, BodyStructure};
+use regex::Regex;
+use std::ops::Range;
+use std::sync::OnceLock;
+
+/// Decode the RFC 2047 encoded-words of a header value, like `=?utf-8?q?Caf=C3=A9?=`.
+///
+/// Only UTF-8, US-ASCII and the Latin-1 family are decoded properly, the other charsets are read
+/// as UTF-8 with replacement characters.
+pub fn decode_header(value: &[u8]) -> String {
+    static WORD: OnceLock<Regex> = OnceLock::new();
+    let word = WORD.get_or_init(|| Regex::new(r"=\?([^?\s]+)\?([bBqQ])\?([^?\s]*)\?=").unwrap());
+
+    let value = unfold(&String::from_utf8_lossy(value));
+    let mut decoded = String::new();
+    let mut end = 0;
+    for captures in word.captures_iter(&value) {
+        let all = captures.get(0).unwrap();
+        let text = captures[3].as_bytes();
+        let bytes = match &captures[2] {
+            "b" | "B" => match base64::decode(text) {
+                Ok(bytes) => bytes,
+                Err(_) => continue,
+            },
+            _ => decode_q(text, true),
+        };
+        // The whitespace between two encoded-words is not part of the text.
+        let between = &value[end..all.start()];
+        if end == 0 || !between.chars().all(char::is_whitespace) {
+            decoded.push_str(between);
+        }
+        decoded.push_str(&decode_charset(&captures[1], &bytes));
+        end = all.end();
+    }
+    decoded.push_str(&value[end..]);
+    decoded
+}
+
+/// The fields of a message header with their decoded values, in order.
+pub fn parse_headers(header: &[u8]) -> Vec<(String, String)> {
+    let header = String::from_utf8_lossy(header);
+    let mut fields: Vec<(String, String)> = Vec::new();
+    for line in header.lines() {
+        if line.starts_with([' ', '\t']) {
+            if let Some((_, value)) = fields.last_mut() {
+                value.push_str(line);
+            }
+        } else if let Some((name, value)) = line.split_once(':') {
+            fields.push((name.trim_end().to_string(), value.to_string()));
+        }
+    }
+    fields
+        .into_iter()
+        .map(|(name, value)| (name, decode_header(value.trim().as_bytes())))
+        .collect()
+}
+
+/// The content types of the signatures, sent as parts but not attached by the sender.
+const SIGNATURES: &[&str] = &[
+    "application/pgp-signature",
+    "application/pkcs7-signature",
+    "application/x-pkcs7-signature",
+];
+
+/// Whether a message has real attachments: the parts attached (with a disposition or only a
+/// name) but not the inline images, like the logos of a multipart/related, nor the signatures.
+pub fn has_attachments(body: &BodyStructure) -> bool {
+    fn walk(body: &BodyStructure, related: bool) -> bool {
+        let (common, inner) = match body {
+            BodyStructure::Multipart { common, bodies, .. } => {
+                let related = related || common.ty.subtype.eq_ignore_ascii_case("related");
+                return bodies.iter().any(|x| walk(x, related));
+            }
+            BodyStructure::Message { common, body, .. } => (common, Some(body)),
+            BodyStructure::Basic { common, .. } | BodyStructure::Text { common, .. } => {
+                (common, None)
+            }
+        };
+        let content_type = format!("{}/{}", common.ty.ty, common.ty.subtype);
+        if SIGNATURES
+            .iter()
+            .any(|x| x.eq_ignore_ascii_case(&content_type))
+        {
+            return false;
+        }
+        match common.disposition.as_ref().map(|x| x.ty) {
+            Some(ty) if ty.eq_ignore_ascii_case("attachment") => true,
+            Some(ty) if ty.eq_ignore_ascii_case("inline") => {
+                inner.is_some_and(|x| walk(x, related))
+            }
+            // The old mailers only give a name to the attached files.
+            _ => (!related && has_param(common, "name")) || inner.is_some_and(|x| walk(x, related)),
+        }
+    }
+    walk(body, false)
+}
+
+fn has_param(common: &BodyContentCommon, name: &str) -> bool {
+    common
+        .ty
+        .params
+        .iter()
+        .flatten()
+        .any(|(key, _)| key.eq_ignore_ascii_case(name))
+}
+
+/// Replace the attachments of a raw message (told apart like `has_attachments` does) by a short
+/// note, None when it has none. The rest of the message is kept byte for byte.
+pub fn strip_attachments(message: &[u8]) -> Option<Vec<u8>> {
+    // Only the parts of a multipart are replaced, never the message itself.
+    strip_multipart(message, false)
+}
+
+fn strip_multipart(entity: &[u8], related: bool) -> Option<Vec<u8>> {
+    let start = body_start(entity);
+    let content = Content::parse(&entity[..start]);
+    if !content.ty.starts_with("multipart/") {
+        return None;
+    }
+    let related = related || content.ty == "multipart/related";
+    let body = &entity[start..];
+    let parts = parts(body, content.param("boundary")?);
+
+    let mut stripped = entity[..start].to_vec();
+    let mut end = 0;
+    let mut changed = false;
+    for part in parts {
+        if let Some(replacement) = strip_part(&body[part.clone()], related) {
+            stripped.extend_from_slice(&body[end..part.start]);
+            stripped.extend_from_slice(&replacement);
+            end = part.end;
+            changed = true;
+        }
+    }
+    stripped.extend_from_slice(&body[end..]);
+    changed.then_some(stripped)
+}
+
+/// The content of each part of a multipart body, between a delimiter line and the line break of
+/// the next one.
+fn parts(body: &[u8], boundary: &str) -> Vec<Range<usize>> {
+    let delimiter = format!("--{}", boundary);
+    let mut parts = Vec::new();
+    let mut open = None;
+    let mut offset = 0;
+    for line in body.split_inclusive(|&x| x == b'\n') {
+        let rest = line
+            .strip_prefix(delimiter.as_bytes())
+            .map(|x| x.trim_ascii_end());
+        if let Some(rest @ (b"" | b"--")) = rest {
+            if let Some(begin) = open {
+                let end = offset - line_break_before(&body[..offset]);
+                parts.push(begin..end.max(begin));
+            }
+            open = Some(offset + line.len());
+            if rest == b"--" {
+                open = None;
+                break;
+            }
+        }
+        offset += line.len();
+    }
+    if let Some(begin) = open {
+        parts.push(begin..body.len());
+    }
+    parts
+}
+
+fn strip_part(part: &[u8], related: bool) -> Option<Vec<u8>> {
+    let content = Content::parse(&part[..body_start(part)]);
+    if content.ty.starts_with("multipart/") {
+        return strip_multipart(part, related);
+    }
+    if !content.is_attachment(related) {
+        return None;
+    }
+    let name = content
+        .disposition_param("filename")
+        .or_else(|| content.param("name"))
+        .unwrap_or("unnamed");
+    Some(
+        format!(
+            "Content-Type: text/plain; charset=utf-8\r\n\
+             Content-Transfer-Encoding: 8bit\r\n\
+             \r\n\
+             The attachment {} ({}, {} bytes) was removed by imap-cleanup.",
+            name,
+            content.ty,
+            part.len() - body_start(part)
+        )
+        .into_bytes(),
+    )
+}
+
+/// The text of a raw message, decoded: its first text/plain part, not attached, or its first
+/// text/html part with the tags removed. Empty when it has none.
+pub fn text(message: &[u8]) -> String {
+    fn find(entity: &[u8], html: bool) -> Option<String> {
+        let start = body_start(entity);
+        let content = Content::parse(&entity[..start]);
+        let body = &entity[start..];
+        if content.ty.starts_with("multipart/") {
+            return parts(body, content.param("boundary")?)
+                .into_iter()
+                .find_map(|x| find(&body[x], html));
+        }
+        let wanted = if html { "text/html" } else { "text/plain" };
+        if content.ty != wanted || content.is_attachment(false) {
+            return None;
+        }
+        let bytes = match content.encoding.as_deref() {
+            Some("quoted-printable") => decode_q(&unfold_soft_breaks(body), false),
+            Some("base64") => {
+                let text = body
+                    .iter()
+                    .copied()
+                    .filter(|x| !x.is_ascii_whitespace())
+                    .collect::<Vec<_>>();
+                base64::decode(text).ok()?
+            }
+            _ => body.to_vec(),
+        };
+        let text = decode_charset(content.param("charset").unwrap_or_default(), &bytes);
+        Some(if html { strip_tags(&text) } else { text })
+    }
+    find(message, false)
+        .or_else(|| find(message, true))
+        .unwrap_or_default()
+}
+
+/// Remove the soft line breaks of a quoted-printable text, `=` at the end of a line.
+fn unfold_soft_breaks(text: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(text.len());
+    for line in text.split_inclusive(|&x| x == b'\n') {
+        let soft = line
+            .strip_suffix(b"=\r\n")
+            .or_else(|| line.strip_suffix(b"=\n"));
+        bytes.extend_from_slice(soft.unwrap_or(line));
+    }
+    bytes
+}
+
+/// The text of an HTML document, roughly: without its tags, styles and scripts, a line per
+/// paragraph.
+fn strip_tags(html: &str) -> String {
+    static BREAKS: OnceLock<Regex> = OnceLock::new();
+    static TAGS: OnceLock<Regex> = OnceLock::new();
+    let breaks =
+        BREAKS.get_or_init(|| Regex::new(r"(?i)<br\s*/?>|</(p|div|li|tr|h[1-6])\s*>").unwrap());
+    let tags = TAGS.get_or_init(|| {
+        Regex::new(r"(?is)<(style|script)\b.*?</(style|script)\s*>|<!--.*?-->|<[^>]*>").unwrap()
+    });
+    let text = breaks.replace_all(html, "\n");
+    let text = tags.replace_all(&text, "");
+    let text = text
+        .replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&");
+    // The indentation of the source, and the empty lines left by the tags.
+    text.lines()
+        .map(str::trim)
+        .filter(|x| !x.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Where the body of an entity starts, after the empty line ending its header.
+pub fn body_start(entity: &[u8]) -> usize {
+    if entity.starts_with(b"\r\n") {
+        return 2;
+    }
+    if entity.starts_with(b"\n") {
+        return 1;
+    }
+    let crlf = entity
+        .windows(4)
+        .position(|x| x == b"\r\n\r\n")
+        .map(|x| x + 4);
+    let lf = entity.windows(2).position(|x| x == b"\n\n").map(|x| x + 2);
+    match (crlf, lf) {
+        (Some(crlf), Some(lf)) => crlf.min(lf),
+        (crlf, lf) => crlf.or(lf).unwrap_or(entity.len()),
+    }
+}
+
+fn line_break_before(bytes: &[u8]) -> usize {
+    if bytes.ends_with(b"\r\n") {
+        2
+    } else if bytes.ends_with(b"\n") {
+        1
+    } else {
+        0
+    }
+}
+
+/// The Content-Type and Content-Disposition of a MIME entity, in lowercase but the parameter
+/// values.
+#[derive(Debug, Default)]
+struct Content {
+    ty: String,
+    params: Vec<(String, String)>,
+    disposition: Option<String>,
+    disposition_params: Vec<(String, String)>,
+    /// The Content-Transfer-Encoding, in lowercase.
+    encoding: Option<String>,
+}
+
+impl Content {
+    fn parse(header: &[u8]) -> Self {
+        let mut content = Content {
+            ty: "text/plain".to_string(),
+            ..Default::default()
+        };
+        for (name, value) in parse_headers(header) {
+            if name.eq_ignore_ascii_case("Content-Type") {
+                let (ty, params) = parse_value(&value);
+                content.ty = ty;
+                content.params = params;
+            } else if name.eq_ignore_ascii_case("Content-Disposition") {
+                let (disposition, params) = parse_value(&value);
+                content.disposition = Some(disposition);
+                content.disposition_params = params;
+            } else if name.eq_ignore_ascii_case("Content-Transfer-Encoding") {
+                content.encoding = Some(value.trim().to_lowercase());
+            }
+        }
+        content
+    }
+
+    fn param(&self, name: &str) -> Option<&str> {
+        find_param(&self.params, name)
+    }
+
+    fn disposition_param(&self, name: &str) -> Option<&str> {
+        find_param(&self.disposition_params, name)
+    }
+
+    fn is_attachment(&self, related: bool) -> bool {
+        if SIGNATURES.contains(&self.ty.as_str()) {
+            return false;
+        }
+        match self.disposition.as_deref() {
+            Some("attachment") => true,
+            Some("inline") => false,
+            _ => {
+                !related
+                    && (self.param("name").is_some()
+                        || self.disposition_param("filename").is_some())
+            }
+        }
+    }
+}
+
+fn find_param<'a>(params: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    params
+        .iter()
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.as_str())
+}
+
+/// Split a value like `text/plain; charset="utf-8"` in its lowercase value and parameters.
+fn parse_value(value: &str) -> (String, Vec<(String, String)>) {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut escaped = false;
+    for c in value.chars() {
+        match c {
+            _ if escaped => {
+                field.push(c);
+                escaped = false;
+            }
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            ';' if !quoted => fields.push(std::mem::take(&mut field)),
+            _ => field.push(c),
+        }
+    }
+    fields.push(field);
+    let mut fields = fields.into_iter();
+    let value = fields.next().unwrap_or_default().trim().to_lowercase();
+    let params = fields
+        .filter_map(|x| {
+            let (key, value) = x.split_once('=')?;
+            Some((key.trim().to_lowercase(), value.trim().to_string()))
+        })
+        .collect();
+    (value, params)
+}
+
+/// Join the lines of a folded header.
+fn unfold(value: &str) -> String {
+    value.replace("\r\n", "").replace('\n', "")
+}
+
+/// Decode the `=XX` escapes of a quoted-printable text, and its underscores in a `header`.
+fn decode_q(text: &[u8], header: bool) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut i = 0;
+    while i < text.len() {
+        let hex = text
+            .get(i + 1..i + 3)
+            .filter(|x| x.iter().all(u8::is_ascii_hexdigit))
+            .and_then(|x| u8::from_str_radix(std::str::from_utf8(x).ok()?, 16).ok());
+        match (text[i], hex) {
+            (b'=', Some(byte)) => {
+                bytes.push(byte);
+                i += 3;
+                continue;
+            }
+            (b'_', _) if header => bytes.push(b' '),
+            (byte, _) => bytes.push(byte),
+        }
+        i += 1;
+    }
+    bytes
+}
+
+fn decode_charset(charset: &str, bytes: &[u8]) -> String {
+    // RFC 2231 allows a language after the charset: utf-8*en.
+    let charset = charset.split('*').next().unwrap_or_default();
+    match charset.to_ascii_lowercase().as_str() {
+        "iso-8859-1" | "iso-8859-15" | "latin1" | "windows-1252" | "cp1252" => {
+            bytes.iter().map(|&x| x as char).collect()
+        }
+        _ => String::from_utf8_lossy(bytes).into_owned(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn headers() {
+        assert_eq!(
+            parse_headers(
+                b"X-Mailer: Mailchimp\r\nList-Id: Rust\r\n <rust.lists.example>\r\n\
+                  Subject: =?utf-8?q?Caf=C3=A9?=\r\n\r\n"
+            ),
+            [
+                ("X-Mailer".to_string(), "Mailchimp".to_string()),
+                (
+                    "List-Id".to_string(),
+                    "Rust <rust.lists.example>".to_string()
+                ),
+                ("Subject".to_string(), "Caf\u{e9}".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn strip() {
+        let message = b"From: a@example.com\r\n\
+            Content-Type: multipart/mixed; boundary=\"b1\"\r\n\
+            \r\n\
+            preamble\r\n\
+            --b1\r\n\
+            Content-Type: multipart/related; boundary=b2\r\n\
+            \r\n\
+            --b2\r\n\
+            Content-Type: text/html\r\n\
+            \r\n\
+            <img src=\"cid:logo\">\r\n\
+            --b2\r\n\
+            Content-Type: image/png; name=logo.png\r\n\
+            \r\n\
+            iVBORw0KGgo=\r\n\
+            --b2--\r\n\
+            \r\n\
+            --b1\r\n\
+            Content-Type: application/pdf; name=\"a.pdf\"\r\n\
+            Content-Disposition: attachment; filename=\"report 1.pdf\"\r\n\
+            Content-Transfer-Encoding: base64\r\n\
+            \r\n\
+            JVBERi0xLjQK\r\n\
+            --b1--\r\n\
+            epilogue\r\n";
+        let stripped = String::from_utf8(strip_attachments(message).unwrap()).unwrap();
+        let (kept, rest) = stripped
+            .split_once("--b1\r\nContent-Type: text/plain")
+            .unwrap();
+        assert!(message.starts_with(kept.as_bytes()));
+        assert_eq!(
+            rest,
+            "; charset=utf-8\r\n\
+             Content-Transfer-Encoding: 8bit\r\n\
+             \r\n\
+             The attachment report 1.pdf (application/pdf, 12 bytes) was removed by imap-cleanup.\r\n\
+             --b1--\r\n\
+             epilogue\r\n"
+        );
+
+        // The inline images and the signatures stay.
+        assert_eq!(
+            strip_attachments(
+                b"Content-Type: multipart/signed; boundary=s\r\n\
+                  \r\n\
+                  --s\r\n\
+                  Content-Type: image/png\r\n\
+                  Content-Disposition: inline; filename=me.png\r\n\
+                  \r\n\
+                  iVBORw0KGgo=\r\n\
+                  --s\r\n\
+                  Content-Type: application/pgp-signature; name=signature.asc\r\n\
+                  \r\n\
+                  sig\r\n\
+                  --s--\r\n"
+            ),
+            None
+        );
+        assert_eq!(
+            strip_attachments(b"Content-Type: application/pdf; name=a.pdf\r\n\r\nJVBERi0xLjQK\r\n"),
+            None
+        );
+    }
+
+    #[test]
+    fn encoded_words() {
+        assert_eq!(decode_header(b"Hello"), "Hello");
+        assert_eq!(decode_header(b"=?utf-8?q?Caf=C3=A9?="), "Caf\u{e9}");
+        assert_eq!(
+            decode_header(b"Re: =?ISO-8859-1?Q?Andr=E9?= Pirard"),
+            "Re: Andr\u{e9} Pirard"
+        );
+        assert_eq!(
+            decode_header(b"=?UTF-8?B?8J+OiSBTYWxl?=\r\n =?utf-8?q?_today?="),
+            "\u{1f389} Sale today"
+        );
+        assert_eq!(decode_header(b"=?utf-8*en?q?a_b?= c"), "a b c");
+        assert_eq!(decode_header(b"=?utf-8?q?100=25?="), "100%");
+        assert_eq!(decode_header(b"=?utf-8?q?bad=?="), "bad=");
+    }
+
+    #[test]
+    fn texts() {
+        let message = b"From: a@example.com\r\n\
+            Content-Type: multipart/mixed; boundary=b1\r\n\
+            \r\n\
+            --b1\r\n\
+            Content-Type: multipart/alternative; boundary=b2\r\n\
+            \r\n\
+            --b2\r\n\
+            Content-Type: text/html\r\n\
+            \r\n\
+            <p>Hello</p>\r\n\
+            --b2\r\n\
+            Content-Type: text/plain; charset=iso-8859-1\r\n\
+            Content-Transfer-Encoding: quoted-printable\r\n\
+            \r\n\
+            Caf=E9 at 5_pm, a very long line cut =\r\n\
+            here.\r\n\
+            --b2--\r\n\
+            --b1--\r\n";
+        assert_eq!(
+            text(message),
+            "Caf\u{e9} at 5_pm, a very long line cut here."
+        );
+        let message = b"Content-Type: text/html; charset=utf-8\r\n\
+            Content-Transfer-Encoding: base64\r\n\
+            \r\n\
+            PHN0eWxlPnB7fTwvc3R5bGU+PHA+SGVsbG8gJmFtcDs8L3A+\r\n\
+            PGJyPldvcmxkPC9wPg==\r\n";
+        assert_eq!(text(message), "Hello &\nWorld");
+        assert_eq!(text(b"Content-Type: image/png\r\n\r\niVBORw0KGgo=\r\n"), "");
+    }
+}
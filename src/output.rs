@@ -0,0 +1,330 @@
+use crate::color::{bold, red, yellow, Painted};
+use crate::error::Error;
+use crate::export;
+use imap::types::Fetch;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+/// The output of the cleanup.
+#[derive(clap::ValueEnum, serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// Lines for humans.
+    Text,
+    /// A JSON document printed at the end, the lines for humans go to the standard error.
+    Json,
+}
+
+static JSON: AtomicBool = AtomicBool::new(false);
+
+static REPORT: Mutex<Report> = Mutex::new(Report {
+    dry_run: false,
+    mailboxes: Vec::new(),
+    total: 0,
+    size: None,
+    failed: 0,
+    interrupted: false,
+    error: None,
+    rule: None,
+    messages: Vec::new(),
+});
+
+/// The document of --format json.
+#[derive(serde::Serialize, Debug)]
+struct Report {
+    dry_run: bool,
+    mailboxes: Vec<Mailbox>,
+    /// The number of messages cleaned (or that would be).
+    total: usize,
+    /// The size of the messages found by the dry run, in bytes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size: Option<u64>,
+    /// The number of mailboxes that failed or were skipped.
+    failed: usize,
+    interrupted: bool,
+    /// The error the run failed with.
+    error: Option<String>,
+    /// The rule being applied, from 1.
+    #[serde(skip)]
+    rule: Option<usize>,
+    /// The messages of the dry run, for the next mailbox done.
+    #[serde(skip)]
+    messages: Vec<Message>,
+}
+
+/// What was done in a mailbox.
+#[derive(serde::Serialize, Debug)]
+struct Mailbox {
+    mailbox: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rule: Option<usize>,
+    /// The mailbox of the messages a dry run would have moved here first.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    moved_from: Option<String>,
+    status: Status,
+    action: String,
+    count: usize,
+    /// The size of the messages found by the dry run, in bytes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size: Option<u64>,
+    /// The UIDs of the messages, like `1:3,5:5`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    uids: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    messages: Vec<Message>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(serde::Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Status {
+    Done,
+    Failed,
+    Skipped,
+    Interrupted,
+}
+
+impl Status {
+    fn painted(self) -> Painted<&'static str> {
+        match self {
+            Status::Done => crate::color::cleaned("done", false),
+            Status::Failed => red("failed"),
+            Status::Skipped => yellow("skipped"),
+            Status::Interrupted => yellow("interrupted"),
+        }
+    }
+}
+
+/// A message found by a dry run.
+#[derive(serde::Serialize, Debug)]
+struct Message {
+    uid: u32,
+    date: Option<String>,
+    from: String,
+    subject: String,
+    size: Option<u32>,
+    flags: Vec<String>,
+}
+
+/// Print a line for humans: on the standard output, or on the standard error with --format json
+/// to leave the standard output to the document. It is logged to the --log-file too, without its
+/// colors.
+macro_rules! say {
+    ($($arg:tt)*) => {{
+        let line = $crate::color::formatting($crate::output::is_json(), || format!($($arg)*));
+        tracing::info!(target: $crate::logging::OUTPUT, "{}", $crate::color::strip(&line));
+        if $crate::output::is_json() {
+            eprintln!("{}", line);
+        } else {
+            println!("{}", line);
+        }
+    }};
+}
+pub(crate) use say;
+
+/// Print a warning or an error for humans on the standard error, logged to the --log-file too.
+macro_rules! tell {
+    ($($arg:tt)*) => {{
+        let line = $crate::color::formatting(true, || format!($($arg)*));
+        tracing::warn!(target: $crate::logging::OUTPUT, "{}", $crate::color::strip(&line));
+        eprintln!("{}", line);
+    }};
+}
+pub(crate) use tell;
+
+pub fn set(format: Format, dry_run: bool) {
+    JSON.store(format == Format::Json, Ordering::Relaxed);
+    REPORT.lock().unwrap().dry_run = dry_run;
+}
+
+pub fn is_json() -> bool {
+    JSON.load(Ordering::Relaxed)
+}
+
+/// Set the rule of the next mailboxes, from 0.
+pub fn rule(index: Option<usize>) {
+    REPORT.lock().unwrap().rule = index.map(|x| x + 1);
+}
+
+/// Add a message found by a dry run to the next mailbox done.
+pub fn message(message: &Fetch) {
+    if let (true, Some(uid)) = (is_json(), message.uid) {
+        let (from, subject) = export::from_and_subject(message);
+        let message = Message {
+            uid,
+            date: message.internal_date().map(|x| x.to_rfc3339()),
+            from,
+            subject,
+            size: message.size,
+            flags: message.flags().iter().map(|x| x.to_string()).collect(),
+        };
+        REPORT.lock().unwrap().messages.push(message);
+    }
+}
+
+/// Record the messages of a mailbox the action was applied to (or would be), `size` bytes for a
+/// dry run.
+pub fn done(mailbox: &str, moved_from: Option<&str>, action: &str, uids: &[u32], size: u64) {
+    record(mailbox, moved_from, action, Status::Done, uids.len(), None);
+    let mut report = REPORT.lock().unwrap();
+    if report.dry_run {
+        *report.size.get_or_insert(0) += size;
+    }
+    let dry_run = report.dry_run;
+    if let Some(last) = report.mailboxes.last_mut() {
+        last.uids = Some(crate::set(uids));
+        last.size = dry_run.then_some(size);
+    }
+}
+
+/// Record a mailbox not done, after applying the action to `count` messages.
+pub fn failed(mailbox: &str, action: &str, status: Status, count: usize, error: Option<String>) {
+    record(mailbox, None, action, status, count, error);
+}
+
+fn record(
+    mailbox: &str,
+    moved_from: Option<&str>,
+    action: &str,
+    status: Status,
+    count: usize,
+    error: Option<String>,
+) {
+    let mut report = REPORT.lock().unwrap();
+    let messages = std::mem::take(&mut report.messages);
+    report.total += count;
+    match status {
+        Status::Done => {}
+        Status::Failed | Status::Skipped => report.failed += 1,
+        Status::Interrupted => report.interrupted = true,
+    }
+    let rule = report.rule;
+    report.mailboxes.push(Mailbox {
+        mailbox: mailbox.to_string(),
+        rule,
+        moved_from: moved_from.map(String::from),
+        status,
+        action: action.to_string(),
+        count,
+        size: None,
+        uids: None,
+        messages,
+        error,
+    });
+}
+
+/// Print what was done in each mailbox once some failed, to tell them apart at the end of a
+/// long output.
+pub fn failures() {
+    let report = REPORT.lock().unwrap();
+    if report.failed == 0 || report.mailboxes.len() < 2 {
+        return;
+    }
+    let names = report
+        .mailboxes
+        .iter()
+        .map(|x| match x.rule {
+            Some(rule) => format!("{} (rule {})", x.mailbox, rule),
+            None => x.mailbox.clone(),
+        })
+        .collect::<Vec<_>>();
+    let width = names.iter().map(|x| x.chars().count()).fold(7, usize::max);
+    say!(
+        "{}",
+        bold(format!(
+            "{:width$} {:11} {:>6} ERROR",
+            "MAILBOX",
+            "STATUS",
+            "COUNT",
+            width = width
+        ))
+    );
+    for (name, mailbox) in names.iter().zip(&report.mailboxes) {
+        let error = mailbox.error.as_ref().map(|x| format!(" {}", x));
+        say!(
+            "{:width$} {:11} {:>6}{}",
+            name,
+            mailbox.status.painted(),
+            mailbox.count,
+            error.unwrap_or_default(),
+            width = width
+        );
+    }
+}
+
+/// Print the document, with the error the run failed with if any.
+pub fn print(error: Option<&Error>) {
+    let mut report = REPORT.lock().unwrap();
+    report.error = error.map(|x| x.to_string());
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&*report).expect("the report is serializable")
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn report() {
+        let mut report = Report {
+            dry_run: true,
+            mailboxes: vec![Mailbox {
+                mailbox: "INBOX".to_string(),
+                rule: None,
+                moved_from: None,
+                status: Status::Done,
+                action: "deleted".to_string(),
+                count: 1,
+                size: Some(120),
+                uids: Some("4:4".to_string()),
+                messages: vec![Message {
+                    uid: 4,
+                    date: Some("2019-03-01T12:00:00+00:00".to_string()),
+                    from: "boss@work.example".to_string(),
+                    subject: "Hello".to_string(),
+                    size: Some(120),
+                    flags: vec!["\\Seen".to_string()],
+                }],
+                error: None,
+            }],
+            total: 1,
+            size: Some(120),
+            failed: 0,
+            interrupted: false,
+            error: None,
+            rule: None,
+            messages: Vec::new(),
+        };
+        report.error = Some("1 of 2 mailboxes failed".to_string());
+        assert_eq!(
+            serde_json::to_value(&report).unwrap(),
+            serde_json::json!({
+                "dry_run": true,
+                "mailboxes": [{
+                    "mailbox": "INBOX",
+                    "status": "done",
+                    "action": "deleted",
+                    "count": 1,
+                    "size": 120,
+                    "uids": "4:4",
+                    "messages": [{
+                        "uid": 4,
+                        "date": "2019-03-01T12:00:00+00:00",
+                        "from": "boss@work.example",
+                        "subject": "Hello",
+                        "size": 120,
+                        "flags": ["\\Seen"],
+                    }],
+                }],
+                "total": 1,
+                "size": 120,
+                "failed": 0,
+                "interrupted": false,
+                "error": "1 of 2 mailboxes failed",
+            })
+        );
+    }
+}
@@ -0,0 +1,250 @@
+use crate::error::{Error, Result};
+use crate::secrets::SecretRef;
+use crate::tunnel::shell;
+use std::env::VarError;
+use std::io::{BufRead, IsTerminal};
+use std::path::PathBuf;
+use std::process::Stdio;
+
+/// Environment variable holding the password, there is no command line option for it so it does
+/// not show up in the process list.
+const PASSWORD_ENV: &str = "IMAP_CLEANUP_PASSWORD";
+
+/// Environment variables holding credentials. They are not passed on to the commands we run.
+pub const SECRET_ENV_VARS: &[&str] = &[
+    PASSWORD_ENV,
+    "IMAP_CLEANUP_OAUTH_TOKEN",
+    "IMAP_CLEANUP_OAUTH_REFRESH_TOKEN",
+    "IMAP_CLEANUP_OAUTH_CLIENT_SECRET",
+];
+
+/// Where to get the password from. When several sources are given, the first one in this order
+/// is used: --password-stdin, --password-file, --password-env, --password-cmd, --secret-ref,
+/// the IMAP_CLEANUP_PASSWORD environment variable. Without any, the password is read from the OS
+/// keyring (see `auth store`) or prompted on the terminal.
+#[derive(clap::Args, Debug)]
+pub struct PasswordArgs {
+    /// Read the password from the first line of the standard input.
+    #[clap(long, env = "IMAP_CLEANUP_PASSWORD_STDIN")]
+    pub password_stdin: bool,
+
+    /// Read the password from the first line of this file.
+    #[clap(long, env = "IMAP_CLEANUP_PASSWORD_FILE")]
+    pub password_file: Option<PathBuf>,
+
+    /// Read the password from this environment variable.
+    #[clap(long, value_name = "VAR", env = "IMAP_CLEANUP_PASSWORD_ENV")]
+    pub password_env: Option<String>,
+
+    /// Run this command and use its output as password, for example "pass show mail/imap".
+    #[clap(long, value_name = "COMMAND", env = "IMAP_CLEANUP_PASSWORD_CMD")]
+    pub password_cmd: Option<String>,
+
+    /// Fetch the password from a secrets manager: vault://<path>#<field> (requires the `vault`
+    /// feature) or aws-sm://<secret-id>[#<key>] (requires the `aws-secrets` feature). With
+    /// --auth xoauth2 or oauthbearer the secret is used as access token instead.
+    #[clap(long, value_name = "URI", env = "IMAP_CLEANUP_SECRET_REF")]
+    pub secret_ref: Option<SecretRef>,
+}
+
+impl PasswordArgs {
+    /// Get the password to login as `user` on `host`.
+    pub fn get(&self, host: &str, user: &str) -> Result<String> {
+        if let Some(password) = self.explicit(|name| std::env::var(name))? {
+            return Ok(password);
+        }
+        if let Some(password) = lookup(host, user) {
+            return Ok(password);
+        }
+        prompt()
+    }
+
+    /// Get the password from the options or the terminal but never from the keyring.
+    pub fn read(&self) -> Result<String> {
+        match self.explicit(|name| std::env::var(name))? {
+            Some(password) => Ok(password),
+            None => prompt(),
+        }
+    }
+
+    /// The password given by the options, with the variables of the environment read by `var`.
+    fn explicit(&self, var: impl Fn(&str) -> Result<String, VarError>) -> Result<Option<String>> {
+        if self.password_stdin {
+            let mut line = String::new();
+            std::io::stdin().lock().read_line(&mut line)?;
+            return non_empty(first_line(&line), "the standard input").map(Some);
+        }
+
+        if let Some(path) = &self.password_file {
+            let content = std::fs::read_to_string(path)
+                .map_err(|err| Error::Password(format!("{}: {}", path.display(), err)))?;
+            return non_empty(first_line(&content), &path.display().to_string()).map(Some);
+        }
+
+        if let Some(name) = &self.password_env {
+            let value = var(name).map_err(|err| Error::Password(format!("{}: {}", name, err)))?;
+            return non_empty(&value, name).map(Some);
+        }
+
+        if let Some(command) = &self.password_cmd {
+            return run_password_command(command, self.password_env.as_deref()).map(Some);
+        }
+
+        if let Some(secret) = &self.secret_ref {
+            return secret.fetch().map(Some);
+        }
+
+        if let Ok(value) = var(PASSWORD_ENV) {
+            return non_empty(&value, PASSWORD_ENV).map(Some);
+        }
+
+        Ok(None)
+    }
+}
+
+/// Run the command with the terminal still attached (for pinentry and the like) and return its
+/// trimmed output. The output is never included in error messages.
+fn run_password_command(command: &str, password_env: Option<&str>) -> Result<String> {
+    let output = shell(command, password_env)
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|err| Error::Password(format!("could not run {:?}: {}", command, err)))?;
+    if !output.status.success() {
+        return Err(Error::Password(format!(
+            "{:?} failed ({})",
+            command, output.status
+        )));
+    }
+    let stdout = String::from_utf8(output.stdout)
+        .map_err(|_| Error::Password(format!("{:?} did not output valid UTF-8", command)))?;
+    non_empty(stdout.trim(), &format!("{:?}", command))
+}
+
+#[cfg(feature = "keyring")]
+const KEYRING_SERVICE: &str = "imap-cleanup";
+
+#[cfg(feature = "keyring")]
+fn keyring_entry(host: &str, user: &str) -> Result<keyring::Entry> {
+    keyring::Entry::new(KEYRING_SERVICE, &format!("{}@{}", user, host))
+        .map_err(|err| Error::Password(format!("keyring: {}", err)))
+}
+
+/// Read the password from the keyring. Any error is treated like a missing entry so the user
+/// gets prompted instead.
+#[cfg(feature = "keyring")]
+fn lookup(host: &str, user: &str) -> Option<String> {
+    keyring_entry(host, user).ok()?.get_password().ok()
+}
+
+#[cfg(not(feature = "keyring"))]
+fn lookup(_host: &str, _user: &str) -> Option<String> {
+    None
+}
+
+#[cfg(feature = "keyring")]
+pub fn store(host: &str, user: &str, password: &str) -> Result<()> {
+    keyring_entry(host, user)?
+        .set_password(password)
+        .map_err(|err| Error::Password(format!("keyring: {}", err)))?;
+    println!("Password saved for {}@{}.", user, host);
+    Ok(())
+}
+
+#[cfg(feature = "keyring")]
+pub fn forget(host: &str, user: &str) -> Result<()> {
+    match keyring_entry(host, user)?.delete_credential() {
+        Ok(()) => println!("Password removed for {}@{}.", user, host),
+        Err(keyring::Error::NoEntry) => println!("No password saved for {}@{}.", user, host),
+        Err(err) => return Err(Error::Password(format!("keyring: {}", err))),
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "keyring"))]
+pub fn store(_host: &str, _user: &str, _password: &str) -> Result<()> {
+    Err(Error::Password(
+        "this binary was built without the keyring feature".to_string(),
+    ))
+}
+
+#[cfg(not(feature = "keyring"))]
+pub fn forget(_host: &str, _user: &str) -> Result<()> {
+    Err(Error::Password(
+        "this binary was built without the keyring feature".to_string(),
+    ))
+}
+
+/// Ask for the password on the terminal, not without one: like the runs of --all-accounts, whose
+/// prompts would not be seen.
+fn prompt() -> Result<String> {
+    if !std::io::stdin().is_terminal() {
+        return Err(Error::Password(
+            "no password given and no terminal to ask for it (see --password-cmd)".to_string(),
+        ));
+    }
+    Ok(rpassword::prompt_password("Password: ")?)
+}
+
+fn first_line(s: &str) -> &str {
+    s.lines().next().unwrap_or_default()
+}
+
+fn non_empty(password: &str, source: &str) -> Result<String> {
+    if password.is_empty() {
+        Err(Error::Password(format!("empty password from {}", source)))
+    } else {
+        Ok(password.to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn precedence() {
+        let dir = std::env::temp_dir().join(format!("imap-cleanup-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("password");
+        std::fs::write(&path, "from file\r\nignored\n").unwrap();
+        // Not the environment of the process, read by the other tests running meanwhile.
+        let env = |name: &str| match name {
+            "IMAP_CLEANUP_TEST_PASSWORD" => Ok("from env".to_string()),
+            _ => Err(VarError::NotPresent),
+        };
+
+        let mut args = PasswordArgs {
+            password_stdin: false,
+            password_file: Some(path.clone()),
+            password_env: Some("IMAP_CLEANUP_TEST_PASSWORD".to_string()),
+            password_cmd: None,
+            secret_ref: None,
+        };
+        assert_eq!(args.explicit(env).unwrap().as_deref(), Some("from file"));
+
+        args.password_file = None;
+        assert_eq!(args.explicit(env).unwrap().as_deref(), Some("from env"));
+
+        args.password_env = Some("IMAP_CLEANUP_TEST_PASSWORD_UNSET".to_string());
+        assert!(args.explicit(env).is_err());
+
+        std::fs::write(&path, "\n").unwrap();
+        args.password_file = Some(path);
+        assert!(args.explicit(env).is_err());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn password_command() {
+        assert_eq!(
+            run_password_command("printf '  secret\\n'", None).unwrap(),
+            "secret"
+        );
+        assert!(run_password_command("true", None).is_err());
+        let err = run_password_command("echo leaked; false", None).unwrap_err();
+        assert!(!err.to_string().contains("leaked\n"));
+    }
+}
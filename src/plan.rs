@@ -0,0 +1,211 @@
+use crate::action::Action;
+use crate::error::{Error, Result};
+use crate::export;
+use crate::uidlist::UidList;
+use imap::types::Fetch;
+use imap::Session;
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+/// The version of the format of the plans, those of another version are refused.
+const VERSION: u32 = 1;
+
+/// What a cleanup would do, written by `plan` to be reviewed then applied as is by `apply`: the
+/// messages of each mailbox, by UID with the UIDVALIDITY they were found with.
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq, Eq)]
+pub struct Plan {
+    pub version: u32,
+    /// When the plan was made, in RFC 3339.
+    pub created: String,
+    /// The account the messages are in, like `me@imap.example.com`.
+    pub account: String,
+    pub action: Action,
+    pub mailboxes: Vec<Mailbox>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq, Eq)]
+pub struct Mailbox {
+    pub mailbox: String,
+    pub uid_validity: Option<u32>,
+    pub messages: Vec<Message>,
+}
+
+/// A message of a plan, with the fields to review it.
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq, Eq)]
+pub struct Message {
+    pub uid: u32,
+    pub date: Option<String>,
+    pub from: String,
+    pub subject: String,
+    pub size: Option<u32>,
+}
+
+/// The messages found by the dry run of `plan`, by mailbox.
+#[derive(Debug, Default)]
+pub struct Planner {
+    mailboxes: Mutex<BTreeMap<String, Mailbox>>,
+}
+
+impl Planner {
+    pub fn add(&self, mailbox: &str, uid_validity: Option<u32>, message: &Fetch) {
+        let Some(uid) = message.uid else {
+            return;
+        };
+        let (from, subject) = export::from_and_subject(message);
+        let mut mailboxes = self.mailboxes.lock().unwrap();
+        let planned = mailboxes
+            .entry(mailbox.to_string())
+            .or_insert_with(|| Mailbox {
+                mailbox: mailbox.to_string(),
+                uid_validity,
+                messages: Vec::new(),
+            });
+        planned.messages.push(Message {
+            uid,
+            date: message.internal_date().map(|x| x.to_rfc3339()),
+            from,
+            subject,
+            size: message.size,
+        });
+    }
+
+    /// The plan of the messages found, in the order of their UIDs.
+    pub fn plan(self, account: String, action: Action) -> Plan {
+        let mut mailboxes = self
+            .mailboxes
+            .into_inner()
+            .unwrap()
+            .into_values()
+            .collect::<Vec<_>>();
+        for mailbox in &mut mailboxes {
+            mailbox.messages.sort_by_key(|x| x.uid);
+        }
+        Plan {
+            version: VERSION,
+            created: chrono::Local::now().to_rfc3339(),
+            account,
+            action,
+            mailboxes,
+        }
+    }
+}
+
+impl Plan {
+    pub fn load(path: &Path) -> Result<Self> {
+        let invalid =
+            |err: &dyn std::fmt::Display| Error::Config(format!("{}: {}", path.display(), err));
+        let content = std::fs::read_to_string(path).map_err(|err| invalid(&err))?;
+        let plan: Plan = serde_json::from_str(&content).map_err(|err| invalid(&err))?;
+        if plan.version != VERSION {
+            return Err(invalid(&format!(
+                "a plan of version {}, expected {}",
+                plan.version, VERSION
+            )));
+        }
+        Ok(plan)
+    }
+
+    /// Write the plan, readable by people too.
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let mut file = std::fs::File::create(path)?;
+        serde_json::to_writer_pretty(&mut file, self).map_err(std::io::Error::from)?;
+        writeln!(file)?;
+        Ok(())
+    }
+
+    pub fn count(&self) -> usize {
+        self.mailboxes.iter().map(|x| x.messages.len()).sum()
+    }
+
+    /// The messages to clean, the only ones.
+    pub fn uid_list(&self) -> UidList {
+        let mut list = UidList::default();
+        for mailbox in &self.mailboxes {
+            let uids = mailbox.messages.iter().map(|x| x.uid).collect();
+            list.insert(&mailbox.mailbox, mailbox.uid_validity, uids);
+        }
+        list
+    }
+
+    /// Refuse to apply the plan to another account, or when a mailbox got new UIDs since: the
+    /// messages planned may not be theirs anymore. Nothing is changed then.
+    pub fn validate<S: Read + Write>(&self, session: &mut Session<S>, account: &str) -> Result<()> {
+        if self.account != account {
+            return Err(Error::Guard(format!(
+                "the plan was made for {}, not {}, nothing was cleaned",
+                self.account, account
+            )));
+        }
+        // EXAMINE rather than STATUS, whose response imap does not read.
+        for mailbox in &self.mailboxes {
+            let current = session.examine(&mailbox.mailbox)?.uid_validity;
+            if current != mailbox.uid_validity {
+                return Err(Error::Guard(format!(
+                    "the UIDVALIDITY of {} changed since the plan was made ({}), nothing was \
+                     cleaned",
+                    mailbox.mailbox, self.created
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::connection::test::session;
+
+    #[test]
+    fn planned() {
+        let plan = Plan {
+            version: VERSION,
+            created: "2024-05-01T10:00:00+02:00".to_string(),
+            account: "me@imap.example.com".to_string(),
+            action: Action::Move("Archive".to_string()),
+            mailboxes: vec![Mailbox {
+                mailbox: "INBOX".to_string(),
+                uid_validity: Some(7),
+                messages: [3, 1]
+                    .map(|uid| Message {
+                        uid,
+                        date: None,
+                        from: "news@example.com".to_string(),
+                        subject: "Hello".to_string(),
+                        size: Some(120),
+                    })
+                    .into(),
+            }],
+        };
+        let json = serde_json::to_string(&plan).unwrap();
+        assert!(json.contains(r#""action":{"move":"Archive"}"#));
+        assert_eq!(serde_json::from_str::<Plan>(&json).unwrap(), plan);
+        assert_eq!(plan.count(), 2);
+        assert_eq!(plan.uid_list().uids("INBOX"), [1, 3]);
+
+        let examined = |uid_validity: u32| {
+            format!(
+                "* OK [UIDVALIDITY {}] UIDs valid\r\na2 OK [READ-ONLY] done\r\n",
+                uid_validity
+            )
+        };
+        let (mut imap, _, sent) = session(examined(7).as_bytes());
+        plan.validate(&mut imap, "me@imap.example.com").unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&sent.borrow()),
+            "a2 EXAMINE \"INBOX\"\r\n"
+        );
+        let (mut imap, _, _) = session(examined(8).as_bytes());
+        assert!(matches!(
+            plan.validate(&mut imap, "me@imap.example.com"),
+            Err(Error::Guard(_))
+        ));
+        let (mut imap, _, _) = session(b"");
+        assert!(matches!(
+            plan.validate(&mut imap, "me@other.example.com"),
+            Err(Error::Guard(_))
+        ));
+    }
+}
@@ -0,0 +1,326 @@
+use crate::action::Action;
+use crate::age::Age;
+use crate::config::one_or_many;
+use crate::error::{Error, Result};
+use crate::search::{self, SearchArgs};
+use std::path::Path;
+
+/// A retention policy, the rules applied in one run by `imap-cleanup apply --policy`:
+///
+/// ```toml
+/// [[rule]]
+/// mailbox = "Lists/*"
+/// max-age = "30d"
+///
+/// [[rule]]
+/// mailbox = ["INBOX", "Archive"]
+/// max-age = "1y"
+/// larger-than = "5M"
+/// protect-flag = ["\\Flagged", "$Important"]
+/// action = "strip-attachments"
+///
+/// [[rule]]
+/// mailbox = "Notifications"
+/// max-age = "90d"
+/// move-to = "Trash"
+/// ```
+///
+/// The rules are applied in their order, except that a rule moving messages to a mailbox goes
+/// before the rules cleaning it, for tiers like "move INBOX to Archive after 90 days, delete
+/// Archive after 3 years". The options given on the command line apply to all of them.
+#[derive(serde::Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Policy {
+    #[serde(default)]
+    pub rule: Vec<Rule>,
+}
+
+/// The messages of some mailboxes to cleanup and how.
+#[derive(serde::Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(try_from = "RawRule")]
+pub struct Rule {
+    /// The names or patterns of the mailboxes, with the wildcards of --mailbox.
+    pub mailbox: Vec<String>,
+    /// The messages older than this are cleaned.
+    pub max_age: Age,
+    /// Same as --larger-than.
+    pub larger_than: Option<u64>,
+    /// Same as --smaller-than.
+    pub smaller_than: Option<u64>,
+    /// Same as --protect-flag, an empty list protects no flag. The flags of the command line
+    /// when not given.
+    pub protect_flag: Option<Vec<String>>,
+    pub action: Action,
+}
+
+/// What to do with the messages, `move` requires `move-to`.
+#[derive(serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+enum RuleAction {
+    Delete,
+    Move,
+    StripAttachments,
+}
+
+/// A rule as written in the file, checked by `Rule::try_from`.
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct RawRule {
+    #[serde(deserialize_with = "one_or_many")]
+    mailbox: Vec<String>,
+    max_age: Age,
+    #[serde(default, deserialize_with = "size")]
+    larger_than: Option<u64>,
+    #[serde(default, deserialize_with = "size")]
+    smaller_than: Option<u64>,
+    protect_flag: Option<Vec<String>>,
+    action: Option<RuleAction>,
+    move_to: Option<String>,
+}
+
+impl TryFrom<RawRule> for Rule {
+    type Error = String;
+
+    fn try_from(raw: RawRule) -> std::result::Result<Self, Self::Error> {
+        if raw.mailbox.is_empty() {
+            return Err("a rule needs at least one mailbox".to_string());
+        }
+        if let (Some(larger), Some(smaller)) = (raw.larger_than, raw.smaller_than) {
+            if larger >= smaller {
+                return Err("larger-than must be less than smaller-than".to_string());
+            }
+        }
+        let protect_flag = raw
+            .protect_flag
+            .map(|flags| {
+                flags
+                    .iter()
+                    .map(|x| search::parse_flag(x).map_err(|err| format!("{}: {}", x, err)))
+                    .collect::<std::result::Result<Vec<_>, _>>()
+            })
+            .transpose()?;
+        let action = match (raw.action, raw.move_to) {
+            (None | Some(RuleAction::Move), Some(move_to)) => Action::Move(move_to),
+            (None | Some(RuleAction::Delete), None) => Action::Delete,
+            (Some(RuleAction::StripAttachments), None) => Action::StripAttachments,
+            (Some(RuleAction::Move), None) => {
+                return Err("action = \"move\" requires move-to".to_string())
+            }
+            (Some(_), Some(_)) => return Err("move-to requires action = \"move\"".to_string()),
+        };
+        Ok(Rule {
+            mailbox: raw.mailbox,
+            max_age: raw.max_age,
+            larger_than: raw.larger_than,
+            smaller_than: raw.smaller_than,
+            protect_flag,
+            action,
+        })
+    }
+}
+
+/// A size like `5M` or a number of bytes.
+fn size<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Option<u64>, D::Error> {
+    use serde::de::Error;
+
+    #[derive(serde::Deserialize)]
+    #[serde(untagged)]
+    enum Size {
+        Bytes(u64),
+        Text(String),
+    }
+
+    match <Size as serde::Deserialize>::deserialize(deserializer)? {
+        Size::Bytes(bytes) => Ok(Some(bytes)),
+        Size::Text(text) => search::parse_size(&text)
+            .map(Some)
+            .map_err(D::Error::custom),
+    }
+}
+
+impl Policy {
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|err| Error::Config(format!("{}: {}", path.display(), err)))?;
+        let policy = Self::parse(&content)
+            .map_err(|err| Error::Config(format!("{}: {}", path.display(), err)))?;
+        if policy.rule.is_empty() {
+            return Err(Error::Config(format!("{}: no [[rule]]", path.display())));
+        }
+        Ok(policy)
+    }
+
+    fn parse(content: &str) -> std::result::Result<Self, toml::de::Error> {
+        toml::from_str(content)
+    }
+}
+
+impl Rule {
+    /// The search options of the command line with the limits of this rule.
+    pub fn search(&self, args: &SearchArgs) -> SearchArgs {
+        let mut search = args.clone();
+        search.larger_than = self.larger_than.or(search.larger_than);
+        search.smaller_than = self.smaller_than.or(search.smaller_than);
+        if let Some(flags) = &self.protect_flag {
+            search.protect_flag = flags.clone();
+            search.no_protect_flag = flags.is_empty();
+        }
+        search
+    }
+}
+
+/// The order to apply rules cleaning these mailboxes with these actions: the rules moving messages
+/// to a mailbox go before the rules cleaning it, so that they clean the moved messages too. The
+/// order of the file is kept otherwise. None if the moves make a cycle.
+pub fn order(rules: &[(&[String], &Action)]) -> Option<Vec<usize>> {
+    let before = |i: usize, j: usize| match rules[i].1 {
+        Action::Move(move_to) => i != j && rules[j].0.contains(move_to),
+        _ => false,
+    };
+    let mut order = Vec::new();
+    let mut left = (0..rules.len()).collect::<Vec<_>>();
+    while !left.is_empty() {
+        // The first rule no rule left has to go before.
+        let next = left
+            .iter()
+            .position(|j| !left.iter().any(|i| before(*i, *j)))?;
+        order.push(left.remove(next));
+    }
+    Some(order)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use clap::Parser;
+
+    #[test]
+    fn parse() {
+        let policy = Policy::parse(
+            r#"
+            [[rule]]
+            mailbox = "Lists/*"
+            max-age = "30d"
+
+            [[rule]]
+            mailbox = ["INBOX", "Archive"]
+            max-age = "1y"
+            larger-than = "5M"
+            smaller-than = 1000000000
+            protect-flag = ["\\flagged", "$Important"]
+            action = "strip-attachments"
+
+            [[rule]]
+            mailbox = "Notifications"
+            max-age = "90d"
+            move-to = "Trash"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            policy.rule,
+            [
+                Rule {
+                    mailbox: vec!["Lists/*".to_string()],
+                    max_age: Age::Days(30),
+                    larger_than: None,
+                    smaller_than: None,
+                    protect_flag: None,
+                    action: Action::Delete,
+                },
+                Rule {
+                    mailbox: vec!["INBOX".to_string(), "Archive".to_string()],
+                    max_age: Age::Years(1),
+                    larger_than: Some(5 << 20),
+                    smaller_than: Some(1000000000),
+                    protect_flag: Some(vec!["\\Flagged".to_string(), "$Important".to_string()]),
+                    action: Action::StripAttachments,
+                },
+                Rule {
+                    mailbox: vec!["Notifications".to_string()],
+                    max_age: Age::Days(90),
+                    larger_than: None,
+                    smaller_than: None,
+                    protect_flag: None,
+                    action: Action::Move("Trash".to_string()),
+                },
+            ]
+        );
+
+        let rule = |content: &str| Policy::parse(&format!("[[rule]]\n{}", content));
+        assert!(rule("mailbox = \"INBOX\"").is_err());
+        assert!(rule("max-age = \"30d\"").is_err());
+        assert!(rule("mailbox = []\nmax-age = \"30d\"").is_err());
+        assert!(rule("mailbox = \"INBOX\"\nmax-age = \"30d\"\nmax-size = 1").is_err());
+        assert!(rule("mailbox = \"INBOX\"\nmax-age = \"30d\"\nlarger-than = \"5X\"").is_err());
+        assert!(rule("mailbox = \"INBOX\"\nmax-age = \"30d\"\naction = \"move\"").is_err());
+        assert!(rule(
+            "mailbox = \"INBOX\"\nmax-age = \"30d\"\naction = \"delete\"\nmove-to = \"Trash\""
+        )
+        .is_err());
+        assert!(
+            rule("mailbox = \"INBOX\"\nmax-age = \"30d\"\nprotect-flag = [\"\\\\Nope\"]").is_err()
+        );
+        assert!(rule(
+            "mailbox = \"INBOX\"\nmax-age = \"30d\"\nlarger-than = \"5M\"\nsmaller-than = \"1M\""
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn search() {
+        #[derive(clap::Parser)]
+        struct Args {
+            #[clap(flatten)]
+            search: SearchArgs,
+        }
+
+        let args = Args::parse_from(["test", "--smaller-than", "1G", "--protect-flag", "$Keep"]);
+        let mut rule = Rule {
+            mailbox: vec!["INBOX".to_string()],
+            max_age: Age::Days(30),
+            larger_than: Some(1024),
+            smaller_than: None,
+            protect_flag: None,
+            action: Action::Delete,
+        };
+        let search = rule.search(&args.search);
+        assert_eq!(
+            (search.larger_than, search.smaller_than),
+            (Some(1024), Some(1 << 30))
+        );
+        assert_eq!(search.protect_flag, ["$Keep"]);
+
+        rule.protect_flag = Some(Vec::new());
+        assert!(rule.search(&args.search).no_protect_flag);
+    }
+
+    #[test]
+    fn tiers() {
+        let names = |names: &[&str]| names.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+        let (inbox, archive, old) = (
+            names(&["INBOX"]),
+            names(&["Archive"]),
+            names(&["Archive/2020", "Archive/2021"]),
+        );
+        let delete = Action::Delete;
+        let to_archive = Action::Move("Archive".to_string());
+        let to_old = Action::Move("Archive/2021".to_string());
+        assert_eq!(
+            order(&[(&old, &delete), (&archive, &to_old), (&inbox, &to_archive)]),
+            Some(vec![2, 1, 0])
+        );
+        assert_eq!(
+            order(&[
+                (&inbox, &delete),
+                (&archive, &delete),
+                (&inbox, &to_archive)
+            ]),
+            Some(vec![0, 2, 1])
+        );
+        let to_inbox = Action::Move("INBOX".to_string());
+        assert_eq!(order(&[(&inbox, &to_archive), (&archive, &to_inbox)]), None);
+    }
+}
@@ -0,0 +1,152 @@
+use crate::color::bold;
+use crate::export;
+use crate::output::say;
+use crate::search::format_size;
+use chrono::{DateTime, FixedOffset};
+use imap::types::Fetch;
+use itertools::Itertools;
+use std::cmp::Ordering;
+use std::fmt;
+
+/// How to order the messages listed by a dry run.
+#[derive(clap::ValueEnum, serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Sort {
+    /// The oldest first.
+    Date,
+    /// The largest first.
+    Size,
+    /// By sender address.
+    Sender,
+}
+
+/// A message listed by a dry run.
+#[derive(Debug)]
+pub struct Row {
+    uid: u32,
+    date: Option<DateTime<FixedOffset>>,
+    size: u32,
+    from: String,
+    flags: String,
+    subject: String,
+}
+
+impl Row {
+    /// The row of a message fetched with its INTERNALDATE, FLAGS, ENVELOPE and RFC822.SIZE.
+    pub fn new(message: &Fetch) -> Self {
+        let (from, subject) = export::from_and_subject(message);
+        Self {
+            uid: message.uid.unwrap_or_default(),
+            date: message.internal_date(),
+            size: message.size.unwrap_or_default(),
+            from,
+            flags: message.flags().iter().map(|x| x.to_string()).join(" "),
+            subject,
+        }
+    }
+
+    pub fn uid(&self) -> u32 {
+        self.uid
+    }
+
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    pub fn date(&self) -> Option<DateTime<FixedOffset>> {
+        self.date
+    }
+
+    /// The address of the sender.
+    pub fn from(&self) -> &str {
+        &self.from
+    }
+}
+
+/// A line under the header.
+impl fmt::Display for Row {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:>7} {:16} {:>7} {:30} {:16} {}",
+            self.uid,
+            self.date
+                .map(|x| x.format("%Y-%m-%d %H:%M").to_string())
+                .unwrap_or_default(),
+            format_size(self.size.into()),
+            self.from,
+            self.flags,
+            self.subject
+        )
+    }
+}
+
+/// The header of the columns of the rows.
+pub fn header() -> String {
+    format!(
+        "{:>7} {:16} {:>7} {:30} {:16} SUBJECT",
+        "UID", "DATE", "SIZE", "FROM", "FLAGS"
+    )
+}
+
+/// Print the messages of a mailbox, in the order of their UIDs unless sorted. The ties stay in
+/// the order of their UIDs, reversed or not.
+pub fn print(rows: &mut [Row], sort: Option<Sort>, reverse: bool) {
+    if rows.is_empty() {
+        return;
+    }
+    sort_rows(rows, sort, reverse);
+    say!("{}", bold(header()));
+    for row in rows.iter() {
+        say!("{}", row);
+    }
+}
+
+pub fn sort_rows(rows: &mut [Row], sort: Option<Sort>, reverse: bool) {
+    let compare = |a: &Row, b: &Row| match sort {
+        Some(Sort::Date) => a.date.cmp(&b.date),
+        Some(Sort::Size) => b.size.cmp(&a.size),
+        Some(Sort::Sender) => a.from.to_lowercase().cmp(&b.from.to_lowercase()),
+        None => Ordering::Equal,
+    };
+    rows.sort_by(|a, b| match reverse {
+        true => compare(b, a),
+        false => compare(a, b),
+    });
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    pub fn row(uid: u32, size: u32, from: &str) -> Row {
+        Row {
+            uid,
+            date: None,
+            size,
+            from: from.to_string(),
+            flags: String::new(),
+            subject: String::new(),
+        }
+    }
+
+    /// A row of a message received at this time, like 2020-01-01T12:00:00+00:00.
+    pub fn dated(uid: u32, from: &str, date: &str) -> Row {
+        Row {
+            date: Some(DateTime::parse_from_rfc3339(date).unwrap()),
+            ..row(uid, 10, from)
+        }
+    }
+
+    #[test]
+    fn sorted() {
+        let uids = |rows: &[Row]| rows.iter().map(|x| x.uid).collect::<Vec<_>>();
+        let mut rows = vec![row(1, 10, "b@x"), row(2, 30, "A@x"), row(3, 10, "c@x")];
+        sort_rows(&mut rows, Some(Sort::Size), false);
+        assert_eq!(uids(&rows), [2, 1, 3]);
+        sort_rows(&mut rows, Some(Sort::Size), true);
+        assert_eq!(uids(&rows), [1, 3, 2]);
+        sort_rows(&mut rows, Some(Sort::Sender), false);
+        assert_eq!(uids(&rows), [2, 1, 3]);
+    }
+}
@@ -0,0 +1,284 @@
+use crate::connection::tcp_connect;
+use crate::error::{Error, Result};
+use std::io::{Read, Write};
+use std::net::{IpAddr, TcpStream, ToSocketAddrs};
+use std::str::FromStr;
+use std::time::Duration;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    /// SOCKS5 with the target resolved locally.
+    Socks5,
+    /// SOCKS5 with the target resolved by the proxy.
+    Socks5h,
+    /// HTTP CONNECT.
+    Http,
+}
+
+/// A proxy given as `socks5://[user:password@]host:port`, `socks5h://...` or `http://...`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Proxy {
+    kind: Kind,
+    host: String,
+    port: u16,
+    credentials: Option<(String, String)>,
+}
+
+impl FromStr for Proxy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (scheme, rest) = s
+            .split_once("://")
+            .ok_or_else(|| "expected scheme://host:port".to_string())?;
+        let (kind, default_port) = match scheme.to_ascii_lowercase().as_str() {
+            "socks5" => (Kind::Socks5, 1080),
+            "socks5h" => (Kind::Socks5h, 1080),
+            "http" => (Kind::Http, 8080),
+            other => return Err(format!("unsupported proxy scheme: {}", other)),
+        };
+        let rest = rest.trim_end_matches('/');
+        let (credentials, address) = match rest.rsplit_once('@') {
+            Some((credentials, address)) => {
+                let (user, password) = credentials.split_once(':').unwrap_or((credentials, ""));
+                (Some((user.to_string(), password.to_string())), address)
+            }
+            None => (None, rest),
+        };
+        let (host, port) = if let Some(rest) = address.strip_prefix('[') {
+            let (host, rest) = rest
+                .split_once(']')
+                .ok_or_else(|| "unterminated IPv6 address".to_string())?;
+            (host, rest.strip_prefix(':'))
+        } else {
+            match address.rsplit_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (address, None),
+            }
+        };
+        let port = match port {
+            Some(port) => port
+                .parse::<u16>()
+                .map_err(|err| format!("invalid proxy port: {}", err))?,
+            None => default_port,
+        };
+        if host.is_empty() {
+            return Err("missing proxy host".to_string());
+        }
+
+        Ok(Proxy {
+            kind,
+            host: host.to_string(),
+            port,
+            credentials,
+        })
+    }
+}
+
+impl Proxy {
+    /// Open a TCP connection to `host:port` through the proxy, see `connection::tcp_connect` for
+    /// the timeout.
+    pub fn connect(&self, host: &str, port: u16, timeout: Option<Duration>) -> Result<TcpStream> {
+        let mut stream = tcp_connect(&self.host, self.port, timeout)?;
+        match self.kind {
+            Kind::Socks5 => {
+                let addr = (host, port)
+                    .to_socket_addrs()?
+                    .next()
+                    .ok_or_else(|| Error::Proxy(format!("could not resolve {}", host)))?;
+                self.socks5(&mut stream, &addr.ip().to_string(), port)?
+            }
+            Kind::Socks5h => self.socks5(&mut stream, host, port)?,
+            Kind::Http => self.http_connect(&mut stream, host, port)?,
+        }
+        Ok(stream)
+    }
+
+    /// SOCKS5 handshake as described in RFC 1928 and RFC 1929.
+    fn socks5<S: Read + Write>(&self, stream: &mut S, host: &str, port: u16) -> Result<()> {
+        let method = if self.credentials.is_some() { 2 } else { 0 };
+        stream.write_all(&[5, 1, method])?;
+        let mut reply = [0; 2];
+        stream.read_exact(&mut reply)?;
+        if reply[0] != 5 || reply[1] != method {
+            return Err(Error::Proxy(
+                "the SOCKS5 proxy refused the authentication method".to_string(),
+            ));
+        }
+
+        if let Some((user, password)) = &self.credentials {
+            if user.len() > 255 || password.len() > 255 {
+                return Err(Error::Proxy("SOCKS5 credentials too long".to_string()));
+            }
+            let mut request = vec![1, user.len() as u8];
+            request.extend_from_slice(user.as_bytes());
+            request.push(password.len() as u8);
+            request.extend_from_slice(password.as_bytes());
+            stream.write_all(&request)?;
+            stream.read_exact(&mut reply)?;
+            if reply[1] != 0 {
+                return Err(Error::Proxy(
+                    "the SOCKS5 proxy rejected the credentials".to_string(),
+                ));
+            }
+        }
+
+        let mut request = vec![5, 1, 0];
+        match host.parse::<IpAddr>() {
+            Ok(IpAddr::V4(ip)) => {
+                request.push(1);
+                request.extend_from_slice(&ip.octets());
+            }
+            Ok(IpAddr::V6(ip)) => {
+                request.push(4);
+                request.extend_from_slice(&ip.octets());
+            }
+            Err(_) if host.len() <= 255 => {
+                request.push(3);
+                request.push(host.len() as u8);
+                request.extend_from_slice(host.as_bytes());
+            }
+            Err(_) => return Err(Error::Proxy(format!("host name too long: {}", host))),
+        }
+        request.extend_from_slice(&port.to_be_bytes());
+        stream.write_all(&request)?;
+
+        let mut reply = [0; 4];
+        stream.read_exact(&mut reply)?;
+        if reply[1] != 0 {
+            return Err(Error::Proxy(format!(
+                "the SOCKS5 proxy could not connect to {}:{} (error {})",
+                host, port, reply[1]
+            )));
+        }
+        // Skip the bound address, it is of no use here.
+        let len = match reply[3] {
+            1 => 4,
+            4 => 16,
+            3 => {
+                let mut len = [0; 1];
+                stream.read_exact(&mut len)?;
+                len[0] as usize
+            }
+            other => {
+                return Err(Error::Proxy(format!(
+                    "unexpected SOCKS5 address type {}",
+                    other
+                )))
+            }
+        };
+        let mut bound = vec![0; len + 2];
+        stream.read_exact(&mut bound)?;
+        Ok(())
+    }
+
+    fn http_connect<S: Read + Write>(&self, stream: &mut S, host: &str, port: u16) -> Result<()> {
+        let mut request = format!(
+            "CONNECT {host}:{port} HTTP/1.1\r\nHost: {host}:{port}\r\n",
+            host = host,
+            port = port
+        );
+        if let Some((user, password)) = &self.credentials {
+            request.push_str(&format!(
+                "Proxy-Authorization: Basic {}\r\n",
+                base64::encode(format!("{}:{}", user, password))
+            ));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes())?;
+        stream.flush()?;
+
+        // Read the response headers byte by byte: the IMAP greeting may follow right after.
+        let mut response = Vec::new();
+        let mut byte = [0; 1];
+        while !response.ends_with(b"\r\n\r\n") {
+            if stream.read(&mut byte)? == 0 {
+                return Err(Error::Proxy(
+                    "the HTTP proxy closed the connection".to_string(),
+                ));
+            }
+            response.push(byte[0]);
+        }
+        let response = String::from_utf8_lossy(&response);
+        let status = response.lines().next().unwrap_or_default();
+        match status.split_whitespace().nth(1) {
+            Some(code) if code.starts_with('2') => Ok(()),
+            _ => Err(Error::Proxy(format!(
+                "the HTTP proxy refused to connect: {}",
+                status
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::connection::test::Mock;
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            "socks5://127.0.0.1:9050".parse(),
+            Ok(Proxy {
+                kind: Kind::Socks5,
+                host: "127.0.0.1".to_string(),
+                port: 9050,
+                credentials: None,
+            })
+        );
+        assert_eq!(
+            "http://user:p@ss@proxy.local".parse(),
+            Ok(Proxy {
+                kind: Kind::Http,
+                host: "proxy.local".to_string(),
+                port: 8080,
+                credentials: Some(("user".to_string(), "p@ss".to_string())),
+            })
+        );
+        assert_eq!(
+            "socks5h://[::1]:1081"
+                .parse::<Proxy>()
+                .map(|x| (x.host, x.port)),
+            Ok(("::1".to_string(), 1081))
+        );
+        assert_eq!(
+            "socks5h://[::1]".parse::<Proxy>().map(|x| (x.host, x.port)),
+            Ok(("::1".to_string(), 1080))
+        );
+        assert!("ftp://proxy:21".parse::<Proxy>().is_err());
+        assert!("proxy:1080".parse::<Proxy>().is_err());
+    }
+
+    #[test]
+    fn socks5_handshake() {
+        let proxy: Proxy = "socks5h://proxy:1080".parse().unwrap();
+        let mut mock = Mock::new(&[5, 0, 5, 0, 0, 1, 10, 0, 0, 1, 0x03, 0xe1]);
+        proxy.socks5(&mut mock, "imap.example.com", 993).unwrap();
+        let mut expected = vec![5, 1, 0, 5, 1, 0, 3, 16];
+        expected.extend_from_slice(b"imap.example.com");
+        expected.extend_from_slice(&[0x03, 0xe1]);
+        assert_eq!(mock.1, expected);
+
+        let mut mock = Mock::new(&[5, 0, 5, 5, 0, 1]);
+        assert!(proxy.socks5(&mut mock, "imap.example.com", 993).is_err());
+    }
+
+    #[test]
+    fn http_handshake() {
+        let proxy: Proxy = "http://proxy:3128".parse().unwrap();
+        let mut mock = Mock::new(b"HTTP/1.1 200 Connection established\r\n\r\n* OK");
+        proxy
+            .http_connect(&mut mock, "imap.example.com", 993)
+            .unwrap();
+        assert_eq!(
+            mock.1,
+            b"CONNECT imap.example.com:993 HTTP/1.1\r\nHost: imap.example.com:993\r\n\r\n"
+        );
+
+        let mut mock = Mock::new(b"HTTP/1.1 403 Forbidden\r\n\r\n");
+        assert!(proxy
+            .http_connect(&mut mock, "imap.example.com", 993)
+            .is_err());
+    }
+}
@@ -0,0 +1,246 @@
+use crate::error::{Error, Result};
+use crate::mailbox::quote;
+use crate::search::format_size;
+use crate::tap::Tap;
+use imap::Session;
+use std::io::{Read, Write};
+
+/// The capability of RFC 2087.
+const CAPABILITY: &str = "QUOTA";
+
+/// A resource of a quota root, like `STORAGE` in KiB or `MESSAGE`.
+#[derive(Debug, PartialEq, Eq)]
+struct Resource {
+    root: String,
+    name: String,
+    usage: u64,
+    limit: u64,
+}
+
+impl Resource {
+    /// The usage in percent of the limit.
+    fn percent(&self) -> u64 {
+        match self.limit {
+            0 => 100,
+            limit => self.usage * 100 / limit,
+        }
+    }
+
+    fn format(&self, value: u64) -> String {
+        match self.name.eq_ignore_ascii_case("STORAGE") {
+            true => format_size(value * 1024),
+            false => value.to_string(),
+        }
+    }
+}
+
+/// Print the usage and limit of the quotas of INBOX, the account's. With `warn_at`, fail when a
+/// resource is used at this percentage of its limit or more, for the monitoring.
+pub fn quota<S: Read + Write>(
+    session: &mut Session<S>,
+    tap: &Tap,
+    warn_at: Option<u8>,
+) -> Result<()> {
+    let resources = resources(session, tap)?;
+    if resources.is_empty() {
+        println!("No quota.");
+        return Ok(());
+    }
+    // The default root is often empty.
+    let root = |x: &Resource| match x.root.is_empty() {
+        true => "\"\"".to_string(),
+        false => x.root.clone(),
+    };
+    let width = resources
+        .iter()
+        .map(|x| root(x).len())
+        .max()
+        .unwrap_or(0)
+        .max(4);
+    println!(
+        "{:width$} {:10} {:>9} {:>9} {:>5}",
+        "ROOT", "RESOURCE", "USAGE", "LIMIT", "USED"
+    );
+    for resource in &resources {
+        println!(
+            "{:width$} {:10} {:>9} {:>9} {:>4}%",
+            root(resource),
+            resource.name,
+            resource.format(resource.usage),
+            resource.format(resource.limit),
+            resource.percent()
+        );
+    }
+    let warn_at = match warn_at {
+        Some(warn_at) => u64::from(warn_at),
+        None => return Ok(()),
+    };
+    match resources.iter().find(|x| x.percent() >= warn_at) {
+        Some(resource) => Err(Error::Quota(format!(
+            "{} is used at {}%, --warn-at is {}%",
+            resource.name,
+            resource.percent(),
+            warn_at
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// The usage and limit of the STORAGE quota of INBOX, in bytes, if any.
+pub fn storage<S: Read + Write>(session: &mut Session<S>, tap: &Tap) -> Result<Option<(u64, u64)>> {
+    Ok(resources(session, tap)?
+        .into_iter()
+        .find(|x| x.name == "STORAGE")
+        .map(|x| (x.usage * 1024, x.limit * 1024)))
+}
+
+/// The resources of the quota roots of INBOX.
+fn resources<S: Read + Write>(session: &mut Session<S>, tap: &Tap) -> Result<Vec<Resource>> {
+    if !session.capabilities()?.has_str(CAPABILITY) {
+        return Err(Error::Protocol(format!(
+            "the server does not support quotas ({})",
+            CAPABILITY
+        )));
+    }
+    let (roots, mut resources) = parse(&run(session, tap, "GETQUOTAROOT INBOX")?);
+    // The servers may leave them to GETQUOTA.
+    for root in roots {
+        if !resources.iter().any(|x| x.root == root) {
+            let command = format!("GETQUOTA {}", quote(&root));
+            resources.extend(parse(&run(session, tap, &command)?).1);
+        }
+    }
+    Ok(resources)
+}
+
+/// Run a command, imap-proto does not know the QUOTA responses: the tap sets them aside.
+fn run<S: Read + Write>(session: &mut Session<S>, tap: &Tap, command: &str) -> Result<String> {
+    tap.take();
+    let mut response = session.run_command_and_read_response(command)?;
+    response.extend(tap.take().concat());
+    Ok(String::from_utf8_lossy(&response).into_owned())
+}
+
+/// The quota roots and the resources in responses like `* QUOTAROOT INBOX ""` and
+/// `* QUOTA "" (STORAGE 10 512)`.
+fn parse(response: &str) -> (Vec<String>, Vec<Resource>) {
+    let mut roots = Vec::new();
+    let mut resources = Vec::new();
+    for line in response.lines() {
+        let upper = line.to_ascii_uppercase();
+        if upper.starts_with("* QUOTAROOT ") {
+            // After the mailbox.
+            roots.extend(words(&line["* QUOTAROOT ".len()..]).into_iter().skip(1));
+        } else if upper.starts_with("* QUOTA ") {
+            let rest = &line["* QUOTA ".len()..];
+            let (root, list) = match rest.rfind('(') {
+                Some(start) => (rest[..start].trim(), &rest[start + 1..]),
+                None => continue,
+            };
+            let root = words(root).into_iter().next().unwrap_or_default();
+            let list = list.trim_end().trim_end_matches(')');
+            let mut items = list.split_ascii_whitespace();
+            while let (Some(name), Some(usage), Some(limit)) =
+                (items.next(), items.next(), items.next())
+            {
+                if let (Ok(usage), Ok(limit)) = (usage.parse(), limit.parse()) {
+                    resources.push(Resource {
+                        root: root.clone(),
+                        name: name.to_ascii_uppercase(),
+                        usage,
+                        limit,
+                    });
+                }
+            }
+        }
+    }
+    (roots, resources)
+}
+
+/// The atoms and quoted strings of a response, unquoted.
+fn words(s: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut chars = s.trim().chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            ' ' => {}
+            '"' => {
+                let mut word = String::new();
+                while let Some(c) = chars.next() {
+                    match c {
+                        '\\' => word.extend(chars.next()),
+                        '"' => break,
+                        c => word.push(c),
+                    }
+                }
+                words.push(word);
+            }
+            c => {
+                let mut word = c.to_string();
+                while let Some(c) = chars.next_if(|x| *x != ' ') {
+                    word.push(c);
+                }
+                words.push(word);
+            }
+        }
+    }
+    words
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::connection::test::session;
+
+    #[test]
+    fn responses() {
+        let (roots, resources) = parse(
+            "* QUOTAROOT INBOX \"\" \"user.me\"\r\n\
+             * QUOTA \"\" (STORAGE 10 512 MESSAGE 3 100)\r\n\
+             a2 OK done\r\n",
+        );
+        assert_eq!(roots, ["", "user.me"]);
+        assert_eq!(
+            resources,
+            [
+                Resource {
+                    root: "".to_string(),
+                    name: "STORAGE".to_string(),
+                    usage: 10,
+                    limit: 512
+                },
+                Resource {
+                    root: "".to_string(),
+                    name: "MESSAGE".to_string(),
+                    usage: 3,
+                    limit: 100
+                },
+            ]
+        );
+        assert_eq!(resources[0].percent(), 1);
+        assert_eq!(resources[0].format(512), "512.0K");
+    }
+
+    #[test]
+    fn warn_at() {
+        let (mut imap, tap, sent) = session(
+            b"* CAPABILITY IMAP4rev1 QUOTA\r\n\
+              a2 OK done\r\n\
+              * QUOTAROOT INBOX \"\" other\r\n\
+              * QUOTA \"\" (STORAGE 950 1000)\r\n\
+              a3 OK done\r\n\
+              * QUOTA other (MESSAGE 1 10)\r\n\
+              a4 OK done\r\n",
+        );
+        assert!(matches!(
+            quota(&mut imap, &tap, Some(90)),
+            Err(Error::Quota(_))
+        ));
+        assert_eq!(
+            String::from_utf8_lossy(&sent.borrow()),
+            "a2 CAPABILITY\r\n\
+             a3 GETQUOTAROOT INBOX\r\n\
+             a4 GETQUOTA \"other\"\r\n"
+        );
+    }
+}
@@ -0,0 +1,137 @@
+use crate::color::yellow;
+use crate::error::{Error, Result};
+use crate::output::tell;
+use std::time::Duration;
+
+/// The retries of the connection on network errors.
+#[derive(clap::Args, Debug)]
+pub struct RetryArgs {
+    /// Retry connecting and logging in this many times when it fails on a network error or the
+    /// server closes the connection (BYE), for the runs from cron. An authentication failure is
+    /// not retried.
+    #[clap(
+        long,
+        value_name = "COUNT",
+        default_value_t = 0,
+        env = "IMAP_CLEANUP_RETRIES"
+    )]
+    pub retries: u32,
+
+    /// The wait before the first retry, doubled before each of the next ones, like 10s or 1m.
+    #[clap(
+        long,
+        value_name = "DURATION",
+        default_value = "5s",
+        value_parser(parse_duration),
+        env = "IMAP_CLEANUP_RETRY_BACKOFF"
+    )]
+    pub retry_backoff: Duration,
+}
+
+impl RetryArgs {
+    /// Run the operation until it succeeds, fails for good or the retries are exhausted.
+    pub fn run<T>(&self, what: &str, mut operation: impl FnMut() -> Result<T>) -> Result<T> {
+        let mut backoff = self.retry_backoff;
+        for attempt in 1.. {
+            match operation() {
+                Err(err) if attempt <= self.retries && is_transient(&err) => {
+                    tell!(
+                        "{} {} failed: {}. Retrying in {}s ({}/{}).",
+                        yellow("Warning:"),
+                        what,
+                        err,
+                        backoff.as_secs_f32(),
+                        attempt,
+                        self.retries
+                    );
+                    std::thread::sleep(backoff);
+                    backoff *= 2;
+                }
+                result => return result,
+            }
+        }
+        unreachable!()
+    }
+}
+
+/// Whether trying again may succeed: a network error or the server closing the
+/// connection, not an answer of the server like a failed login.
+pub fn is_transient(err: &Error) -> bool {
+    matches!(
+        err,
+        Error::Io(_)
+            | Error::Bye(_)
+            | Error::Imap(imap::Error::Io(_) | imap::Error::ConnectionLost)
+    )
+}
+
+/// A duration like 30s, 5m or 1h, in seconds without a unit.
+pub fn parse_duration(s: &str) -> std::result::Result<Duration, String> {
+    let (number, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+    let number = number
+        .parse::<u64>()
+        .map_err(|_| format!("invalid duration: {}", s))?;
+    let seconds = match unit {
+        "" | "s" => number,
+        "m" => number * 60,
+        "h" => number * 3600,
+        _ => {
+            return Err(format!(
+                "invalid duration unit: {} (expected s, m or h)",
+                unit
+            ))
+        }
+    };
+    Ok(Duration::from_secs(seconds))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn duration() {
+        assert_eq!(parse_duration("30s"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse_duration("5m"), Ok(Duration::from_secs(300)));
+        assert_eq!(parse_duration("1h"), Ok(Duration::from_secs(3600)));
+        assert_eq!(parse_duration("10"), Ok(Duration::from_secs(10)));
+        assert!(parse_duration("m").is_err());
+        assert!(parse_duration("3d").is_err());
+    }
+
+    #[test]
+    fn retries() {
+        let retry = RetryArgs {
+            retries: 2,
+            retry_backoff: Duration::ZERO,
+        };
+        let mut attempts = 0;
+        let result = retry.run("connecting", || {
+            attempts += 1;
+            match attempts {
+                1 => Err(Error::Imap(imap::Error::ConnectionLost)),
+                2 => Err(Error::Bye("too many connections".to_string())),
+                _ => Ok(attempts),
+            }
+        });
+        assert_eq!(result.unwrap(), 3);
+
+        let mut attempts = 0;
+        let result: Result<()> = retry.run("connecting", || {
+            attempts += 1;
+            Err(Error::Imap(imap::Error::No(
+                "authentication failed".to_string(),
+            )))
+        });
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+
+        let mut attempts = 0;
+        let result: Result<()> = retry.run("connecting", || {
+            attempts += 1;
+            Err(Error::Imap(imap::Error::ConnectionLost))
+        });
+        assert!(result.is_err());
+        assert_eq!(attempts, 3);
+    }
+}
@@ -0,0 +1,597 @@
+use crate::action::Action;
+use crate::color::{bold, cleaned, formatting, yellow};
+use crate::error::{Error, Result};
+use crate::mime;
+use crate::preview::{self, Row};
+use crate::search::format_size;
+use crate::uidlist::UidList;
+use console::{Key, Term};
+use imap::Session;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Read, Write};
+use std::sync::Mutex;
+
+/// The fields shown above the text of a message.
+const HEADERS: &[&str] = &["From", "To", "Cc", "Date", "Subject"];
+
+/// The start of a message fetched to read it, enough for its text.
+const READ: usize = 64 << 10;
+
+/// Ctrl-L, to clear and draw the screen again after a resize of the terminal.
+const REDRAW: Key = Key::Char('\x0c');
+
+const KEYS: &str = "space: clean or keep · a/n: all/none · g: group by · enter: read · x: go on · \
+                    q: quit";
+
+/// The candidates of `review`, found by a dry run in each mailbox, to choose those to clean.
+#[derive(Debug, Default)]
+pub struct Review {
+    candidates: Mutex<Vec<Candidate>>,
+}
+
+#[derive(Debug)]
+struct Candidate {
+    mailbox: String,
+    uid_validity: Option<u32>,
+    row: Row,
+    /// Chosen to clean, as they all are at first.
+    clean: bool,
+}
+
+impl Review {
+    pub fn add(&self, mailbox: &str, uid_validity: Option<u32>, rows: Vec<Row>) {
+        let mut candidates = self.candidates.lock().unwrap();
+        candidates.extend(rows.into_iter().map(|row| Candidate {
+            mailbox: mailbox.to_string(),
+            uid_validity,
+            row,
+            clean: true,
+        }));
+    }
+
+    /// Show the candidates on the terminal and return those chosen to clean, the others are
+    /// kept. Quitting aborts the run.
+    pub fn run<S: Read + Write>(
+        &self,
+        session: &mut Session<S>,
+        action: &Action,
+    ) -> Result<UidList> {
+        let candidates = std::mem::take(&mut *self.candidates.lock().unwrap());
+        if candidates.is_empty() {
+            return Ok(UidList::default());
+        }
+        let mut screen = Screen::new(candidates);
+        let term = Term::stderr();
+        let _alternate = Alternate::enter(&term)?;
+        // The messages read already.
+        let mut read = HashMap::new();
+        loop {
+            let (height, width) = size(&term);
+            let mut lines = screen.render(height.saturating_sub(3));
+            lines.push(screen.status(action));
+            lines.push(KEYS.to_string());
+            draw(&term, &lines, width)?;
+            match term.read_key()? {
+                Key::ArrowUp | Key::Char('k') => screen.move_by(-1),
+                Key::ArrowDown | Key::Char('j') => screen.move_by(1),
+                Key::PageUp => screen.move_by(-(height as isize - 3)),
+                Key::PageDown => screen.move_by(height as isize - 3),
+                Key::Home => screen.cursor = 0,
+                Key::End => screen.move_by(isize::MAX),
+                Key::Char(' ') => screen.toggle(),
+                Key::Char('a') => screen.set_all(true),
+                Key::Char('n') => screen.set_all(false),
+                Key::Char('g') => screen.regroup(screen.group.next()),
+                REDRAW => term.clear_screen()?,
+                Key::Enter => match screen.lines[screen.cursor] {
+                    Line::Group(..) => screen.toggle(),
+                    Line::Message(i) => {
+                        let candidate = &screen.candidates[i];
+                        let key = (candidate.mailbox.clone(), candidate.row.uid());
+                        if !read.contains_key(&key) {
+                            read.insert(key.clone(), fetch(session, candidate)?);
+                        }
+                        page(&term, &read[&key])?;
+                    }
+                },
+                Key::Char('x') => {
+                    let (count, size) = screen.chosen();
+                    let question = formatting(true, || {
+                        format!(
+                            "{} messages ({}) will be {}, the others kept. Go on? [y/N]",
+                            count,
+                            format_size(size),
+                            cleaned(action.done(), action.frees())
+                        )
+                    });
+                    lines.truncate(lines.len() - 2);
+                    lines.extend([question, String::new()]);
+                    draw(&term, &lines, width)?;
+                    if let Key::Char('y' | 'Y') = term.read_key()? {
+                        return Ok(screen.selection());
+                    }
+                }
+                Key::Char('q') | Key::Escape | Key::CtrlC => return Err(Error::Aborted),
+                _ => {}
+            }
+        }
+    }
+}
+
+/// How the candidates are grouped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Group {
+    Mailbox,
+    Sender,
+    Year,
+}
+
+impl Group {
+    fn next(self) -> Self {
+        match self {
+            Group::Mailbox => Group::Sender,
+            Group::Sender => Group::Year,
+            Group::Year => Group::Mailbox,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Group::Mailbox => "mailbox",
+            Group::Sender => "sender",
+            Group::Year => "year",
+        }
+    }
+
+    fn key(self, candidate: &Candidate) -> String {
+        match self {
+            Group::Mailbox => candidate.mailbox.clone(),
+            Group::Sender => candidate.row.from().to_lowercase(),
+            Group::Year => candidate
+                .row
+                .date()
+                .map_or("no date".to_string(), |x| x.format("%Y").to_string()),
+        }
+    }
+}
+
+/// A line of the list: a group with its candidates, or a candidate under its group.
+#[derive(Debug)]
+enum Line {
+    Group(String, Vec<usize>),
+    Message(usize),
+}
+
+/// The list of the candidates.
+struct Screen {
+    candidates: Vec<Candidate>,
+    group: Group,
+    lines: Vec<Line>,
+    cursor: usize,
+    /// The first line shown.
+    top: usize,
+}
+
+impl Screen {
+    fn new(candidates: Vec<Candidate>) -> Self {
+        let mut screen = Screen {
+            candidates,
+            group: Group::Mailbox,
+            lines: Vec::new(),
+            cursor: 0,
+            top: 0,
+        };
+        screen.regroup(Group::Mailbox);
+        screen
+    }
+
+    fn regroup(&mut self, group: Group) {
+        let mut groups = BTreeMap::<_, Vec<_>>::new();
+        for (i, candidate) in self.candidates.iter().enumerate() {
+            groups.entry(group.key(candidate)).or_default().push(i);
+        }
+        self.lines = groups
+            .into_iter()
+            .flat_map(|(name, members)| {
+                let messages = members
+                    .iter()
+                    .map(|&i| Line::Message(i))
+                    .collect::<Vec<_>>();
+                std::iter::once(Line::Group(name, members)).chain(messages)
+            })
+            .collect();
+        self.group = group;
+        self.cursor = 0;
+        self.top = 0;
+    }
+
+    fn move_by(&mut self, lines: isize) {
+        self.cursor = self
+            .cursor
+            .saturating_add_signed(lines)
+            .min(self.lines.len() - 1);
+    }
+
+    /// The candidates of the line under the cursor: all those of a group.
+    fn members(&self) -> &[usize] {
+        match &self.lines[self.cursor] {
+            Line::Group(_, members) => members,
+            Line::Message(i) => std::slice::from_ref(i),
+        }
+    }
+
+    /// Clean the candidates of the line under the cursor, or keep them when they are all
+    /// cleaned already.
+    fn toggle(&mut self) {
+        let members = self.members().to_vec();
+        let clean = !members.iter().all(|&i| self.candidates[i].clean);
+        for i in members {
+            self.candidates[i].clean = clean;
+        }
+    }
+
+    fn set_all(&mut self, clean: bool) {
+        for candidate in &mut self.candidates {
+            candidate.clean = clean;
+        }
+    }
+
+    /// The number and the size of the candidates chosen.
+    fn chosen(&self) -> (usize, u64) {
+        let chosen = self.candidates.iter().filter(|x| x.clean);
+        chosen.fold((0, 0), |(count, size), x| {
+            (count + 1, size + u64::from(x.row.size()))
+        })
+    }
+
+    fn selection(&self) -> UidList {
+        let mut mailboxes = BTreeMap::<_, (_, Vec<_>)>::new();
+        for candidate in self.candidates.iter().filter(|x| x.clean) {
+            mailboxes
+                .entry(candidate.mailbox.as_str())
+                .or_insert((candidate.uid_validity, Vec::new()))
+                .1
+                .push(candidate.row.uid());
+        }
+        let mut selection = UidList::default();
+        for (mailbox, (uid_validity, uids)) in mailboxes {
+            selection.insert(mailbox, uid_validity, uids);
+        }
+        selection
+    }
+
+    /// The header and the lines of the list fitting in `height`, scrolled to the cursor.
+    fn render(&mut self, height: usize) -> Vec<String> {
+        let height = height.max(1);
+        self.top = self
+            .top
+            .min(self.cursor)
+            .max((self.cursor + 1).saturating_sub(height));
+        let mailbox = |name: &str| match self.group {
+            Group::Mailbox => String::new(),
+            _ => format!("{:20} ", name),
+        };
+        formatting(true, || {
+            let mut lines = vec![bold(format!(
+                "{:10}{}{}",
+                "",
+                mailbox("MAILBOX"),
+                preview::header()
+            ))
+            .to_string()];
+            for (i, line) in self.lines.iter().enumerate().skip(self.top).take(height) {
+                let cursor = if i == self.cursor { ">" } else { " " };
+                lines.push(match line {
+                    Line::Group(name, members) => {
+                        let count = members
+                            .iter()
+                            .filter(|&&i| self.candidates[i].clean)
+                            .count();
+                        let mark = match count {
+                            0 => "[ ]",
+                            _ if count == members.len() => "[x]",
+                            _ => "[-]",
+                        };
+                        let size = members
+                            .iter()
+                            .map(|&i| u64::from(self.candidates[i].row.size()))
+                            .sum();
+                        let name = format!(
+                            "{} {}: {} of {} messages ({})",
+                            mark,
+                            sanitize(name),
+                            count,
+                            members.len(),
+                            format_size(size)
+                        );
+                        format!("{} {}", cursor, bold(name))
+                    }
+                    Line::Message(i) => {
+                        let candidate = &self.candidates[*i];
+                        let row =
+                            sanitize(&format!("{}{}", mailbox(&candidate.mailbox), candidate.row));
+                        match candidate.clean {
+                            true => format!("{}     [x] {}", cursor, row),
+                            false => format!("{}     [ ] {}", cursor, yellow(row)),
+                        }
+                    }
+                });
+            }
+            lines
+        })
+    }
+
+    fn status(&self, action: &Action) -> String {
+        let (count, size) = self.chosen();
+        formatting(true, || {
+            format!(
+                "{} of {} messages ({}) to be {}. Grouped by {}, g for {}.",
+                bold(count),
+                self.candidates.len(),
+                format_size(size),
+                cleaned(action.done(), action.frees()),
+                self.group.name(),
+                self.group.next().name()
+            )
+        })
+    }
+}
+
+/// The terminal shows the alternate screen until dropped, leaving the output of the run as it
+/// was.
+struct Alternate<'a>(&'a Term);
+
+impl<'a> Alternate<'a> {
+    fn enter(term: &'a Term) -> io::Result<Self> {
+        term.write_str("\x1b[?1049h")?;
+        term.hide_cursor()?;
+        Ok(Alternate(term))
+    }
+}
+
+impl Drop for Alternate<'_> {
+    fn drop(&mut self) {
+        // Nothing else to try.
+        let _ = self.0.show_cursor();
+        let _ = self.0.write_str("\x1b[?1049l");
+    }
+}
+
+/// The rows and the columns of the terminal.
+fn size(term: &Term) -> (usize, usize) {
+    let (height, width) = term.size();
+    (usize::from(height), usize::from(width))
+}
+
+/// Draw the screen, each line cut to the width.
+fn draw(term: &Term, lines: &[String], width: usize) -> io::Result<()> {
+    let mut frame = "\x1b[H".to_string();
+    for (i, line) in lines.iter().enumerate() {
+        if i > 0 {
+            frame.push_str("\r\n");
+        }
+        frame.push_str(&console::truncate_str(line, width, ""));
+        frame.push_str("\x1b[0m\x1b[K");
+    }
+    frame.push_str("\x1b[J");
+    term.write_str(&frame)
+}
+
+/// Show a message, scrolled with the arrows and the space, until Enter, Escape or q.
+fn page(term: &Term, message: &[String]) -> io::Result<()> {
+    let mut top = 0;
+    loop {
+        let (height, width) = size(term);
+        let lines = message
+            .iter()
+            .flat_map(|x| wrap(x, width))
+            .collect::<Vec<_>>();
+        let height = height.saturating_sub(1).max(1);
+        let last = lines.len().saturating_sub(height);
+        top = top.min(last);
+        let mut shown = lines[top..]
+            .iter()
+            .take(height)
+            .cloned()
+            .collect::<Vec<_>>();
+        shown.resize(height, String::new());
+        shown.push(formatting(true, || {
+            yellow("arrows/space: scroll · enter/q: back").to_string()
+        }));
+        draw(term, &shown, width)?;
+        match term.read_key()? {
+            Key::ArrowUp | Key::Char('k') => top = top.saturating_sub(1),
+            Key::ArrowDown | Key::Char('j') => top += 1,
+            Key::PageUp | Key::Char('b') => top = top.saturating_sub(height),
+            Key::PageDown | Key::Char(' ') => top += height,
+            REDRAW => term.clear_screen()?,
+            Key::Enter | Key::Escape | Key::Backspace | Key::Char('q') | Key::CtrlC => {
+                return Ok(())
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The lines of a line longer than the width.
+fn wrap(line: &str, width: usize) -> Vec<String> {
+    let chars = line.chars().collect::<Vec<_>>();
+    if chars.is_empty() {
+        return vec![String::new()];
+    }
+    chars
+        .chunks(width.max(1))
+        .map(|x| x.iter().collect())
+        .collect()
+}
+
+/// The fields and the text of a message, from the start of it.
+fn fetch<S: Read + Write>(session: &mut Session<S>, candidate: &Candidate) -> Result<Vec<String>> {
+    session.examine(&candidate.mailbox)?;
+    let fetch = session.uid_fetch(
+        candidate.row.uid().to_string(),
+        format!("BODY.PEEK[]<0.{}>", READ),
+    )?;
+    let message = fetch.iter().find_map(|x| x.body()).unwrap_or_default();
+    Ok(message_lines(message))
+}
+
+fn message_lines(message: &[u8]) -> Vec<String> {
+    let fields = mime::parse_headers(&message[..mime::body_start(message)]);
+    let mut lines = HEADERS
+        .iter()
+        .filter_map(|name| fields.iter().find(|(x, _)| x.eq_ignore_ascii_case(name)))
+        .map(|(name, value)| sanitize(&format!("{}: {}", name, value)))
+        .collect::<Vec<_>>();
+    lines.push(String::new());
+    lines.extend(mime::text(message).lines().map(sanitize));
+    lines
+}
+
+/// A line of a message without its control characters, the escape sequences would be run by
+/// the terminal.
+fn sanitize(line: &str) -> String {
+    line.trim_end()
+        .replace('\t', "    ")
+        .chars()
+        .filter(|x| !x.is_control())
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::color::strip;
+    use crate::connection::test::session;
+    use crate::preview::test::{dated, row};
+
+    /// The groups of the list, with the UIDs of their candidates.
+    fn groups(screen: &Screen) -> Vec<(String, Vec<u32>)> {
+        let uid = |&i: &usize| screen.candidates[i].row.uid();
+        screen
+            .lines
+            .iter()
+            .filter_map(|x| match x {
+                Line::Group(name, members) => {
+                    Some((name.clone(), members.iter().map(uid).collect()))
+                }
+                Line::Message(_) => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn grouped() {
+        let review = Review::default();
+        review.add(
+            "INBOX",
+            Some(7),
+            vec![
+                dated(1, "b@x", "2020-03-01T12:00:00+00:00"),
+                dated(2, "A@x", "2021-01-01T00:30:00+01:00"),
+            ],
+        );
+        review.add(
+            "Archive",
+            None,
+            vec![dated(5, "a@x", "2020-06-01T12:00:00+00:00")],
+        );
+        review.add("Sent", None, vec![row(9, 10, "b@x")]);
+        let mut screen = Screen::new(review.candidates.into_inner().unwrap());
+        assert_eq!(
+            groups(&screen),
+            [
+                ("Archive".to_string(), vec![5]),
+                ("INBOX".to_string(), vec![1, 2]),
+                ("Sent".to_string(), vec![9]),
+            ]
+        );
+        screen.regroup(Group::Sender);
+        assert_eq!(
+            groups(&screen),
+            [
+                ("a@x".to_string(), vec![2, 5]),
+                ("b@x".to_string(), vec![1, 9])
+            ]
+        );
+        // The year where the message was received.
+        screen.regroup(Group::Year);
+        assert_eq!(
+            groups(&screen),
+            [
+                ("2020".to_string(), vec![1, 5]),
+                ("2021".to_string(), vec![2]),
+                ("no date".to_string(), vec![9]),
+            ]
+        );
+        assert_eq!(screen.lines.len(), 3 + 4);
+        assert!(matches!(screen.lines[1], Line::Message(_)));
+    }
+
+    #[test]
+    fn sanitized() {
+        assert_eq!(sanitize("\x1b]0;title\x07x\ty "), "]0;titlex    y");
+        let lines = message_lines(
+            b"From: a@x\r\nSubject: \x1b[2Jgone\x07\r\nX-Other: no\r\n\r\nHi\x1b[31m\r\n",
+        );
+        assert_eq!(lines, ["From: a@x", "Subject: [2Jgone", "", "Hi[31m"]);
+        // The groups named after the senders too.
+        let review = Review::default();
+        review.add("INBOX", None, vec![row(1, 10, "a\x1b[5m@x")]);
+        let mut screen = Screen::new(review.candidates.into_inner().unwrap());
+        screen.regroup(Group::Sender);
+        let lines = screen.render(10);
+        assert!(lines.iter().all(|x| !strip(x).contains('\x1b')));
+        assert!(strip(&lines[1]).contains("a[5m@x: 1 of 1 messages"));
+    }
+
+    #[test]
+    fn read_only() {
+        let (mut imap, _, sent) = session(
+            b"* 1 EXISTS\r\na2 OK [READ-ONLY] done\r\n\
+              * 1 FETCH (UID 3 BODY[]<0> {22}\r\nSubject: Hi\r\n\r\nHello\r\n)\r\n\
+              a3 OK done\r\n",
+        );
+        let candidate = Candidate {
+            mailbox: "INBOX".to_string(),
+            uid_validity: Some(7),
+            row: row(3, 24, "a@x"),
+            clean: true,
+        };
+        assert_eq!(
+            fetch(&mut imap, &candidate).unwrap(),
+            ["Subject: Hi", "", "Hello"]
+        );
+        // Read without SELECT, not to change the \Seen flags nor the \Recent ones.
+        assert_eq!(
+            String::from_utf8_lossy(&sent.borrow()),
+            "a2 EXAMINE \"INBOX\"\r\na3 UID FETCH 3 BODY.PEEK[]<0.65536>\r\n"
+        );
+    }
+
+    #[test]
+    fn chosen() {
+        let review = Review::default();
+        review.add("INBOX", Some(7), vec![row(1, 10, "a@x"), row(2, 20, "b@x")]);
+        review.add("Archive", None, vec![row(5, 30, "A@x")]);
+        let mut screen = Screen::new(review.candidates.into_inner().unwrap());
+        screen.regroup(Group::Sender);
+        // a@x, with 1 and 5.
+        screen.toggle();
+        assert_eq!(screen.chosen(), (1, 20));
+        screen.move_by(1);
+        screen.toggle();
+        assert_eq!(screen.chosen(), (2, 30));
+        let lines = screen
+            .render(10)
+            .iter()
+            .map(|x| strip(x))
+            .collect::<Vec<_>>();
+        assert!(lines[1].starts_with("  [-] a@x: 1 of 2 messages (40B)"));
+        assert!(lines[2].starts_with(">     [x] INBOX "));
+        assert!(lines[3].starts_with("      [ ] Archive "));
+        let selection = screen.selection();
+        assert_eq!(selection.uids("INBOX"), [1, 2]);
+        assert!(selection.uids("Archive").is_empty());
+    }
+}
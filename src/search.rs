@@ -0,0 +1,535 @@
+use crate::mailbox::quote;
+use chrono::{Date, Local, NaiveDate, TimeZone};
+
+/// The system flags of RFC 3501 and their search key.
+const SYSTEM_FLAGS: &[(&str, &str)] = &[
+    ("\\Answered", "ANSWERED"),
+    ("\\Deleted", "DELETED"),
+    ("\\Draft", "DRAFT"),
+    ("\\Flagged", "FLAGGED"),
+    ("\\Seen", "SEEN"),
+];
+
+/// The flag protected when --protect-flag is not given.
+pub const DEFAULT_PROTECTED_FLAG: &str = "\\Flagged";
+
+/// Which messages to cleanup, on top of --before.
+#[derive(clap::Args, Clone, Debug)]
+pub struct SearchArgs {
+    /// Only cleanup the messages of this day or later, for example --after 2019-01-01 --before
+    /// 2020-01-01 for the mails of 2019.
+    #[clap(long, value_parser(parse_date), env = "IMAP_CLEANUP_AFTER")]
+    pub after: Option<Date<Local>>,
+
+    /// The date of the messages compared to --before and --after. The internal date is when the
+    /// server received the message: for a mailbox imported from elsewhere, it is the date of the
+    /// import. The sent date comes from the Date: header, which the sender can get wrong.
+    #[clap(
+        long,
+        value_enum,
+        default_value_t = DateSource::Internal,
+        env = "IMAP_CLEANUP_DATE_SOURCE"
+    )]
+    pub date_source: DateSource,
+
+    /// Only cleanup the messages larger than this size, for example 5M (K, M and G are powers of
+    /// 1024). Useful to purge the old bulky mail first when the quota is full.
+    #[clap(
+        long,
+        value_name = "SIZE",
+        value_parser(parse_size),
+        env = "IMAP_CLEANUP_LARGER_THAN"
+    )]
+    pub larger_than: Option<u64>,
+
+    /// Only cleanup the messages smaller than this size, same format as --larger-than.
+    #[clap(
+        long,
+        value_name = "SIZE",
+        value_parser(parse_size),
+        env = "IMAP_CLEANUP_SMALLER_THAN"
+    )]
+    pub smaller_than: Option<u64>,
+
+    /// Only cleanup the messages from this sender, can be given multiple times to match any of
+    /// them. Matches a part of the From: header, like an address or a domain.
+    #[clap(long, value_name = "ADDRESS", env = "IMAP_CLEANUP_FROM")]
+    pub from: Vec<String>,
+
+    /// Only cleanup the messages to this recipient, same as --from for the To: header.
+    #[clap(long, value_name = "ADDRESS", env = "IMAP_CLEANUP_TO")]
+    pub to: Vec<String>,
+
+    /// Only cleanup the messages with this header, as NAME=VALUE where the value is a part of the
+    /// header's, for example 'X-Mailer=Mailchimp'. Can be given multiple times, all must match.
+    /// An empty value matches all the messages with the header.
+    #[clap(
+        long,
+        value_name = "NAME=VALUE",
+        value_parser(parse_header),
+        env = "IMAP_CLEANUP_HEADER"
+    )]
+    pub header: Vec<(String, String)>,
+
+    /// Only cleanup the messages matching this IMAP SEARCH criteria too, given verbatim, for example
+    /// 'OR HEADER List-Id "" KEYWORD $Newsletter'. The final search is shown with --dry-run.
+    #[clap(
+        long,
+        value_name = "CRITERIA",
+        value_parser(parse_search),
+        env = "IMAP_CLEANUP_SEARCH"
+    )]
+    pub search: Option<String>,
+
+    /// Only cleanup the messages already read, for example the old newsletters one read.
+    #[clap(long, env = "IMAP_CLEANUP_ONLY_SEEN")]
+    pub only_seen: bool,
+
+    /// Only cleanup the messages not read yet.
+    #[clap(long, conflicts_with = "only-seen", env = "IMAP_CLEANUP_ONLY_UNSEEN")]
+    pub only_unseen: bool,
+
+    /// Never cleanup the messages with this flag, can be given multiple times: a system flag like
+    /// \Answered or a keyword like $Important [default: \Flagged].
+    #[clap(
+        long,
+        value_name = "FLAG",
+        value_parser(parse_flag),
+        env = "IMAP_CLEANUP_PROTECT_FLAG"
+    )]
+    pub protect_flag: Vec<String>,
+
+    /// Also cleanup the flagged (starred) messages, for those who flag junk. Asks for a
+    /// confirmation when run from a terminal without --dry-run.
+    #[clap(long, env = "IMAP_CLEANUP_INCLUDE_FLAGGED")]
+    pub include_flagged: bool,
+
+    /// Do not protect any flag, not even \Flagged.
+    #[clap(
+        long,
+        conflicts_with = "protect-flag",
+        env = "IMAP_CLEANUP_NO_PROTECT_FLAG"
+    )]
+    pub no_protect_flag: bool,
+}
+
+impl SearchArgs {
+    /// Check the options that only make sense together.
+    pub fn validate<Tz: TimeZone>(&self, before: &Date<Tz>) -> Result<(), String> {
+        if let Some(after) = &self.after {
+            if after.naive_utc() >= before.naive_utc() {
+                return Err(format!(
+                    "--after ({}) must be earlier than --before ({})",
+                    after.naive_utc().format("%Y-%m-%d"),
+                    before.naive_utc().format("%Y-%m-%d")
+                ));
+            }
+        }
+        if let (Some(larger), Some(smaller)) = (self.larger_than, self.smaller_than) {
+            if larger >= smaller {
+                return Err("--larger-than must be less than --smaller-than".to_string());
+            }
+        }
+        Ok(())
+    }
+
+    /// The query for the messages before this day.
+    pub fn query<Tz: TimeZone>(&self, before: &Date<Tz>) -> Query {
+        let mut query = Query::default();
+        query.date_source(self.date_source);
+        if let Some(after) = &self.after {
+            query.since(after);
+        }
+        query.before(before);
+        if let Some(size) = self.larger_than {
+            query.larger(size);
+        }
+        if let Some(size) = self.smaller_than {
+            query.smaller(size);
+        }
+        query.any(self.from.iter().map(|x| format!("FROM {}", quote(x))));
+        query.any(self.to.iter().map(|x| format!("TO {}", quote(x))));
+        for (name, value) in &self.header {
+            query.criterion(format!("HEADER {} {}", name, quote(value)));
+        }
+        if let Some(search) = &self.search {
+            // A list keeps several keys together: (FROM a TO b) NOT FLAGGED.
+            query.criterion(format!("({})", search));
+        }
+        if self.only_seen {
+            query.criterion("SEEN".to_string());
+        }
+        if self.only_unseen {
+            query.criterion("UNSEEN".to_string());
+        }
+        if !self.no_protect_flag {
+            let default = [DEFAULT_PROTECTED_FLAG.to_string()];
+            let flags = match self.protect_flag.as_slice() {
+                [] => &default[..],
+                flags => flags,
+            };
+            for flag in flags {
+                if !(self.include_flagged && flag == "\\Flagged") {
+                    query.protect(flag);
+                }
+            }
+        }
+        query
+    }
+}
+
+/// Which date of the messages --before and --after compare to.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DateSource {
+    /// When the server received the message (INTERNALDATE), the date of the import for imported
+    /// mailboxes.
+    #[default]
+    Internal,
+    /// The Date: header, when the message was sent (SENTBEFORE and SENTSINCE).
+    Sent,
+}
+
+/// The SEARCH query selecting the messages to cleanup.
+#[derive(Debug, Default)]
+pub struct Query {
+    date_source: DateSource,
+    criteria: Vec<String>,
+}
+
+impl Query {
+    /// Compare the dates given afterwards to this date of the messages.
+    pub fn date_source(&mut self, date_source: DateSource) -> &mut Self {
+        self.date_source = date_source;
+        self
+    }
+
+    /// Messages dated before this day.
+    pub fn before<Tz: TimeZone>(&mut self, date: &Date<Tz>) -> &mut Self {
+        let key = match self.date_source {
+            DateSource::Internal => "BEFORE",
+            DateSource::Sent => "SENTBEFORE",
+        };
+        self.date(key, date)
+    }
+
+    /// Messages dated this day or later.
+    pub fn since<Tz: TimeZone>(&mut self, date: &Date<Tz>) -> &mut Self {
+        let key = match self.date_source {
+            DateSource::Internal => "SINCE",
+            DateSource::Sent => "SENTSINCE",
+        };
+        self.date(key, date)
+    }
+
+    fn date<Tz: TimeZone>(&mut self, key: &str, date: &Date<Tz>) -> &mut Self {
+        self.criterion(format!("{} {}", key, date.naive_utc().format("%-e-%b-%Y")))
+    }
+
+    /// Messages larger than this number of bytes.
+    pub fn larger(&mut self, size: u64) -> &mut Self {
+        self.criterion(format!("LARGER {}", size))
+    }
+
+    /// Messages smaller than this number of bytes.
+    pub fn smaller(&mut self, size: u64) -> &mut Self {
+        self.criterion(format!("SMALLER {}", size))
+    }
+
+    /// Messages without this flag, a system flag or a keyword checked by `parse_flag`.
+    pub fn protect(&mut self, flag: &str) -> &mut Self {
+        let criterion = match SYSTEM_FLAGS
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(flag))
+        {
+            Some((_, key)) => format!("NOT {}", key),
+            None => format!("NOT KEYWORD {}", flag),
+        };
+        self.criterion(criterion)
+    }
+
+    /// Messages matching any of these criteria, nothing is added without criteria.
+    pub fn any(&mut self, criteria: impl IntoIterator<Item = String>) -> &mut Self {
+        let criteria = criteria.into_iter().collect::<Vec<_>>();
+        // OR takes two keys: OR a OR b c.
+        match criteria.split_last() {
+            Some((last, rest)) => {
+                let mut any = last.clone();
+                for criterion in rest.iter().rev() {
+                    any = format!("OR {} {}", criterion, any);
+                }
+                self.criterion(any)
+            }
+            None => self,
+        }
+    }
+
+    pub fn criterion(&mut self, criterion: String) -> &mut Self {
+        self.criteria.push(criterion);
+        self
+    }
+
+    pub fn build(&self) -> String {
+        if self.criteria.is_empty() {
+            return "ALL".to_string();
+        }
+        self.criteria.join(" ")
+    }
+}
+
+pub fn parse_date(s: &str) -> chrono::ParseResult<Date<Local>> {
+    Ok(Local
+        .from_local_date(&NaiveDate::parse_from_str(s, "%Y-%m-%d")?)
+        .unwrap())
+}
+
+/// Parse a size like `5M`: a number of bytes with an optional suffix K, M or G (powers of 1024).
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let (number, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, ""),
+    };
+    let multiplier = match unit.to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1 << 10,
+        "M" | "MB" | "MIB" => 1 << 20,
+        "G" | "GB" | "GIB" => 1 << 30,
+        _ => return Err("expected a size like 500K, 5M or 1G".to_string()),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|x| x.checked_mul(multiplier))
+        .ok_or_else(|| "expected a size like 500K, 5M or 1G".to_string())
+}
+
+/// A size for humans like `1.5M`, with the suffixes of `parse_size`.
+pub fn format_size(bytes: u64) -> String {
+    match bytes {
+        0..=1023 => format!("{}B", bytes),
+        1024..=1048575 => format!("{:.1}K", bytes as f64 / 1024.0),
+        1048576..=1073741823 => format!("{:.1}M", bytes as f64 / 1048576.0),
+        _ => format!("{:.1}G", bytes as f64 / 1073741824.0),
+    }
+}
+
+/// Parse a header match like `X-Mailer=Mailchimp`.
+pub fn parse_header(s: &str) -> Result<(String, String), String> {
+    let (name, value) = s
+        .split_once('=')
+        .ok_or_else(|| "expected NAME=VALUE".to_string())?;
+    // A field name is printable ASCII but the colon (RFC 5322).
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_graphic() && c != ':') {
+        return Err(format!("invalid header name {:?}", name));
+    }
+    Ok((name.to_string(), value.to_string()))
+}
+
+/// Check criteria given to --search: the parentheses must be balanced and the quoted strings
+/// closed, or the rest of the query would end up inside them.
+pub fn parse_search(s: &str) -> Result<String, String> {
+    if s.trim().is_empty() {
+        return Err("empty search criteria".to_string());
+    }
+    if s.contains(['\r', '\n']) {
+        return Err("the search criteria cannot span lines".to_string());
+    }
+    let mut depth = 0usize;
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => {
+                        chars.next();
+                    }
+                    Some(_) => {}
+                    None => return Err("unterminated quoted string".to_string()),
+                }
+            },
+            '(' => depth += 1,
+            ')' => {
+                depth = depth
+                    .checked_sub(1)
+                    .ok_or_else(|| "unbalanced parentheses: unexpected ')'".to_string())?;
+            }
+            _ => {}
+        }
+    }
+    if depth > 0 {
+        return Err("unbalanced parentheses: missing ')'".to_string());
+    }
+    Ok(s.trim().to_string())
+}
+
+/// Check a flag given to --protect-flag: a system flag like `\Answered` or a keyword like
+/// `$Important`.
+pub fn parse_flag(s: &str) -> Result<String, String> {
+    if let Some(name) = s.strip_prefix('\\') {
+        return match SYSTEM_FLAGS
+            .iter()
+            .find(|(flag, _)| flag[1..].eq_ignore_ascii_case(name))
+        {
+            Some((flag, _)) => Ok(flag.to_string()),
+            None => Err(format!(
+                "unknown system flag, expected one of {}",
+                SYSTEM_FLAGS
+                    .iter()
+                    .map(|(flag, _)| *flag)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )),
+        };
+    }
+    // A keyword is an atom (RFC 3501).
+    let invalid = |c: char| c <= ' ' || c >= '\x7f' || "(){%*\"\\]".contains(c);
+    if s.is_empty() || s.contains(invalid) {
+        return Err("invalid keyword".to_string());
+    }
+    Ok(s.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::Utc;
+
+    #[test]
+    fn query() {
+        let before = Utc.ymd(2020, 1, 1);
+        assert_eq!(
+            Query::default()
+                .before(&before)
+                .protect(DEFAULT_PROTECTED_FLAG)
+                .build(),
+            "BEFORE 1-Jan-2020 NOT FLAGGED"
+        );
+        assert_eq!(
+            Query::default()
+                .before(&before)
+                .protect("\\Answered")
+                .protect("$Important")
+                .criterion("X-GM-RAW \"is:unread\"".to_string())
+                .build(),
+            "BEFORE 1-Jan-2020 NOT ANSWERED NOT KEYWORD $Important X-GM-RAW \"is:unread\""
+        );
+        assert_eq!(
+            Query::default()
+                .since(&Utc.ymd(2019, 1, 1))
+                .before(&before)
+                .build(),
+            "SINCE 1-Jan-2019 BEFORE 1-Jan-2020"
+        );
+        assert_eq!(
+            Query::default()
+                .date_source(DateSource::Sent)
+                .since(&Utc.ymd(2019, 1, 1))
+                .before(&before)
+                .build(),
+            "SENTSINCE 1-Jan-2019 SENTBEFORE 1-Jan-2020"
+        );
+        assert_eq!(Query::default().build(), "ALL");
+    }
+
+    #[test]
+    fn args() {
+        use clap::Parser;
+
+        #[derive(clap::Parser)]
+        struct Args {
+            #[clap(flatten)]
+            search: SearchArgs,
+        }
+
+        let query = |args: &[&str]| {
+            let args = Args::parse_from(std::iter::once("test").chain(args.iter().copied()));
+            args.search.query(&Utc.ymd(2020, 1, 1)).build()
+        };
+        assert_eq!(query(&[]), "BEFORE 1-Jan-2020 NOT FLAGGED");
+        assert_eq!(
+            query(&["--only-seen", "--include-flagged"]),
+            "BEFORE 1-Jan-2020 SEEN"
+        );
+        assert_eq!(
+            query(&["--only-unseen", "--protect-flag", "$Important"]),
+            "BEFORE 1-Jan-2020 UNSEEN NOT KEYWORD $Important"
+        );
+        assert_eq!(query(&["--no-protect-flag"]), "BEFORE 1-Jan-2020");
+        assert_eq!(
+            query(&["--header", "X-Mailer=Mail chimp", "--no-protect-flag"]),
+            "BEFORE 1-Jan-2020 HEADER X-Mailer \"Mail chimp\""
+        );
+        assert_eq!(
+            query(&["--search", "OR FROM a TO b"]),
+            "BEFORE 1-Jan-2020 (OR FROM a TO b) NOT FLAGGED"
+        );
+        assert_eq!(
+            query(&[
+                "--from",
+                "a@example.com",
+                "--from",
+                "b.example",
+                "--from",
+                "c@example.com",
+                "--to",
+                "me@example.com",
+                "--no-protect-flag"
+            ]),
+            "BEFORE 1-Jan-2020 OR FROM \"a@example.com\" OR FROM \"b.example\" \
+             FROM \"c@example.com\" TO \"me@example.com\""
+        );
+    }
+
+    #[test]
+    fn headers() {
+        assert_eq!(
+            parse_header("X-Mailer=Mailchimp"),
+            Ok(("X-Mailer".to_string(), "Mailchimp".to_string()))
+        );
+        assert_eq!(
+            parse_header("List-Id="),
+            Ok(("List-Id".to_string(), String::new()))
+        );
+        assert!(parse_header("X-Mailer").is_err());
+        assert!(parse_header("X Mailer=a").is_err());
+    }
+
+    #[test]
+    fn search() {
+        assert_eq!(
+            parse_search(" OR HEADER List-Id \"\" KEYWORD $Newsletter "),
+            Ok("OR HEADER List-Id \"\" KEYWORD $Newsletter".to_string())
+        );
+        assert!(parse_search("(FROM a (TO b))").is_ok());
+        assert!(parse_search("SUBJECT \"a ) \\\" b\"").is_ok());
+        assert!(parse_search("(FROM a").is_err());
+        assert!(parse_search("FROM a)(").is_err());
+        assert!(parse_search("SUBJECT \"a").is_err());
+        assert!(parse_search("SUBJECT \"a\\\"").is_err());
+        assert!(parse_search("FROM a\r\nb2 LOGOUT").is_err());
+        assert!(parse_search(" ").is_err());
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("1234"), Ok(1234));
+        assert_eq!(parse_size("5M"), Ok(5 * 1024 * 1024));
+        assert_eq!(parse_size("500k"), Ok(500 * 1024));
+        assert_eq!(parse_size("1GiB"), Ok(1 << 30));
+        assert!(parse_size("M").is_err());
+        assert!(parse_size("5T").is_err());
+        assert!(parse_size("-5M").is_err());
+        assert_eq!(format_size(512), "512B");
+        assert_eq!(format_size(1536), "1.5K");
+        assert_eq!(format_size(5 * 1024 * 1024), "5.0M");
+    }
+
+    #[test]
+    fn flags() {
+        assert_eq!(parse_flag("\\flagged").unwrap(), "\\Flagged");
+        assert_eq!(parse_flag("$Important").unwrap(), "$Important");
+        assert!(parse_flag("\\Recent").is_err());
+        assert!(parse_flag("two words").is_err());
+        assert!(parse_flag("").is_err());
+    }
+}
@@ -0,0 +1,333 @@
+use crate::error::{Error, Result};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::{Mutex, OnceLock};
+
+/// A reference to a secret kept in a secrets manager:
+///
+/// * `vault://<path>#<field>`: HashiCorp Vault (KV v1 or v2), using `VAULT_ADDR` and
+///   `VAULT_TOKEN` (or `~/.vault-token`). The field defaults to `password`.
+/// * `aws-sm://<secret-id>[#<key>]`: AWS Secrets Manager, using the usual `AWS_*` environment
+///   variables. Without a key the whole secret string is used, otherwise it is parsed as JSON.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum SecretRef {
+    Vault {
+        path: String,
+        field: String,
+    },
+    AwsSecretsManager {
+        secret_id: String,
+        key: Option<String>,
+    },
+}
+
+impl FromStr for SecretRef {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (scheme, rest) = s
+            .split_once("://")
+            .ok_or_else(|| "expected vault://... or aws-sm://...".to_string())?;
+        let (location, fragment) = match rest.split_once('#') {
+            Some((location, fragment)) => (location, Some(fragment.to_string())),
+            None => (rest, None),
+        };
+        if location.is_empty() {
+            return Err("missing secret location".to_string());
+        }
+        match scheme {
+            "vault" => Ok(SecretRef::Vault {
+                path: location.trim_matches('/').to_string(),
+                field: fragment.unwrap_or_else(|| "password".to_string()),
+            }),
+            "aws-sm" => Ok(SecretRef::AwsSecretsManager {
+                secret_id: location.to_string(),
+                key: fragment,
+            }),
+            other => Err(format!("unsupported secrets manager: {}", other)),
+        }
+    }
+}
+
+impl SecretRef {
+    /// Fetch the secret. It is cached for the rest of the run so connecting several times only
+    /// hits the secrets manager once.
+    pub fn fetch(&self) -> Result<String> {
+        static CACHE: OnceLock<Mutex<HashMap<SecretRef, String>>> = OnceLock::new();
+        let cache = CACHE.get_or_init(Default::default);
+
+        if let Some(secret) = cache.lock().unwrap().get(self) {
+            return Ok(secret.clone());
+        }
+        let secret = match self {
+            SecretRef::Vault { path, field } => vault::fetch(path, field)?,
+            SecretRef::AwsSecretsManager { secret_id, key } => {
+                aws::fetch(secret_id, key.as_deref())?
+            }
+        };
+        cache.lock().unwrap().insert(self.clone(), secret.clone());
+        Ok(secret)
+    }
+}
+
+#[cfg(any(feature = "vault", feature = "aws-secrets"))]
+fn env(name: &str) -> Result<String> {
+    std::env::var(name).map_err(|_| Error::Secret(format!("{} is not set", name)))
+}
+
+#[cfg(feature = "vault")]
+mod vault {
+    use super::*;
+
+    pub fn fetch(path: &str, field: &str) -> Result<String> {
+        let addr = env("VAULT_ADDR")?;
+        let token = match env("VAULT_TOKEN") {
+            Ok(token) => token,
+            Err(err) => std::env::var_os("HOME")
+                .map(|home| std::path::Path::new(&home).join(".vault-token"))
+                .and_then(|path| std::fs::read_to_string(path).ok())
+                .map(|token| token.trim().to_string())
+                .ok_or(err)?,
+        };
+
+        let url = format!("{}/v1/{}", addr.trim_end_matches('/'), path);
+        let response: serde_json::Value = crate::auth::http_agent()?
+            .get(&url)
+            .set("X-Vault-Token", &token)
+            .call()
+            .map_err(|err| Error::Secret(format!("vault: {}", err)))?
+            .into_json()?;
+
+        // KV v2 nests the secret in data.data, KV v1 in data.
+        let data = &response["data"];
+        data["data"][field]
+            .as_str()
+            .or_else(|| data[field].as_str())
+            .map(String::from)
+            .ok_or_else(|| Error::Secret(format!("vault: no field {:?} in {}", field, path)))
+    }
+}
+
+#[cfg(not(feature = "vault"))]
+mod vault {
+    use super::*;
+
+    pub fn fetch(_path: &str, _field: &str) -> Result<String> {
+        Err(Error::Secret(
+            "this binary was built without the vault feature".to_string(),
+        ))
+    }
+}
+
+#[cfg(feature = "aws-secrets")]
+mod aws {
+    use super::*;
+    use chrono::Utc;
+    use hmac::{Hmac, Mac};
+    use sha2::{Digest, Sha256};
+
+    const TARGET: &str = "secretsmanager.GetSecretValue";
+    const CONTENT_TYPE: &str = "application/x-amz-json-1.1";
+
+    pub fn fetch(secret_id: &str, key: Option<&str>) -> Result<String> {
+        let region = region_from_arn(secret_id)
+            .map(String::from)
+            .or_else(|_| env("AWS_REGION"))
+            .or_else(|_| env("AWS_DEFAULT_REGION"))?;
+        let access_key = env("AWS_ACCESS_KEY_ID")?;
+        let secret_key = env("AWS_SECRET_ACCESS_KEY")?;
+        let session_token = std::env::var("AWS_SESSION_TOKEN").ok();
+
+        let host = format!("secretsmanager.{}.amazonaws.com", region);
+        let body = serde_json::json!({ "SecretId": secret_id }).to_string();
+        let amz_date = Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+
+        let mut headers = vec![
+            ("content-type", CONTENT_TYPE.to_string()),
+            ("host", host.clone()),
+            ("x-amz-date", amz_date.clone()),
+        ];
+        if let Some(token) = &session_token {
+            headers.push(("x-amz-security-token", token.clone()));
+        }
+        headers.push(("x-amz-target", TARGET.to_string()));
+        let authorization = authorization(
+            &access_key,
+            &secret_key,
+            &region,
+            &amz_date,
+            &headers,
+            &body,
+        );
+
+        let mut request = crate::auth::http_agent()?
+            .post(&format!("https://{}/", host))
+            .set("Authorization", &authorization);
+        for (name, value) in &headers {
+            if *name != "host" {
+                request = request.set(name, value);
+            }
+        }
+        let response: serde_json::Value = request
+            .send_string(&body)
+            .map_err(|err| Error::Secret(format!("AWS Secrets Manager: {}", err)))?
+            .into_json()?;
+
+        let secret = response["SecretString"].as_str().ok_or_else(|| {
+            Error::Secret(format!(
+                "AWS Secrets Manager: {} has no SecretString",
+                secret_id
+            ))
+        })?;
+        match key {
+            None => Ok(secret.to_string()),
+            Some(key) => serde_json::from_str::<serde_json::Value>(secret)
+                .ok()
+                .and_then(|x| x[key].as_str().map(String::from))
+                .ok_or_else(|| {
+                    Error::Secret(format!(
+                        "AWS Secrets Manager: no key {:?} in {}",
+                        key, secret_id
+                    ))
+                }),
+        }
+    }
+
+    fn region_from_arn(secret_id: &str) -> Result<&str> {
+        match secret_id.split(':').collect::<Vec<_>>().as_slice() {
+            ["arn", _, "secretsmanager", region, ..] => Ok(region),
+            _ => Err(Error::Secret("not an ARN".to_string())),
+        }
+    }
+
+    fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any size");
+        mac.update(data.as_bytes());
+        mac.finalize().into_bytes().to_vec()
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|x| format!("{:02x}", x)).collect()
+    }
+
+    fn signing_key(secret_key: &str, date: &str, region: &str, service: &str) -> Vec<u8> {
+        let key = hmac(format!("AWS4{}", secret_key).as_bytes(), date);
+        let key = hmac(&key, region);
+        let key = hmac(&key, service);
+        hmac(&key, "aws4_request")
+    }
+
+    /// AWS Signature Version 4 of a POST to `/`. The headers must be sorted by name.
+    fn authorization(
+        access_key: &str,
+        secret_key: &str,
+        region: &str,
+        amz_date: &str,
+        headers: &[(&str, String)],
+        body: &str,
+    ) -> String {
+        let date = &amz_date[..8];
+        let signed_headers = headers
+            .iter()
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>()
+            .join(";");
+        let canonical_headers: String = headers
+            .iter()
+            .map(|(name, value)| format!("{}:{}\n", name, value.trim()))
+            .collect();
+        let canonical_request = format!(
+            "POST\n/\n\n{}\n{}\n{}",
+            canonical_headers,
+            signed_headers,
+            hex(&Sha256::digest(body.as_bytes()))
+        );
+        let scope = format!("{}/{}/secretsmanager/aws4_request", date, region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            hex(&Sha256::digest(canonical_request.as_bytes()))
+        );
+        let signature = hex(&hmac(
+            &signing_key(secret_key, date, region, "secretsmanager"),
+            &string_to_sign,
+        ));
+        format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            access_key, scope, signed_headers, signature
+        )
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+
+        #[test]
+        fn derive_signing_key() {
+            // Example from the AWS Signature Version 4 documentation.
+            assert_eq!(
+                hex(&signing_key(
+                    "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY",
+                    "20120215",
+                    "us-east-1",
+                    "iam"
+                )),
+                "f4780e2d9f65fa895f9c67b32ce1baf0b0d8a43505a000a1a9e090d414db404d"
+            );
+        }
+
+        #[test]
+        fn arn_region() {
+            assert_eq!(
+                region_from_arn("arn:aws:secretsmanager:eu-west-1:123456789012:secret:imap-AbCdEf")
+                    .unwrap(),
+                "eu-west-1"
+            );
+            assert!(region_from_arn("imap/password").is_err());
+        }
+    }
+}
+
+#[cfg(not(feature = "aws-secrets"))]
+mod aws {
+    use super::*;
+
+    pub fn fetch(_secret_id: &str, _key: Option<&str>) -> Result<String> {
+        Err(Error::Secret(
+            "this binary was built without the aws-secrets feature".to_string(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            "vault://secret/data/mail#imap".parse(),
+            Ok(SecretRef::Vault {
+                path: "secret/data/mail".to_string(),
+                field: "imap".to_string(),
+            })
+        );
+        assert_eq!(
+            "vault://secret/mail".parse(),
+            Ok(SecretRef::Vault {
+                path: "secret/mail".to_string(),
+                field: "password".to_string(),
+            })
+        );
+        assert_eq!(
+            "aws-sm://arn:aws:secretsmanager:eu-west-1:1234:secret:imap#password".parse(),
+            Ok(SecretRef::AwsSecretsManager {
+                secret_id: "arn:aws:secretsmanager:eu-west-1:1234:secret:imap".to_string(),
+                key: Some("password".to_string()),
+            })
+        );
+        assert!("gcp://secret".parse::<SecretRef>().is_err());
+        assert!("vault://".parse::<SecretRef>().is_err());
+    }
+}
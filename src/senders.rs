@@ -0,0 +1,179 @@
+use crate::error::{Error, Result};
+use crate::try_batches;
+use imap::Session;
+use imap_proto::types::Address;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+/// The senders never cleaned.
+#[derive(clap::Args, Debug)]
+pub struct SendersArgs {
+    /// Never cleanup the messages from the senders listed in this file, one per line: an address
+    /// like boss@example.com, a domain like example.com or a glob like *@*.example.com. The
+    /// empty lines and the lines starting with # are ignored.
+    #[clap(long, value_name = "PATH", env = "IMAP_CLEANUP_KEEP_SENDERS_FILE")]
+    pub keep_senders_file: Option<PathBuf>,
+}
+
+impl SendersArgs {
+    pub fn load(&self) -> Result<Option<KeepSenders>> {
+        self.keep_senders_file
+            .as_deref()
+            .map(KeepSenders::load)
+            .transpose()
+    }
+}
+
+/// The entries of --keep-senders-file.
+#[derive(Debug)]
+pub struct KeepSenders {
+    pub entries: Vec<String>,
+}
+
+/// The UIDs left to cleanup by `KeepSenders::apply`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Kept {
+    pub uids: Vec<u32>,
+    /// The number of messages protected by each entry, by the first matching one.
+    pub protected: Vec<usize>,
+}
+
+impl KeepSenders {
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|err| Error::Config(format!("{}: {}", path.display(), err)))?;
+        Ok(Self::parse(&content))
+    }
+
+    fn parse(content: &str) -> Self {
+        let entries = content
+            .lines()
+            .map(str::trim)
+            .filter(|x| !x.is_empty() && !x.starts_with('#'))
+            .map(str::to_lowercase)
+            .collect();
+        KeepSenders { entries }
+    }
+
+    /// The index of the first entry matching this address.
+    pub fn position(&self, address: &str) -> Option<usize> {
+        let address = address.to_lowercase();
+        let domain = address.rsplit_once('@').map(|(_, x)| x).unwrap_or_default();
+        self.entries
+            .iter()
+            .position(|entry| match entry.strip_prefix('@') {
+                Some(entry) => glob(entry, domain),
+                None if entry.contains('@') => glob(entry, &address),
+                None => glob(entry, domain),
+            })
+    }
+
+    /// Keep out the UIDs of the selected mailbox whose sender is an entry.
+    pub fn apply<S: Read + Write>(&self, session: &mut Session<S>, uids: &[u32]) -> Result<Kept> {
+        let mut kept = Kept {
+            uids: Vec::new(),
+            protected: vec![0; self.entries.len()],
+        };
+        try_batches("FETCH", uids, |set, _| {
+            let fetch = session.uid_fetch(set, "(UID ENVELOPE)")?;
+            for message in fetch.iter() {
+                let uid = match message.uid {
+                    Some(uid) => uid,
+                    None => continue,
+                };
+                let from = message.envelope().and_then(|x| x.from.as_ref());
+                match addresses(from).iter().find_map(|x| self.position(x)) {
+                    Some(i) => kept.protected[i] += 1,
+                    None => kept.uids.push(uid),
+                }
+            }
+            Ok(())
+        })?;
+        kept.uids.sort_unstable();
+        Ok(kept)
+    }
+}
+
+/// The addresses of an envelope field, like `boss@example.com`.
+pub fn addresses(addresses: Option<&Vec<Address>>) -> Vec<String> {
+    addresses
+        .into_iter()
+        .flatten()
+        // The groups of RFC 5322 come without a host.
+        .filter_map(|x| Some((x.mailbox?, x.host?)))
+        .map(|(mailbox, host)| {
+            format!(
+                "{}@{}",
+                String::from_utf8_lossy(mailbox),
+                String::from_utf8_lossy(host)
+            )
+        })
+        .collect()
+}
+
+/// Match a glob with `*` and `?`.
+fn glob(pattern: &str, s: &str) -> bool {
+    fn glob_chars(pattern: &[char], s: &[char]) -> bool {
+        match pattern.split_first() {
+            None => s.is_empty(),
+            Some(('*', rest)) => (0..=s.len()).any(|i| glob_chars(rest, &s[i..])),
+            Some(('?', rest)) => !s.is_empty() && glob_chars(rest, &s[1..]),
+            Some((c, rest)) => s.first() == Some(c) && glob_chars(rest, &s[1..]),
+        }
+    }
+    glob_chars(
+        &pattern.chars().collect::<Vec<_>>(),
+        &s.chars().collect::<Vec<_>>(),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::connection::test::session;
+
+    #[test]
+    fn entries() {
+        let senders = KeepSenders::parse(
+            "# family\n\
+             Mom@Example.com\n\
+             \n\
+             work.example\n\
+             *@*.bank.example\n\
+             @school.example\n",
+        );
+        assert_eq!(senders.position("mom@example.com"), Some(0));
+        assert_eq!(senders.position("dad@example.com"), None);
+        assert_eq!(senders.position("Boss@Work.example"), Some(1));
+        assert_eq!(senders.position("boss@mail.work.example"), None);
+        assert_eq!(senders.position("alerts@eu.bank.example"), Some(2));
+        assert_eq!(senders.position("alerts@bank.example"), None);
+        assert_eq!(senders.position("teacher@school.example"), Some(3));
+    }
+
+    #[test]
+    fn protected() {
+        let (mut imap, _, sent) = session(
+            b"* 1 FETCH (UID 3 ENVELOPE (NIL NIL ((NIL NIL \"mom\" \"example.com\")) \
+              NIL NIL NIL NIL NIL NIL NIL))\r\n\
+              * 2 FETCH (UID 4 ENVELOPE (NIL NIL ((NIL NIL \"news\" \"shop.example\")) \
+              NIL NIL NIL NIL NIL NIL NIL))\r\n\
+              * 3 FETCH (UID 5 ENVELOPE (NIL NIL ((\"Mom\" NIL \"Mom\" \"Example.com\")) \
+              NIL NIL NIL NIL NIL NIL NIL))\r\n\
+              * 4 FETCH (UID 6 ENVELOPE (NIL NIL NIL NIL NIL NIL NIL NIL NIL NIL))\r\n\
+              a2 OK done\r\n",
+        );
+        let senders = KeepSenders::parse("mom@example.com\nwork.example\n");
+        assert_eq!(
+            senders.apply(&mut imap, &[3, 4, 5, 6]).unwrap(),
+            Kept {
+                uids: vec![4, 6],
+                protected: vec![2, 0],
+            }
+        );
+        assert_eq!(
+            String::from_utf8_lossy(&sent.borrow()),
+            "a2 UID FETCH 3:6 (UID ENVELOPE)\r\n"
+        );
+    }
+}
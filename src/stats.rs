@@ -0,0 +1,604 @@
+use crate::error::Result;
+use crate::mailbox::quote;
+use crate::mime::decode_header;
+use crate::search::format_size;
+use crate::senders::addresses;
+use crate::tap::Tap;
+use crate::uidlist;
+use crate::BATCH_SIZE;
+use chrono::{DateTime, FixedOffset};
+use imap::Session;
+use itertools::Itertools;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{Read, Write};
+use std::sync::atomic::Ordering;
+
+/// The status size of RFC 8438.
+const STATUS_SIZE: &str = "STATUS=SIZE";
+
+/// The number of messages whose size is fetched to estimate the size of a mailbox, without
+/// STATUS=SIZE.
+const SAMPLE: u32 = 200;
+
+/// What is in a mailbox.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub messages: u32,
+    pub unseen: u32,
+    pub size: u64,
+    /// Whether the size is estimated from a sample of the messages.
+    pub estimated: bool,
+}
+
+/// Print the number of messages, unseen messages and the size of these mailboxes, to see where
+/// the cleanup would help.
+pub fn list<S: Read + Write>(
+    session: &mut Session<S>,
+    tap: &Tap,
+    mailboxes: &[String],
+) -> Result<()> {
+    let status_size = session.capabilities()?.has_str(STATUS_SIZE);
+    let width = mailboxes.iter().map(|x| x.len()).max().unwrap_or(0).max(7);
+    println!(
+        "{:width$} {:>9} {:>9} {:>9}",
+        "MAILBOX", "MESSAGES", "UNSEEN", "SIZE"
+    );
+    let mut total = Stats::default();
+    for name in mailboxes {
+        let stats = stats(session, tap, name, status_size)?;
+        println!(
+            "{:width$} {:>9} {:>9} {:>9}",
+            name,
+            stats.messages,
+            stats.unseen,
+            size(&stats)
+        );
+        total.messages += stats.messages;
+        total.unseen += stats.unseen;
+        total.size += stats.size;
+        total.estimated |= stats.estimated;
+    }
+    if mailboxes.len() > 1 {
+        println!(
+            "{:width$} {:>9} {:>9} {:>9}",
+            "Total",
+            total.messages,
+            total.unseen,
+            size(&total)
+        );
+    }
+    if total.estimated {
+        println!("~: estimated from the sizes of {} messages.", SAMPLE);
+    }
+    Ok(())
+}
+
+fn size(stats: &Stats) -> String {
+    match stats.estimated {
+        true => format!("~{}", format_size(stats.size)),
+        false => format_size(stats.size),
+    }
+}
+
+/// The periods of the histogram of the ages.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Period {
+    Month,
+    Year,
+}
+
+/// The number and size of the messages of a period.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Count {
+    pub messages: u32,
+    pub size: u64,
+}
+
+/// Print the number and size of the messages of these mailboxes per month or year of their
+/// INTERNALDATE, with the totals since the oldest: what --before the next period would cleanup.
+pub fn histogram<S: Read + Write>(
+    session: &mut Session<S>,
+    mailboxes: &[String],
+    period: Period,
+) -> Result<()> {
+    let mut periods = BTreeMap::new();
+    for mailbox in mailboxes {
+        count(session, mailbox, period, &mut periods)?;
+    }
+    println!(
+        "{:7} {:>9} {:>9} {:>9} {:>10}",
+        "PERIOD", "MESSAGES", "SIZE", "TOTAL", "TOTAL SIZE"
+    );
+    let mut total = Count::default();
+    for (name, count) in &periods {
+        total.messages += count.messages;
+        total.size += count.size;
+        println!(
+            "{:7} {:>9} {:>9} {:>9} {:>10}",
+            name,
+            count.messages,
+            format_size(count.size),
+            total.messages,
+            format_size(total.size)
+        );
+    }
+    Ok(())
+}
+
+/// Count the messages of a mailbox by period, like `2019-03` for a month.
+fn count<S: Read + Write>(
+    session: &mut Session<S>,
+    mailbox: &str,
+    period: Period,
+    periods: &mut BTreeMap<String, Count>,
+) -> Result<()> {
+    let exists = session.examine(mailbox)?.exists;
+    for set in sequence_sets(exists) {
+        for message in session.fetch(set, "(INTERNALDATE RFC822.SIZE)")?.iter() {
+            let date = match message.internal_date() {
+                Some(date) => date,
+                None => continue,
+            };
+            let name = match period {
+                Period::Month => date.format("%Y-%m"),
+                Period::Year => date.format("%Y"),
+            };
+            let count = periods.entry(name.to_string()).or_default();
+            count.messages += 1;
+            count.size += u64::from(message.size.unwrap_or_default());
+        }
+    }
+    Ok(())
+}
+
+/// The sequence sets of the messages of a mailbox, in batches of --batch-size.
+fn sequence_sets(exists: u32) -> Vec<String> {
+    let size = BATCH_SIZE.load(Ordering::Relaxed) as u32;
+    (0..exists.div_ceil(size))
+        .map(|i| format!("{}:{}", i * size + 1, ((i + 1) * size).min(exists)))
+        .collect()
+}
+
+/// How `top` ranks the messages.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rank {
+    /// The biggest first.
+    Size,
+    /// The oldest first, by INTERNALDATE.
+    Age,
+    /// The senders whose messages take the most space, with their number of messages.
+    Sender,
+    /// Like sender, by the domain of the sender.
+    Domain,
+}
+
+/// A message ranked by `top`.
+#[derive(Debug, PartialEq, Eq)]
+struct Ranked {
+    /// The index of its mailbox.
+    mailbox: usize,
+    uid_validity: Option<u32>,
+    uid: u32,
+    date: DateTime<FixedOffset>,
+    size: u32,
+}
+
+/// Print the first `count` messages of these mailboxes by size or age, with their mailbox, UID,
+/// date, sender and subject. With `uid_list`, print them as a list for --uids-from instead. By
+/// sender or domain, print the first `count` senders instead.
+pub fn top<S: Read + Write>(
+    session: &mut Session<S>,
+    mailboxes: &[String],
+    rank: Rank,
+    count: usize,
+    uid_list: bool,
+) -> Result<()> {
+    if let Rank::Sender | Rank::Domain = rank {
+        return senders(session, mailboxes, rank, count);
+    }
+    let mut top = Vec::new();
+    for (i, mailbox) in mailboxes.iter().enumerate() {
+        self::rank(session, i, mailbox, rank, count, &mut top)?;
+    }
+    if uid_list {
+        for message in &top {
+            let mailbox = &mailboxes[message.mailbox];
+            println!(
+                "{}",
+                uidlist::line(mailbox, message.uid_validity, message.uid)
+            );
+        }
+        return Ok(());
+    }
+    let envelopes = envelopes(session, mailboxes, &top)?;
+    let width = top
+        .iter()
+        .map(|x| mailboxes[x.mailbox].len())
+        .max()
+        .unwrap_or(0)
+        .max(7);
+    println!(
+        "{:>7} {:10} {:width$} {:>7} {:30} SUBJECT",
+        "SIZE", "DATE", "MAILBOX", "UID", "FROM"
+    );
+    for message in &top {
+        let (from, subject) = envelopes
+            .get(&(message.mailbox, message.uid))
+            .cloned()
+            .unwrap_or_default();
+        println!(
+            "{:>7} {:10} {:width$} {:>7} {:30} {}",
+            format_size(message.size.into()),
+            message.date.format("%Y-%m-%d").to_string(),
+            mailboxes[message.mailbox],
+            message.uid,
+            from,
+            subject
+        );
+    }
+    Ok(())
+}
+
+/// Add the messages of a mailbox to the `count` first ones.
+fn rank<S: Read + Write>(
+    session: &mut Session<S>,
+    index: usize,
+    mailbox: &str,
+    rank: Rank,
+    count: usize,
+    top: &mut Vec<Ranked>,
+) -> Result<()> {
+    let selected = session.examine(mailbox)?;
+    for set in sequence_sets(selected.exists) {
+        for message in session.fetch(set, "(UID INTERNALDATE RFC822.SIZE)")?.iter() {
+            if let (Some(uid), Some(date)) = (message.uid, message.internal_date()) {
+                top.push(Ranked {
+                    mailbox: index,
+                    uid_validity: selected.uid_validity,
+                    uid,
+                    date,
+                    size: message.size.unwrap_or_default(),
+                });
+            }
+        }
+        // Only the first ones are kept, the big mailboxes would not fit in memory.
+        match rank {
+            Rank::Age => top.sort_by(|a, b| a.date.cmp(&b.date).then(b.size.cmp(&a.size))),
+            // The senders are ranked by `senders`.
+            Rank::Size | Rank::Sender | Rank::Domain => {
+                top.sort_by(|a, b| b.size.cmp(&a.size).then(a.date.cmp(&b.date)))
+            }
+        }
+        top.truncate(count);
+    }
+    Ok(())
+}
+
+/// Print the `count` senders, or their domains, whose messages take the most space in these
+/// mailboxes, with the number and size of their messages.
+fn senders<S: Read + Write>(
+    session: &mut Session<S>,
+    mailboxes: &[String],
+    rank: Rank,
+    count: usize,
+) -> Result<()> {
+    let mut senders = HashMap::new();
+    for mailbox in mailboxes {
+        count_senders(session, mailbox, rank, &mut senders)?;
+    }
+    let name = match rank {
+        Rank::Domain => "DOMAIN",
+        _ => "SENDER",
+    };
+    println!("{:>9} {:>9} {}", "MESSAGES", "SIZE", name);
+    let senders = senders
+        .into_iter()
+        .sorted_by(|a, b| b.1.size.cmp(&a.1.size).then_with(|| a.0.cmp(&b.0)))
+        .take(count);
+    for (name, count) in senders {
+        let name = match name.is_empty() {
+            true => "(none)",
+            false => &name,
+        };
+        println!(
+            "{:>9} {:>9} {}",
+            count.messages,
+            format_size(count.size),
+            name
+        );
+    }
+    Ok(())
+}
+
+/// Count the messages of a mailbox by sender, or domain of the sender, in lowercase. The messages
+/// without a sender are counted under an empty name.
+fn count_senders<S: Read + Write>(
+    session: &mut Session<S>,
+    mailbox: &str,
+    rank: Rank,
+    senders: &mut HashMap<String, Count>,
+) -> Result<()> {
+    let exists = session.examine(mailbox)?.exists;
+    for set in sequence_sets(exists) {
+        for message in session.fetch(set, "(RFC822.SIZE ENVELOPE)")?.iter() {
+            let from = addresses(message.envelope().and_then(|x| x.from.as_ref()))
+                .into_iter()
+                .next()
+                .unwrap_or_default()
+                .to_lowercase();
+            let name = match (rank, from.rsplit_once('@')) {
+                (Rank::Domain, Some((_, domain))) => domain.to_string(),
+                _ => from,
+            };
+            let count = senders.entry(name).or_default();
+            count.messages += 1;
+            count.size += u64::from(message.size.unwrap_or_default());
+        }
+    }
+    Ok(())
+}
+
+/// The sender and decoded subject of these messages, by mailbox index and UID.
+fn envelopes<S: Read + Write>(
+    session: &mut Session<S>,
+    mailboxes: &[String],
+    top: &[Ranked],
+) -> Result<HashMap<(usize, u32), (String, String)>> {
+    let mut envelopes = HashMap::new();
+    for (i, mailbox) in mailboxes.iter().enumerate() {
+        let uids = top
+            .iter()
+            .filter(|x| x.mailbox == i)
+            .map(|x| x.uid)
+            .sorted()
+            .collect::<Vec<_>>();
+        if uids.is_empty() {
+            continue;
+        }
+        session.examine(mailbox)?;
+        for (set, _) in crate::batches(&uids) {
+            for message in session.uid_fetch(set, "ENVELOPE")?.iter() {
+                let (uid, envelope) = match (message.uid, message.envelope()) {
+                    (Some(uid), Some(envelope)) => (uid, envelope),
+                    _ => continue,
+                };
+                let from = addresses(envelope.from.as_ref())
+                    .into_iter()
+                    .next()
+                    .unwrap_or_default();
+                let subject = envelope.subject.map(decode_header).unwrap_or_default();
+                envelopes.insert((i, uid), (from, subject));
+            }
+        }
+    }
+    Ok(envelopes)
+}
+
+/// The stats of a mailbox from STATUS, its size from SIZE or else from the RFC822.SIZE of its
+/// messages, of a sample of them in the big mailboxes.
+fn stats<S: Read + Write>(
+    session: &mut Session<S>,
+    tap: &Tap,
+    mailbox: &str,
+    status_size: bool,
+) -> Result<Stats> {
+    let items = match status_size {
+        true => "MESSAGES UNSEEN SIZE",
+        false => "MESSAGES UNSEEN",
+    };
+    // imap-proto does not know SIZE, the tap sets the response aside then.
+    tap.take();
+    let mut response =
+        session.run_command_and_read_response(format!("STATUS {} ({})", quote(mailbox), items))?;
+    response.extend(tap.take().concat());
+    let (mut stats, size) = parse_status(&String::from_utf8_lossy(&response));
+    match size {
+        Some(size) => stats.size = size,
+        None if stats.messages == 0 => {}
+        None => {
+            let exists = session.examine(mailbox)?.exists;
+            let set = match exists > SAMPLE {
+                true => (0..SAMPLE)
+                    .map(|i| (u64::from(i) * u64::from(exists) / u64::from(SAMPLE) + 1).to_string())
+                    .collect::<Vec<_>>()
+                    .join(","),
+                false => "1:*".to_string(),
+            };
+            let sizes = session
+                .fetch(set, "RFC822.SIZE")?
+                .iter()
+                .filter_map(|x| x.size)
+                .map(u64::from)
+                .collect::<Vec<_>>();
+            if !sizes.is_empty() {
+                stats.size = sizes.iter().sum::<u64>() * u64::from(exists) / sizes.len() as u64;
+                stats.estimated = exists > SAMPLE;
+            }
+        }
+    }
+    Ok(stats)
+}
+
+/// The stats in a response like `* STATUS "INBOX" (MESSAGES 231 UNSEEN 3 SIZE 44040)`, with
+/// the SIZE if any.
+fn parse_status(response: &str) -> (Stats, Option<u64>) {
+    let mut stats = Stats::default();
+    let mut size = None;
+    for line in response.lines() {
+        if !line.to_ascii_uppercase().starts_with("* STATUS ") {
+            continue;
+        }
+        let items = match line.rfind('(') {
+            Some(start) => line[start + 1..].trim_end().trim_end_matches(')'),
+            None => continue,
+        };
+        let mut items = items.split_ascii_whitespace();
+        while let (Some(name), Some(value)) = (items.next(), items.next()) {
+            match name.to_ascii_uppercase().as_str() {
+                "MESSAGES" => stats.messages = value.parse().unwrap_or_default(),
+                "UNSEEN" => stats.unseen = value.parse().unwrap_or_default(),
+                "SIZE" => size = value.parse().ok(),
+                _ => {}
+            }
+        }
+    }
+    (stats, size)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::connection::test::session;
+
+    #[test]
+    fn status() {
+        assert_eq!(
+            parse_status("* STATUS \"INBOX\" (MESSAGES 231 UNSEEN 3 SIZE 44040)\r\na2 OK done\r\n"),
+            (
+                Stats {
+                    messages: 231,
+                    unseen: 3,
+                    ..Stats::default()
+                },
+                Some(44040)
+            )
+        );
+        assert_eq!(
+            parse_status("* STATUS Lists (MESSAGES 0 UNSEEN 0)\r\n"),
+            (Stats::default(), None)
+        );
+    }
+
+    #[test]
+    fn ages() {
+        let (mut imap, _, sent) = session(
+            b"* 3 EXISTS\r\n\
+              a2 OK [READ-ONLY] done\r\n\
+              * 1 FETCH (INTERNALDATE \"20-Dec-2019 10:00:00 +0000\" RFC822.SIZE 1000)\r\n\
+              * 2 FETCH (INTERNALDATE \"02-Dec-2019 10:00:00 +0000\" RFC822.SIZE 500)\r\n\
+              * 3 FETCH (INTERNALDATE \"03-Jan-2020 10:00:00 +0000\" RFC822.SIZE 200)\r\n\
+              a3 OK done\r\n",
+        );
+        let mut periods = BTreeMap::new();
+        count(&mut imap, "INBOX", Period::Month, &mut periods).unwrap();
+        assert_eq!(
+            periods.into_iter().collect::<Vec<_>>(),
+            [
+                (
+                    "2019-12".to_string(),
+                    Count {
+                        messages: 2,
+                        size: 1500
+                    }
+                ),
+                (
+                    "2020-01".to_string(),
+                    Count {
+                        messages: 1,
+                        size: 200
+                    }
+                ),
+            ]
+        );
+        assert_eq!(
+            String::from_utf8_lossy(&sent.borrow()),
+            "a2 EXAMINE \"INBOX\"\r\n\
+             a3 FETCH 1:3 (INTERNALDATE RFC822.SIZE)\r\n"
+        );
+    }
+
+    #[test]
+    fn biggest() {
+        let (mut imap, _, sent) = session(
+            b"* 3 EXISTS\r\n\
+              * OK [UIDVALIDITY 7] UIDs valid\r\n\
+              a2 OK [READ-ONLY] done\r\n\
+              * 1 FETCH (UID 10 INTERNALDATE \"20-Dec-2019 10:00:00 +0000\" RFC822.SIZE 1000)\r\n\
+              * 2 FETCH (UID 11 INTERNALDATE \"02-Dec-2019 10:00:00 +0000\" RFC822.SIZE 5000)\r\n\
+              * 3 FETCH (UID 12 INTERNALDATE \"03-Jan-2020 10:00:00 +0000\" RFC822.SIZE 200)\r\n\
+              a3 OK done\r\n",
+        );
+        let mut top = Vec::new();
+        rank(&mut imap, 0, "INBOX", Rank::Size, 2, &mut top).unwrap();
+        assert_eq!(
+            top.iter()
+                .map(|x| (x.uid_validity, x.uid, x.size))
+                .collect::<Vec<_>>(),
+            [(Some(7), 11, 5000), (Some(7), 10, 1000)]
+        );
+        assert_eq!(
+            String::from_utf8_lossy(&sent.borrow()),
+            "a2 EXAMINE \"INBOX\"\r\n\
+             a3 FETCH 1:3 (UID INTERNALDATE RFC822.SIZE)\r\n"
+        );
+    }
+
+    #[test]
+    fn domains() {
+        let (mut imap, _, sent) = session(
+            b"* 3 EXISTS\r\n\
+              a2 OK [READ-ONLY] done\r\n\
+              * 1 FETCH (RFC822.SIZE 1000 ENVELOPE (NIL \"Hi\" ((NIL NIL \"news\" \"List.example\")) NIL NIL NIL NIL NIL NIL NIL))\r\n\
+              * 2 FETCH (RFC822.SIZE 500 ENVELOPE (NIL \"Hi\" ((NIL NIL \"promo\" \"list.example\")) NIL NIL NIL NIL NIL NIL NIL))\r\n\
+              * 3 FETCH (RFC822.SIZE 200 ENVELOPE (NIL \"Hi\" NIL NIL NIL NIL NIL NIL NIL NIL))\r\n\
+              a3 OK done\r\n",
+        );
+        let mut senders = HashMap::new();
+        count_senders(&mut imap, "INBOX", Rank::Domain, &mut senders).unwrap();
+        assert_eq!(
+            senders
+                .into_iter()
+                .sorted_by(|a, b| a.0.cmp(&b.0))
+                .collect::<Vec<_>>(),
+            [
+                (
+                    "".to_string(),
+                    Count {
+                        messages: 1,
+                        size: 200
+                    }
+                ),
+                (
+                    "list.example".to_string(),
+                    Count {
+                        messages: 2,
+                        size: 1500
+                    }
+                ),
+            ]
+        );
+        assert_eq!(
+            String::from_utf8_lossy(&sent.borrow()),
+            "a2 EXAMINE \"INBOX\"\r\n\
+             a3 FETCH 1:3 (RFC822.SIZE ENVELOPE)\r\n"
+        );
+    }
+
+    #[test]
+    fn sizes() {
+        let (mut imap, tap, sent) = session(
+            b"* STATUS \"INBOX\" (MESSAGES 2 UNSEEN 1)\r\n\
+              a2 OK done\r\n\
+              * 2 EXISTS\r\n\
+              a3 OK [READ-ONLY] done\r\n\
+              * 1 FETCH (RFC822.SIZE 1000)\r\n\
+              * 2 FETCH (RFC822.SIZE 3000)\r\n\
+              a4 OK done\r\n",
+        );
+        assert_eq!(
+            stats(&mut imap, &tap, "INBOX", false).unwrap(),
+            Stats {
+                messages: 2,
+                unseen: 1,
+                size: 4000,
+                estimated: false,
+            }
+        );
+        assert_eq!(
+            String::from_utf8_lossy(&sent.borrow()),
+            "a2 STATUS \"INBOX\" (MESSAGES UNSEEN)\r\n\
+             a3 EXAMINE \"INBOX\"\r\n\
+             a4 FETCH 1:* RFC822.SIZE\r\n"
+        );
+    }
+}
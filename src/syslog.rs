@@ -0,0 +1,113 @@
+use crate::error::{Error, Result};
+use std::io::Write;
+use std::os::unix::net::UnixDatagram;
+use tracing::{Level, Metadata};
+use tracing_subscriber::fmt::MakeWriter;
+
+/// The sockets of the system logger, on Linux then on the BSDs and macOS.
+const SOCKETS: &[&str] = &["/dev/log", "/var/run/syslog", "/var/run/log"];
+
+/// The facility of the messages: user-level (RFC 5424 6.2.1).
+const FACILITY: u8 = 1;
+
+/// The system logger, sent a datagram per event with the priority of its level.
+pub struct Syslog {
+    socket: UnixDatagram,
+}
+
+impl Syslog {
+    pub fn connect() -> Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        for path in SOCKETS {
+            if socket.connect(path).is_ok() {
+                return Ok(Self { socket });
+            }
+        }
+        Err(Error::Config(
+            "--log-target syslog: no system logger listens on /dev/log".to_string(),
+        ))
+    }
+}
+
+impl<'a> MakeWriter<'a> for Syslog {
+    type Writer = Message<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        Message {
+            socket: &self.socket,
+            severity: severity(&Level::INFO),
+            line: Vec::new(),
+        }
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        let mut message = self.make_writer();
+        message.severity = severity(meta.level());
+        message
+    }
+}
+
+/// A message written by the logger, sent once dropped.
+pub struct Message<'a> {
+    socket: &'a UnixDatagram,
+    severity: u8,
+    line: Vec<u8>,
+}
+
+impl Write for Message<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.line.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for Message<'_> {
+    fn drop(&mut self) {
+        // Nowhere to tell a message lost.
+        let _ = self.socket.send(&datagram(self.severity, &self.line));
+    }
+}
+
+/// The severity of a level (RFC 5424 6.2.1): err, warning, info or debug.
+fn severity(level: &Level) -> u8 {
+    match *level {
+        Level::ERROR => 3,
+        Level::WARN => 4,
+        Level::INFO => 6,
+        _ => 7,
+    }
+}
+
+/// A message in the format of the local syslog sockets (RFC 3164 without the time and host,
+/// added by the logger).
+fn datagram(severity: u8, line: &[u8]) -> Vec<u8> {
+    let mut datagram = format!(
+        "<{}>imap-cleanup[{}]: ",
+        FACILITY * 8 + severity,
+        std::process::id()
+    )
+    .into_bytes();
+    datagram.extend_from_slice(line.strip_suffix(b"\n").unwrap_or(line));
+    datagram
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn datagrams() {
+        let datagram = datagram(severity(&Level::ERROR), b"Error: 1 of 2 mailboxes failed\n");
+        assert_eq!(
+            String::from_utf8(datagram).unwrap(),
+            format!(
+                "<11>imap-cleanup[{}]: Error: 1 of 2 mailboxes failed",
+                std::process::id()
+            )
+        );
+    }
+}
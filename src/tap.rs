@@ -0,0 +1,200 @@
+use crate::error::Result;
+use imap::Session;
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How long the session can stay idle before `Tap::keepalive` sends a NOOP, well below the 30
+/// minutes after which RFC 3501 lets the servers drop it: some do much earlier.
+pub const KEEPALIVE: Duration = Duration::from_secs(60);
+
+/// Handle on the responses taken out of the stream by `Tapped`.
+///
+/// imap-proto fails on responses it does not know (NAMESPACE, QUOTA, unknown FETCH items...) and
+/// that makes the whole command fail. `Tapped` sets them aside instead so they can be parsed here,
+/// and keeps the last tagged completion because imap drops its response codes (like COPYUID).
+#[derive(Clone, Default)]
+pub struct Tap(Arc<Mutex<State>>);
+
+#[derive(Default)]
+struct State {
+    diverted: Vec<Vec<u8>>,
+    completion: Option<String>,
+    /// When the last command was sent.
+    written: Option<Instant>,
+}
+
+impl Tap {
+    pub fn wrap<S>(&self, inner: S) -> Tapped<S> {
+        Tapped {
+            inner,
+            tap: self.clone(),
+            input: Vec::new(),
+            output: io::Cursor::new(Vec::new()),
+        }
+    }
+
+    /// Run a command and return the responses imap could not parse.
+    pub fn run<S: Read + Write>(
+        &self,
+        session: &mut Session<S>,
+        command: &str,
+    ) -> Result<Vec<Vec<u8>>> {
+        self.take();
+        session.run_command_and_read_response(command)?;
+        Ok(self.take())
+    }
+
+    /// The responses set aside since the last call.
+    pub fn take(&self) -> Vec<Vec<u8>> {
+        std::mem::take(&mut self.0.lock().unwrap().diverted)
+    }
+
+    /// Send a NOOP if no command was sent for a while, to call during the long work done
+    /// between the commands (matching the messages, parsing them) so that the server does not
+    /// drop the session.
+    pub fn keepalive<S: Read + Write>(&self, session: &mut Session<S>) -> Result<()> {
+        let idle = self
+            .0
+            .lock()
+            .unwrap()
+            .written
+            .is_some_and(|x| x.elapsed() >= KEEPALIVE);
+        if idle {
+            session.noop()?;
+        }
+        Ok(())
+    }
+
+    /// The last tagged completion, for example `a4 OK [COPYUID 38505 304,319 3956:3957] Done`.
+    pub fn completion(&self) -> Option<String> {
+        self.0.lock().unwrap().completion.clone()
+    }
+}
+
+pub struct Tapped<S> {
+    inner: S,
+    tap: Tap,
+    /// What was read from the server and does not make a complete response yet.
+    input: Vec<u8>,
+    /// What is left to give to imap.
+    output: io::Cursor<Vec<u8>>,
+}
+
+impl<S> Tapped<S> {
+    fn process(&mut self, response: Vec<u8>) {
+        if response.starts_with(b"* ") {
+            if imap_proto::parse_response(&response).is_err() {
+                self.tap.0.lock().unwrap().diverted.push(response);
+                return;
+            }
+        } else if !response.starts_with(b"+") {
+            self.tap.0.lock().unwrap().completion =
+                Some(String::from_utf8_lossy(&response).trim_end().to_string());
+        }
+        let mut output = self.output.get_ref()[self.output.position() as usize..].to_vec();
+        output.extend_from_slice(&response);
+        self.output = io::Cursor::new(output);
+    }
+}
+
+/// The length of the first complete response in `buf`: a line and its literals.
+fn complete_response(buf: &[u8]) -> Option<usize> {
+    let mut start = 0;
+    loop {
+        let end = start + buf[start..].windows(2).position(|x| x == b"\r\n")? + 2;
+        let line = &buf[..end - 2];
+        let literal = line
+            .strip_suffix(b"}")
+            .and_then(|x| x.iter().rposition(|c| *c == b'{').map(|i| &x[i + 1..]))
+            .and_then(|x| std::str::from_utf8(x).ok())
+            .and_then(|x| x.trim_end_matches('+').parse::<usize>().ok());
+        match literal {
+            Some(len) if buf.len() >= end + len => start = end + len,
+            Some(_) => return None,
+            None => return Some(end),
+        }
+    }
+}
+
+impl<S: Read> Read for Tapped<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if (self.output.position() as usize) < self.output.get_ref().len() {
+                return self.output.read(buf);
+            }
+            if let Some(len) = complete_response(&self.input) {
+                let response = self.input.drain(..len).collect();
+                self.process(response);
+                continue;
+            }
+            let mut chunk = [0; 4096];
+            let n = self.inner.read(&mut chunk)?;
+            if n == 0 {
+                // Let imap see whatever is left, it will report the connection as lost.
+                let rest = std::mem::take(&mut self.input);
+                self.output = io::Cursor::new(rest);
+                return self.output.read(buf);
+            }
+            self.input.extend_from_slice(&chunk[..n]);
+        }
+    }
+}
+
+impl<S: Write> Write for Tapped<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.tap.0.lock().unwrap().written = Some(Instant::now());
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::connection::test::{session, Mock};
+
+    #[test]
+    fn literals() {
+        assert_eq!(complete_response(b"* 1 EXISTS\r\n* 2"), Some(12));
+        assert_eq!(complete_response(b"* 1 EXISTS"), None);
+        let fetch = b"* 1 FETCH (BODY[] {5}\r\nab\r\nc UID 3)\r\n";
+        assert_eq!(complete_response(fetch), Some(fetch.len()));
+        assert_eq!(complete_response(&fetch[..25]), None);
+    }
+
+    #[test]
+    fn divert_unknown_responses() {
+        let tap = Tap::default();
+        let mut stream = tap.wrap(Mock::new(
+            b"* NAMESPACE ((\"\" \"/\")) NIL NIL\r\n\
+              * 1 EXISTS\r\n\
+              a1 OK [COPYUID 1 2 3] done\r\n",
+        ));
+        let mut output = String::new();
+        stream.read_to_string(&mut output).unwrap();
+        assert_eq!(output, "* 1 EXISTS\r\na1 OK [COPYUID 1 2 3] done\r\n");
+        assert_eq!(
+            tap.take(),
+            [b"* NAMESPACE ((\"\" \"/\")) NIL NIL\r\n".to_vec()]
+        );
+        assert!(tap.take().is_empty());
+        assert_eq!(
+            tap.completion().as_deref(),
+            Some("a1 OK [COPYUID 1 2 3] done")
+        );
+    }
+
+    #[test]
+    fn keepalive() {
+        let (mut imap, tap, sent) = session(b"a2 OK done\r\n");
+        tap.keepalive(&mut imap).unwrap();
+        assert!(sent.borrow().is_empty());
+        tap.0.lock().unwrap().written = Some(Instant::now() - KEEPALIVE);
+        tap.keepalive(&mut imap).unwrap();
+        assert_eq!(String::from_utf8_lossy(&sent.borrow()), "a2 NOOP\r\n");
+    }
+}
@@ -0,0 +1,212 @@
+use crate::age::Age;
+use crate::error::Result;
+use crate::mime::parse_headers;
+use crate::search::Query;
+use crate::tap::Tap;
+use crate::try_batches;
+use chrono::{Date, Local};
+use imap::Session;
+use regex::Regex;
+use std::collections::{BTreeSet, HashMap};
+use std::io::{Read, Write};
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+/// The protection of the conversations still going on.
+#[derive(clap::Args, Debug)]
+pub struct ThreadArgs {
+    /// Never cleanup a message whose conversation (by Message-ID, In-Reply-To and References) has
+    /// a message newer than this in the same mailbox, like 30d, 6w or 3m.
+    #[clap(
+        long,
+        value_name = "AGE",
+        value_parser(Age::from_str),
+        env = "IMAP_CLEANUP_PROTECT_ACTIVE_THREADS"
+    )]
+    pub protect_active_threads: Option<Age>,
+}
+
+impl ThreadArgs {
+    /// The protection on `today`, None without --protect-active-threads.
+    pub fn active_threads(&self, today: Date<Local>) -> Option<ActiveThreads> {
+        self.protect_active_threads.map(|age| ActiveThreads {
+            since: age.before(today),
+        })
+    }
+}
+
+/// The conversations with a message received since this day are active.
+#[derive(Clone, Debug)]
+pub struct ActiveThreads {
+    pub since: Date<Local>,
+}
+
+impl ActiveThreads {
+    /// Keep out the UIDs of the selected mailbox in an active conversation, returns the others and
+    /// the number kept out.
+    pub fn apply<S: Read + Write>(
+        &self,
+        session: &mut Session<S>,
+        tap: &Tap,
+        uids: &[u32],
+    ) -> Result<(Vec<u32>, usize)> {
+        if uids.is_empty() {
+            return Ok((Vec::new(), 0));
+        }
+        let recent = session
+            .uid_search(Query::default().since(&self.since).build())?
+            .into_iter()
+            .collect::<BTreeSet<_>>();
+        let all = uids
+            .iter()
+            .chain(&recent)
+            .copied()
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect::<Vec<_>>();
+
+        let mut threads = Threads::default();
+        try_batches("FETCH", &all, |set, _| {
+            let fetch = session.uid_fetch(
+                set,
+                "(UID BODY.PEEK[HEADER.FIELDS (MESSAGE-ID IN-REPLY-TO REFERENCES)])",
+            )?;
+            for message in fetch.iter() {
+                tap.keepalive(session)?;
+                if let (Some(uid), Some(header)) = (message.uid, message.header()) {
+                    threads.add(uid, header);
+                }
+            }
+            Ok(())
+        })?;
+
+        let active = recent
+            .iter()
+            .filter_map(|uid| threads.root(*uid))
+            .collect::<BTreeSet<_>>();
+        // The messages that could not be fetched are kept out too, their conversation is unknown.
+        let (protected, kept): (Vec<u32>, Vec<u32>) = uids
+            .iter()
+            .partition(|uid| threads.root(**uid).is_none_or(|x| active.contains(&x)));
+        Ok((kept, protected.len()))
+    }
+}
+
+/// The conversations, as the sets of message IDs linked by the replies (union-find).
+#[derive(Debug, Default)]
+struct Threads {
+    parents: HashMap<String, String>,
+    /// The Message-ID of each message, or `uid:N` without one.
+    messages: HashMap<u32, String>,
+}
+
+impl Threads {
+    fn add(&mut self, uid: u32, header: &[u8]) {
+        static ID: OnceLock<Regex> = OnceLock::new();
+        let id = ID.get_or_init(|| Regex::new(r"<[^<>\s]+>").unwrap());
+
+        let fields = parse_headers(header);
+        let ids = |name: &str| {
+            fields
+                .iter()
+                .filter(|(field, _)| field.eq_ignore_ascii_case(name))
+                .flat_map(|(_, value)| id.find_iter(value).map(|x| x.as_str().to_string()))
+                .collect::<Vec<_>>()
+        };
+        let own = match ids("Message-ID").into_iter().next() {
+            Some(own) => own,
+            None => format!("uid:{}", uid),
+        };
+        let ids = ids("In-Reply-To")
+            .into_iter()
+            .chain(ids("References"))
+            .collect::<Vec<_>>();
+        for id in &ids {
+            self.union(&own, id);
+        }
+        self.find(&own);
+        self.messages.insert(uid, own);
+    }
+
+    fn find(&mut self, id: &str) -> String {
+        let parent = self
+            .parents
+            .entry(id.to_string())
+            .or_insert_with(|| id.to_string())
+            .clone();
+        if parent == id {
+            return parent;
+        }
+        let root = self.find(&parent);
+        self.parents.insert(id.to_string(), root.clone());
+        root
+    }
+
+    fn union(&mut self, a: &str, b: &str) {
+        let (a, b) = (self.find(a), self.find(b));
+        if a != b {
+            self.parents.insert(a, b);
+        }
+    }
+
+    /// The conversation of a message added before.
+    fn root(&mut self, uid: u32) -> Option<String> {
+        let id = self.messages.get(&uid)?.clone();
+        Some(self.find(&id))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::connection::test::session;
+    use chrono::TimeZone;
+
+    #[test]
+    fn threads() {
+        let mut threads = Threads::default();
+        threads.add(1, b"Message-ID: <a@x>\r\n\r\n");
+        threads.add(2, b"Message-ID: <b@x>\r\nIn-Reply-To: <a@x>\r\n\r\n");
+        threads.add(
+            3,
+            b"Message-ID: <c@x>\r\nReferences: <a@x>\r\n <b@x>\r\n\r\n",
+        );
+        threads.add(4, b"Message-ID: <d@x>\r\n\r\n");
+        threads.add(5, b"\r\n");
+        threads.add(6, b"\r\n");
+        let root = threads.root(1);
+        assert_eq!(threads.root(2), root);
+        assert_eq!(threads.root(3), root);
+        assert_ne!(threads.root(4), root);
+        assert_ne!(threads.root(5), threads.root(6));
+        assert_eq!(threads.root(7), None);
+    }
+
+    #[test]
+    fn active() {
+        let (mut imap, tap, sent) = session(
+            b"* SEARCH 9\r\n\
+              a2 OK done\r\n\
+              * 1 FETCH (UID 3 BODY[HEADER.FIELDS (MESSAGE-ID IN-REPLY-TO REFERENCES)] {21}\r\n\
+              Message-ID: <a@x>\r\n\r\n)\r\n\
+              * 2 FETCH (UID 4 BODY[HEADER.FIELDS (MESSAGE-ID IN-REPLY-TO REFERENCES)] {21}\r\n\
+              Message-ID: <b@x>\r\n\r\n)\r\n\
+              * 3 FETCH (UID 9 BODY[HEADER.FIELDS (MESSAGE-ID IN-REPLY-TO REFERENCES)] {41}\r\n\
+              Message-ID: <c@x>\r\nIn-Reply-To: <a@x>\r\n\r\n)\r\n\
+              a3 OK done\r\n",
+        );
+        let threads = ActiveThreads {
+            since: Local.ymd(2024, 1, 1),
+        };
+        assert_eq!(
+            threads.apply(&mut imap, &tap, &[3, 4]).unwrap(),
+            (vec![4], 1)
+        );
+        assert_eq!(
+            String::from_utf8_lossy(&sent.borrow()),
+            "a2 UID SEARCH SINCE 1-Jan-2024\r\n\
+             a3 UID FETCH 3:4,9:9 \
+             (UID BODY.PEEK[HEADER.FIELDS (MESSAGE-ID IN-REPLY-TO REFERENCES)])\r\n"
+        );
+    }
+}
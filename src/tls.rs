@@ -0,0 +1,314 @@
+use crate::color;
+use crate::connection::Stream;
+use crate::error::{Error, Result};
+use crate::output::tell;
+use sha2::{Digest, Sha256};
+use std::net::TcpStream;
+use std::path::PathBuf;
+
+#[cfg(not(any(feature = "tls-native", feature = "tls-rustls")))]
+compile_error!("at least one of the features tls-native or tls-rustls must be enabled");
+
+/// TLS implementation used for the IMAP connection.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TlsBackend {
+    /// The platform's TLS library (OpenSSL, Secure Transport or SChannel).
+    Native,
+    /// rustls, a pure Rust implementation.
+    Rustls,
+}
+
+impl Default for TlsBackend {
+    fn default() -> Self {
+        if cfg!(feature = "tls-native") {
+            TlsBackend::Native
+        } else {
+            TlsBackend::Rustls
+        }
+    }
+}
+
+#[derive(clap::Args, Debug)]
+pub struct TlsArgs {
+    /// TLS implementation [default: native if available, rustls otherwise].
+    #[clap(long, value_enum, env = "IMAP_CLEANUP_TLS_BACKEND")]
+    pub tls_backend: Option<TlsBackend>,
+
+    /// Trust the certificates of this PEM file in addition to the system ones, or with rustls to
+    /// the Mozilla ones built in: the certificates added to the system are not trusted by rustls.
+    #[clap(long, env = "IMAP_CLEANUP_CAFILE")]
+    pub cafile: Option<PathBuf>,
+
+    /// Only accept a server certificate with this SHA-256 fingerprint (hex, colons allowed).
+    /// The certificate chain is not validated in this case, the pin replaces it.
+    #[clap(long, value_parser(parse_fingerprint), env = "IMAP_CLEANUP_PIN_SHA256")]
+    pub pin_sha256: Option<[u8; 32]>,
+
+    /// Do not verify the server certificate at all. DANGEROUS: anyone on the network path can
+    /// impersonate the server and read your credentials.
+    #[clap(long, env = "IMAP_CLEANUP_INSECURE")]
+    pub insecure: bool,
+}
+
+impl TlsArgs {
+    /// Whether the usual chain and host name validation must be skipped.
+    fn skip_validation(&self) -> bool {
+        self.insecure || self.pin_sha256.is_some()
+    }
+
+    fn check_pin(&self, certificate: &[u8]) -> Result<()> {
+        match &self.pin_sha256 {
+            Some(pin) if Sha256::digest(certificate).as_slice() != pin => Err(Error::Tls(format!(
+                "the server certificate fingerprint {} does not match --pin-sha256",
+                format_fingerprint(&Sha256::digest(certificate))
+            ))),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn parse_fingerprint(s: &str) -> std::result::Result<[u8; 32], String> {
+    let hex = s.replace(':', "");
+    if hex.len() != 64 || !hex.is_ascii() {
+        return Err("expected 32 hex-encoded bytes".to_string());
+    }
+    let mut fingerprint = [0; 32];
+    for (i, byte) in fingerprint.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).map_err(|err| err.to_string())?;
+    }
+    Ok(fingerprint)
+}
+
+fn format_fingerprint(fingerprint: &[u8]) -> String {
+    fingerprint
+        .iter()
+        .map(|x| format!("{:02X}", x))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// Wrap a TCP stream with TLS, validating the certificate against `host`.
+pub fn wrap(args: &TlsArgs, host: &str, stream: TcpStream) -> Result<Box<dyn Stream>> {
+    if args.insecure {
+        tell!(
+            "{} --insecure is set, the server certificate is NOT verified!",
+            color::error("WARNING:")
+        );
+    }
+    match args.tls_backend.unwrap_or_default() {
+        #[cfg(feature = "tls-native")]
+        TlsBackend::Native => native::wrap(args, host, stream),
+        #[cfg(feature = "tls-rustls")]
+        TlsBackend::Rustls => rustls::wrap(args, host, stream),
+        #[allow(unreachable_patterns)]
+        backend => Err(Error::Tls(format!(
+            "this binary was built without the {:?} TLS backend",
+            backend
+        ))),
+    }
+}
+
+#[cfg(feature = "tls-native")]
+mod native {
+    use super::*;
+    use native_tls::{Certificate, TlsConnector};
+
+    pub fn wrap(args: &TlsArgs, host: &str, stream: TcpStream) -> Result<Box<dyn Stream>> {
+        let mut builder = TlsConnector::builder();
+        if let Some(path) = &args.cafile {
+            for pem in split_pem(&std::fs::read_to_string(path)?) {
+                let certificate = Certificate::from_pem(pem.as_bytes())
+                    .map_err(|err| Error::Tls(format!("{}: {}", path.display(), err)))?;
+                builder.add_root_certificate(certificate);
+            }
+        }
+        if args.skip_validation() {
+            builder.danger_accept_invalid_certs(true);
+            builder.danger_accept_invalid_hostnames(true);
+        }
+        let connector = builder.build().map_err(|err| Error::Tls(err.to_string()))?;
+
+        let stream = connector
+            .connect(host, stream)
+            .map_err(|err| Error::Tls(err.to_string()))?;
+        if args.pin_sha256.is_some() {
+            let certificate = stream
+                .peer_certificate()
+                .map_err(|err| Error::Tls(err.to_string()))?
+                .ok_or_else(|| Error::Tls("the server did not send a certificate".to_string()))?
+                .to_der()
+                .map_err(|err| Error::Tls(err.to_string()))?;
+            args.check_pin(&certificate)?;
+        }
+        Ok(Box::new(stream))
+    }
+
+    /// native-tls only loads one certificate per PEM string.
+    fn split_pem(bundle: &str) -> Vec<String> {
+        let mut certificates = Vec::new();
+        let mut current = None;
+        for line in bundle.lines() {
+            if line.starts_with("-----BEGIN CERTIFICATE-----") {
+                current = Some(String::new());
+            }
+            if let Some(pem) = current.as_mut() {
+                pem.push_str(line);
+                pem.push('\n');
+            }
+            if line.starts_with("-----END CERTIFICATE-----") {
+                certificates.extend(current.take());
+            }
+        }
+        certificates
+    }
+}
+
+#[cfg(feature = "tls-rustls")]
+mod rustls {
+    use super::*;
+    use ::rustls::client::danger::{
+        HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+    };
+    use ::rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+    use ::rustls::pki_types::pem::PemObject;
+    use ::rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+    use ::rustls::{
+        ClientConfig, ClientConnection, DigitallySignedStruct, RootCertStore, SignatureScheme,
+        StreamOwned,
+    };
+    use std::sync::Arc;
+
+    pub fn wrap(args: &TlsArgs, host: &str, mut stream: TcpStream) -> Result<Box<dyn Stream>> {
+        let provider = Arc::new(::rustls::crypto::ring::default_provider());
+        let builder = ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(|err| Error::Tls(err.to_string()))?;
+        let config = if args.skip_validation() {
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(Unverified { provider }))
+                .with_no_client_auth()
+        } else {
+            let mut roots = RootCertStore::empty();
+            roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+            if let Some(path) = &args.cafile {
+                add_cafile(&mut roots, path)
+                    .map_err(|err| Error::Tls(format!("{}: {}", path.display(), err)))?;
+            }
+            builder.with_root_certificates(roots).with_no_client_auth()
+        };
+
+        let server_name =
+            ServerName::try_from(host.to_string()).map_err(|err| Error::Tls(err.to_string()))?;
+        let mut conn = ClientConnection::new(Arc::new(config), server_name)
+            .map_err(|err| Error::Tls(err.to_string()))?;
+        // rustls handshakes lazily, do it now so failures are reported as TLS errors.
+        while conn.is_handshaking() {
+            conn.complete_io(&mut stream)
+                .map_err(|err| Error::Tls(err.to_string()))?;
+        }
+        if args.pin_sha256.is_some() {
+            let certificate = conn
+                .peer_certificates()
+                .and_then(|x| x.first())
+                .ok_or_else(|| Error::Tls("the server did not send a certificate".to_string()))?;
+            args.check_pin(certificate)?;
+        }
+        Ok(Box::new(StreamOwned::new(conn, stream)))
+    }
+
+    fn add_cafile(
+        roots: &mut RootCertStore,
+        path: &std::path::Path,
+    ) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        for certificate in CertificateDer::pem_file_iter(path)? {
+            roots.add(certificate?)?;
+        }
+        Ok(())
+    }
+
+    /// Accepts any certificate. Signatures are still checked so the handshake itself is sound;
+    /// the pin (if any) is checked after the handshake.
+    #[derive(Debug)]
+    struct Unverified {
+        provider: Arc<CryptoProvider>,
+    }
+
+    impl ServerCertVerifier for Unverified {
+        fn verify_server_cert(
+            &self,
+            _end_entity: &CertificateDer<'_>,
+            _intermediates: &[CertificateDer<'_>],
+            _server_name: &ServerName<'_>,
+            _ocsp_response: &[u8],
+            _now: UnixTime,
+        ) -> std::result::Result<ServerCertVerified, ::rustls::Error> {
+            Ok(ServerCertVerified::assertion())
+        }
+
+        fn verify_tls12_signature(
+            &self,
+            message: &[u8],
+            cert: &CertificateDer<'_>,
+            dss: &DigitallySignedStruct,
+        ) -> std::result::Result<HandshakeSignatureValid, ::rustls::Error> {
+            verify_tls12_signature(
+                message,
+                cert,
+                dss,
+                &self.provider.signature_verification_algorithms,
+            )
+        }
+
+        fn verify_tls13_signature(
+            &self,
+            message: &[u8],
+            cert: &CertificateDer<'_>,
+            dss: &DigitallySignedStruct,
+        ) -> std::result::Result<HandshakeSignatureValid, ::rustls::Error> {
+            verify_tls13_signature(
+                message,
+                cert,
+                dss,
+                &self.provider.signature_verification_algorithms,
+            )
+        }
+
+        fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+            self.provider
+                .signature_verification_algorithms
+                .supported_schemes()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn fingerprint() {
+        let expected = [
+            0x9F, 0x86, 0xD0, 0x81, 0x88, 0x4C, 0x7D, 0x65, 0x9A, 0x2F, 0xEA, 0xA0, 0xC5, 0x5A,
+            0xD0, 0x15, 0xA3, 0xBF, 0x4F, 0x1B, 0x2B, 0x0B, 0x82, 0x2C, 0xD1, 0x5D, 0x6C, 0x15,
+            0xB0, 0xF0, 0x0A, 0x08,
+        ];
+        assert_eq!(
+            parse_fingerprint("9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08"),
+            Ok(expected),
+        );
+        let formatted = format_fingerprint(&expected);
+        assert_eq!(parse_fingerprint(&formatted), Ok(expected));
+        assert!(parse_fingerprint("9f86").is_err());
+        assert!(parse_fingerprint(&"zz".repeat(32)).is_err());
+
+        let args = TlsArgs {
+            tls_backend: None,
+            cafile: None,
+            pin_sha256: Some(expected),
+            insecure: false,
+        };
+        assert!(args.check_pin(b"test").is_ok());
+        assert!(args.check_pin(b"other").is_err());
+    }
+}
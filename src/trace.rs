@@ -0,0 +1,251 @@
+use crate::error::Result;
+use chrono::Local;
+use std::fs::OpenOptions;
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock};
+
+/// Where the trace is written, once enabled.
+static SINK: OnceLock<Mutex<Box<dyn Write + Send>>> = OnceLock::new();
+
+/// The number of connections traced so far.
+static CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+
+const REDACTED: &str = "<redacted>";
+
+/// The trace of the IMAP protocol, to debug a server misbehaving.
+#[derive(clap::Args, Debug)]
+pub struct TraceArgs {
+    /// Log every command sent and every response received on the standard error. The
+    /// credentials and the literals, like the message bodies and headers, are redacted.
+    #[clap(long, env = "IMAP_CLEANUP_TRACE")]
+    pub trace: bool,
+
+    /// Append the trace to this file instead of the standard error. Implies --trace.
+    #[clap(long, value_name = "PATH", env = "IMAP_CLEANUP_TRACE_FILE")]
+    pub trace_file: Option<PathBuf>,
+}
+
+impl TraceArgs {
+    /// Enable the trace of the next connections, if asked.
+    pub fn init(&self) -> Result<()> {
+        let sink: Box<dyn Write + Send> = match &self.trace_file {
+            Some(path) => Box::new(OpenOptions::new().create(true).append(true).open(path)?),
+            None if self.trace => Box::new(io::stderr()),
+            None => return Ok(()),
+        };
+        let _ = SINK.set(Mutex::new(sink));
+        Ok(())
+    }
+}
+
+/// The trace of a connection, numbered to tell apart those of --jobs and the reconnections.
+pub struct Trace {
+    id: usize,
+    client: Lines,
+    server: Lines,
+    /// An AUTHENTICATE is running: the client lines are the responses to the challenges.
+    authenticating: bool,
+}
+
+impl Trace {
+    /// The trace of a new connection, which does nothing unless --trace is set.
+    pub fn start() -> Self {
+        Self {
+            id: CONNECTIONS.fetch_add(1, Ordering::Relaxed) + 1,
+            client: Lines::default(),
+            server: Lines::default(),
+            authenticating: false,
+        }
+    }
+
+    pub fn wrap<S>(self, inner: S) -> Traced<S> {
+        Traced { inner, trace: self }
+    }
+
+    /// The lines completed by these bytes sent, redacted.
+    fn sent(&mut self, bytes: &[u8]) -> Vec<String> {
+        let lines = self.client.feed(bytes);
+        lines.into_iter().map(|x| self.redact(x)).collect()
+    }
+
+    /// The responses completed by these bytes received.
+    fn received(&mut self, bytes: &[u8]) -> Vec<String> {
+        let lines = self.server.feed(bytes);
+        if lines
+            .iter()
+            .any(|x| !x.starts_with('*') && !x.starts_with('+'))
+        {
+            self.authenticating = false;
+        }
+        lines
+    }
+
+    /// Redact the credentials of a command: the arguments of LOGIN, the initial response of
+    /// AUTHENTICATE and the responses to its challenges.
+    fn redact(&mut self, line: String) -> String {
+        if self.authenticating {
+            return REDACTED.to_string();
+        }
+        let mut words = line.splitn(4, ' ');
+        let tag = words.next().unwrap_or_default();
+        let command = words.next().unwrap_or_default();
+        if command.eq_ignore_ascii_case("LOGIN") {
+            format!("{} {} {}", tag, command, REDACTED)
+        } else if command.eq_ignore_ascii_case("AUTHENTICATE") {
+            self.authenticating = true;
+            let mechanism = words.next().unwrap_or_default();
+            match words.next() {
+                Some(_) => format!("{} {} {} {}", tag, command, mechanism, REDACTED),
+                None => format!("{} {} {}", tag, command, mechanism),
+            }
+        } else {
+            line
+        }
+    }
+
+    fn log(&self, direction: &str, lines: &[String]) {
+        if let Some(sink) = SINK.get() {
+            let time = Local::now().format("%H:%M:%S%.3f");
+            let mut sink = sink.lock().unwrap();
+            for line in lines {
+                // Nowhere to tell the trace failed.
+                let _ = writeln!(sink, "{} [{}] {}: {}", time, self.id, direction, line);
+            }
+        }
+    }
+}
+
+/// Splits a direction into lines, each with its literals replaced by their size: they hold
+/// the message bodies and headers, or the messages appended.
+#[derive(Default)]
+struct Lines {
+    /// The current line, what follows the last literal.
+    line: Vec<u8>,
+    /// What precedes the last literal.
+    done: String,
+    /// The bytes of the literal left to skip.
+    literal: usize,
+}
+
+impl Lines {
+    fn feed(&mut self, mut bytes: &[u8]) -> Vec<String> {
+        let mut lines = Vec::new();
+        while !bytes.is_empty() {
+            if self.literal > 0 {
+                let skipped = self.literal.min(bytes.len());
+                self.literal -= skipped;
+                bytes = &bytes[skipped..];
+                continue;
+            }
+            let end = bytes
+                .iter()
+                .position(|x| *x == b'\n')
+                .map_or(bytes.len(), |x| x + 1);
+            self.line.extend_from_slice(&bytes[..end]);
+            bytes = &bytes[end..];
+            let line = match self.line.strip_suffix(b"\r\n") {
+                Some(line) => String::from_utf8_lossy(line).into_owned(),
+                None => continue,
+            };
+            self.line.clear();
+            match literal(&line) {
+                Some((start, len)) => {
+                    self.done.push_str(&line[..start]);
+                    self.done.push_str(&format!("<{} bytes>", len));
+                    self.literal = len;
+                }
+                None => lines.push(std::mem::take(&mut self.done) + &line),
+            }
+        }
+        lines
+    }
+}
+
+/// The start and the length of the literal announced at the end of a line, like `{120}` or
+/// `{120+}`.
+fn literal(line: &str) -> Option<(usize, usize)> {
+    let start = line.strip_suffix('}')?.rfind('{')?;
+    let len = line[start + 1..line.len() - 1].trim_end_matches('+');
+    Some((start, len.parse().ok()?))
+}
+
+/// A stream whose commands and responses are traced on --trace.
+pub struct Traced<S> {
+    inner: S,
+    trace: Trace,
+}
+
+impl<S> Traced<S> {
+    /// The stream and its trace, to go on with it on another stream, like after STARTTLS.
+    pub fn into_parts(self) -> (S, Trace) {
+        (self.inner, self.trace)
+    }
+}
+
+impl<S: Read> Read for Traced<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if SINK.get().is_some() {
+            let lines = self.trace.received(&buf[..n]);
+            self.trace.log("S", &lines);
+        }
+        Ok(n)
+    }
+}
+
+impl<S: Write> Write for Traced<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        if SINK.get().is_some() {
+            let lines = self.trace.sent(&buf[..n]);
+            self.trace.log("C", &lines);
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn literals() {
+        let mut lines = Lines::default();
+        assert!(lines.feed(b"* 1 FETCH (UID 3 BODY[] {5}\r\nab").is_empty());
+        assert_eq!(
+            lines.feed(b"\r\nc UID 3)\r\n* 2 EXI"),
+            ["* 1 FETCH (UID 3 BODY[] <5 bytes> UID 3)"]
+        );
+        assert_eq!(lines.feed(b"STS\r\n"), ["* 2 EXISTS"]);
+        assert_eq!(literal("a3 APPEND INBOX {120+}"), Some((16, 120)));
+        assert_eq!(literal("* OK [UIDNEXT 4] {ready}"), None);
+    }
+
+    #[test]
+    fn redacted() {
+        let mut trace = Trace::start();
+        assert_eq!(
+            trace.sent(b"a1 LOGIN \"user\" \"secret\"\r\n"),
+            ["a1 LOGIN <redacted>"]
+        );
+        assert_eq!(
+            trace.sent(b"a2 AUTHENTICATE XOAUTH2\r\n"),
+            ["a2 AUTHENTICATE XOAUTH2"]
+        );
+        trace.received(b"+ \r\n");
+        assert_eq!(trace.sent(b"dXNlcj1zZWNyZXQ=\r\n"), [REDACTED]);
+        trace.received(b"a2 OK authenticated\r\n");
+        assert_eq!(
+            trace.sent(b"a3 AUTHENTICATE PLAIN AHVzZXIAc2VjcmV0\r\n"),
+            ["a3 AUTHENTICATE PLAIN <redacted>"]
+        );
+        trace.received(b"a3 OK authenticated\r\n");
+        assert_eq!(trace.sent(b"a4 SELECT INBOX\r\n"), ["a4 SELECT INBOX"]);
+    }
+}
@@ -0,0 +1,180 @@
+use crate::error::Result;
+use crate::password::SECRET_ENV_VARS;
+use std::io::{self, Read, Write};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+
+/// IMAP over the stdin/stdout of a command, for example `ssh mailhost /usr/lib/dovecot/imap`.
+pub struct Tunnel {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: ChildStdout,
+}
+
+impl Tunnel {
+    pub fn spawn(command: &str, password_env: Option<&str>) -> Result<Self> {
+        let mut command = shell(command, password_env);
+        command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit());
+        // Out of the terminal's process group, Ctrl+C does not kill it before the cleanup stops.
+        #[cfg(unix)]
+        std::os::unix::process::CommandExt::process_group(&mut command, 0);
+        let mut child = command.spawn()?;
+        let stdin = child.stdin.take().expect("stdin is piped");
+        let stdout = child.stdout.take().expect("stdout is piped");
+        Ok(Tunnel {
+            child,
+            stdin,
+            stdout,
+        })
+    }
+}
+
+/// Build a command running `command` through the system shell, without our credentials in its
+/// environment: neither the variables of SECRET_ENV_VARS nor that of --password-env.
+pub fn shell(command: &str, password_env: Option<&str>) -> Command {
+    #[cfg(unix)]
+    let mut shell = {
+        let mut shell = Command::new("sh");
+        shell.arg("-c").arg(command);
+        shell
+    };
+    #[cfg(windows)]
+    let mut shell = {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C").arg(command);
+        shell
+    };
+    for var in SECRET_ENV_VARS.iter().copied().chain(password_env) {
+        shell.env_remove(var);
+    }
+    shell
+}
+
+impl Read for Tunnel {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stdout.read(buf)
+    }
+}
+
+impl Write for Tunnel {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stdin.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stdin.flush()
+    }
+}
+
+impl Drop for Tunnel {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Answers the first command locally instead of sending it to the server.
+///
+/// `imap::Client` can only become a `Session` through LOGIN or AUTHENTICATE, which a server that
+/// greeted with PREAUTH would reject. The login command is swallowed and acknowledged here.
+pub struct Preauth<S> {
+    inner: S,
+    first_line: Option<Vec<u8>>,
+    reply: io::Cursor<Vec<u8>>,
+}
+
+impl<S> Preauth<S> {
+    pub fn new(inner: S) -> Self {
+        Preauth {
+            inner,
+            first_line: Some(Vec::new()),
+            reply: io::Cursor::new(Vec::new()),
+        }
+    }
+}
+
+impl<S: Read> Read for Preauth<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if (self.reply.position() as usize) < self.reply.get_ref().len() {
+            self.reply.read(buf)
+        } else {
+            self.inner.read(buf)
+        }
+    }
+}
+
+impl<S: Write> Write for Preauth<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let line = match self.first_line.as_mut() {
+            Some(line) => line,
+            None => return self.inner.write(buf),
+        };
+        line.extend_from_slice(buf);
+        if let Some(end) = line.windows(2).position(|x| x == b"\r\n") {
+            let tag = line
+                .split(|x| *x == b' ')
+                .next()
+                .unwrap_or_default()
+                .to_vec();
+            let rest = line.split_off(end + 2);
+            let mut reply = tag;
+            reply.extend_from_slice(b" OK already authenticated (PREAUTH)\r\n");
+            self.reply = io::Cursor::new(reply);
+            self.first_line = None;
+            self.inner.write_all(&rest)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::connection::test::Mock;
+
+    #[test]
+    fn preauth_swallows_login() {
+        let mut stream = Preauth::new(Mock::new(b"* 1 EXISTS\r\n"));
+        stream.write_all(b"a1 LOGIN \"user\" ").unwrap();
+        stream.write_all(b"\"\"\r\na2 SELECT INBOX\r\n").unwrap();
+
+        let mut reply = Vec::new();
+        stream.read_to_end(&mut reply).unwrap();
+        assert_eq!(
+            reply,
+            b"a1 OK already authenticated (PREAUTH)\r\n* 1 EXISTS\r\n"
+        );
+        assert_eq!(stream.inner.1, b"a2 SELECT INBOX\r\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn tunnel_echo() {
+        let mut tunnel = Tunnel::spawn("head -n 1", None).unwrap();
+        tunnel.write_all(b"* PREAUTH hello\r\n").unwrap();
+        tunnel.flush().unwrap();
+        let mut output = String::new();
+        tunnel.read_to_string(&mut output).unwrap();
+        assert_eq!(output, "* PREAUTH hello\r\n");
+    }
+
+    #[test]
+    fn shell_hides_credentials() {
+        // Without setting them in the environment, read by the other tests running meanwhile.
+        let command = shell("true", Some("IMAP_CLEANUP_TEST_PASSWORD"));
+        let removed = command
+            .get_envs()
+            .filter(|(_, value)| value.is_none())
+            .map(|(name, _)| name.to_str().unwrap())
+            .collect::<Vec<_>>();
+        assert!(removed.contains(&"IMAP_CLEANUP_OAUTH_CLIENT_SECRET"));
+        assert!(removed.contains(&"IMAP_CLEANUP_TEST_PASSWORD"));
+        assert_eq!(removed.len(), SECRET_ENV_VARS.len() + 1);
+    }
+}
@@ -0,0 +1,136 @@
+use crate::error::{Error, Result};
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::path::Path;
+
+/// A list of messages with a `MAILBOX<TAB>UIDVALIDITY<TAB>UID` line per message, as written by
+/// `top --uid-list` and read by --uids-from. The UIDVALIDITY is `-` when the server has none.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct UidList(BTreeMap<String, Uids>);
+
+/// The messages of a mailbox in a `UidList`.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Uids {
+    uid_validity: Option<u32>,
+    /// Sorted.
+    uids: Vec<u32>,
+}
+
+/// The line of a message in a `UidList`.
+pub fn line(mailbox: &str, uid_validity: Option<u32>, uid: u32) -> String {
+    let uid_validity = uid_validity.map_or("-".to_string(), |x| x.to_string());
+    format!("{}\t{}\t{}", mailbox, uid_validity, uid)
+}
+
+impl UidList {
+    /// Read a list from a file, or from the standard input for `-`.
+    pub fn load(path: &Path) -> Result<Self> {
+        let mut content = String::new();
+        let read = match path.to_str() {
+            Some("-") => std::io::stdin().read_to_string(&mut content),
+            _ => std::fs::File::open(path).and_then(|mut x| x.read_to_string(&mut content)),
+        };
+        read.map_err(|err| Error::Config(format!("{}: {}", path.display(), err)))?;
+        Self::parse(&content).map_err(|err| Error::Config(format!("{}: {}", path.display(), err)))
+    }
+
+    fn parse(content: &str) -> std::result::Result<Self, String> {
+        let mut list = UidList::default();
+        for (i, line) in content.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let invalid = || format!("line {}: expected MAILBOX<TAB>UIDVALIDITY<TAB>UID", i + 1);
+            let mut fields = line.rsplitn(3, '\t');
+            let (uid, uid_validity, mailbox) = match (fields.next(), fields.next(), fields.next()) {
+                (Some(uid), Some(uid_validity), Some(mailbox)) => (uid, uid_validity, mailbox),
+                _ => return Err(invalid()),
+            };
+            let uid = uid.trim().parse::<u32>().map_err(|_| invalid())?;
+            let uid_validity = match uid_validity {
+                "-" => None,
+                x => Some(x.parse::<u32>().map_err(|_| invalid())?),
+            };
+            let uids = list.0.entry(mailbox.to_string()).or_insert(Uids {
+                uid_validity,
+                uids: Vec::new(),
+            });
+            if uids.uid_validity != uid_validity {
+                return Err(format!(
+                    "line {}: another UIDVALIDITY for {}",
+                    i + 1,
+                    mailbox
+                ));
+            }
+            uids.uids.push(uid);
+        }
+        for uids in list.0.values_mut() {
+            uids.uids.sort_unstable();
+            uids.uids.dedup();
+        }
+        Ok(list)
+    }
+
+    /// Add the messages of a mailbox, of this UIDVALIDITY.
+    pub fn insert(&mut self, mailbox: &str, uid_validity: Option<u32>, mut uids: Vec<u32>) {
+        uids.sort_unstable();
+        uids.dedup();
+        self.0
+            .insert(mailbox.to_string(), Uids { uid_validity, uids });
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The mailboxes of the list.
+    pub fn mailboxes(&self) -> Vec<String> {
+        self.0.keys().cloned().collect()
+    }
+
+    /// The UIDs of the messages of this mailbox in the list, sorted.
+    pub fn uids(&self, mailbox: &str) -> &[u32] {
+        self.0.get(mailbox).map_or(&[], |x| &x.uids)
+    }
+
+    /// Check that the UIDs of the list are still those of the mailbox, opened with this
+    /// UIDVALIDITY.
+    pub fn check(&self, mailbox: &str, uid_validity: Option<u32>) -> Result<()> {
+        match self.0.get(mailbox) {
+            Some(uids) if uids.uid_validity != uid_validity => Err(Error::Protocol(format!(
+                "the UIDVALIDITY of {} changed since the list was written, its messages were \
+                 not changed",
+                mailbox
+            ))),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let list = UidList::parse(&format!(
+            "{}\n{}\n\n{}\n",
+            line("Lists/rust", Some(7), 12),
+            line("INBOX", Some(3), 5),
+            line("Lists/rust", Some(7), 4),
+        ))
+        .unwrap();
+        assert_eq!(list.mailboxes(), ["INBOX", "Lists/rust"]);
+        assert_eq!(list.uids("Lists/rust"), [4, 12]);
+        assert_eq!(list.uids("Archive"), [] as [u32; 0]);
+        assert!(list.check("INBOX", Some(3)).is_ok());
+        assert!(list.check("INBOX", Some(4)).is_err());
+
+        assert!(UidList::parse("INBOX 3 5\n").is_err());
+        assert!(UidList::parse("INBOX\t3\t5\nINBOX\t4\t6\n").is_err());
+        assert_eq!(
+            UidList::parse("My Mail\t-\t5\n").unwrap().uids("My Mail"),
+            [5]
+        );
+    }
+}
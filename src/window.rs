@@ -0,0 +1,262 @@
+use crate::error::Result;
+use crate::output::tell;
+use crate::search::{DateSource, Query};
+use chrono::{Date, Datelike, Local, NaiveDate, TimeZone, Utc};
+use imap::Session;
+use std::io::{Read, Write};
+use std::str::FromStr;
+
+/// The search of the huge mailboxes.
+#[derive(clap::Args, Debug)]
+pub struct WindowArgs {
+    /// Search the mailboxes in windows instead of all at once, for the huge mailboxes whose
+    /// SEARCH times out: `month` to search month by month, or a number of messages like 10000.
+    /// The progress is shown after each window.
+    #[clap(
+        long,
+        value_name = "WINDOW",
+        value_parser(Window::from_str),
+        env = "IMAP_CLEANUP_SEARCH_WINDOW"
+    )]
+    pub search_window: Option<Window>,
+}
+
+impl WindowArgs {
+    /// The windows of a search for the messages in these dates, None without --search-window.
+    pub fn windows(
+        &self,
+        date_source: DateSource,
+        after: Option<Date<Local>>,
+        before: Date<Local>,
+    ) -> Option<Windows> {
+        self.search_window.map(|window| Windows {
+            window,
+            date_source,
+            after,
+            before,
+        })
+    }
+}
+
+/// How to split a search.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Window {
+    /// One search per month.
+    Month,
+    /// One search per this number of messages.
+    Messages(u32),
+}
+
+impl FromStr for Window {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("month") {
+            return Ok(Window::Month);
+        }
+        match s.parse::<u32>() {
+            Ok(0) | Err(_) => Err("expected `month` or a number of messages".to_string()),
+            Ok(count) => Ok(Window::Messages(count)),
+        }
+    }
+}
+
+/// The windows of a search for the messages `after` (included) and `before` this day.
+#[derive(Clone, Debug)]
+pub struct Windows {
+    pub window: Window,
+    pub date_source: DateSource,
+    pub after: Option<Date<Local>>,
+    pub before: Date<Local>,
+}
+
+impl Windows {
+    /// The UIDs of the selected mailbox of `exists` messages matching the query, searched window
+    /// by window, sorted.
+    pub fn search<S: Read + Write>(
+        &self,
+        session: &mut Session<S>,
+        mailbox: &str,
+        exists: u32,
+        query: &str,
+    ) -> Result<Vec<u32>> {
+        if exists == 0 {
+            return Ok(Vec::new());
+        }
+        let windows = match self.window {
+            Window::Messages(count) => messages(count, exists),
+            Window::Month => {
+                let first = match self.after {
+                    Some(after) => Some(after.naive_local()),
+                    None => session
+                        .fetch("1", "INTERNALDATE")?
+                        .iter()
+                        .find_map(|x| x.internal_date())
+                        .map(|x| x.naive_local().date()),
+                };
+                match first {
+                    Some(first) => months(first, self.before.naive_local(), self.date_source),
+                    None => vec![(String::new(), "all".to_string())],
+                }
+            }
+        };
+        let mut uids = Vec::new();
+        for (i, (criteria, label)) in windows.iter().enumerate() {
+            let query = match criteria.as_str() {
+                "" => query.to_string(),
+                criteria => format!("{} {}", criteria, query),
+            };
+            uids.extend(session.uid_search(&query)?);
+            tell!(
+                "{}: searched {} ({}/{}), {} found.",
+                mailbox,
+                label,
+                i + 1,
+                windows.len(),
+                uids.len()
+            );
+        }
+        uids.sort_unstable();
+        uids.dedup();
+        Ok(uids)
+    }
+}
+
+/// The search criteria and the name of the windows of `count` messages among `exists`.
+fn messages(count: u32, exists: u32) -> Vec<(String, String)> {
+    (0..exists.div_ceil(count))
+        .map(|i| {
+            let set = format!("{}:{}", i * count + 1, ((i + 1) * count).min(exists));
+            (set.clone(), format!("messages {}", set))
+        })
+        .collect()
+}
+
+/// The search criteria and the name of the windows of a month from the month of `first` to the
+/// month of `before`. The first and last windows are left open, for the messages whose date
+/// (like a sent date) is out of the bounds.
+fn months(first: NaiveDate, before: NaiveDate, date_source: DateSource) -> Vec<(String, String)> {
+    let next = |date: NaiveDate| match date.month() {
+        12 => NaiveDate::from_ymd(date.year() + 1, 1, 1),
+        month => NaiveDate::from_ymd(date.year(), month + 1, 1),
+    };
+    let mut starts = Vec::new();
+    let mut start = next(first);
+    while start < before {
+        starts.push(start);
+        start = next(start);
+    }
+    let criteria = |since: Option<NaiveDate>, before: Option<NaiveDate>| {
+        let mut query = Query::default();
+        query.date_source(date_source);
+        if let Some(since) = since {
+            query.since(&Utc.from_utc_date(&since));
+        }
+        if let Some(before) = before {
+            query.before(&Utc.from_utc_date(&before));
+        }
+        query.build()
+    };
+    let month = |date: NaiveDate| date.format("%b %Y").to_string();
+    match (starts.first(), starts.last()) {
+        (Some(first), Some(last)) => std::iter::once((
+            criteria(None, Some(*first)),
+            format!("before {}", month(*first)),
+        ))
+        .chain(
+            starts
+                .windows(2)
+                .map(|x| (criteria(Some(x[0]), Some(x[1])), month(x[0]))),
+        )
+        .chain(std::iter::once((
+            criteria(Some(*last), None),
+            format!("since {}", month(*last)),
+        )))
+        .collect(),
+        _ => vec![(String::new(), "all".to_string())],
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::connection::test::session;
+
+    #[test]
+    fn windows() {
+        assert_eq!(
+            messages(2, 5),
+            [
+                ("1:2".to_string(), "messages 1:2".to_string()),
+                ("3:4".to_string(), "messages 3:4".to_string()),
+                ("5:5".to_string(), "messages 5:5".to_string()),
+            ]
+        );
+        assert_eq!(
+            months(
+                NaiveDate::from_ymd(2019, 11, 20),
+                NaiveDate::from_ymd(2020, 2, 15),
+                DateSource::Internal
+            ),
+            [
+                (
+                    "BEFORE 1-Dec-2019".to_string(),
+                    "before Dec 2019".to_string()
+                ),
+                (
+                    "SINCE 1-Dec-2019 BEFORE 1-Jan-2020".to_string(),
+                    "Dec 2019".to_string()
+                ),
+                (
+                    "SINCE 1-Jan-2020 BEFORE 1-Feb-2020".to_string(),
+                    "Jan 2020".to_string()
+                ),
+                ("SINCE 1-Feb-2020".to_string(), "since Feb 2020".to_string()),
+            ]
+        );
+        assert_eq!(
+            months(
+                NaiveDate::from_ymd(2020, 2, 1),
+                NaiveDate::from_ymd(2020, 2, 15),
+                DateSource::Sent
+            ),
+            [(String::new(), "all".to_string())]
+        );
+        assert_eq!("month".parse(), Ok(Window::Month));
+        assert_eq!("500".parse(), Ok(Window::Messages(500)));
+        assert!("0".parse::<Window>().is_err());
+    }
+
+    #[test]
+    fn search() {
+        let (mut imap, _, sent) = session(
+            b"* 1 FETCH (INTERNALDATE \"20-Dec-2019 10:00:00 +0000\")\r\n\
+              a2 OK done\r\n\
+              * SEARCH 4 2\r\n\
+              a3 OK done\r\n\
+              * SEARCH\r\n\
+              a4 OK done\r\n\
+              * SEARCH 7\r\n\
+              a5 OK done\r\n",
+        );
+        let windows = Windows {
+            window: Window::Month,
+            date_source: DateSource::Sent,
+            after: None,
+            before: Local.ymd(2020, 2, 15),
+        };
+        assert_eq!(
+            windows
+                .search(&mut imap, "INBOX", 9, "NOT FLAGGED")
+                .unwrap(),
+            [2, 4, 7]
+        );
+        assert_eq!(
+            String::from_utf8_lossy(&sent.borrow()),
+            "a2 FETCH 1 INTERNALDATE\r\n\
+             a3 UID SEARCH SENTBEFORE 1-Jan-2020 NOT FLAGGED\r\n\
+             a4 UID SEARCH SENTSINCE 1-Jan-2020 SENTBEFORE 1-Feb-2020 NOT FLAGGED\r\n\
+             a5 UID SEARCH SENTSINCE 1-Feb-2020 NOT FLAGGED\r\n"
+        );
+    }
+}